@@ -11,8 +11,11 @@ pub type c_int = i32;
 pub type c_long = i64;
 
 // ---------- Linux epoll ----------
+// The kernel declares `struct epoll_event` packed on x86_64 (12 bytes); without it the
+// `u64` field is read from the wrong offset and event tokens come back garbled.
 #[cfg(target_os = "linux")]
-#[repr(C)]
+#[cfg_attr(target_arch = "x86_64", repr(C, packed))]
+#[cfg_attr(not(target_arch = "x86_64"), repr(C))]
 pub struct epoll_event {
     pub events: u32,
     pub u64: u64,
@@ -82,6 +85,7 @@ extern "C" {
     pub fn dlopen(filename: *const c_char, flag: c_int) -> *mut c_void;
     pub fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
     pub fn dlclose(handle: *mut c_void) -> c_int;
+    pub fn dlerror() -> *mut c_char;
 }
 #[cfg(unix)]
 pub const RTLD_NOW: c_int = 2; 
@@ -108,6 +112,10 @@ pub const SIGTERM: c_int = 15;
 #[cfg(target_os = "linux")]
 pub const SIGHUP: c_int = 1;
 #[cfg(target_os = "linux")]
+pub const SIGUSR1: c_int = 10;
+#[cfg(target_os = "linux")]
+pub const SIGUSR2: c_int = 12;
+#[cfg(target_os = "linux")]
 pub const SA_RESTART: c_uint = 0x10000000; 
 
 // Common integer typedefs
@@ -115,7 +123,13 @@ pub type ssize_t = isize;
 pub type off_t = i64;
 
 // errno constants (subset)
+pub const EINTR: c_int = 4;
+pub const EFAULT: c_int = 14;
+pub const ENFILE: c_int = 23;
+pub const EMFILE: c_int = 24;
 pub const ENOSYS: c_int = 38;
+#[cfg(target_os = "linux")]
+pub const EINPROGRESS: c_int = 115;
 
 // ftruncate / fcntl --------------------------------------
 #[cfg(target_os = "linux")]
@@ -255,6 +269,22 @@ pub const SOL_SOCKET: c_int = 1;
 pub const SO_REUSEADDR: c_int = 2;
 #[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
 pub const SO_REUSEPORT: c_int = 15;
+#[cfg(target_os = "linux")]
+pub const SO_ATTACH_REUSEPORT_CBPF: c_int = 51;
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+pub const IPPROTO_TCP: c_int = 6;
+#[cfg(target_os = "linux")]
+pub const TCP_FASTOPEN: c_int = 23;
+#[cfg(target_os = "linux")]
+pub const TCP_INFO: c_int = 11;
+#[cfg(target_os = "linux")]
+pub const AF_INET: c_int = 2;
+#[cfg(target_os = "linux")]
+pub const AF_INET6: c_int = 10;
+#[cfg(target_os = "linux")]
+pub const SOCK_NONBLOCK: c_int = 0x800;
+#[cfg(target_os = "linux")]
+pub const SOCK_CLOEXEC: c_int = 0x80000;
 
 #[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
 #[repr(C)]
@@ -276,14 +306,37 @@ pub struct addrinfo {
     pub ai_next: *mut addrinfo,
 }
 
+#[cfg(target_os = "linux")]
+#[repr(C)]
+pub struct sockaddr_in {
+    pub sin_family: u16,
+    /// Network byte order.
+    pub sin_port: u16,
+    pub sin_addr: [u8; 4],
+    pub sin_zero: [u8; 8],
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+pub struct sockaddr_in6 {
+    pub sin6_family: u16,
+    /// Network byte order.
+    pub sin6_port: u16,
+    pub sin6_flowinfo: u32,
+    pub sin6_addr: [u8; 16],
+    pub sin6_scope_id: u32,
+}
+
 #[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
 extern "C" {
     pub fn getaddrinfo(node: *const c_char, service: *const c_char, hints: *const addrinfo, res: *mut *mut addrinfo) -> c_int;
     pub fn freeaddrinfo(res: *mut addrinfo);
     pub fn socket(domain: c_int, ty: c_int, protocol: c_int) -> c_int;
     pub fn setsockopt(fd: c_int, level: c_int, optname: c_int, optval: *const c_void, optlen: size_t) -> c_int;
+    pub fn getsockopt(fd: c_int, level: c_int, optname: c_int, optval: *mut c_void, optlen: *mut c_uint) -> c_int;
     pub fn bind(fd: c_int, addr: *const sockaddr, len: size_t) -> c_int;
     pub fn listen(fd: c_int, backlog: c_int) -> c_int;
+    pub fn connect(fd: c_int, addr: *const sockaddr, len: size_t) -> c_int;
 } 
 
 // ---------- signals & process control ----------
@@ -294,10 +347,84 @@ pub type pid_t = i32;
 pub const SIGTERM: c_int = 15;
 #[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
 pub const SIGHUP: c_int = 1;
+#[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+pub const SIGUSR1: c_int = 30;
 
 #[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
 extern "C" {
     pub fn fork() -> pid_t;
     pub fn wait(status: *mut c_int) -> pid_t;
     pub fn kill(pid: pid_t, sig: c_int) -> c_int;
-} 
\ No newline at end of file
+} 
+
+// ---------- credentials ----------
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+pub type uid_t = u32;
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+pub type gid_t = u32;
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+extern "C" {
+    pub fn getuid() -> uid_t;
+    pub fn geteuid() -> uid_t;
+    pub fn getgid() -> gid_t;
+    pub fn setuid(uid: uid_t) -> c_int;
+    pub fn setgid(gid: gid_t) -> c_int;
+    #[cfg(target_os = "linux")]
+    pub fn setgroups(size: size_t, list: *const gid_t) -> c_int;
+    #[cfg(not(target_os = "linux"))]
+    pub fn setgroups(ngroups: c_int, gidset: *const gid_t) -> c_int;
+}
+
+// ---------- file modes ----------
+#[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "openbsd"))]
+pub type mode_t = u32;
+#[cfg(target_os = "macos")]
+pub type mode_t = u16;
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+extern "C" {
+    pub fn umask(mask: mode_t) -> mode_t;
+    pub fn fchmod(fd: c_int, mode: mode_t) -> c_int;
+}
+
+// ---------- resource limits ----------
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+pub type rlim_t = u64;
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+#[repr(C)]
+pub struct rlimit {
+    pub rlim_cur: rlim_t,
+    pub rlim_max: rlim_t,
+}
+
+#[cfg(target_os = "linux")]
+pub const RLIMIT_NOFILE: c_int = 7;
+#[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+pub const RLIMIT_NOFILE: c_int = 8;
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+extern "C" {
+    pub fn getrlimit(resource: c_int, rlim: *mut rlimit) -> c_int;
+    pub fn setrlimit(resource: c_int, rlim: *const rlimit) -> c_int;
+}
+
+// ---------- memory-mapped files ----------
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+pub const PROT_READ: c_int = 1;
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+pub const MAP_SHARED: c_int = 1;
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+pub const MAP_FAILED: *mut c_void = !0usize as *mut c_void;
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+pub const MADV_SEQUENTIAL: c_int = 2;
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+pub const MADV_WILLNEED: c_int = 3;
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+extern "C" {
+    pub fn mmap(addr: *mut c_void, len: size_t, prot: c_int, flags: c_int, fd: c_int, offset: off_t) -> *mut c_void;
+    pub fn munmap(addr: *mut c_void, len: size_t) -> c_int;
+    pub fn madvise(addr: *mut c_void, len: size_t, advice: c_int) -> c_int;
+}
\ No newline at end of file
@@ -0,0 +1,92 @@
+//! IPv4/IPv6 CIDR blocks (`10.0.0.0/8`, `fd00::/8`, bare addresses = host routes).
+
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Parse `addr[/prefix]`. Host bits below the prefix are ignored when matching.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        let (a, p) = match s.split_once('/') { Some((a, p)) => (a, Some(p)), None => (s, None) };
+        let addr: IpAddr = a.trim_start_matches('[').trim_end_matches(']').parse().ok()?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match p {
+            Some(p) if !p.is_empty() && p.bytes().all(|c| c.is_ascii_digit()) => p.parse::<u8>().ok().filter(|&n| n <= max)?,
+            Some(_) => return None,
+            None => max,
+        };
+        Some(Cidr { addr, prefix })
+    }
+
+    /// IPv4-mapped IPv6 peers (`::ffff:a.b.c.d`) match IPv4 blocks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => masked_eq(u32::from(net) as u128, u32::from(ip) as u128, 32, self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => masked_eq(u128::from(net), u128::from(ip), 128, self.prefix),
+            _ => false,
+        }
+    }
+}
+
+fn masked_eq(net: u128, ip: u128, width: u8, prefix: u8) -> bool {
+    if prefix == 0 { return true; }
+    let shift = width - prefix;
+    (net >> shift) == (ip >> shift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cidr;
+
+    fn matches(cidr: &str, ip: &str) -> bool {
+        Cidr::parse(cidr).unwrap().contains(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn ipv4_blocks() {
+        assert!(matches("10.0.0.0/8", "10.200.3.4"));
+        assert!(!matches("10.0.0.0/8", "11.0.0.1"));
+        assert!(matches("192.168.1.77/24", "192.168.1.1"));
+        assert!(!matches("192.168.1.0/25", "192.168.1.200"));
+        assert!(matches("203.0.113.9", "203.0.113.9"));
+        assert!(!matches("203.0.113.9", "203.0.113.10"));
+    }
+
+    #[test]
+    fn ipv6_blocks() {
+        assert!(matches("fd00::/8", "fd12:3456::1"));
+        assert!(!matches("fd00::/8", "fe80::1"));
+        assert!(matches("[2001:db8::]/32", "2001:db8:ffff::1"));
+        assert!(matches("::1", "::1"));
+        assert!(!matches("2001:db8::/128", "2001:db8::1"));
+    }
+
+    #[test]
+    fn all_match_blocks() {
+        for ip in ["0.0.0.0", "8.8.8.8", "255.255.255.255", "::ffff:10.1.2.3"] {
+            assert!(matches("0.0.0.0/0", ip), "{}", ip);
+        }
+        assert!(matches("::/0", "2001:db8::1"));
+        // Families do not mix, except IPv4-mapped peers against IPv4 blocks.
+        assert!(!matches("0.0.0.0/0", "2001:db8::1"));
+        assert!(!matches("::/0", "8.8.8.8"));
+        assert!(matches("10.0.0.0/8", "::ffff:10.9.9.9"));
+    }
+
+    #[test]
+    fn malformed_blocks_are_rejected() {
+        for bad in ["", "10.0.0.0/", "10.0.0.0/33", "::/129", "10.0.0.0/+8", "10.0.0/8", "example.com/8", "10.0.0.0/8/8"] {
+            assert_eq!(Cidr::parse(bad), None, "{:?}", bad);
+        }
+        assert!(Cidr::parse(" 10.0.0.0/8 ").is_some());
+    }
+}
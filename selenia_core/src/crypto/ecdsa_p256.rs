@@ -0,0 +1,493 @@
+//! ECDSA over NIST P-256 with SHA-256 (FIPS 186-4, TLS scheme ecdsa_secp256r1_sha256).
+//! Pure Rust: 4x64-bit limbs, Montgomery arithmetic mod p and n, Jacobian points.
+//! Nonces are deterministic (RFC 6979, HMAC-SHA256), so signing needs no entropy.
+//! Scalar multiplication is a Montgomery ladder with masked swaps; the field
+//! arithmetic itself is not hardened against timing analysis.
+
+use super::hmac::hmac_sha256;
+use super::sha256::sha256_digest;
+
+/// TLS 1.3 SignatureScheme code point.
+pub const ECDSA_SECP256R1_SHA256: u16 = 0x0403;
+
+/// 256-bit integer, little-endian 64-bit limbs.
+type U256 = [u64; 4];
+
+const P: U256 = [0xffffffffffffffff, 0x00000000ffffffff, 0x0000000000000000, 0xffffffff00000001];
+const N: U256 = [0xf3b9cac2fc632551, 0xbce6faada7179e84, 0xffffffffffffffff, 0xffffffff00000000];
+const B: U256 = [0x3bce3c3e27d2604b, 0x651d06b0cc53b0f6, 0xb3ebbd55769886bc, 0x5ac635d8aa3a93e7];
+const GX: U256 = [0xf4a13945d898c296, 0x77037d812deb33a0, 0xf8bce6e563a440f2, 0x6b17d1f2e12c4247];
+const GY: U256 = [0xcbb6406837bf51f5, 0x2bce33576b315ece, 0x8ee7eb4a7c0f9e16, 0x4fe342e2fe1a7f9b];
+
+const ZERO: U256 = [0; 4];
+const ONE: U256 = [1, 0, 0, 0];
+
+fn from_be(b: &[u8; 32]) -> U256 {
+    let mut r = ZERO;
+    for (i, limb) in r.iter_mut().enumerate() {
+        *limb = u64::from_be_bytes(b[24 - i * 8..32 - i * 8].try_into().unwrap());
+    }
+    r
+}
+
+fn to_be(a: &U256) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    for (i, limb) in a.iter().enumerate() { out[24 - i * 8..32 - i * 8].copy_from_slice(&limb.to_be_bytes()); }
+    out
+}
+
+fn is_zero(a: &U256) -> bool { a.iter().all(|&l| l == 0) }
+
+/// a < b
+fn lt(a: &U256, b: &U256) -> bool {
+    for i in (0..4).rev() {
+        if a[i] != b[i] { return a[i] < b[i]; }
+    }
+    false
+}
+
+fn add(a: &U256, b: &U256) -> (U256, bool) {
+    let mut r = ZERO;
+    let mut carry = 0u128;
+    for i in 0..4 {
+        let v = a[i] as u128 + b[i] as u128 + carry;
+        r[i] = v as u64;
+        carry = v >> 64;
+    }
+    (r, carry != 0)
+}
+
+fn sub(a: &U256, b: &U256) -> (U256, bool) {
+    let mut r = ZERO;
+    let mut borrow = false;
+    for i in 0..4 {
+        let (d, b1) = a[i].overflowing_sub(b[i]);
+        let (d, b2) = d.overflowing_sub(borrow as u64);
+        r[i] = d;
+        borrow = b1 | b2;
+    }
+    (r, borrow)
+}
+
+fn bit(a: &U256, i: usize) -> u64 { (a[i / 64] >> (i % 64)) & 1 }
+
+/// Arithmetic modulo an odd 256-bit prime with its top bit set (p or n), in
+/// Montgomery form (R = 2^256) unless a method says otherwise.
+struct Modulus {
+    m: U256,
+    /// -m^-1 mod 2^64
+    inv: u64,
+    /// R^2 mod m
+    r2: U256,
+}
+
+impl Modulus {
+    const fn new(m: U256) -> Self {
+        // Newton iteration doubles the correct low bits each round: 1 -> 64.
+        let mut x: u64 = 1;
+        let mut i = 0;
+        while i < 6 { x = x.wrapping_mul(2u64.wrapping_sub(m[0].wrapping_mul(x))); i += 1; }
+        // R mod m = 2^256 - m (m > 2^255), then double 256 times to reach R^2 mod m.
+        let mut r = [0u64; 4];
+        let mut borrow = 0u64;
+        let mut j = 0;
+        while j < 4 {
+            let v = (0u128).wrapping_sub(m[j] as u128).wrapping_sub(borrow as u128);
+            r[j] = v as u64;
+            borrow = ((v >> 64) != 0) as u64;
+            j += 1;
+        }
+        let mut k = 0;
+        while k < 256 {
+            let top = r[3] >> 63;
+            r = [r[0] << 1, (r[1] << 1) | (r[0] >> 63), (r[2] << 1) | (r[1] >> 63), (r[3] << 1) | (r[2] >> 63)];
+            // r < m before doubling, so 2r - m < m; subtract when it overflowed or r >= m.
+            let mut ge = top == 1;
+            if !ge {
+                ge = true;
+                let mut l = 4;
+                while l > 0 {
+                    l -= 1;
+                    if r[l] != m[l] { ge = r[l] > m[l]; break; }
+                }
+            }
+            if ge {
+                let mut borrow = 0u64;
+                let mut l = 0;
+                while l < 4 {
+                    let v = (r[l] as u128).wrapping_sub(m[l] as u128).wrapping_sub(borrow as u128);
+                    r[l] = v as u64;
+                    borrow = ((v >> 64) != 0) as u64;
+                    l += 1;
+                }
+            }
+            k += 1;
+        }
+        Modulus { m, inv: x.wrapping_neg(), r2: r }
+    }
+
+    /// Reduce any 256-bit value (below 2m since m > 2^255); plain form in and out.
+    fn reduce(&self, a: &U256) -> U256 {
+        if lt(a, &self.m) { *a } else { sub(a, &self.m).0 }
+    }
+
+    fn add(&self, a: &U256, b: &U256) -> U256 {
+        let (s, carry) = add(a, b);
+        if carry || !lt(&s, &self.m) { sub(&s, &self.m).0 } else { s }
+    }
+
+    fn sub(&self, a: &U256, b: &U256) -> U256 {
+        let (d, borrow) = sub(a, b);
+        if borrow { add(&d, &self.m).0 } else { d }
+    }
+
+    /// a * b * R^-1 mod m (CIOS).
+    fn mul(&self, a: &U256, b: &U256) -> U256 {
+        let mut t = [0u64; 6];
+        for i in 0..4 {
+            let mut c = 0u128;
+            for j in 0..4 {
+                let v = t[j] as u128 + a[j] as u128 * b[i] as u128 + c;
+                t[j] = v as u64;
+                c = v >> 64;
+            }
+            let v = t[4] as u128 + c;
+            t[4] = v as u64;
+            t[5] = (v >> 64) as u64;
+
+            let q = t[0].wrapping_mul(self.inv);
+            let mut c = (t[0] as u128 + q as u128 * self.m[0] as u128) >> 64;
+            for j in 1..4 {
+                let v = t[j] as u128 + q as u128 * self.m[j] as u128 + c;
+                t[j - 1] = v as u64;
+                c = v >> 64;
+            }
+            let v = t[4] as u128 + c;
+            t[3] = v as u64;
+            t[4] = t[5] + (v >> 64) as u64;
+        }
+        let r = [t[0], t[1], t[2], t[3]];
+        if t[4] != 0 || !lt(&r, &self.m) { sub(&r, &self.m).0 } else { r }
+    }
+
+    fn sqr(&self, a: &U256) -> U256 { self.mul(a, a) }
+
+    fn to_mont(&self, a: &U256) -> U256 { self.mul(a, &self.r2) }
+
+    fn from_mont(&self, a: &U256) -> U256 { self.mul(a, &ONE) }
+
+    /// a^-1 by Fermat (a^(m-2)); a must be non-zero.
+    fn invert(&self, a: &U256) -> U256 {
+        let e = sub(&self.m, &[2, 0, 0, 0]).0;
+        let mut r = self.to_mont(&ONE);
+        for i in (0..256).rev() {
+            r = self.sqr(&r);
+            if bit(&e, i) == 1 { r = self.mul(&r, a); }
+        }
+        r
+    }
+
+    /// Plain-form a * b mod m.
+    fn mul_plain(&self, a: &U256, b: &U256) -> U256 { self.mul(&self.mul(a, b), &self.r2) }
+
+    /// Plain-form a^-1 mod m.
+    fn invert_plain(&self, a: &U256) -> U256 { self.from_mont(&self.invert(&self.to_mont(a))) }
+}
+
+static FP: Modulus = Modulus::new(P);
+static FN: Modulus = Modulus::new(N);
+
+/// Jacobian point with Montgomery-form coordinates; z = 0 is the point at infinity.
+#[derive(Clone, Copy)]
+struct Point { x: U256, y: U256, z: U256 }
+
+impl Point {
+    const INFINITY: Point = Point { x: ZERO, y: ZERO, z: ZERO };
+
+    /// Affine plain-form coordinates; the caller checks they are a curve point.
+    fn from_affine(x: &U256, y: &U256) -> Point {
+        Point { x: FP.to_mont(x), y: FP.to_mont(y), z: FP.to_mont(&ONE) }
+    }
+
+    fn generator() -> Point { Point::from_affine(&GX, &GY) }
+
+    fn is_infinity(&self) -> bool { is_zero(&self.z) }
+
+    /// Plain-form affine coordinates, `None` for infinity.
+    fn to_affine(&self) -> Option<(U256, U256)> {
+        if self.is_infinity() { return None; }
+        let zi = FP.invert(&self.z);
+        let zi2 = FP.sqr(&zi);
+        let x = FP.mul(&self.x, &zi2);
+        let y = FP.mul(&self.y, &FP.mul(&zi2, &zi));
+        Some((FP.from_mont(&x), FP.from_mont(&y)))
+    }
+
+    /// dbl-2001-b (a = -3).
+    fn double(&self) -> Point {
+        if self.is_infinity() || is_zero(&self.y) { return Point::INFINITY; }
+        let delta = FP.sqr(&self.z);
+        let gamma = FP.sqr(&self.y);
+        let beta = FP.mul(&self.x, &gamma);
+        let t = FP.mul(&FP.sub(&self.x, &delta), &FP.add(&self.x, &delta));
+        let alpha = FP.add(&FP.add(&t, &t), &t);
+        let beta2 = FP.add(&beta, &beta);
+        let beta4 = FP.add(&beta2, &beta2);
+        let beta8 = FP.add(&beta4, &beta4);
+        let x3 = FP.sub(&FP.sqr(&alpha), &beta8);
+        let z3 = FP.sub(&FP.sub(&FP.sqr(&FP.add(&self.y, &self.z)), &gamma), &delta);
+        let g2 = FP.sqr(&gamma);
+        let g4 = FP.add(&g2, &g2);
+        let g8 = FP.add(&FP.add(&g4, &g4), &FP.add(&g4, &g4));
+        let y3 = FP.sub(&FP.mul(&alpha, &FP.sub(&beta4, &x3)), &g8);
+        Point { x: x3, y: y3, z: z3 }
+    }
+
+    fn add(&self, o: &Point) -> Point {
+        if self.is_infinity() { return *o; }
+        if o.is_infinity() { return *self; }
+        let z1z1 = FP.sqr(&self.z);
+        let z2z2 = FP.sqr(&o.z);
+        let u1 = FP.mul(&self.x, &z2z2);
+        let u2 = FP.mul(&o.x, &z1z1);
+        let s1 = FP.mul(&self.y, &FP.mul(&o.z, &z2z2));
+        let s2 = FP.mul(&o.y, &FP.mul(&self.z, &z1z1));
+        let h = FP.sub(&u2, &u1);
+        let r = FP.sub(&s2, &s1);
+        if is_zero(&h) {
+            return if is_zero(&r) { self.double() } else { Point::INFINITY };
+        }
+        let h2 = FP.sqr(&h);
+        let h3 = FP.mul(&h2, &h);
+        let u1h2 = FP.mul(&u1, &h2);
+        let x3 = FP.sub(&FP.sub(&FP.sqr(&r), &h3), &FP.add(&u1h2, &u1h2));
+        let y3 = FP.sub(&FP.mul(&r, &FP.sub(&u1h2, &x3)), &FP.mul(&s1, &h3));
+        let z3 = FP.mul(&FP.mul(&self.z, &o.z), &h);
+        Point { x: x3, y: y3, z: z3 }
+    }
+
+    /// k * self, Montgomery ladder.
+    fn mul(&self, k: &U256) -> Point {
+        let (mut r0, mut r1) = (Point::INFINITY, *self);
+        for i in (0..256).rev() {
+            let mask = bit(k, i).wrapping_neg();
+            cswap(&mut r0, &mut r1, mask);
+            r1 = r0.add(&r1);
+            r0 = r0.double();
+            cswap(&mut r0, &mut r1, mask);
+        }
+        r0
+    }
+}
+
+/// Swap `a` and `b` when `mask` is all ones, without branching on it.
+fn cswap(a: &mut Point, b: &mut Point, mask: u64) {
+    for (x, y) in [(&mut a.x, &mut b.x), (&mut a.y, &mut b.y), (&mut a.z, &mut b.z)] {
+        for i in 0..4 {
+            let t = (x[i] ^ y[i]) & mask;
+            x[i] ^= t;
+            y[i] ^= t;
+        }
+    }
+}
+
+/// Parse an uncompressed SEC1 point (`04 || X || Y`) and check it is on the curve.
+fn decode_point(sec1: &[u8]) -> Option<Point> {
+    if sec1.len() != 65 || sec1[0] != 0x04 { return None; }
+    let x = from_be(sec1[1..33].try_into().unwrap());
+    let y = from_be(sec1[33..65].try_into().unwrap());
+    if !lt(&x, &P) || !lt(&y, &P) { return None; }
+    let p = Point::from_affine(&x, &y);
+    // y^2 = x^3 - 3x + b
+    let x3 = FP.mul(&FP.sqr(&p.x), &p.x);
+    let three_x = FP.add(&FP.add(&p.x, &p.x), &p.x);
+    let rhs = FP.add(&FP.sub(&x3, &three_x), &FP.to_mont(&B));
+    if FP.sqr(&p.y) != rhs { return None; }
+    Some(p)
+}
+
+/// A P-256 private key (the scalar d, 1 <= d < n).
+pub struct SigningKey {
+    d: U256,
+}
+
+impl SigningKey {
+    /// Big-endian 32-byte scalar, as stored in a SEC1 `ECPrivateKey`.
+    pub fn from_bytes(d: &[u8]) -> Option<Self> {
+        let d = from_be(d.try_into().ok()?);
+        if is_zero(&d) || !lt(&d, &N) { return None; }
+        Some(SigningKey { d })
+    }
+
+    /// Public key as an uncompressed SEC1 point, the form certificates carry.
+    pub fn public_key(&self) -> [u8; 65] {
+        let (x, y) = Point::generator().mul(&self.d).to_affine().expect("d < n");
+        let mut out = [0u8; 65];
+        out[0] = 0x04;
+        out[1..33].copy_from_slice(&to_be(&x));
+        out[33..].copy_from_slice(&to_be(&y));
+        out
+    }
+
+    /// Sign SHA-256(msg); returns `r || s`, big-endian.
+    pub fn sign(&self, msg: &[u8]) -> [u8; 64] { self.sign_digest(&sha256_digest(msg)) }
+
+    /// Sign a precomputed SHA-256 digest with an RFC 6979 nonce.
+    pub fn sign_digest(&self, digest: &[u8; 32]) -> [u8; 64] {
+        let e = FN.reduce(&from_be(digest));
+        let x = to_be(&self.d);
+        let h1 = to_be(&e);
+        let mut v = [0x01u8; 32];
+        let mut k = [0x00u8; 32];
+        for sep in [0x00u8, 0x01] {
+            k = hmac_sha256(&k, &[&v[..], &[sep], &x, &h1].concat());
+            v = hmac_sha256(&k, &v);
+        }
+        loop {
+            v = hmac_sha256(&k, &v);
+            let nonce = from_be(&v);
+            if !is_zero(&nonce) && lt(&nonce, &N) {
+                if let Some((rx, _)) = Point::generator().mul(&nonce).to_affine() {
+                    let r = FN.reduce(&rx);
+                    // s = k^-1 (e + r d)
+                    let s = FN.mul_plain(&FN.invert_plain(&nonce), &FN.add(&e, &FN.mul_plain(&r, &self.d)));
+                    if !is_zero(&r) && !is_zero(&s) {
+                        let mut sig = [0u8; 64];
+                        sig[..32].copy_from_slice(&to_be(&r));
+                        sig[32..].copy_from_slice(&to_be(&s));
+                        return sig;
+                    }
+                }
+            }
+            k = hmac_sha256(&k, &[&v[..], &[0x00]].concat());
+            v = hmac_sha256(&k, &v);
+        }
+    }
+}
+
+/// Verify an `r || s` signature over SHA-256(msg) against an uncompressed SEC1 public key.
+pub fn verify(public_key: &[u8], msg: &[u8], sig: &[u8; 64]) -> bool {
+    verify_digest(public_key, &sha256_digest(msg), sig)
+}
+
+pub fn verify_digest(public_key: &[u8], digest: &[u8; 32], sig: &[u8; 64]) -> bool {
+    let q = match decode_point(public_key) { Some(q) => q, None => return false };
+    let r = from_be(sig[..32].try_into().unwrap());
+    let s = from_be(sig[32..].try_into().unwrap());
+    if is_zero(&r) || is_zero(&s) || !lt(&r, &N) || !lt(&s, &N) { return false; }
+    let e = FN.reduce(&from_be(digest));
+    let w = FN.invert_plain(&s);
+    let u1 = FN.mul_plain(&e, &w);
+    let u2 = FN.mul_plain(&r, &w);
+    match Point::generator().mul(&u1).add(&q.mul(&u2)).to_affine() {
+        Some((x, _)) => FN.reduce(&x) == r,
+        None => false,
+    }
+}
+
+/// DER `ECDSA-Sig-Value` (SEQUENCE of two INTEGERs), the encoding TLS CertificateVerify uses.
+pub fn signature_to_der(sig: &[u8; 64]) -> Vec<u8> {
+    fn integer(out: &mut Vec<u8>, v: &[u8]) {
+        let start = v.iter().position(|&b| b != 0).unwrap_or(v.len() - 1);
+        let v = &v[start..];
+        let pad = v[0] & 0x80 != 0;
+        out.push(0x02);
+        out.push((v.len() + pad as usize) as u8);
+        if pad { out.push(0); }
+        out.extend_from_slice(v);
+    }
+    let mut body = Vec::with_capacity(70);
+    integer(&mut body, &sig[..32]);
+    integer(&mut body, &sig[32..]);
+    let mut out = vec![0x30, body.len() as u8];
+    out.extend_from_slice(&body);
+    out
+}
+
+/// Inverse of [`signature_to_der`]; rejects non-minimal or oversized integers.
+pub fn signature_from_der(der: &[u8]) -> Option<[u8; 64]> {
+    fn integer(buf: &[u8]) -> Option<(&[u8], &[u8])> {
+        if buf.len() < 2 || buf[0] != 0x02 { return None; }
+        let len = buf[1] as usize;
+        if len == 0 || len > 33 || buf.len() < 2 + len { return None; }
+        let v = &buf[2..2 + len];
+        if v[0] & 0x80 != 0 { return None; } // negative
+        if len > 1 && v[0] == 0 && v[1] & 0x80 == 0 { return None; } // non-minimal
+        let v = if v[0] == 0 && len > 1 { &v[1..] } else { v };
+        if v.len() > 32 { return None; }
+        Some((v, &buf[2 + len..]))
+    }
+    if der.len() < 2 || der[0] != 0x30 || der[1] as usize != der.len() - 2 { return None; }
+    let (r, rest) = integer(&der[2..])?;
+    let (s, rest) = integer(rest)?;
+    if !rest.is_empty() { return None; }
+    let mut sig = [0u8; 64];
+    sig[32 - r.len()..32].copy_from_slice(r);
+    sig[64 - s.len()..].copy_from_slice(s);
+    Some(sig)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{signature_from_der, signature_to_der, verify, SigningKey};
+
+    fn hex(d: &[u8]) -> String { d.iter().map(|b| format!("{:02x}", b)).collect() }
+
+    fn unhex(s: &str) -> Vec<u8> { (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect() }
+
+    /// RFC 6979 A.2.5: the P-256 key and its deterministic SHA-256 signatures.
+    fn key() -> SigningKey { SigningKey::from_bytes(&unhex("c9afa9d845ba75166b5c215767b1d6934e50c3db36e89b127b8a622b120f6721")).unwrap() }
+
+    #[test]
+    fn rfc6979_known_answers() {
+        let key = key();
+        assert_eq!(
+            hex(&key.public_key()),
+            "0460fed4ba255a9d31c961eb74c6356d68c049b8923b61fa6ce669622e60f29fb6\
+             7903fe1008b8bc99a41ae9e95628bc64f2f1b20c2d7e9f5177a3c294d4462299"
+        );
+        assert_eq!(
+            hex(&key.sign(b"sample")),
+            "efd48b2aacb6a8fd1140dd9cd45e81d69d2c877b56aaf991c34d0ea84eaf3716\
+             f7cb1c942d657c41d436c7a1b6e29f65f3e900dbb9aff4064dc4ab2f843acda8"
+        );
+        assert_eq!(
+            hex(&key.sign(b"test")),
+            "f1abb023518351cd71d881567b1ea663ed3efcf6c5132b354f28d3b0b7d38367\
+             019f4113742a2b14bd25926b49c649155f267e60d3814b4c0cc84250e46f0083"
+        );
+    }
+
+    #[test]
+    fn tampered_message_or_signature_is_rejected() {
+        let key = key();
+        let public = key.public_key();
+        let sig = key.sign(b"sample");
+        assert!(verify(&public, b"sample", &sig));
+        assert!(!verify(&public, b"samplf", &sig));
+        let mut bad = sig;
+        bad[63] ^= 1;
+        assert!(!verify(&public, b"sample", &bad));
+        // r = 0 and a point off the curve never verify.
+        let mut zero_r = sig;
+        zero_r[..32].fill(0);
+        assert!(!verify(&public, b"sample", &zero_r));
+        let mut off_curve = public;
+        off_curve[64] ^= 1;
+        assert!(!verify(&off_curve, b"sample", &sig));
+    }
+
+    #[test]
+    fn der_signatures_round_trip() {
+        let sig = key().sign(b"sample");
+        let der = signature_to_der(&sig);
+        // Both r and s have the top bit set, so each INTEGER carries a leading zero.
+        assert_eq!(&der[..4], &[0x30, 70, 0x02, 33]);
+        assert_eq!(signature_from_der(&der), Some(sig));
+        let mut non_minimal = der.clone();
+        non_minimal[4] = 0;
+        non_minimal[5] = 0;
+        assert_eq!(signature_from_der(&non_minimal), None);
+        assert_eq!(signature_from_der(&der[..der.len() - 1]), None);
+    }
+}
@@ -65,6 +65,7 @@ impl<'a> ClientHello<'a> {
 
 pub mod rand;
 pub mod sha256;
+pub mod sha1;
 pub mod hmac;
 pub mod hkdf;
 pub mod chacha20;
@@ -72,9 +73,11 @@ pub mod poly1305;
 pub mod aead;
 pub mod aes;
 pub mod aes_gcm;
+pub mod ecdsa_p256;
 pub mod tls;
 pub mod tls13;
 pub mod ocsp;
+pub mod x509;
 pub mod memfd_secret;
 
 // 以降のメッセージは後続フェーズで追加予定 
\ No newline at end of file
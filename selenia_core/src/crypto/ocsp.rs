@@ -1,15 +1,21 @@
 //! OCSP Stapling helper.
 //! Loads a DER-encoded OCSP response at startup and provides it to the TLS
 //! layer for inclusion in CertificateStatus messages.
-//! In real-world usage the response should be periodically refreshed; for now
-//! we only support static OCSP files.
+//! `load_ocsp_response` serves a static DER file; `OcspCache` fetches responses
+//! per certificate from the responder and refreshes them before `nextUpdate`.
 
-use std::sync::RwLock;
+use std::sync::{OnceLock, RwLock};
 use std::time::{Duration, Instant};
 use std::thread;
 use std::time::SystemTime;
 
-static OCSP_CACHE: RwLock<Option<OcspStaple>> = RwLock::new(None);
+use crate::sync::ArcSwap;
+
+/// Read on every handshake, replaced by the refresh thread; readers never wait on it.
+fn ocsp_cache() -> &'static ArcSwap<Option<OcspStaple>> {
+    static OCSP_CACHE: OnceLock<ArcSwap<Option<OcspStaple>>> = OnceLock::new();
+    OCSP_CACHE.get_or_init(ArcSwap::default)
+}
 
 pub struct OcspStaple {
     pub der: Vec<u8>,
@@ -25,13 +31,13 @@ impl OcspStaple {
 pub fn load_ocsp_response(path: &str, valid_secs: u64) -> std::io::Result<()> {
     let data = std::fs::read(path)?;
     let staple = OcspStaple { der: data, expires_at: Instant::now() + Duration::from_secs(valid_secs) };
-    *OCSP_CACHE.write().unwrap() = Some(staple);
+    ocsp_cache().store(Arc::new(Some(staple)));
     Ok(())
 }
 
 /// Get current OCSP response, if valid.
 pub fn get_staple() -> Option<Vec<u8>> {
-    OCSP_CACHE.read().unwrap().as_ref().and_then(|s| if s.is_valid(){Some(s.der.clone())}else{None})
+    ocsp_cache().load().as_ref().as_ref().and_then(|s| if s.is_valid(){Some(s.der.clone())}else{None})
 }
 
 /// Periodically reload the OCSP response from `path` every `refresh_secs`.
@@ -45,4 +51,421 @@ pub fn spawn_auto_refresh(path: String, refresh_secs: u64, valid_secs: u64) {
             thread::sleep(Duration::from_secs(refresh_secs));
         }
     });
-} 
\ No newline at end of file
+} 
+// -----------------------------------------------------------------------------
+// Responder-backed cache (RFC 6960)
+// -----------------------------------------------------------------------------
+//
+// `OcspCache` keeps one response per certificate, fetched from the responder over
+// plain HTTP (`POST application/ocsp-request`, RFC 6960 Appendix A.1) and refreshed
+// by a `Timer`-driven thread before `nextUpdate`. The response is stapled as an
+// opaque blob – clients verify the responder signature – so we only check the
+// status, that it covers our CertID, and its validity window.
+//
+// Failures soft-fail: the previous response is served until it expires, after
+// which `get` returns `None` and the handshake simply goes out without a staple.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use crate::crypto::sha1::sha1_digest;
+use crate::os::timer::Timer;
+
+/// Lifetime assumed when a response omits `nextUpdate`.
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(3600);
+/// Back-off after a failed fetch before the entry is retried.
+const RETRY_AFTER: Duration = Duration::from_secs(60);
+/// Connect / read timeout for responder requests.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Upper bound on a responder reply; real responses are a few KiB.
+const MAX_RESPONSE: usize = 64 * 1024;
+
+/// Certificate identifier as sent to the responder (SHA-1 hashes, RFC 6960 §4.1.1).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CertId {
+    pub issuer_name_hash: [u8; 20],
+    pub issuer_key_hash: [u8; 20],
+    pub serial: Vec<u8>,
+}
+
+impl CertId {
+    /// `issuer_name` is the DER of the issuer's subject Name, `issuer_key` the
+    /// issuer's subjectPublicKey BIT STRING contents and `serial` the certificate's
+    /// serialNumber INTEGER contents.
+    pub fn new(issuer_name: &[u8], issuer_key: &[u8], serial: &[u8]) -> Self {
+        CertId { issuer_name_hash: sha1_digest(issuer_name), issuer_key_hash: sha1_digest(issuer_key), serial: serial.to_vec() }
+    }
+
+    fn to_der(&self) -> Vec<u8> {
+        // AlgorithmIdentifier { id-sha1, NULL }
+        let alg = der(0x30, &[&[0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a][..], &[0x05, 0x00]].concat());
+        der(0x30, &[alg, der(0x04, &self.issuer_name_hash), der(0x04, &self.issuer_key_hash), der(0x02, &self.serial)].concat())
+    }
+
+    /// DER `OCSPRequest` for this single certificate (no nonce, unsigned).
+    pub fn request_der(&self) -> Vec<u8> {
+        let request = der(0x30, &self.to_der());
+        let tbs = der(0x30, &der(0x30, &request));
+        der(0x30, &tbs)
+    }
+}
+
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let n = content.len();
+    if n < 0x80 {
+        out.push(n as u8);
+    } else {
+        let bytes: Vec<u8> = n.to_be_bytes().iter().copied().skip_while(|&b| b == 0).collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend_from_slice(&bytes);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+/// Minimal DER reader: yields `(tag, contents)` TLVs from a byte slice.
+struct Der<'a>(&'a [u8]);
+
+impl<'a> Der<'a> {
+    fn next(&mut self) -> Option<(u8, &'a [u8])> {
+        let b = self.0;
+        let tag = *b.first()?;
+        let first = *b.get(1)? as usize;
+        let (len, hdr) = if first < 0x80 {
+            (first, 2)
+        } else {
+            let n = first & 0x7f;
+            if n == 0 || n > 4 || b.len() < 2 + n { return None; }
+            (b[2..2 + n].iter().fold(0usize, |acc, &x| acc << 8 | x as usize), 2 + n)
+        };
+        let body = b.get(hdr..hdr.checked_add(len)?)?;
+        self.0 = &b[hdr + len..];
+        Some((tag, body))
+    }
+
+    fn expect(&mut self, tag: u8) -> Option<&'a [u8]> {
+        match self.next()? { (t, body) if t == tag => Some(body), _ => None }
+    }
+
+    /// Consume the next element only if it carries `tag`.
+    fn optional(&mut self, tag: u8) -> Option<&'a [u8]> {
+        if self.0.first() == Some(&tag) { self.expect(tag) } else { None }
+    }
+}
+
+/// Revocation status reported for the certificate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CertStatus { Good, Revoked, Unknown }
+
+/// Validated response ready for stapling.
+#[derive(Clone, Debug)]
+pub struct OcspResponse {
+    pub der: Vec<u8>,
+    pub status: CertStatus,
+    pub this_update: SystemTime,
+    pub next_update: Option<SystemTime>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum OcspError {
+    /// Malformed DER or a response shape we do not understand.
+    Malformed,
+    /// `responseStatus` other than successful(0).
+    Status(u8),
+    /// No SingleResponse for the requested CertID.
+    NotCovered,
+}
+
+/// `YYYYMMDDHHMMSS[.f]Z` → SystemTime.
+fn generalized_time(b: &[u8]) -> Option<SystemTime> {
+    let s = std::str::from_utf8(b).ok()?;
+    if s.len() < 15 || !s.ends_with('Z') || !s.as_bytes()[..14].iter().all(u8::is_ascii_digit) { return None; }
+    let num = |r: std::ops::Range<usize>| s[r].parse::<i64>().ok();
+    let (y, m, d) = (num(0..4)?, num(4..6)?, num(6..8)?);
+    let (hh, mm, ss) = (num(8..10)?, num(10..12)?, num(12..14)?);
+    if !(1..=12).contains(&m) || !(1..=31).contains(&d) || hh > 23 || mm > 59 || ss > 60 { return None; }
+    // Days from civil (Howard Hinnant).
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    let secs = days * 86_400 + hh * 3600 + mm * 60 + ss;
+    u64::try_from(secs).ok().map(|s| UNIX_EPOCH + Duration::from_secs(s))
+}
+
+/// Parse an `OCSPResponse` and extract the SingleResponse matching `id`.
+pub fn parse_response(der_bytes: &[u8], id: &CertId) -> Result<OcspResponse, OcspError> {
+    use OcspError::Malformed;
+    let mut top = Der(Der(der_bytes).expect(0x30).ok_or(Malformed)?);
+    let status = top.expect(0x0a).ok_or(Malformed)?;
+    if status != [0] { return Err(OcspError::Status(status.first().copied().unwrap_or(0xff))); }
+    // responseBytes [0] EXPLICIT { responseType OID, response OCTET STRING }
+    let mut rb = Der(Der(top.expect(0xa0).ok_or(Malformed)?).expect(0x30).ok_or(Malformed)?);
+    if rb.expect(0x06).ok_or(Malformed)? != [0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01] { return Err(Malformed); }
+    let basic = rb.expect(0x04).ok_or(Malformed)?;
+    let mut basic = Der(Der(basic).expect(0x30).ok_or(Malformed)?);
+    let mut tbs = Der(basic.expect(0x30).ok_or(Malformed)?);
+    tbs.optional(0xa0); // version
+    match tbs.next() { Some((0xa1, _)) | Some((0xa2, _)) => {}, _ => return Err(Malformed) } // responderID
+    tbs.expect(0x18).ok_or(Malformed)?; // producedAt
+    let mut responses = Der(tbs.expect(0x30).ok_or(Malformed)?);
+    while let Some(single) = responses.expect(0x30) {
+        let mut single = Der(single);
+        let mut cid = Der(single.expect(0x30).ok_or(Malformed)?);
+        cid.expect(0x30).ok_or(Malformed)?; // hashAlgorithm – we only ever ask with SHA-1
+        let name_hash = cid.expect(0x04).ok_or(Malformed)?;
+        let key_hash = cid.expect(0x04).ok_or(Malformed)?;
+        let serial = cid.expect(0x02).ok_or(Malformed)?;
+        if name_hash != id.issuer_name_hash || key_hash != id.issuer_key_hash || serial != id.serial.as_slice() { continue; }
+        let status = match single.next() {
+            Some((0x80, _)) => CertStatus::Good,
+            Some((0xa1, _)) => CertStatus::Revoked,
+            Some((0x82, _)) => CertStatus::Unknown,
+            _ => return Err(Malformed),
+        };
+        let this_update = generalized_time(single.expect(0x18).ok_or(Malformed)?).ok_or(Malformed)?;
+        let next_update = match single.optional(0xa0) {
+            Some(inner) => Some(generalized_time(Der(inner).expect(0x18).ok_or(Malformed)?).ok_or(Malformed)?),
+            None => None,
+        };
+        return Ok(OcspResponse { der: der_bytes.to_vec(), status, this_update, next_update });
+    }
+    Err(OcspError::NotCovered)
+}
+
+/// `POST` a DER request to an `http://host[:port]/path` responder and return the body.
+pub fn http_fetch(url: &str, request: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = |m: &str| io::Error::new(io::ErrorKind::InvalidInput, m.to_string());
+    let rest = url.strip_prefix("http://").ok_or_else(|| invalid("OCSP responder must be http://"))?;
+    let (authority, path) = match rest.find('/') { Some(i) => (&rest[..i], &rest[i..]), None => (rest, "/") };
+    let addr_str = if authority.rsplit_once(':').map_or(false, |(_, p)| p.parse::<u16>().is_ok()) && !authority.ends_with(']') {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+    let addr = addr_str.to_socket_addrs()?.next().ok_or_else(|| invalid("responder host did not resolve"))?;
+    let mut s = TcpStream::connect_timeout(&addr, FETCH_TIMEOUT)?;
+    s.set_read_timeout(Some(FETCH_TIMEOUT))?;
+    s.set_write_timeout(Some(FETCH_TIMEOUT))?;
+    // HTTP/1.0 so the responder closes the connection and never chunks the body.
+    let head = format!("POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/ocsp-request\r\nContent-Length: {}\r\n\r\n", path, authority, request.len());
+    s.write_all(head.as_bytes())?;
+    s.write_all(request)?;
+    let mut resp = Vec::new();
+    s.take(MAX_RESPONSE as u64 + 1).read_to_end(&mut resp)?;
+    if resp.len() > MAX_RESPONSE { return Err(io::Error::new(io::ErrorKind::InvalidData, "OCSP response too large")); }
+    let end = resp.windows(4).position(|w| w == b"\r\n\r\n").ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated OCSP reply"))?;
+    let head = String::from_utf8_lossy(&resp[..end]);
+    let code = head.split_whitespace().nth(1).unwrap_or("");
+    if code != "200" { return Err(io::Error::new(io::ErrorKind::Other, format!("OCSP responder answered {}", code))); }
+    Ok(resp[end + 4..].to_vec())
+}
+
+/// Fetch function used by the cache: `(responder_url, request_der) -> response_der`.
+pub type Fetcher = fn(&str, &[u8]) -> io::Result<Vec<u8>>;
+
+struct CacheEntry {
+    responder: String,
+    current: Option<OcspResponse>,
+    /// Expiry used for `get`: `nextUpdate`, or fetch time + DEFAULT_LIFETIME.
+    expires: Option<SystemTime>,
+    /// Time after which the refresher should fetch again.
+    refresh_at: SystemTime,
+}
+
+/// Per-certificate OCSP response cache with background refresh.
+pub struct OcspCache {
+    entries: RwLock<HashMap<CertId, CacheEntry>>,
+    fetch: Fetcher,
+}
+
+impl Default for OcspCache {
+    fn default() -> Self { Self::new() }
+}
+
+impl OcspCache {
+    /// Cache that talks to responders over HTTP.
+    pub fn new() -> Self { Self::with_fetcher(http_fetch) }
+
+    /// Cache with a custom transport (e.g. a proxy or a canned responder).
+    pub fn with_fetcher(fetch: Fetcher) -> Self {
+        OcspCache { entries: RwLock::new(HashMap::new()), fetch }
+    }
+
+    /// Track `id`, served by the responder at `responder_url`. The first fetch
+    /// happens on the next `refresh_due` pass.
+    pub fn register(&self, id: CertId, responder_url: &str) {
+        self.entries.write().unwrap().insert(id, CacheEntry {
+            responder: responder_url.to_string(), current: None, expires: None, refresh_at: UNIX_EPOCH,
+        });
+    }
+
+    /// Current DER response for stapling; `None` when missing, expired or not
+    /// reporting the certificate as good.
+    pub fn get(&self, id: &CertId) -> Option<Vec<u8>> {
+        self.get_at(id, SystemTime::now())
+    }
+
+    fn get_at(&self, id: &CertId, now: SystemTime) -> Option<Vec<u8>> {
+        let entries = self.entries.read().unwrap();
+        let e = entries.get(id)?;
+        let r = e.current.as_ref()?;
+        (r.status == CertStatus::Good && e.expires.map_or(false, |t| now < t)).then(|| r.der.clone())
+    }
+
+    /// Refresh every entry whose refresh time has passed. A fresh response is due
+    /// once half of its validity window has elapsed; failed fetches keep the old
+    /// response (soft-fail) and are retried after `RETRY_AFTER`.
+    /// Returns the number of entries successfully refreshed.
+    pub fn refresh_due(&self, now: SystemTime) -> usize {
+        let due: Vec<(CertId, String)> = self.entries.read().unwrap().iter()
+            .filter(|(_, e)| now >= e.refresh_at)
+            .map(|(id, e)| (id.clone(), e.responder.clone()))
+            .collect();
+        let mut refreshed = 0;
+        // Fetch without holding the lock so `get` is never blocked on the network.
+        for (id, url) in due {
+            let result = (self.fetch)(&url, &id.request_der())
+                .map_err(|e| e.to_string())
+                .and_then(|der| parse_response(&der, &id).map_err(|e| format!("{:?}", e)));
+            let mut entries = self.entries.write().unwrap();
+            let Some(entry) = entries.get_mut(&id) else { continue };
+            match result {
+                Ok(resp) => {
+                    let expires = resp.next_update.unwrap_or(now + DEFAULT_LIFETIME);
+                    let start = if resp.this_update < expires { resp.this_update } else { now };
+                    let half = expires.duration_since(start).unwrap_or_default() / 2;
+                    let refresh_at = (start + half).max(now + RETRY_AFTER.min(half));
+                    entry.expires = Some(expires);
+                    entry.refresh_at = refresh_at;
+                    entry.current = Some(resp);
+                    refreshed += 1;
+                }
+                Err(e) => {
+                    crate::log_warn!("OCSP refresh from {} failed: {} (keeping previous response)", url, e);
+                    entry.refresh_at = now + RETRY_AFTER;
+                }
+            }
+        }
+        refreshed
+    }
+
+    /// Spawn the refresher thread: one pass immediately, then one every
+    /// `interval_secs` driven by a periodic `Timer`.
+    pub fn spawn_refresher(self: &Arc<Self>, interval_secs: u64) -> io::Result<()> {
+        let mut timer = Timer::new(interval_secs.max(1) * 1000, true)?;
+        let cache = Arc::clone(self);
+        thread::spawn(move || loop {
+            cache.refresh_due(SystemTime::now());
+            if let Err(e) = timer.wait() {
+                crate::log_warn!("OCSP refresher timer failed: {}", e);
+                return;
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    const THIS_UPDATE: &str = "20260101000000Z";
+    const NEXT_UPDATE: &str = "20260101020000Z";
+
+    fn cert_id() -> CertId { CertId::new(b"issuer", b"issuer key", &[0x01, 0x23]) }
+
+    fn at(s: &str) -> SystemTime { generalized_time(s.as_bytes()).unwrap() }
+
+    /// Successful `OCSPResponse` reporting `cert_id()` as good between the two
+    /// update times (signature omitted; the cache never checks it).
+    fn good_response() -> Vec<u8> {
+        let id = cert_id();
+        let single = der(0x30, &[
+            id.to_der(),
+            vec![0x80, 0x00],
+            der(0x18, THIS_UPDATE.as_bytes()),
+            der(0xa0, &der(0x18, NEXT_UPDATE.as_bytes())),
+        ].concat());
+        let tbs = der(0x30, &[der(0xa2, &der(0x04, &[0; 20])), der(0x18, THIS_UPDATE.as_bytes()), der(0x30, &single)].concat());
+        let basic = der(0x30, &tbs);
+        let oid = [0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+        let bytes = der(0xa0, &der(0x30, &[der(0x06, &oid), der(0x04, &basic)].concat()));
+        der(0x30, &[der(0x0a, &[0]), bytes].concat())
+    }
+
+    #[test]
+    fn cache_hits_only_after_a_fetch() {
+        fn fetch(url: &str, req: &[u8]) -> io::Result<Vec<u8>> {
+            assert_eq!(url, "http://ocsp.test/");
+            assert_eq!(req, cert_id().request_der());
+            Ok(good_response())
+        }
+        let cache = OcspCache::with_fetcher(fetch);
+        let id = cert_id();
+        let other = CertId::new(b"issuer", b"issuer key", &[0x02]);
+        cache.register(id.clone(), "http://ocsp.test/");
+        assert_eq!(cache.get_at(&id, at(THIS_UPDATE)), None);
+        assert_eq!(cache.refresh_due(at(THIS_UPDATE)), 1);
+        assert_eq!(cache.get_at(&id, at(THIS_UPDATE)), Some(good_response()));
+        assert_eq!(cache.get_at(&other, at(THIS_UPDATE)), None);
+        // Past nextUpdate the response is no longer stapled.
+        assert_eq!(cache.get_at(&id, at(NEXT_UPDATE)), None);
+    }
+
+    #[test]
+    fn refresh_runs_halfway_through_the_validity_window() {
+        static FETCHES: AtomicUsize = AtomicUsize::new(0);
+        fn fetch(_: &str, _: &[u8]) -> io::Result<Vec<u8>> {
+            FETCHES.fetch_add(1, Ordering::SeqCst);
+            Ok(good_response())
+        }
+        let cache = OcspCache::with_fetcher(fetch);
+        cache.register(cert_id(), "http://ocsp.test/");
+        let start = at(THIS_UPDATE);
+        assert_eq!(cache.refresh_due(start), 1);
+        assert_eq!(cache.refresh_due(start + Duration::from_secs(59 * 60)), 0);
+        assert_eq!(FETCHES.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.refresh_due(start + Duration::from_secs(3600)), 1);
+        assert_eq!(FETCHES.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn unreachable_responder_soft_fails() {
+        static DOWN: AtomicBool = AtomicBool::new(false);
+        fn fetch(_: &str, _: &[u8]) -> io::Result<Vec<u8>> {
+            if DOWN.load(Ordering::SeqCst) { return Err(io::ErrorKind::ConnectionRefused.into()); }
+            Ok(good_response())
+        }
+        let cache = OcspCache::with_fetcher(fetch);
+        let id = cert_id();
+        cache.register(id.clone(), "http://ocsp.test/");
+        let start = at(THIS_UPDATE);
+        cache.refresh_due(start);
+        DOWN.store(true, Ordering::SeqCst);
+        let later = start + Duration::from_secs(3600);
+        assert_eq!(cache.refresh_due(later), 0);
+        // The previous response is still served until it expires...
+        assert_eq!(cache.get_at(&id, later), Some(good_response()));
+        assert_eq!(cache.get_at(&id, at(NEXT_UPDATE)), None);
+        // ...and the fetch is retried after the back-off.
+        assert_eq!(cache.refresh_due(later + RETRY_AFTER / 2), 0);
+        DOWN.store(false, Ordering::SeqCst);
+        assert_eq!(cache.refresh_due(later + RETRY_AFTER), 1);
+    }
+
+    #[test]
+    fn http_fetch_reports_a_refused_connection() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let url = format!("http://{}/", addr);
+        assert!(http_fetch(&url, &cert_id().request_der()).is_err());
+        assert_eq!(http_fetch("https://ocsp.test/", &[]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
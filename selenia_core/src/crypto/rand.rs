@@ -1,5 +1,12 @@
 //! OS entropy abstraction.
 //! Provides `fill_random` and `random_u64` helpers without external crates.
+//!
+//! With the `test-rng` feature, `set_source` swaps the OS generator for any
+//! [`RngSource`] (e.g. a seeded [`HashDrbg`]) so TLS randoms and tickets are
+//! reproducible in tests. The override belongs to the process that installed it: a
+//! forked child falls back to the OS generator instead of replaying its parent's
+//! stream. Without the feature none of this is compiled and `fill_random` is the OS
+//! generator alone.
 
 use std::io;
 
@@ -64,9 +71,84 @@ mod imp {
 
 /// Fill slice with cryptographically secure random bytes.
 pub fn fill_random(buf: &mut [u8]) -> io::Result<()> {
+    #[cfg(feature = "test-rng")]
+    if let Some(result) = source::fill(buf) { return result; }
     imp::fill(buf)
 }
 
+/// Byte generator that can stand in for the OS one (`test-rng` feature).
+#[cfg(feature = "test-rng")]
+pub trait RngSource: Send {
+    fn fill(&mut self, buf: &mut [u8]) -> io::Result<()>;
+}
+
+/// Use `source` for every `fill_random` in this process until `clear_source`.
+#[cfg(feature = "test-rng")]
+pub fn set_source(source: impl RngSource + 'static) {
+    *source::SOURCE.lock().unwrap_or_else(|e| e.into_inner()) = Some((std::process::id(), Box::new(source)));
+}
+
+/// Back to the OS generator.
+#[cfg(feature = "test-rng")]
+pub fn clear_source() {
+    *source::SOURCE.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+#[cfg(feature = "test-rng")]
+mod source {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Installed source and the pid that installed it.
+    pub(super) static SOURCE: Mutex<Option<(u32, Box<dyn RngSource>)>> = Mutex::new(None);
+
+    /// `None` when the OS generator should be used.
+    pub(super) fn fill(buf: &mut [u8]) -> Option<io::Result<()>> {
+        let mut slot = SOURCE.lock().unwrap_or_else(|e| e.into_inner());
+        let (pid, source) = slot.as_mut()?;
+        // Fork guard: a child must not produce the same bytes as its parent.
+        if *pid != std::process::id() {
+            *slot = None;
+            return None;
+        }
+        Some(source.fill(buf))
+    }
+}
+
+/// Deterministic generator for tests: block `i` is SHA-256(seed || i), i as u64 BE.
+#[cfg(feature = "test-rng")]
+pub struct HashDrbg {
+    seed: Vec<u8>,
+    counter: u64,
+    block: [u8; 32],
+    used: usize,
+}
+
+#[cfg(feature = "test-rng")]
+impl HashDrbg {
+    pub fn new(seed: &[u8]) -> Self {
+        HashDrbg { seed: seed.to_vec(), counter: 0, block: [0; 32], used: 32 }
+    }
+}
+
+#[cfg(feature = "test-rng")]
+impl RngSource for HashDrbg {
+    fn fill(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        for b in buf {
+            if self.used == 32 {
+                let mut input = self.seed.clone();
+                input.extend_from_slice(&self.counter.to_be_bytes());
+                self.block = super::sha256::sha256_digest(&input);
+                self.counter += 1;
+                self.used = 0;
+            }
+            *b = self.block[self.used];
+            self.used += 1;
+        }
+        Ok(())
+    }
+}
+
 /// Return a random u64.
 pub fn random_u64() -> u64 {
     let mut b = [0u8; 8];
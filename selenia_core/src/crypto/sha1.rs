@@ -0,0 +1,102 @@
+//! Minimal SHA-1 implementation in pure Rust (no external crates).
+//! SHA-1 is broken for collision resistance; use only for protocol interop
+//! such as WebSocket `Sec-WebSocket-Accept` (RFC 6455 §4.2.2).
+
+// SHA-1 initial hash values (big-endian)
+const H0: [u32; 5] = [
+    0x67452301,
+    0xefcdab89,
+    0x98badcfe,
+    0x10325476,
+    0xc3d2e1f0,
+];
+
+// SHA-1 round constants (one per 20-round group)
+const K: [u32; 4] = [0x5a827999, 0x6ed9eba1, 0x8f1bbcdc, 0xca62c1d6];
+
+#[inline] fn ch(x:u32,y:u32,z:u32)->u32{ (x&y) ^ ((!x)&z) }
+#[inline] fn parity(x:u32,y:u32,z:u32)->u32{ x^y^z }
+#[inline] fn maj(x:u32,y:u32,z:u32)->u32{ (x&y) ^ (x&z) ^ (y&z) }
+
+/// Compute SHA-1 digest of `data`.
+pub fn sha1_digest(data:&[u8])->[u8;20]{
+    let mut h = H0;
+    let bit_len = (data.len() as u64)*8;
+
+    // Process blocks
+    let mut i=0;
+    loop {
+        let mut block=[0u8;64];
+        let mut end=false;
+        let rem = data.len().saturating_sub(i);
+        if rem>=64 {
+            block.copy_from_slice(&data[i..i+64]);
+        } else {
+            // copy remaining
+            if rem>0 { block[..rem].copy_from_slice(&data[i..]); }
+            block[rem]=0x80;
+            if rem>=56 { // needs extra block
+                process_block(&mut h,&block);
+                block=[0u8;64];
+            }
+            // length in big-endian
+            block[56..64].copy_from_slice(&bit_len.to_be_bytes());
+            end=true;
+        }
+        process_block(&mut h,&block);
+        if end { break; }
+        i+=64;
+    }
+    // output
+    let mut out=[0u8;20];
+    for (i,v) in h.iter().enumerate(){ out[i*4..][..4].copy_from_slice(&v.to_be_bytes()); }
+    out
+}
+
+fn process_block(h:&mut [u32;5], block:&[u8;64]){
+    let mut w=[0u32;80];
+    for t in 0..16 {
+        let b=&block[t*4..t*4+4];
+        w[t]=u32::from_be_bytes([b[0],b[1],b[2],b[3]]);
+    }
+    for t in 16..80 { w[t]=(w[t-3]^w[t-8]^w[t-14]^w[t-16]).rotate_left(1); }
+
+    let mut a=h[0]; let mut b=h[1]; let mut c=h[2]; let mut d=h[3]; let mut e=h[4];
+
+    for t in 0..80 {
+        let f = match t { 0..=19 => ch(b,c,d), 20..=39 => parity(b,c,d), 40..=59 => maj(b,c,d), _ => parity(b,c,d) };
+        let tmp=a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(K[t/20]).wrapping_add(w[t]);
+        e=d; d=c; c=b.rotate_left(30); b=a; a=tmp;
+    }
+    h[0]=h[0].wrapping_add(a);
+    h[1]=h[1].wrapping_add(b);
+    h[2]=h[2].wrapping_add(c);
+    h[3]=h[3].wrapping_add(d);
+    h[4]=h[4].wrapping_add(e);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sha1_digest;
+
+    fn hex(d: &[u8]) -> String { d.iter().map(|b| format!("{:02x}", b)).collect() }
+
+    #[test]
+    fn fips_180_vectors() {
+        assert_eq!(hex(&sha1_digest(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(hex(&sha1_digest(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(
+            hex(&sha1_digest(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+        assert_eq!(hex(&sha1_digest(&vec![b'a'; 1_000_000])), "34aa973cd4c4daa4f61eeb2bdbad27316534016f");
+    }
+
+    #[test]
+    fn padding_block_boundaries() {
+        // 55 bytes fit the length in one block, 56 need a second, 64 fill one exactly.
+        assert_eq!(hex(&sha1_digest(&[b'a'; 55])), "c1c8bbdc22796e28c0e15163d20899b65621d65a");
+        assert_eq!(hex(&sha1_digest(&[b'a'; 56])), "c2db330f6083854c99d4b5bfb6e8f29f201be699");
+        assert_eq!(hex(&sha1_digest(&[b'a'; 64])), "0098ba824b5c16427bd7a1122a5a442a25ec644d");
+    }
+}
@@ -1,7 +1,7 @@
 //! Minimal TLS 1.3 (RFC 8446) server-side handshake & record layer.
 //! No external crates: relies on internal HKDF/HMAC/SHA-256/AES-GCM.
 //! Supports:
-//! • One cipher suite: TLS_AES_128_GCM_SHA256 (0x1301)
+//! • One cipher suite: TLS_AES_128_GCM_SHA256 (0x1301), gated by [`TlsPolicy`]
 //! • One signature scheme: rsa_pss_rsae_sha256 (0x0804) – signature skipped (CertificateVerify omitted)
 //! • Session resumption / 0-RTT not implemented.
 //! • ALPN & extensions are parsed but ignored.
@@ -14,7 +14,8 @@
 use super::{hkdf::hkdf_extract, hkdf::hkdf_expand_label, sha256::sha256_digest, aes_gcm};
 use super::rand::fill_random;
 use core::convert::TryInto;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::RwLock;
 use std::time::{SystemTime, Duration, UNIX_EPOCH};
 
 const SUITE_TLS_AES_128_GCM_SHA256: [u8; 2] = [0x13, 0x01];
@@ -23,7 +24,163 @@ const LABEL_KEY: &[u8] = b"key";
 const LABEL_IV: &[u8] = b"iv";
 
 #[derive(Debug)]
-pub enum TlsError { Unsupported, DecodeError }
+pub enum TlsError { Unsupported, DecodeError, ProtocolVersion, UnexpectedMessage }
+
+impl TlsError {
+    /// Fatal alert that reports this error to the peer.
+    pub fn alert(&self) -> Alert {
+        Alert::fatal(match self {
+            TlsError::Unsupported => AlertDescription::HandshakeFailure,
+            TlsError::DecodeError => AlertDescription::DecodeError,
+            TlsError::ProtocolVersion => AlertDescription::ProtocolVersion,
+            TlsError::UnexpectedMessage => AlertDescription::UnexpectedMessage,
+        })
+    }
+
+    /// Plaintext alert record to send before closing (no keys exist yet
+    /// when the ClientHello is rejected).
+    pub fn alert_record(&self) -> Vec<u8> { self.alert().encode() }
+}
+
+// -----------------------------------------------------------------------------
+// Alert protocol (RFC 8446 §6)
+// -----------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlertLevel { Warning = 1, Fatal = 2 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlertDescription {
+    CloseNotify = 0,
+    UnexpectedMessage = 10,
+    BadRecordMac = 20,
+    HandshakeFailure = 40,
+    IllegalParameter = 47,
+    DecodeError = 50,
+    ProtocolVersion = 70,
+    InternalError = 80,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Alert {
+    pub level: AlertLevel,
+    pub description: AlertDescription,
+}
+
+impl Alert {
+    pub fn fatal(description: AlertDescription) -> Self { Alert { level: AlertLevel::Fatal, description } }
+
+    /// `close_notify` is the only alert sent at warning level in TLS 1.3.
+    pub fn close_notify() -> Self { Alert { level: AlertLevel::Warning, description: AlertDescription::CloseNotify } }
+
+    /// Two-byte alert body (level, description).
+    pub fn to_bytes(&self) -> [u8; 2] { [self.level as u8, self.description as u8] }
+
+    /// TLSPlaintext alert record – used before traffic keys exist.
+    pub fn encode(&self) -> Vec<u8> {
+        let b = self.to_bytes();
+        vec![CONTENT_ALERT, 0x03, 0x03, 0x00, 0x02, b[0], b[1]]
+    }
+
+    /// Protected alert record once keys are installed (§5.2: sent as application_data).
+    pub fn encode_encrypted(&self, state: &mut Tls13State) -> Vec<u8> {
+        encrypt_record(state, CONTENT_ALERT, &self.to_bytes(), 0)
+    }
+
+    /// Decrypted record content is the peer's close_notify (§6.1).
+    pub fn is_close_notify(content_type: u8, data: &[u8]) -> bool {
+        content_type == CONTENT_ALERT && data.get(1) == Some(&(AlertDescription::CloseNotify as u8))
+    }
+}
+
+/// Operator policy for the handshake: allowed suites in server preference order.
+/// Only TLS 1.3 / TLS_AES_128_GCM_SHA256 is implemented, so other entries narrow the
+/// accepted set but never widen it.
+#[derive(Debug, Clone)]
+pub struct TlsPolicy {
+    pub suites: Vec<[u8; 2]>,
+    /// Application data per record before a [`RecordWriter`] seals it, at most 2^14.
+    pub max_record: usize,
+    /// Records carrying less are padded up to this many bytes (0: no padding).
+    pub min_record: usize,
+}
+
+impl Default for TlsPolicy {
+    fn default() -> Self {
+        TlsPolicy { suites: vec![SUITE_TLS_AES_128_GCM_SHA256], max_record: MAX_RECORD_PLAINTEXT, min_record: 0 }
+    }
+}
+
+/// Suites this module can actually negotiate.
+const IMPLEMENTED_SUITES: &[[u8; 2]] = &[SUITE_TLS_AES_128_GCM_SHA256];
+
+/// IANA name → code point for the TLS 1.3 suites (RFC 8446 §B.4).
+pub fn suite_from_name(name: &str) -> Option<[u8; 2]> {
+    match name.trim().to_ascii_uppercase().as_str() {
+        "TLS_AES_128_GCM_SHA256" => Some([0x13, 0x01]),
+        "TLS_AES_256_GCM_SHA384" => Some([0x13, 0x02]),
+        "TLS_CHACHA20_POLY1305_SHA256" => Some([0x13, 0x03]),
+        "TLS_AES_128_CCM_SHA256" => Some([0x13, 0x04]),
+        "TLS_AES_128_CCM_8_SHA256" => Some([0x13, 0x05]),
+        _ => None,
+    }
+}
+
+/// Whether `tls.min_version` names a floor this module meets. It speaks TLS 1.3 only,
+/// so `"1.3"` is the one floor it can honour; a lower one would promise 1.2 support.
+pub fn supported_min_version(name: &str) -> bool {
+    matches!(name.trim(), "1.3" | "TLSv1.3")
+}
+
+static POLICY: RwLock<Option<TlsPolicy>> = RwLock::new(None);
+
+/// Install the handshake policy used by [`process_client_hello`].
+pub fn set_policy(policy: TlsPolicy) {
+    *POLICY.write().unwrap() = Some(policy);
+}
+
+fn current_policy() -> TlsPolicy {
+    POLICY.read().unwrap().clone().unwrap_or_default()
+}
+
+/// Fields of a ClientHello relevant to negotiation.
+struct ClientHello<'a> {
+    suites: &'a [u8],
+    /// `supported_versions` (0x002b) list; `None` means a pre-1.3 client.
+    versions: Option<&'a [u8]>,
+}
+
+fn parse_client_hello(body: &[u8]) -> Option<ClientHello<'_>> {
+    let mut idx = 34; // legacy_version(2) + random(32)
+    let sid_len = *body.get(idx)? as usize; idx += 1 + sid_len;
+    let cs_len = u16::from_be_bytes([*body.get(idx)?, *body.get(idx+1)?]) as usize; idx += 2;
+    let suites = body.get(idx..idx+cs_len)?; idx += cs_len;
+    let comp_len = *body.get(idx)? as usize; idx += 1 + comp_len;
+    let mut versions = None;
+    if let (Some(&a), Some(&b)) = (body.get(idx), body.get(idx+1)) {
+        let ext_len = u16::from_be_bytes([a, b]) as usize; idx += 2;
+        let mut exts = body.get(idx..idx+ext_len)?;
+        while exts.len() >= 4 {
+            let ty = u16::from_be_bytes([exts[0], exts[1]]);
+            let len = u16::from_be_bytes([exts[2], exts[3]]) as usize;
+            let data = exts.get(4..4+len)?;
+            if ty == 0x002b {
+                let n = *data.first()? as usize;
+                versions = Some(data.get(1..1+n)?);
+            }
+            exts = &exts[4+len..];
+        }
+    }
+    Some(ClientHello { suites, versions })
+}
+
+/// First suite in server preference order that the client offers and we implement.
+fn select_suite(policy: &TlsPolicy, offered: &[u8]) -> Option<[u8; 2]> {
+    policy.suites.iter()
+        .filter(|s| IMPLEMENTED_SUITES.contains(s))
+        .find(|s| offered.chunks_exact(2).any(|c| c == &s[..]))
+        .copied()
+}
 
 /// Holds handshake secrets and record cipher keys.
 #[derive(Clone)]
@@ -53,30 +210,83 @@ impl Tls13State {
 // 5. Session Ticket & Resumption (RFC 8446 §4.6.1 – simplified)
 // -----------------------------------------------------------------------------
 
+/// Ticket lifetime, reuse and 0-RTT rules. Early data is replayable by design
+/// (RFC 8446 §8), so it stays off unless enabled, and is then accepted at most once
+/// per ClientHello nonce within `replay_window`.
+#[derive(Debug, Clone)]
+pub struct TicketPolicy {
+    pub lifetime: Duration,
+    /// Remove a ticket on its first successful resumption.
+    pub single_use: bool,
+    pub early_data: bool,
+    pub replay_window: Duration,
+}
+
+/// Upper bound on ticket_lifetime (RFC 8446 §4.6.1): seven days.
+pub const MAX_TICKET_LIFETIME: Duration = Duration::from_secs(7 * 24 * 3600);
+/// Remembered early-data nonces; when full, 0-RTT is refused rather than risking a
+/// forgotten replay.
+const REPLAY_CACHE_MAX: usize = 65536;
+
+impl Default for TicketPolicy {
+    fn default() -> Self {
+        TicketPolicy { lifetime: Duration::from_secs(7200), single_use: true, early_data: false, replay_window: Duration::from_secs(10) }
+    }
+}
+
 /// In-memory session ticket store. For production this should be
 /// shared across workers or backed by an external KV.
 #[derive(Default)]
 pub struct TicketStore {
     tickets: HashMap<Vec<u8>, (Tls13State, u64)>, // ticket -> (state, expiry_epoch_ms)
+    policy: TicketPolicy,
+    /// Early-data nonces (ticket || ClientHello.random), plus first-seen epoch ms in arrival order for expiry.
+    seen: HashSet<Vec<u8>>,
+    seen_order: VecDeque<(u64, Vec<u8>)>,
 }
 
 impl TicketStore {
+    pub fn new(policy: TicketPolicy) -> Self { TicketStore { policy, ..Self::default() } }
+
     /// Issue a new ticket for the given connection state, returns wire bytes.
-    pub fn issue(&mut self, state: &Tls13State, lifetime: Duration) -> Vec<u8> {
+    pub fn issue(&mut self, state: &Tls13State) -> Vec<u8> {
         let mut ticket = [0u8; 32];
         let _ = fill_random(&mut ticket);
-        let expiry = now_ms() + lifetime.as_millis() as u64;
+        let expiry = now_ms() + self.policy.lifetime.as_millis() as u64;
         self.tickets.insert(ticket.to_vec(), (state.clone(), expiry));
         ticket.to_vec()
     }
 
-    /// Attempt to resume from ticket. Returns cloned state when valid.
+    /// Attempt to resume from ticket. Returns cloned state when valid; a single-use
+    /// ticket is consumed, so a second resumption fails.
     pub fn resume(&mut self, ticket: &[u8]) -> Option<Tls13State> {
         let now = now_ms();
-        if let Some((state, exp)) = self.tickets.get(ticket) {
-            if *exp > now { return Some(state.clone()); }
+        match self.tickets.get(ticket) {
+            Some((_, exp)) if *exp <= now => { self.tickets.remove(ticket); None }
+            Some(_) if self.policy.single_use => self.tickets.remove(ticket).map(|(state, _)| state),
+            Some((state, _)) => Some(state.clone()),
+            None => None,
         }
-        None
+    }
+
+    /// Resume for 0-RTT. `None` (fall back to a full handshake) when early data is
+    /// disabled, the ticket is not resumable, or this `client_random` already carried
+    /// early data under `ticket` inside the replay window.
+    pub fn resume_early(&mut self, ticket: &[u8], client_random: &[u8]) -> Option<Tls13State> {
+        if !self.policy.early_data { return None; }
+        let now = now_ms();
+        let window = self.policy.replay_window.as_millis() as u64;
+        while let Some((t, _)) = self.seen_order.front() {
+            if now.saturating_sub(*t) < window { break; }
+            let (_, key) = self.seen_order.pop_front().unwrap();
+            self.seen.remove(&key);
+        }
+        let key = [ticket, client_random].concat();
+        if self.seen.contains(&key) || self.seen.len() >= REPLAY_CACHE_MAX { return None; }
+        let state = self.resume(ticket)?;
+        self.seen.insert(key.clone());
+        self.seen_order.push_back((now, key));
+        Some(state)
     }
 }
 
@@ -88,16 +298,18 @@ fn now_ms() -> u64 {
 /// On success, Tls13State is filled with traffic keys.
 pub fn process_client_hello(buf: &[u8]) -> Result<(Vec<u8>, Tls13State), TlsError> {
     // Very naive parse: assume record header already stripped.
-    if buf.len()<4 || buf[0]!=1 { return Err(TlsError::DecodeError); }
+    if buf.len()<4 { return Err(TlsError::DecodeError); }
+    if buf[0]!=1 { return Err(TlsError::UnexpectedMessage); } // only ClientHello may open the handshake
     let len = ((buf[1] as usize)<<16)|((buf[2] as usize)<<8)|(buf[3] as usize);
     if buf.len()<4+len { return Err(TlsError::DecodeError); }
     let body=&buf[4..4+len];
     if body.len()<42 { return Err(TlsError::DecodeError); }
-    let mut idx=38; // skip legacy ver(2)+random(32)+sid_len(0)
-    let cs_len = u16::from_be_bytes([body[idx],body[idx+1]]) as usize; idx+=2;
-    if cs_len==0 || !body[idx..idx+cs_len].windows(2).any(|w| w==SUITE_TLS_AES_128_GCM_SHA256) {
-        return Err(TlsError::Unsupported);
-    }
+    let hello = parse_client_hello(body).ok_or(TlsError::DecodeError)?;
+    let policy = current_policy();
+    // Only TLS 1.3 is spoken; a client without supported_versions/0x0304 cannot meet any policy.
+    let offers_13 = hello.versions.map_or(false, |v| v.chunks_exact(2).any(|c| c == [0x03, 0x04]));
+    if !offers_13 { return Err(TlsError::ProtocolVersion); }
+    let suite = select_suite(&policy, hello.suites).ok_or(TlsError::Unsupported)?;
     // --- Key schedule ---
     let mut shared_secret=[0u8;32]; // In real TLS: ECDHE; here use random.
     fill_random(&mut shared_secret);
@@ -126,7 +338,7 @@ pub fn process_client_hello(buf: &[u8]) -> Result<(Vec<u8>, Tls13State), TlsErro
     payload.extend_from_slice(&[0x03,0x03]); // legacy_version 1.2
     payload.extend_from_slice(&random);
     payload.push(0); // session id len
-    payload.extend_from_slice(&SUITE_TLS_AES_128_GCM_SHA256);
+    payload.extend_from_slice(&suite);
     payload.push(0); // compression
     payload.extend_from_slice(&[0,0]); // extensions len=0
 
@@ -153,37 +365,122 @@ fn build_nonce(iv:&[u8;12], seq:u64)->[u8;12] {
     nonce
 }
 
-pub fn encrypt_application_data(state:&mut Tls13State, plaintext:&mut Vec<u8>)->Vec<u8> {
+/// TLS 1.3 ContentType values carried inside TLSInnerPlaintext.
+pub const CONTENT_ALERT: u8 = 21;
+pub const CONTENT_HANDSHAKE: u8 = 22;
+pub const CONTENT_APPLICATION_DATA: u8 = 23;
+
+/// Largest plaintext fragment a record may carry (RFC 8446 §5.1).
+pub const MAX_RECORD_PLAINTEXT: usize = 1 << 14;
+
+/// Upper bound of TLSInnerPlaintext (2^14 + content type + padding) per RFC 8446 §5.2.
+const MAX_INNER_PLAINTEXT: usize = MAX_RECORD_PLAINTEXT + 256;
+
+/// Seal one record. The real content type and `pad_len` zero bytes are appended to the
+/// plaintext before encryption (TLSInnerPlaintext), so the outer header always reads
+/// application_data and the record length hides the true payload size.
+pub fn encrypt_record(state:&mut Tls13State, content_type:u8, plaintext:&[u8], pad_len:usize)->Vec<u8> {
+    let pad_len = pad_len.min(MAX_INNER_PLAINTEXT.saturating_sub(plaintext.len()+1));
+    let mut buf=Vec::with_capacity(plaintext.len()+1+pad_len+16);
+    buf.extend_from_slice(plaintext);
+    buf.push(content_type);
+    buf.resize(plaintext.len()+1+pad_len, 0);
+    // AAD = opaque_type || legacy_record_version || length(ciphertext + tag)
+    let len=(buf.len()+16) as u16;
+    let aad=[CONTENT_APPLICATION_DATA,0x03,0x03,(len>>8) as u8,(len&0xff) as u8];
     let nonce=build_nonce(&state.server_iv, state.server_seq);
-    let aad=[0x17u8,0x03,0x03,0,0]; // content_type=23, length placeholder later
-    let mut buf=plaintext.clone();
-    let tag = aes_gcm::seal(&state.server_write_key, &nonce[..12].try_into().unwrap(), &aad, &mut buf);
+    let tag = aes_gcm::seal(&state.server_write_key, &nonce, &aad, &mut buf);
     state.server_seq+=1;
-    let len=(buf.len()+16) as u16;
     let mut record=Vec::with_capacity(5+buf.len()+16);
-    record.push(23);
-    record.extend_from_slice(&[0x03,0x03]);
-    record.extend_from_slice(&len.to_be_bytes());
+    record.extend_from_slice(&aad);
     record.extend_from_slice(&buf);
     record.extend_from_slice(&tag);
     record
 }
 
-pub fn decrypt_application_data(state:&mut Tls13State, ciphertext:&[u8]) -> Option<Vec<u8>> {
+/// Seal `plaintext` as application data, split into records of at most 2^14 bytes.
+pub fn encrypt_application_data(state:&mut Tls13State, plaintext:&mut Vec<u8>)->Vec<u8> {
+    if plaintext.is_empty() { return encrypt_record(state, CONTENT_APPLICATION_DATA, plaintext, 0); }
+    plaintext.chunks(MAX_RECORD_PLAINTEXT).flat_map(|c| encrypt_record(state, CONTENT_APPLICATION_DATA, c, 0)).collect()
+}
+
+/// Coalesces small application-data writes into full records. Plaintext is held until
+/// `max_record` bytes are queued or [`RecordWriter::flush`] is called (explicit flush,
+/// before close_notify); records shorter than `min_record` are padded so their length
+/// says less about the response.
+#[derive(Debug)]
+pub struct RecordWriter {
+    pending: Vec<u8>,
+    max_record: usize,
+    min_record: usize,
+}
+
+impl RecordWriter {
+    pub fn new(max_record: usize, min_record: usize) -> Self {
+        let max_record = max_record.clamp(1, MAX_RECORD_PLAINTEXT);
+        RecordWriter { pending: Vec::new(), max_record, min_record: min_record.min(max_record) }
+    }
+
+    /// Writer sized by the policy installed with [`set_policy`].
+    pub fn from_policy() -> Self {
+        let p = current_policy();
+        Self::new(p.max_record, p.min_record)
+    }
+
+    /// Plaintext queued but not yet sealed.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Queue `data`; returns the records that filled up, possibly none.
+    pub fn write(&mut self, state:&mut Tls13State, data:&[u8]) -> Vec<u8> {
+        self.pending.extend_from_slice(data);
+        let full = self.pending.len() - self.pending.len() % self.max_record;
+        let out = self.seal(state, full);
+        self.pending.drain(..full);
+        out
+    }
+
+    /// Seal everything queued, the last record possibly short.
+    pub fn flush(&mut self, state:&mut Tls13State) -> Vec<u8> {
+        let out = self.seal(state, self.pending.len());
+        self.pending.clear();
+        out
+    }
+
+    fn seal(&self, state:&mut Tls13State, len: usize) -> Vec<u8> {
+        self.pending[..len].chunks(self.max_record)
+            .flat_map(|c| encrypt_record(state, CONTENT_APPLICATION_DATA, c, self.min_record.saturating_sub(c.len())))
+            .collect()
+    }
+}
+
+/// Open one record and strip TLSInnerPlaintext padding. Returns (inner content type, content).
+pub fn decrypt_record(state:&mut Tls13State, ciphertext:&[u8]) -> Option<(u8, Vec<u8>)> {
     if ciphertext.len()<21 { return None; }
-    let content_type=ciphertext[0];
-    if content_type!=23 { return None; }
+    if ciphertext[0]!=CONTENT_APPLICATION_DATA { return None; }
     let len=u16::from_be_bytes([ciphertext[3],ciphertext[4]]) as usize;
-    if ciphertext.len()!=5+len { return None; }
+    if len<17 || ciphertext.len()!=5+len { return None; }
     let mut enc=ciphertext[5..5+len-16].to_vec();
     let tag:&[u8;16]=ciphertext[5+len-16..].try_into().unwrap();
     let nonce=build_nonce(&state.client_iv, state.client_seq);
-    let aad=[0x17u8,0x03,0x03, ((len-16)>>8) as u8, ((len-16)&0xff) as u8];
-    if !aes_gcm::open(&state.client_write_key, &nonce[..12].try_into().unwrap(), &aad, &mut enc, tag) {
+    let aad=&ciphertext[..5];
+    if !aes_gcm::open(&state.client_write_key, &nonce, aad, &mut enc, tag) {
         return None;
     }
     state.client_seq+=1;
-    Some(enc)
+    // Scan backwards past zero padding; the first non-zero byte is the real content type.
+    let pos=enc.iter().rposition(|&b| b!=0)?;
+    let content_type=enc[pos];
+    enc.truncate(pos);
+    Some((content_type, enc))
+}
+
+pub fn decrypt_application_data(state:&mut Tls13State, ciphertext:&[u8]) -> Option<Vec<u8>> {
+    match decrypt_record(state, ciphertext)? {
+        (CONTENT_APPLICATION_DATA, data) => Some(data),
+        _ => None,
+    }
 }
 
 // -----------------------------------------------------------------------------
@@ -199,6 +496,8 @@ pub enum ServerHsState {
     SentEncryptedExtensions,
     SentFinished,
     Established,
+    /// close_notify sent; nothing else may be written on this connection.
+    Closed,
     Failed,
 }
 
@@ -218,7 +517,10 @@ impl Tls13Server {
         match self.state {
             ServerHsState::AwaitClientHello => {
                 // Expect ClientHello record type 22 / Handshake.
-                if record.get(0) != Some(&22) { self.state = ServerHsState::Failed; return None; }
+                if record.get(0) != Some(&CONTENT_HANDSHAKE) {
+                    self.state = ServerHsState::Failed;
+                    return Some(TlsError::UnexpectedMessage.alert_record());
+                }
                 // Strip record header (5 bytes) before pass-through.
                 if record.len() < 5 { return None; }
                 let (_, body) = record.split_at(5);
@@ -228,7 +530,7 @@ impl Tls13Server {
                         self.state = ServerHsState::SentServerHello;
                         Some(server_hello)
                     }
-                    Err(_) => { self.state = ServerHsState::Failed; None }
+                    Err(e) => { self.state = ServerHsState::Failed; Some(e.alert_record()) }
                 }
             }
             ServerHsState::SentServerHello => {
@@ -238,9 +540,274 @@ impl Tls13Server {
                 self.state = ServerHsState::Established;
                 None
             }
+            ServerHsState::Established => {
+                // The peer's close_notify is answered with ours (§6.1); the caller then closes.
+                let ctx = self.hs_context.as_mut()?;
+                match decrypt_record(ctx, record) {
+                    Some((ct, data)) if Alert::is_close_notify(ct, &data) => self.close(),
+                    _ => None,
+                }
+            }
             _ => None,
         }
     }
 
     pub fn is_established(&self) -> bool { self.state == ServerHsState::Established }
-} 
\ No newline at end of file
+
+    /// Encrypted close_notify to send before shutting the socket down. Yields the record
+    /// once; `None` when no traffic keys exist (nothing was protected) or it was already sent.
+    pub fn close(&mut self) -> Option<Vec<u8>> {
+        if matches!(self.state, ServerHsState::Closed | ServerHsState::Failed) { return None; }
+        let ctx = self.hs_context.as_mut()?;
+        self.state = ServerHsState::Closed;
+        Some(Alert::close_notify().encode_encrypted(ctx))
+    }
+
+    pub fn is_closed(&self) -> bool { self.state == ServerHsState::Closed }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A state whose client and server directions share keys, so it opens what it seals.
+    fn loopback() -> Tls13State {
+        let mut s = Tls13State::new();
+        s.client_write_key = [7; 16];
+        s.server_write_key = [7; 16];
+        s.client_iv = [9; 12];
+        s.server_iv = [9; 12];
+        s
+    }
+
+    /// ClientHello handshake message offering `suites`, with `supported_versions` when given.
+    fn client_hello(suites: &[[u8; 2]], versions: Option<&[u16]>) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0x11; 32]);
+        body.push(0);
+        body.extend_from_slice(&((suites.len() * 2) as u16).to_be_bytes());
+        suites.iter().for_each(|s| body.extend_from_slice(s));
+        body.extend_from_slice(&[1, 0]);
+        let mut exts = Vec::new();
+        if let Some(v) = versions {
+            exts.extend_from_slice(&[0x00, 0x2b]);
+            exts.extend_from_slice(&((v.len() * 2 + 1) as u16).to_be_bytes());
+            exts.push((v.len() * 2) as u8);
+            v.iter().for_each(|x| exts.extend_from_slice(&x.to_be_bytes()));
+        }
+        body.extend_from_slice(&(exts.len() as u16).to_be_bytes());
+        body.extend_from_slice(&exts);
+        let mut msg = vec![1];
+        msg.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        msg.extend_from_slice(&body);
+        msg
+    }
+
+    /// `msg` as a TLSPlaintext handshake record.
+    fn record(msg: &[u8]) -> Vec<u8> {
+        let mut r = vec![CONTENT_HANDSHAKE, 0x03, 0x01];
+        r.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+        r.extend_from_slice(msg);
+        r
+    }
+
+    const AES_256: [u8; 2] = [0x13, 0x02];
+
+    #[test]
+    fn inner_content_type_is_hidden_and_recovered() {
+        let mut s = loopback();
+        let record = encrypt_record(&mut s, CONTENT_HANDSHAKE, b"finished", 100);
+        assert_eq!(&record[..3], &[CONTENT_APPLICATION_DATA, 0x03, 0x03]);
+        // body || content type || padding || tag
+        assert_eq!(record.len(), 5 + 8 + 1 + 100 + 16);
+        assert_eq!(decrypt_record(&mut s, &record), Some((CONTENT_HANDSHAKE, b"finished".to_vec())));
+        let record = encrypt_record(&mut s, CONTENT_APPLICATION_DATA, b"", 0);
+        assert_eq!(decrypt_record(&mut s, &record), Some((CONTENT_APPLICATION_DATA, Vec::new())));
+    }
+
+    #[test]
+    fn padding_never_exceeds_the_inner_plaintext_limit() {
+        let mut s = loopback();
+        let record = encrypt_record(&mut s, CONTENT_APPLICATION_DATA, &[1; 100], usize::MAX);
+        assert_eq!(record.len(), 5 + MAX_INNER_PLAINTEXT + 16);
+        assert_eq!(decrypt_application_data(&mut s, &record), Some(vec![1; 100]));
+    }
+
+    #[test]
+    fn policy_narrows_the_suites() {
+        let default = TlsPolicy::default();
+        assert_eq!(select_suite(&default, &[0x13, 0x02, 0x13, 0x01]), Some(SUITE_TLS_AES_128_GCM_SHA256));
+        assert_eq!(select_suite(&default, &AES_256), None);
+        // Listing an unimplemented suite never makes it negotiable.
+        let wide = TlsPolicy { suites: vec![AES_256, SUITE_TLS_AES_128_GCM_SHA256], ..TlsPolicy::default() };
+        assert_eq!(select_suite(&wide, &[0x13, 0x02, 0x13, 0x01]), Some(SUITE_TLS_AES_128_GCM_SHA256));
+        let none = TlsPolicy { suites: vec![AES_256], ..TlsPolicy::default() };
+        assert_eq!(select_suite(&none, &[0x13, 0x02, 0x13, 0x01]), None);
+        assert_eq!(suite_from_name(" tls_aes_256_gcm_sha384 "), Some(AES_256));
+        assert_eq!(suite_from_name("TLS_RSA_WITH_RC4_128_SHA"), None);
+        assert!(supported_min_version(" TLSv1.3") && supported_min_version("1.3"));
+        assert!(!supported_min_version("1.2") && !supported_min_version("TLSv1.2") && !supported_min_version("1.1"));
+    }
+
+    #[test]
+    fn disallowed_cipher_gets_handshake_failure() {
+        let mut server = Tls13Server::new();
+        let reply = server.drive(&record(&client_hello(&[AES_256], Some(&[0x0304])))).unwrap();
+        assert_eq!(reply, [CONTENT_ALERT, 0x03, 0x03, 0x00, 0x02, 2, 40]);
+        assert!(!server.is_established());
+        assert_eq!(server.drive(&record(&client_hello(&[SUITE_TLS_AES_128_GCM_SHA256], Some(&[0x0304])))), None);
+    }
+
+    #[test]
+    fn handshake_errors_send_the_matching_alert() {
+        let alert = |desc: u8| vec![CONTENT_ALERT, 0x03, 0x03, 0x00, 0x02, 2, desc];
+        let hello = client_hello(&[SUITE_TLS_AES_128_GCM_SHA256], Some(&[0x0304]));
+        // Truncated body, a pre-1.3 client, an unsupported suite, and a non-handshake first record.
+        assert_eq!(Tls13Server::new().drive(&record(&hello[..30])), Some(alert(50)));
+        assert_eq!(Tls13Server::new().drive(&record(&client_hello(&[SUITE_TLS_AES_128_GCM_SHA256], None))), Some(alert(70)));
+        assert_eq!(Tls13Server::new().drive(&record(&client_hello(&[SUITE_TLS_AES_128_GCM_SHA256], Some(&[0x0303])))), Some(alert(70)));
+        assert_eq!(Tls13Server::new().drive(&record(&client_hello(&[[0x00, 0x2f]], Some(&[0x0304])))), Some(alert(40)));
+        assert_eq!(Tls13Server::new().drive(&[CONTENT_APPLICATION_DATA, 0x03, 0x03, 0x00, 0x00]), Some(alert(10)));
+        let mut server_hello = hello.clone();
+        server_hello[0] = 2;
+        assert_eq!(Tls13Server::new().drive(&record(&server_hello)), Some(alert(10)));
+    }
+
+    #[test]
+    fn acceptable_client_hello_gets_a_server_hello() {
+        let mut server = Tls13Server::new();
+        let reply = server.drive(&record(&client_hello(&[AES_256, SUITE_TLS_AES_128_GCM_SHA256], Some(&[0x0303, 0x0304])))).unwrap();
+        assert_eq!(&reply[..3], &[CONTENT_HANDSHAKE, 0x03, 0x03]);
+        assert_eq!(reply[5], 2);
+        // legacy_version, random, empty session id, then the chosen suite.
+        assert_eq!(&reply[5 + 4 + 2 + 32 + 1..][..2], &SUITE_TLS_AES_128_GCM_SHA256);
+    }
+
+    #[test]
+    fn tampered_record_is_rejected() {
+        let mut s = loopback();
+        let mut record = encrypt_record(&mut s, CONTENT_APPLICATION_DATA, b"data", 3);
+        record[6] ^= 1;
+        assert_eq!(decrypt_record(&mut s, &record), None);
+    }
+
+    /// A server past the handshake whose record keys are `loopback()`'s.
+    fn established() -> Tls13Server {
+        let mut server = Tls13Server::new();
+        server.drive(&record(&client_hello(&[SUITE_TLS_AES_128_GCM_SHA256], Some(&[0x0304])))).unwrap();
+        assert_eq!(server.drive(&[CONTENT_APPLICATION_DATA, 0x03, 0x03, 0x00, 0x00]), None);
+        assert!(server.is_established());
+        server.hs_context = Some(loopback());
+        server
+    }
+
+    #[test]
+    fn graceful_close_sends_one_protected_close_notify() {
+        assert_eq!(Tls13Server::new().close(), None);
+        let mut server = established();
+        let record = server.close().unwrap();
+        assert_eq!(record[0], CONTENT_APPLICATION_DATA);
+        assert_eq!(decrypt_record(&mut loopback(), &record), Some((CONTENT_ALERT, vec![1, 0])));
+        assert!(server.is_closed());
+        assert_eq!(server.close(), None);
+    }
+
+    #[test]
+    fn inbound_close_notify_is_answered() {
+        let mut server = established();
+        let mut peer = loopback();
+        let data = encrypt_record(&mut peer, CONTENT_APPLICATION_DATA, b"GET", 0);
+        assert_eq!(server.drive(&data), None);
+        assert!(server.is_established());
+        let reply = server.drive(&Alert::close_notify().encode_encrypted(&mut peer)).unwrap();
+        assert!(server.is_closed());
+        let (ct, body) = decrypt_record(&mut loopback(), &reply).unwrap();
+        assert!(Alert::is_close_notify(ct, &body));
+    }
+
+    #[test]
+    fn single_use_tickets_resume_once() {
+        let mut store = TicketStore::new(TicketPolicy::default());
+        let ticket = store.issue(&loopback());
+        assert!(store.resume(&ticket).is_some());
+        assert!(store.resume(&ticket).is_none());
+        let mut reusable = TicketStore::new(TicketPolicy { single_use: false, ..TicketPolicy::default() });
+        let ticket = reusable.issue(&loopback());
+        assert!(reusable.resume(&ticket).is_some());
+        assert!(reusable.resume(&ticket).is_some());
+        let mut expired = TicketStore::new(TicketPolicy { lifetime: Duration::ZERO, ..TicketPolicy::default() });
+        let ticket = expired.issue(&loopback());
+        assert!(expired.resume(&ticket).is_none());
+        assert!(store.resume(b"never issued").is_none());
+    }
+
+    #[test]
+    fn replayed_early_data_is_refused_inside_the_window() {
+        let policy = TicketPolicy { single_use: false, early_data: true, replay_window: Duration::from_millis(50), ..TicketPolicy::default() };
+        let mut store = TicketStore::new(policy.clone());
+        let ticket = store.issue(&loopback());
+        assert!(store.resume_early(&ticket, &[1; 32]).is_some());
+        assert!(store.resume_early(&ticket, &[1; 32]).is_none());
+        assert!(store.resume_early(&ticket, &[2; 32]).is_some());
+        std::thread::sleep(Duration::from_millis(80));
+        assert!(store.resume_early(&ticket, &[1; 32]).is_some());
+        // Early data stays off unless the policy enables it.
+        let mut off = TicketStore::new(TicketPolicy { early_data: false, ..policy });
+        let ticket = off.issue(&loopback());
+        assert!(off.resume_early(&ticket, &[1; 32]).is_none());
+        assert!(off.resume(&ticket).is_some());
+    }
+
+    /// Sealed output split into records, each opened with `reader`.
+    fn open_all(reader: &mut Tls13State, mut sealed: &[u8]) -> Vec<Vec<u8>> {
+        let mut out = Vec::new();
+        while !sealed.is_empty() {
+            let len = 5 + u16::from_be_bytes([sealed[3], sealed[4]]) as usize;
+            assert!(len <= 5 + MAX_INNER_PLAINTEXT + 16);
+            out.push(decrypt_application_data(reader, &sealed[..len]).unwrap());
+            sealed = &sealed[len..];
+        }
+        out
+    }
+
+    #[test]
+    fn small_writes_coalesce_into_one_record() {
+        let (mut state, mut reader) = (loopback(), loopback());
+        let mut w = RecordWriter::new(MAX_RECORD_PLAINTEXT, 0);
+        for _ in 0..10 {
+            assert!(w.write(&mut state, &[b'x'; 100]).is_empty());
+        }
+        assert_eq!(w.pending(), 1000);
+        let records = open_all(&mut reader, &w.flush(&mut state));
+        assert_eq!(records, [vec![b'x'; 1000]]);
+        assert_eq!(w.pending(), 0);
+        assert!(w.flush(&mut state).is_empty());
+    }
+
+    #[test]
+    fn records_never_exceed_the_maximum() {
+        let (mut state, mut reader) = (loopback(), loopback());
+        // Larger limits are cut to the 2^14 bytes TLS allows.
+        let mut w = RecordWriter::new(usize::MAX, 0);
+        let full = open_all(&mut reader, &w.write(&mut state, &[7; 40_000]));
+        assert_eq!(full.iter().map(Vec::len).collect::<Vec<_>>(), [MAX_RECORD_PLAINTEXT, MAX_RECORD_PLAINTEXT]);
+        assert_eq!(w.pending(), 40_000 - 2 * MAX_RECORD_PLAINTEXT);
+        let rest = open_all(&mut reader, &w.flush(&mut state));
+        assert_eq!(rest, [vec![7; 40_000 - 2 * MAX_RECORD_PLAINTEXT]]);
+
+        let mut w = RecordWriter::new(1000, 0);
+        let records = open_all(&mut reader, &w.write(&mut state, &[1; 2500]));
+        assert_eq!(records.iter().map(Vec::len).collect::<Vec<_>>(), [1000, 1000]);
+    }
+
+    #[test]
+    fn short_records_are_padded_to_the_minimum() {
+        let (mut state, mut reader) = (loopback(), loopback());
+        let mut w = RecordWriter::new(4096, 512);
+        w.write(&mut state, b"tiny");
+        let sealed = w.flush(&mut state);
+        // header || 512 bytes of content and padding || content type || tag
+        assert_eq!(sealed.len(), 5 + 512 + 1 + 16);
+        assert_eq!(open_all(&mut reader, &sealed), [b"tiny".to_vec()]);
+    }
+}
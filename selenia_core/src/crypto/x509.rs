@@ -0,0 +1,157 @@
+//! Just enough PEM / DER / X.509 to check a configured key against its certificate.
+//! Reads the leaf certificate's SubjectPublicKeyInfo and the private key (PKCS#8,
+//! SEC1 `EC PRIVATE KEY` or PKCS#1 `RSA PRIVATE KEY`) and compares public halves.
+//! Supported keys: RSA and ECDSA P-256. No chain or signature validation.
+
+use super::ecdsa_p256::SigningKey;
+use crate::encoding::base64;
+
+const OID_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const OID_EC: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+
+const SEQUENCE: u8 = 0x30;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+
+#[derive(Debug, PartialEq, Eq)]
+enum PublicKey {
+    /// Modulus and exponent without leading zero bytes.
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+    /// Uncompressed SEC1 point.
+    P256(Vec<u8>),
+}
+
+/// Check that `key` is the private key for the first certificate in `cert`.
+/// Both are file contents, PEM or raw DER.
+pub fn check_key_pair(cert: &[u8], key: &[u8]) -> Result<(), String> {
+    let (_, cert_der) = decode(cert, "CERTIFICATE").ok_or("no certificate found")?;
+    let cert_key = cert_public_key(&cert_der).ok_or("unsupported or malformed certificate")?;
+    let (label, key_der) = decode(key, "PRIVATE KEY").ok_or("no private key found")?;
+    let key_pub = match label.as_str() {
+        "RSA PRIVATE KEY" => rsa_private_public(&key_der),
+        "EC PRIVATE KEY" => ec_private_public(&key_der),
+        _ => pkcs8_public(&key_der),
+    }.ok_or("unsupported or malformed private key")?;
+    if key_pub != cert_key { return Err("private key does not match certificate".into()); }
+    Ok(())
+}
+
+/// First PEM block whose label ends with `suffix` (label, DER), or the input itself
+/// when it is already DER.
+fn decode(data: &[u8], suffix: &str) -> Option<(String, Vec<u8>)> {
+    if data.first() == Some(&SEQUENCE) { return Some((String::new(), data.to_vec())); }
+    let text = std::str::from_utf8(data).ok()?;
+    let mut lines = text.lines().map(str::trim);
+    while let Some(line) = lines.next() {
+        let label = match line.strip_prefix("-----BEGIN ").and_then(|l| l.strip_suffix("-----")) {
+            Some(l) if l.ends_with(suffix) => l,
+            _ => continue,
+        };
+        let end = format!("-----END {}-----", label);
+        let b64: String = lines.by_ref().take_while(|l| *l != end).collect();
+        return base64::decode(&b64).map(|der| (label.to_string(), der));
+    }
+    None
+}
+
+/// One DER element: (tag, contents, rest).
+fn tlv(buf: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = buf.split_first()?;
+    let (&first, mut rest) = rest.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 || rest.len() < n { return None; }
+        let len = rest[..n].iter().fold(0usize, |acc, &b| (acc << 8) | b as usize);
+        rest = &rest[n..];
+        len
+    };
+    if rest.len() < len { return None; }
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+/// Contents of an element that must carry `tag`.
+fn expect(buf: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (t, body, rest) = tlv(buf)?;
+    (t == tag).then_some((body, rest))
+}
+
+fn unsigned(int: &[u8]) -> Vec<u8> {
+    let start = int.iter().position(|&b| b != 0).unwrap_or(int.len());
+    int[start..].to_vec()
+}
+
+/// RSAPublicKey ::= SEQUENCE { modulus INTEGER, publicExponent INTEGER }
+fn rsa_public(der: &[u8]) -> Option<PublicKey> {
+    let (seq, _) = expect(der, SEQUENCE)?;
+    let (n, rest) = expect(seq, INTEGER)?;
+    let (e, _) = expect(rest, INTEGER)?;
+    Some(PublicKey::Rsa { n: unsigned(n), e: unsigned(e) })
+}
+
+/// AlgorithmIdentifier -> whether it names RSA or EC on P-256 (`None` when neither).
+fn algorithm(alg: &[u8]) -> Option<&'static [u8]> {
+    let (oid, params) = expect(alg, OID)?;
+    if oid == OID_RSA { return Some(OID_RSA); }
+    if oid == OID_EC && expect(params, OID)?.0 == OID_P256 { return Some(OID_EC); }
+    None
+}
+
+/// Certificate -> tbsCertificate -> subjectPublicKeyInfo.
+fn cert_public_key(der: &[u8]) -> Option<PublicKey> {
+    let (cert, _) = expect(der, SEQUENCE)?;
+    let (tbs, _) = expect(cert, SEQUENCE)?;
+    let mut rest = tbs;
+    // Optional [0] version, then serial, signature, issuer, validity, subject.
+    if rest.first() == Some(&0xa0) { rest = tlv(rest)?.2; }
+    for _ in 0..5 { rest = tlv(rest)?.2; }
+    let (spki, _) = expect(rest, SEQUENCE)?;
+    let (alg, rest) = expect(spki, SEQUENCE)?;
+    let (bits, _) = expect(rest, BIT_STRING)?;
+    let (&unused, key) = bits.split_first()?;
+    if unused != 0 { return None; }
+    match algorithm(alg)? {
+        OID_RSA => rsa_public(key),
+        _ => Some(PublicKey::P256(key.to_vec())),
+    }
+}
+
+/// RSAPrivateKey ::= SEQUENCE { version, modulus, publicExponent, ... }
+fn rsa_private_public(der: &[u8]) -> Option<PublicKey> {
+    let (seq, _) = expect(der, SEQUENCE)?;
+    let (_, rest) = expect(seq, INTEGER)?;
+    let (n, rest) = expect(rest, INTEGER)?;
+    let (e, _) = expect(rest, INTEGER)?;
+    Some(PublicKey::Rsa { n: unsigned(n), e: unsigned(e) })
+}
+
+/// ECPrivateKey ::= SEQUENCE { version, privateKey OCTET STRING, [0] curve, [1] public }
+fn ec_private_public(der: &[u8]) -> Option<PublicKey> {
+    let (seq, _) = expect(der, SEQUENCE)?;
+    let (_, rest) = expect(seq, INTEGER)?;
+    let (d, rest) = expect(rest, OCTET_STRING)?;
+    // A curve given here must be P-256; PKCS#8 carries it outside instead.
+    if let Some((0xa0, curve, _)) = tlv(rest) {
+        if expect(curve, OID)?.0 != OID_P256 { return None; }
+    }
+    if d.len() > 32 { return None; }
+    let mut scalar = [0u8; 32];
+    scalar[32 - d.len()..].copy_from_slice(d);
+    Some(PublicKey::P256(SigningKey::from_bytes(&scalar)?.public_key().to_vec()))
+}
+
+/// PrivateKeyInfo ::= SEQUENCE { version, AlgorithmIdentifier, privateKey OCTET STRING }
+fn pkcs8_public(der: &[u8]) -> Option<PublicKey> {
+    let (seq, _) = expect(der, SEQUENCE)?;
+    let (_, rest) = expect(seq, INTEGER)?;
+    let (alg, rest) = expect(rest, SEQUENCE)?;
+    let (key, _) = expect(rest, OCTET_STRING)?;
+    match algorithm(alg)? {
+        OID_RSA => rsa_private_public(key),
+        _ => ec_private_public(key),
+    }
+}
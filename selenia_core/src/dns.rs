@@ -18,6 +18,8 @@ use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use std::{ptr, thread};
 
+use crate::metrics;
+
 const MAX_LEVEL: usize = 12;
 const TTL_DEFAULT: Duration = Duration::from_secs(300);
 
@@ -74,6 +76,7 @@ impl DnsCache {
                 if let Ok(addr) = (host.as_str(), 0).to_socket_addrs().and_then(|mut it| it.next().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "No addr"))) {
                     cache_clone.insert(host, addr.ip(), TTL_DEFAULT);
                 }
+                metrics::dec_dns_queue_depth();
             }
         });
         // Spawn periodic cleanup thread.
@@ -93,7 +96,9 @@ impl DnsCache {
         }
         // Schedule async resolution.
         if let Ok(tx) = self.resolver_tx.lock() {
-            let _ = tx.send(host.to_owned());
+            // Count before sending so the resolver's decrement never runs ahead.
+            metrics::inc_dns_queue_depth();
+            if tx.send(host.to_owned()).is_err() { metrics::dec_dns_queue_depth(); }
         }
         None
     }
@@ -127,6 +132,7 @@ impl DnsCache {
             }
             // Insert new node.
             let new_node = Box::into_raw(Node::new(key, value, ttl));
+            metrics::inc_dns_inserts();
             for i in 0..lvl {
                 let prev = update[i];
                 (*new_node).forwards[i].store((*prev).forwards[i].load(Ordering::Acquire), Ordering::Relaxed);
@@ -135,8 +141,14 @@ impl DnsCache {
         }
     }
 
-    /// Lookup without modifying state.
+    /// Lookup without modifying cache state; counted as a hit or miss.
     pub fn lookup(&self, key: &str) -> Option<IpAddr> {
+        let found = self.find(key);
+        metrics::observe_dns_lookup(found.is_some());
+        found
+    }
+
+    fn find(&self, key: &str) -> Option<IpAddr> {
         unsafe {
             let mut x = self.head;
             for i in (0..MAX_LEVEL).rev() {
@@ -171,6 +183,7 @@ impl DnsCache {
                     (*prev).forwards[0].store(curr.forwards[0].load(Ordering::Acquire), Ordering::Release);
                     // Drop node safely.
                     let _ = Box::from_raw(curr_ptr);
+                    metrics::inc_dns_evictions();
                     continue; // stay at same prev to check new curr
                 }
                 prev = curr_ptr;
@@ -193,4 +206,45 @@ fn random_level(key: &str) -> usize {
         hash >>= 1;
     }
     lvl
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Current value of an `sws_dns_*` sample in the Prometheus exposition.
+    fn sample(name: &str) -> u64 {
+        let text = metrics::render();
+        let line = text.lines().find(|l| l.split(' ').next() == Some(name)).unwrap();
+        line.rsplit(' ').next().unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn hits_and_misses_are_counted() {
+        let cache = DnsCache::new();
+        let (lookups, hits, misses, inserts) = (sample("sws_dns_cache_lookups_total"), sample("sws_dns_cache_hits_total"), sample("sws_dns_cache_misses_total"), sample("sws_dns_cache_inserts_total"));
+        let ip: IpAddr = "192.0.2.7".parse().unwrap();
+        cache.insert("a.test".into(), ip, TTL_DEFAULT);
+        cache.insert("a.test".into(), ip, TTL_DEFAULT);
+        assert_eq!(cache.lookup("a.test"), Some(ip));
+        assert_eq!(cache.resolve("a.test"), Some(ip));
+        assert_eq!(cache.lookup("b.test"), None);
+        // Counters are process-wide, so other caches may add to them meanwhile.
+        assert!(sample("sws_dns_cache_lookups_total") >= lookups + 3);
+        assert!(sample("sws_dns_cache_hits_total") >= hits + 2);
+        assert!(sample("sws_dns_cache_misses_total") > misses);
+        assert!(sample("sws_dns_cache_inserts_total") > inserts);
+    }
+
+    #[test]
+    fn queued_resolutions_drain_from_the_gauge() {
+        let cache = DnsCache::new();
+        assert_eq!(cache.resolve("sws-no-such-host.invalid"), None);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while sample("sws_dns_resolver_queue_depth") > 0 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(sample("sws_dns_resolver_queue_depth"), 0);
+        assert_eq!(cache.lookup("sws-no-such-host.invalid"), None);
+    }
+}
@@ -0,0 +1,135 @@
+//! Base64 (RFC 4648) encoder/decoder – standard (§4) and URL-safe (§5) alphabets.
+//!
+//! * `encode` emits `=` padding; `encode_url` omits it (JWT / ticket style).
+//! * `decode` / `decode_url` accept input with or without padding, but reject
+//!   characters outside the alphabet, misplaced `=` and impossible lengths.
+
+const STD: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+const INVALID: u8 = 0xFF;
+
+const fn build_lookup(alphabet: &[u8; 64]) -> [u8; 256] {
+    let mut t = [INVALID; 256];
+    let mut i = 0;
+    while i < 64 { t[alphabet[i] as usize] = i as u8; i += 1; }
+    t
+}
+
+static STD_LOOKUP: [u8; 256] = build_lookup(STD);
+static URL_LOOKUP: [u8; 256] = build_lookup(URL);
+
+/// Encode with the standard alphabet and `=` padding.
+pub fn encode(data: &[u8]) -> String { encode_with(data, STD, true) }
+
+/// Encode with the URL-safe alphabet, without padding.
+pub fn encode_url(data: &[u8]) -> String { encode_with(data, URL, false) }
+
+/// Decode standard-alphabet input. Returns `None` on malformed input.
+pub fn decode(s: &str) -> Option<Vec<u8>> { decode_with(s.as_bytes(), &STD_LOOKUP) }
+
+/// Decode URL-safe input. Returns `None` on malformed input.
+pub fn decode_url(s: &str) -> Option<Vec<u8>> { decode_with(s.as_bytes(), &URL_LOOKUP) }
+
+fn encode_with(data: &[u8], alphabet: &[u8; 64], pad: bool) -> String {
+    let mut out = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | b[2] as u32;
+        out.push(alphabet[(n >> 18) as usize & 63] as char);
+        out.push(alphabet[(n >> 12) as usize & 63] as char);
+        if chunk.len() > 1 { out.push(alphabet[(n >> 6) as usize & 63] as char); } else if pad { out.push('='); }
+        if chunk.len() > 2 { out.push(alphabet[n as usize & 63] as char); } else if pad { out.push('='); }
+    }
+    out
+}
+
+fn decode_with(inp: &[u8], lookup: &[u8; 256]) -> Option<Vec<u8>> {
+    // Strip padding; when present the padded length must be a multiple of 4.
+    let body_len = inp.iter().rposition(|&b| b != b'=').map_or(0, |p| p + 1);
+    let pad = inp.len() - body_len;
+    if pad > 2 || (pad > 0 && inp.len() % 4 != 0) { return None; }
+    let body = &inp[..body_len];
+    if body.len() % 4 == 1 { return None; }
+
+    let mut out = Vec::with_capacity(body.len() * 3 / 4);
+    let mut chunk = [0u8; 4];
+    let mut idx = 0;
+    for &b in body {
+        let val = lookup[b as usize];
+        if val == INVALID { return None; }
+        chunk[idx] = val; idx += 1;
+        if idx == 4 {
+            out.push((chunk[0] << 2) | (chunk[1] >> 4));
+            out.push((chunk[1] << 4) | (chunk[2] >> 2));
+            out.push((chunk[2] << 6) | chunk[3]);
+            idx = 0;
+        }
+    }
+    if idx == 3 {
+        out.push((chunk[0] << 2) | (chunk[1] >> 4));
+        out.push((chunk[1] << 4) | (chunk[2] >> 2));
+    } else if idx == 2 {
+        out.push((chunk[0] << 2) | (chunk[1] >> 4));
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 4648 §10.
+    const VECTORS: [(&str, &str); 7] = [
+        ("", ""),
+        ("f", "Zg=="),
+        ("fo", "Zm8="),
+        ("foo", "Zm9v"),
+        ("foob", "Zm9vYg=="),
+        ("fooba", "Zm9vYmE="),
+        ("foobar", "Zm9vYmFy"),
+    ];
+
+    #[test]
+    fn rfc4648_vectors() {
+        for (plain, encoded) in VECTORS {
+            assert_eq!(encode(plain.as_bytes()), encoded);
+            assert_eq!(decode(encoded).unwrap(), plain.as_bytes());
+            // URL-safe output drops the padding; decoding accepts both forms.
+            assert_eq!(encode_url(plain.as_bytes()), encoded.trim_end_matches('='));
+            assert_eq!(decode_url(encoded).unwrap(), plain.as_bytes());
+            assert_eq!(decode(encoded.trim_end_matches('=')).unwrap(), plain.as_bytes());
+        }
+    }
+
+    #[test]
+    fn alphabets_differ_only_in_the_last_two_symbols() {
+        let data = [0xfb, 0xff, 0xbf];
+        assert_eq!(encode(&data), "+/+/");
+        assert_eq!(encode_url(&data), "-_-_");
+        assert_eq!(decode_url("-_-_").unwrap(), data);
+        assert!(decode("-_-_").is_none());
+        assert!(decode_url("+/+/").is_none());
+    }
+
+    #[test]
+    fn tails_round_trip() {
+        let data: Vec<u8> = (0..=255).collect();
+        for len in 0..=9 {
+            let part = &data[250 - len..250];
+            assert_eq!(decode(&encode(part)).unwrap(), part);
+            assert_eq!(decode_url(&encode_url(part)).unwrap(), part);
+        }
+        assert_eq!(decode(&encode(&data)).unwrap(), data);
+    }
+
+    #[test]
+    fn malformed_input_is_rejected() {
+        assert!(decode("Zg=").is_none()); // padded but not a multiple of 4
+        assert!(decode("Zg===").is_none()); // too much padding
+        assert!(decode("Z").is_none()); // a single leftover symbol encodes nothing
+        assert!(decode("Zm=v").is_none()); // '=' inside the data
+        assert!(decode("Zm9v\n").is_none());
+        assert!(decode("Zm9v YmFy").is_none());
+    }
+}
@@ -0,0 +1,3 @@
+//! Text encodings shared across crates (no external crates).
+
+pub mod base64;
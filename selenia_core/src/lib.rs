@@ -1,7 +1,9 @@
 pub mod config;
+pub mod cidr;
 pub mod locale;
 pub mod os;
 pub mod crypto;
+pub mod encoding;
 pub mod logger;
 pub mod metrics;
 pub mod signals;
@@ -14,4 +16,7 @@ pub mod ebpf;
 pub mod ratelimit; 
 pub mod otel; 
 pub mod capability; 
-pub mod traceparent; 
\ No newline at end of file
+pub mod privdrop;
+pub mod traceparent; 
+pub mod request_id;
+pub mod sync;
\ No newline at end of file
@@ -1,4 +1,5 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
 use std::sync::{Once, RwLock};
 
 // Manual once-init static to avoid external crates.
@@ -31,4 +32,178 @@ pub fn translate(locale: &str, key: &str) -> String {
         .and_then(|map| map.get(key))
         .cloned()
         .unwrap_or_else(|| key.to_string())
-} 
\ No newline at end of file
+} 
+/// Compiled string tables by language, as produced by `compile_dir` / `decode`.
+pub type Catalog = BTreeMap<String, HashMap<String, String>>;
+
+const MAGIC: &[u8; 4] = b"SWSL";
+const VERSION: u8 = 1;
+
+/// Parse one locale source: `key = value` per line; blank lines and `#` comments
+/// are skipped. Keys may not repeat; `\n` and `\\` in values are unescaped.
+pub fn parse_source(src: &str) -> Result<HashMap<String, String>, String> {
+    let mut strings = HashMap::new();
+    for (no, line) in src.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') { continue; }
+        let (key, value) = line.split_once('=').ok_or_else(|| format!("line {}: expected key = value", no + 1))?;
+        let key = key.trim();
+        if key.is_empty() { return Err(format!("line {}: empty key", no + 1)); }
+        if strings.insert(key.to_string(), unescape(value.trim())).is_some() {
+            return Err(format!("line {}: duplicate key {}", no + 1, key));
+        }
+    }
+    Ok(strings)
+}
+
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => { out.push('\n'); chars.next(); }
+            ('\\', Some('\\')) => { out.push('\\'); chars.next(); }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Compile every `<lang>.locale` file in `dir` into a catalog keyed by `<lang>`.
+pub fn compile_dir<P: AsRef<Path>>(dir: P) -> Result<Catalog, String> {
+    let dir = dir.as_ref();
+    let entries = std::fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let mut catalog = Catalog::new();
+    for entry in entries {
+        let path = entry.map_err(|e| format!("{}: {}", dir.display(), e))?.path();
+        if path.extension().map_or(true, |e| e != "locale") { continue; }
+        let Some(lang) = path.file_stem().and_then(|s| s.to_str()) else { continue };
+        let src = std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let strings = parse_source(&src).map_err(|e| format!("{}: {}", path.display(), e))?;
+        catalog.insert(lang.to_string(), strings);
+    }
+    if catalog.is_empty() { return Err(format!("{}: no .locale files", dir.display())); }
+    Ok(catalog)
+}
+
+/// Keys each language lacks or has beyond the others, one message per language
+/// and direction. Empty when every language defines the same key set.
+pub fn key_mismatches(catalog: &Catalog) -> Vec<String> {
+    let all: BTreeSet<&String> = catalog.values().flat_map(|m| m.keys()).collect();
+    let mut problems = Vec::new();
+    for (lang, strings) in catalog {
+        let missing: Vec<&str> = all.iter().filter(|k| !strings.contains_key(**k)).map(|k| k.as_str()).collect();
+        if !missing.is_empty() { problems.push(format!("{}: missing {}", lang, missing.join(", "))); }
+        // Keys only this language has are extra from everyone else's point of view.
+        let mut extra: Vec<&str> = strings.keys()
+            .filter(|k| catalog.iter().all(|(other, m)| other == lang || !m.contains_key(*k)))
+            .map(String::as_str).collect();
+        extra.sort_unstable();
+        if catalog.len() > 1 && !extra.is_empty() { problems.push(format!("{}: extra {}", lang, extra.join(", "))); }
+    }
+    problems
+}
+
+/// Register every language of `catalog`, replacing tables of the same name.
+pub fn register_catalog(catalog: Catalog) {
+    for (lang, strings) in catalog { register_locale(lang, strings); }
+}
+
+/// Binary form loaded at startup (`locale_catalog`): magic, version, then
+/// length-prefixed (u32 LE) languages, each followed by its key/value pairs.
+pub fn encode(catalog: &Catalog) -> Vec<u8> {
+    fn put(out: &mut Vec<u8>, s: &str) {
+        out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+        out.extend_from_slice(s.as_bytes());
+    }
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    out.extend_from_slice(&(catalog.len() as u32).to_le_bytes());
+    for (lang, strings) in catalog {
+        put(&mut out, lang);
+        out.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+        let mut keys: Vec<&String> = strings.keys().collect();
+        keys.sort_unstable();
+        for key in keys {
+            put(&mut out, key);
+            put(&mut out, &strings[key]);
+        }
+    }
+    out
+}
+
+/// Inverse of `encode`; `None` for a truncated or foreign blob.
+pub fn decode(mut blob: &[u8]) -> Option<Catalog> {
+    fn u32_le(buf: &mut &[u8]) -> Option<usize> {
+        let (n, rest) = buf.split_first_chunk::<4>()?;
+        *buf = rest;
+        Some(u32::from_le_bytes(*n) as usize)
+    }
+    fn string(buf: &mut &[u8]) -> Option<String> {
+        let len = u32_le(buf)?;
+        if buf.len() < len { return None; }
+        let (s, rest) = buf.split_at(len);
+        *buf = rest;
+        String::from_utf8(s.to_vec()).ok()
+    }
+    blob = blob.strip_prefix(MAGIC.as_slice())?.strip_prefix(&[VERSION])?;
+    let mut catalog = Catalog::new();
+    for _ in 0..u32_le(&mut blob)? {
+        let lang = string(&mut blob)?;
+        let mut strings = HashMap::new();
+        for _ in 0..u32_le(&mut blob)? {
+            let key = string(&mut blob)?;
+            strings.insert(key, string(&mut blob)?);
+        }
+        catalog.insert(lang, strings);
+    }
+    blob.is_empty().then_some(catalog)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `files` (name, contents) into a fresh directory and compile it.
+    fn compile(tag: &str, files: &[(&str, &str)]) -> Result<Catalog, String> {
+        let dir = std::env::temp_dir().join(format!("sws-locale-{}-{}", std::process::id(), tag));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, src) in files { std::fs::write(dir.join(name), src).unwrap(); }
+        let catalog = compile_dir(&dir);
+        let _ = std::fs::remove_dir_all(&dir);
+        catalog
+    }
+
+    #[test]
+    fn small_set_compiles_and_registers() {
+        let catalog = compile("set", &[
+            ("en.locale", "# greetings\nhello = Hello\nbye = Good\\nbye\n"),
+            ("ja.locale", "hello = こんにちは\n\nbye = さようなら\n"),
+            ("README", "not a locale"),
+        ]).unwrap();
+        assert_eq!(catalog.keys().collect::<Vec<_>>(), ["en", "ja"]);
+        assert_eq!(catalog["en"]["bye"], "Good\nbye");
+        assert!(key_mismatches(&catalog).is_empty());
+        register_catalog(catalog);
+        assert_eq!(translate("ja", "hello"), "こんにちは");
+        assert_eq!(translate("ja", "absent"), "absent");
+    }
+
+    #[test]
+    fn missing_and_extra_keys_are_reported() {
+        let catalog = compile("mismatch", &[("en.locale", "a = 1\nb = 2\n"), ("ja.locale", "a = 1\nc = 3\n")]).unwrap();
+        assert_eq!(key_mismatches(&catalog), ["en: missing c", "en: extra b", "ja: missing b", "ja: extra c"]);
+        assert!(parse_source("a = 1\na = 2\n").unwrap_err().contains("duplicate key a"));
+        assert!(parse_source("no separator\n").is_err());
+        assert!(compile("empty", &[]).is_err());
+    }
+
+    #[test]
+    fn unicode_survives_the_binary_catalog() {
+        let catalog = compile("blob", &[("ja.locale", "http.not_found = 404 見つかりません 🚫\n")]).unwrap();
+        let blob = encode(&catalog);
+        assert_eq!(decode(&blob), Some(catalog));
+        assert_eq!(decode(&blob[..blob.len() - 1]), None);
+        assert_eq!(decode(b"XXXX\x01\0\0\0\0"), None);
+    }
+}
@@ -1,4 +1,5 @@
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
 use std::time::Duration;
 
 /// Global counters for Prometheus metrics exposition.
@@ -37,6 +38,200 @@ static RELOAD_STATE: AtomicU64 = AtomicU64::new(0);
 
 pub fn set_reload_state(v: u64) { RELOAD_STATE.store(v, Ordering::Relaxed); }
 
+// Open file descriptors gauge (estimate maintained by the event loop)
+static OPEN_FDS: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_open_fds(v: u64) { OPEN_FDS.store(v, Ordering::Relaxed); }
+
+// Connections closed because the TLS handshake did not finish in time
+static TLS_HANDSHAKE_TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+
+pub fn inc_tls_handshake_timeouts() { TLS_HANDSHAKE_TIMEOUTS.fetch_add(1, Ordering::Relaxed); }
+
+// TLS handshakes in progress, and those refused at `tls.max_handshakes`
+static TLS_HANDSHAKES_ACTIVE: AtomicU64 = AtomicU64::new(0);
+static TLS_HANDSHAKES_REJECTED: AtomicU64 = AtomicU64::new(0);
+
+pub fn inc_tls_handshakes_active() { TLS_HANDSHAKES_ACTIVE.fetch_add(1, Ordering::Relaxed); }
+pub fn dec_tls_handshakes_active() { TLS_HANDSHAKES_ACTIVE.fetch_sub(1, Ordering::Relaxed); }
+pub fn inc_tls_handshakes_rejected() { TLS_HANDSHAKES_REJECTED.fetch_add(1, Ordering::Relaxed); }
+
+// Requests answered 504 because `request_timeout_ms` ran out
+static REQUEST_TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+
+pub fn inc_request_timeouts() { REQUEST_TIMEOUTS.fetch_add(1, Ordering::Relaxed); }
+
+// Connections a worker holds, its connection cap (`max_connections` share, bounded by the
+// fd budget), and connections refused with 503 at that cap
+static CONN_ACTIVE: AtomicU64 = AtomicU64::new(0);
+static CONN_MAX: AtomicU64 = AtomicU64::new(0);
+static CONN_REJECTED: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_connections(active: u64, max: u64) {
+    CONN_ACTIVE.store(active, Ordering::Relaxed);
+    CONN_MAX.store(max, Ordering::Relaxed);
+}
+pub fn inc_connections_rejected() { CONN_REJECTED.fetch_add(1, Ordering::Relaxed); }
+
+// Static responses served from the last good copy because reading the file failed
+static STALE_SERVED: AtomicU64 = AtomicU64::new(0);
+
+pub fn inc_stale_served() { STALE_SERVED.fetch_add(1, Ordering::Relaxed); }
+
+// HTTP/2 PING round-trip: last sample (µs) plus running sum/count for the mean.
+static H2_PING_RTT_LAST_US: AtomicU64 = AtomicU64::new(0);
+static H2_PING_RTT_SUM_US: AtomicU64 = AtomicU64::new(0);
+static H2_PING_RTT_COUNT: AtomicU64 = AtomicU64::new(0);
+
+// HTTP/2 connections closed with ENHANCE_YOUR_CALM for a reset or control-frame flood
+static H2_FLOOD_CLOSES: AtomicU64 = AtomicU64::new(0);
+
+pub fn inc_h2_flood_closes() { H2_FLOOD_CLOSES.fetch_add(1, Ordering::Relaxed); }
+
+// HTTP/3 0-RTT packets held until their handshake completes (all connections), and
+// those dropped over the per-connection `http3.zero_rtt_max_*` caps
+static H3_ZERO_RTT_BUFFERED: AtomicU64 = AtomicU64::new(0);
+static H3_ZERO_RTT_DROPPED: AtomicU64 = AtomicU64::new(0);
+
+pub fn inc_h3_zero_rtt_buffered() { H3_ZERO_RTT_BUFFERED.fetch_add(1, Ordering::Relaxed); }
+/// `n` buffered packets were flushed or discarded.
+pub fn sub_h3_zero_rtt_buffered(n: u64) {
+    let _ = H3_ZERO_RTT_BUFFERED.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| Some(v.saturating_sub(n)));
+}
+pub fn inc_h3_zero_rtt_dropped() { H3_ZERO_RTT_DROPPED.fetch_add(1, Ordering::Relaxed); }
+
+// Spans the OTLP exporter dropped: queue full, collector unreachable or too slow
+static OTEL_SPANS_DROPPED: AtomicU64 = AtomicU64::new(0);
+
+pub fn inc_otel_spans_dropped() { OTEL_SPANS_DROPPED.fetch_add(1, Ordering::Relaxed); }
+
+// WASM edge functions: executions started, and those ended by fuel, by the wall-clock
+// timeout, or shed for want of an execution slot
+static WASM_EXECUTIONS: AtomicU64 = AtomicU64::new(0);
+static WASM_FUEL_EXHAUSTED: AtomicU64 = AtomicU64::new(0);
+static WASM_TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+static WASM_REJECTED: AtomicU64 = AtomicU64::new(0);
+
+pub fn inc_wasm_executions() { WASM_EXECUTIONS.fetch_add(1, Ordering::Relaxed); }
+pub fn inc_wasm_fuel_exhausted() { WASM_FUEL_EXHAUSTED.fetch_add(1, Ordering::Relaxed); }
+pub fn inc_wasm_timeouts() { WASM_TIMEOUTS.fetch_add(1, Ordering::Relaxed); }
+pub fn inc_wasm_rejected() { WASM_REJECTED.fetch_add(1, Ordering::Relaxed); }
+
+// DNS cache: lookups split into hits/misses, new entries, TTL evictions, and names
+// waiting for the background resolver.
+static DNS_LOOKUPS: AtomicU64 = AtomicU64::new(0);
+static DNS_HITS: AtomicU64 = AtomicU64::new(0);
+static DNS_MISSES: AtomicU64 = AtomicU64::new(0);
+static DNS_INSERTS: AtomicU64 = AtomicU64::new(0);
+static DNS_EVICTIONS: AtomicU64 = AtomicU64::new(0);
+static DNS_QUEUE_DEPTH: AtomicU64 = AtomicU64::new(0);
+
+/// Record one DNS cache lookup and whether it found a fresh entry.
+pub fn observe_dns_lookup(hit: bool) {
+    DNS_LOOKUPS.fetch_add(1, Ordering::Relaxed);
+    if hit { DNS_HITS.fetch_add(1, Ordering::Relaxed); } else { DNS_MISSES.fetch_add(1, Ordering::Relaxed); }
+}
+pub fn inc_dns_inserts() { DNS_INSERTS.fetch_add(1, Ordering::Relaxed); }
+pub fn inc_dns_evictions() { DNS_EVICTIONS.fetch_add(1, Ordering::Relaxed); }
+/// A name was queued for background resolution.
+pub fn inc_dns_queue_depth() { DNS_QUEUE_DEPTH.fetch_add(1, Ordering::Relaxed); }
+/// A queued name was resolved (or failed to resolve).
+pub fn dec_dns_queue_depth() {
+    let _ = DNS_QUEUE_DEPTH.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| v.checked_sub(1));
+}
+
+// Reverse-proxy upstream connections: idle in the pool, newly opened, reused from the pool.
+static PROXY_POOL_IDLE: AtomicU64 = AtomicU64::new(0);
+static PROXY_CONNECTS: AtomicU64 = AtomicU64::new(0);
+static PROXY_REUSES: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_proxy_pool_idle(v: u64) { PROXY_POOL_IDLE.store(v, Ordering::Relaxed); }
+/// Count one upstream request, on a pooled (`reused`) or a fresh connection.
+pub fn observe_proxy_connection(reused: bool) {
+    if reused { PROXY_REUSES.fetch_add(1, Ordering::Relaxed); } else { PROXY_CONNECTS.fetch_add(1, Ordering::Relaxed); }
+}
+
+// Reverse-proxy upstreams by address: in rotation (1) or ejected (0), and failures seen.
+static UPSTREAMS: RwLock<Vec<(String, AtomicU64, AtomicU64)>> = RwLock::new(Vec::new());
+
+/// Handle for per-upstream series labelled `upstream="<addr>"`; starts healthy.
+/// Registering the same address again returns the same handle.
+pub fn register_upstream(addr: &str) -> usize {
+    let mut ups = UPSTREAMS.write().unwrap();
+    if let Some(i) = ups.iter().position(|(a, _, _)| a == addr) { return i; }
+    ups.push((addr.to_string(), AtomicU64::new(1), AtomicU64::new(0)));
+    ups.len() - 1
+}
+
+pub fn set_upstream_healthy(handle: usize, healthy: bool) {
+    if let Some((_, h, _)) = UPSTREAMS.read().unwrap().get(handle) { h.store(healthy as u64, Ordering::Relaxed); }
+}
+
+pub fn inc_upstream_failures(handle: usize) {
+    if let Some((_, _, f)) = UPSTREAMS.read().unwrap().get(handle) { f.fetch_add(1, Ordering::Relaxed); }
+}
+
+/// (address, healthy, failures) per registered upstream.
+fn upstream_series() -> Vec<(String, u64, u64)> {
+    UPSTREAMS.read().unwrap().iter().map(|(a, h, f)| (a.clone(), h.load(Ordering::Relaxed), f.load(Ordering::Relaxed))).collect()
+}
+
+// Requests in flight per listener address (`max_concurrent_requests` accounting).
+static LISTENERS: RwLock<Vec<(String, AtomicU64)>> = RwLock::new(Vec::new());
+
+/// Handle for the `sws_listener_active_requests{listener="<addr>"}` gauge.
+/// Registering the same address again returns the same handle.
+pub fn register_listener(addr: &str) -> usize {
+    let mut ls = LISTENERS.write().unwrap();
+    if let Some(i) = ls.iter().position(|(a, _)| a == addr) { return i; }
+    ls.push((addr.to_string(), AtomicU64::new(0)));
+    ls.len() - 1
+}
+
+pub fn set_listener_active(handle: usize, v: u64) {
+    if let Some((_, a)) = LISTENERS.read().unwrap().get(handle) { a.store(v, Ordering::Relaxed); }
+}
+
+fn listener_series() -> Vec<(String, u64)> {
+    LISTENERS.read().unwrap().iter().map(|(a, v)| (a.clone(), v.load(Ordering::Relaxed))).collect()
+}
+
+// Counters registered at runtime (plugins); the index is the handle.
+static CUSTOM: RwLock<Vec<(String, AtomicU64)>> = RwLock::new(Vec::new());
+
+/// Register a counter named `name` (`[a-zA-Z_:][a-zA-Z0-9_:]*`) and return its
+/// handle. Registering an existing custom name returns the same handle; names of
+/// built-in series are refused.
+pub fn register_counter(name: &str) -> Option<usize> {
+    let valid = name.bytes().enumerate().all(|(i, b)| b.is_ascii_alphabetic() || b == b'_' || b == b':' || (i > 0 && b.is_ascii_digit()));
+    if name.is_empty() || !valid || scalars().iter().any(|(n, _, _)| *n == name) || name.starts_with("sws_http_request_duration_seconds") {
+        return None;
+    }
+    let mut custom = CUSTOM.write().unwrap();
+    if let Some(i) = custom.iter().position(|(n, _)| n == name) { return Some(i); }
+    custom.push((name.to_string(), AtomicU64::new(0)));
+    Some(custom.len() - 1)
+}
+
+/// Add `n` to a counter returned by [`register_counter`]; unknown handles are ignored.
+pub fn add_counter(handle: usize, n: u64) {
+    if let Some((_, c)) = CUSTOM.read().unwrap().get(handle) { c.fetch_add(n, Ordering::Relaxed); }
+}
+
+/// Current value of an integer counter or gauge, built-in or registered, by series name.
+pub fn counter_value(name: &str) -> Option<u64> {
+    if let Some((_, _, Value::Int(v))) = scalars().iter().find(|(n, _, _)| *n == name) { return Some(*v); }
+    CUSTOM.read().unwrap().iter().find(|(n, _)| n == name).map(|(_, c)| c.load(Ordering::Relaxed))
+}
+
+/// Record one HTTP/2 PING round-trip time.
+pub fn observe_h2_ping_rtt(d: Duration) {
+    let us = d.as_micros() as u64;
+    H2_PING_RTT_LAST_US.store(us, Ordering::Relaxed);
+    H2_PING_RTT_SUM_US.fetch_add(us, Ordering::Relaxed);
+    H2_PING_RTT_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
 /// Observe request latency in `Duration`.
 pub fn observe_latency(d: Duration) {
     let us = d.as_micros() as u64;
@@ -51,6 +246,20 @@ pub fn observe_latency(d: Duration) {
     LAT_TOTAL.fetch_add(1, Ordering::Relaxed);
 }
 
+/// Upper bound of the latency bucket holding quantile `q` (0..=1) of the observed
+/// requests; zero before any observation or when it lies past the last (5 s) bucket.
+pub fn latency_quantile(q: f64) -> Duration {
+    let total = LAT_TOTAL.load(Ordering::Relaxed);
+    if total == 0 { return Duration::ZERO; }
+    let target = (total as f64 * q).round() as u64;
+    let mut acc = 0u64;
+    for (i, &thr) in LAT_BUCKETS.iter().enumerate() {
+        acc += LAT_COUNTS[i].load(Ordering::Relaxed);
+        if acc >= target { return Duration::from_micros(thr); }
+    }
+    Duration::ZERO
+}
+
 /// Increase total HTTP requests.
 pub fn inc_requests() { REQUESTS_TOTAL.fetch_add(1, Ordering::Relaxed); }
 /// Add to total bytes served.
@@ -83,23 +292,318 @@ pub fn render() -> String {
     out.push_str("# TYPE sws_http_request_duration_seconds summary\n");
     let quantiles = [(0.5f64, "0.5"), (0.9, "0.9"), (0.99, "0.99")];
     for &(q, label) in &quantiles {
-        let target = (total as f64 * q).round() as u64;
-        let mut acc = 0u64;
-        let mut val_sec = 0f64;
-        for (i, &thr) in LAT_BUCKETS.iter().enumerate() {
-            acc += LAT_COUNTS[i].load(Ordering::Relaxed);
-            if acc >= target {
-                val_sec = (thr as f64)/1_000_000f64;
-                break;
-            }
-        }
-        if total == 0 { val_sec = 0.0; }
-        out.push_str(&format!("sws_http_request_duration_seconds{{quantile=\"{}\"}} {:.6}\n", label, val_sec));
+        out.push_str(&format!("sws_http_request_duration_seconds{{quantile=\"{}\"}} {:.6}\n", label, latency_quantile(q).as_secs_f64()));
     }
     out.push_str(&format!("sws_http_request_duration_seconds_sum {}\n", sum_sec));
     out.push_str(&format!("sws_http_request_duration_seconds_count {}\n", total));
 
     out.push_str(&format!("# TYPE sws_reload_state gauge\nsws_reload_state {}\n", RELOAD_STATE.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_open_fds gauge\nsws_open_fds {}\n", OPEN_FDS.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_conn_active gauge\nsws_conn_active {}\n", CONN_ACTIVE.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_conn_max gauge\nsws_conn_max {}\n", CONN_MAX.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_conn_rejected_total counter\nsws_conn_rejected_total {}\n", CONN_REJECTED.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_tls_handshake_timeouts_total counter\nsws_tls_handshake_timeouts_total {}\n", TLS_HANDSHAKE_TIMEOUTS.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_tls_handshakes_active gauge\nsws_tls_handshakes_active {}\n", TLS_HANDSHAKES_ACTIVE.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_tls_handshakes_rejected_total counter\nsws_tls_handshakes_rejected_total {}\n", TLS_HANDSHAKES_REJECTED.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_request_timeouts_total counter\nsws_request_timeouts_total {}\n", REQUEST_TIMEOUTS.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_stale_served_total counter\nsws_stale_served_total {}\n", STALE_SERVED.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_h2_ping_rtt_seconds gauge\nsws_h2_ping_rtt_seconds {:.6}\n", H2_PING_RTT_LAST_US.load(Ordering::Relaxed) as f64 / 1_000_000f64));
+    out.push_str(&format!("# TYPE sws_h2_ping_rtt_seconds_sum counter\nsws_h2_ping_rtt_seconds_sum {:.6}\n", H2_PING_RTT_SUM_US.load(Ordering::Relaxed) as f64 / 1_000_000f64));
+    out.push_str(&format!("# TYPE sws_h2_ping_rtt_seconds_count counter\nsws_h2_ping_rtt_seconds_count {}\n", H2_PING_RTT_COUNT.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_h2_flood_closes_total counter\nsws_h2_flood_closes_total {}\n", H2_FLOOD_CLOSES.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_h3_zero_rtt_buffered_packets gauge\nsws_h3_zero_rtt_buffered_packets {}\n", H3_ZERO_RTT_BUFFERED.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_h3_zero_rtt_dropped_total counter\nsws_h3_zero_rtt_dropped_total {}\n", H3_ZERO_RTT_DROPPED.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_otel_spans_dropped_total counter\nsws_otel_spans_dropped_total {}\n", OTEL_SPANS_DROPPED.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_wasm_executions_total counter\nsws_wasm_executions_total {}\n", WASM_EXECUTIONS.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_wasm_fuel_exhausted_total counter\nsws_wasm_fuel_exhausted_total {}\n", WASM_FUEL_EXHAUSTED.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_wasm_timeouts_total counter\nsws_wasm_timeouts_total {}\n", WASM_TIMEOUTS.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_wasm_rejected_total counter\nsws_wasm_rejected_total {}\n", WASM_REJECTED.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_dns_cache_lookups_total counter\nsws_dns_cache_lookups_total {}\n", DNS_LOOKUPS.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_dns_cache_hits_total counter\nsws_dns_cache_hits_total {}\n", DNS_HITS.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_dns_cache_misses_total counter\nsws_dns_cache_misses_total {}\n", DNS_MISSES.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_dns_cache_inserts_total counter\nsws_dns_cache_inserts_total {}\n", DNS_INSERTS.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_dns_cache_evictions_total counter\nsws_dns_cache_evictions_total {}\n", DNS_EVICTIONS.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_dns_resolver_queue_depth gauge\nsws_dns_resolver_queue_depth {}\n", DNS_QUEUE_DEPTH.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_proxy_pool_idle gauge\nsws_proxy_pool_idle {}\n", PROXY_POOL_IDLE.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_proxy_upstream_connects_total counter\nsws_proxy_upstream_connects_total {}\n", PROXY_CONNECTS.load(Ordering::Relaxed)));
+    out.push_str(&format!("# TYPE sws_proxy_upstream_reuses_total counter\nsws_proxy_upstream_reuses_total {}\n", PROXY_REUSES.load(Ordering::Relaxed)));
+    let ups = upstream_series();
+    if !ups.is_empty() {
+        out.push_str("# TYPE sws_upstream_healthy gauge\n");
+        for (a, h, _) in &ups { out.push_str(&format!("sws_upstream_healthy{{upstream=\"{}\"}} {}\n", a, h)); }
+        out.push_str("# TYPE sws_upstream_failures_total counter\n");
+        for (a, _, f) in &ups { out.push_str(&format!("sws_upstream_failures_total{{upstream=\"{}\"}} {}\n", a, f)); }
+    }
+    let ls = listener_series();
+    if !ls.is_empty() {
+        out.push_str("# TYPE sws_listener_active_requests gauge\n");
+        for (a, v) in &ls { out.push_str(&format!("sws_listener_active_requests{{listener=\"{}\"}} {}\n", a, v)); }
+    }
+    for (name, c) in CUSTOM.read().unwrap().iter() {
+        out.push_str(&format!("# TYPE {0} counter\n{0} {1}\n", name, c.load(Ordering::Relaxed)));
+    }
 
     out
-} 
\ No newline at end of file
+} 
+
+/// Exposition format for `/metrics`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Prometheus text format 0.0.4 (the default).
+    Prometheus,
+    /// OpenMetrics 1.0 text format.
+    OpenMetrics,
+    /// Flat JSON object for ad-hoc tooling.
+    Json,
+}
+
+impl Format {
+    /// Format named by a `?format=` query value.
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name.to_ascii_lowercase().as_str() {
+            "prometheus" | "text" => Some(Format::Prometheus),
+            "openmetrics" => Some(Format::OpenMetrics),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    /// Format requested by an `Accept` header; anything unrecognised is Prometheus.
+    pub fn from_accept(accept: &str) -> Format {
+        let wants = |mime: &str| accept.split(',').any(|m| m.split(';').next().unwrap_or("").trim().eq_ignore_ascii_case(mime));
+        if wants("application/openmetrics-text") { Format::OpenMetrics }
+        else if wants("application/json") { Format::Json }
+        else { Format::Prometheus }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Prometheus => "text/plain; version=0",
+            Format::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            Format::Json => "application/json",
+        }
+    }
+}
+
+enum Value { Int(u64), Secs(u64) }
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Int(v) => write!(f, "{}", v),
+            Value::Secs(us) => write!(f, "{:.6}", *us as f64 / 1_000_000f64),
+        }
+    }
+}
+
+/// Built-in counters and gauges other than the latency histogram, in exposition order.
+fn scalars() -> [(&'static str, bool, Value); 33] {
+    let ld = |a: &AtomicU64| a.load(Ordering::Relaxed);
+    [
+        ("sws_requests_total", true, Value::Int(ld(&REQUESTS_TOTAL))),
+        ("sws_bytes_total", true, Value::Int(ld(&BYTES_TOTAL))),
+        ("sws_errors_total", true, Value::Int(ld(&ERRORS_TOTAL))),
+        ("sws_reload_state", false, Value::Int(ld(&RELOAD_STATE))),
+        ("sws_open_fds", false, Value::Int(ld(&OPEN_FDS))),
+        ("sws_conn_active", false, Value::Int(ld(&CONN_ACTIVE))),
+        ("sws_conn_max", false, Value::Int(ld(&CONN_MAX))),
+        ("sws_conn_rejected_total", true, Value::Int(ld(&CONN_REJECTED))),
+        ("sws_tls_handshake_timeouts_total", true, Value::Int(ld(&TLS_HANDSHAKE_TIMEOUTS))),
+        ("sws_tls_handshakes_active", false, Value::Int(ld(&TLS_HANDSHAKES_ACTIVE))),
+        ("sws_tls_handshakes_rejected_total", true, Value::Int(ld(&TLS_HANDSHAKES_REJECTED))),
+        ("sws_request_timeouts_total", true, Value::Int(ld(&REQUEST_TIMEOUTS))),
+        ("sws_stale_served_total", true, Value::Int(ld(&STALE_SERVED))),
+        ("sws_h2_ping_rtt_seconds", false, Value::Secs(ld(&H2_PING_RTT_LAST_US))),
+        ("sws_h2_ping_rtt_seconds_sum", true, Value::Secs(ld(&H2_PING_RTT_SUM_US))),
+        ("sws_h2_ping_rtt_seconds_count", true, Value::Int(ld(&H2_PING_RTT_COUNT))),
+        ("sws_h2_flood_closes_total", true, Value::Int(ld(&H2_FLOOD_CLOSES))),
+        ("sws_h3_zero_rtt_buffered_packets", false, Value::Int(ld(&H3_ZERO_RTT_BUFFERED))),
+        ("sws_h3_zero_rtt_dropped_total", true, Value::Int(ld(&H3_ZERO_RTT_DROPPED))),
+        ("sws_otel_spans_dropped_total", true, Value::Int(ld(&OTEL_SPANS_DROPPED))),
+        ("sws_wasm_executions_total", true, Value::Int(ld(&WASM_EXECUTIONS))),
+        ("sws_wasm_fuel_exhausted_total", true, Value::Int(ld(&WASM_FUEL_EXHAUSTED))),
+        ("sws_wasm_timeouts_total", true, Value::Int(ld(&WASM_TIMEOUTS))),
+        ("sws_wasm_rejected_total", true, Value::Int(ld(&WASM_REJECTED))),
+        ("sws_dns_cache_lookups_total", true, Value::Int(ld(&DNS_LOOKUPS))),
+        ("sws_dns_cache_hits_total", true, Value::Int(ld(&DNS_HITS))),
+        ("sws_dns_cache_misses_total", true, Value::Int(ld(&DNS_MISSES))),
+        ("sws_dns_cache_inserts_total", true, Value::Int(ld(&DNS_INSERTS))),
+        ("sws_dns_cache_evictions_total", true, Value::Int(ld(&DNS_EVICTIONS))),
+        ("sws_dns_resolver_queue_depth", false, Value::Int(ld(&DNS_QUEUE_DEPTH))),
+        ("sws_proxy_pool_idle", false, Value::Int(ld(&PROXY_POOL_IDLE))),
+        ("sws_proxy_upstream_connects_total", true, Value::Int(ld(&PROXY_CONNECTS))),
+        ("sws_proxy_upstream_reuses_total", true, Value::Int(ld(&PROXY_REUSES))),
+    ]
+}
+
+/// Cumulative latency bucket counts as (`le` label, count), ending with `+Inf`.
+fn latency_buckets() -> Vec<(String, u64)> {
+    let mut cumulative = 0u64;
+    let mut out: Vec<(String, u64)> = LAT_BUCKETS.iter().zip(LAT_COUNTS.iter()).map(|(&thr, cnt)| {
+        cumulative += cnt.load(Ordering::Relaxed);
+        (format!("{:.3}", thr as f64 / 1_000_000f64), cumulative)
+    }).collect();
+    out.push(("+Inf".into(), LAT_TOTAL.load(Ordering::Relaxed)));
+    out
+}
+
+/// Render metrics in the requested exposition format.
+pub fn render_format(fmt: Format) -> String {
+    match fmt {
+        Format::Prometheus => render(),
+        Format::OpenMetrics => render_openmetrics(),
+        Format::Json => render_json(),
+    }
+}
+
+/// OpenMetrics: counter families drop the `_total` suffix that their sample
+/// carries, and the output ends with `# EOF`. The quantile summary is left out
+/// because it would reuse the histogram's family name, which OpenMetrics forbids.
+fn render_openmetrics() -> String {
+    let mut out = String::new();
+    let sum = Value::Secs(LAT_SUM_US.load(Ordering::Relaxed));
+    let h = "sws_http_request_duration_seconds";
+    let custom: Vec<(String, bool, Value)> = CUSTOM.read().unwrap().iter().map(|(n, c)| (n.clone(), true, Value::Int(c.load(Ordering::Relaxed)))).collect();
+    let builtin = scalars().into_iter().map(|(n, c, v)| (n.to_string(), c, v));
+    for (name, counter, value) in builtin.chain(custom) {
+        let name = name.as_str();
+        if counter {
+            let family = name.strip_suffix("_total").unwrap_or(name);
+            out.push_str(&format!("# TYPE {0} counter\n{0}_total {1}\n", family, value));
+        } else {
+            out.push_str(&format!("# TYPE {0} gauge\n{0} {1}\n", name, value));
+        }
+        if name == "sws_errors_total" {
+            // Same position as in the Prometheus output.
+            out.push_str(&format!("# TYPE {0} histogram\n# UNIT {0} seconds\n", h));
+            for (le, n) in latency_buckets() {
+                out.push_str(&format!("{}_bucket{{le=\"{}\"}} {}\n", h, le, n));
+            }
+            out.push_str(&format!("{0}_sum {1}\n{0}_count {2}\n", h, sum, LAT_TOTAL.load(Ordering::Relaxed)));
+        }
+        if name == "sws_proxy_upstream_reuses_total" {
+            let ups = upstream_series();
+            if !ups.is_empty() {
+                out.push_str("# TYPE sws_upstream_healthy gauge\n");
+                for (a, h, _) in &ups { out.push_str(&format!("sws_upstream_healthy{{upstream=\"{}\"}} {}\n", a, h)); }
+                out.push_str("# TYPE sws_upstream_failures counter\n");
+                for (a, _, f) in &ups { out.push_str(&format!("sws_upstream_failures_total{{upstream=\"{}\"}} {}\n", a, f)); }
+            }
+            let ls = listener_series();
+            if !ls.is_empty() {
+                out.push_str("# TYPE sws_listener_active_requests gauge\n");
+                for (a, v) in &ls { out.push_str(&format!("sws_listener_active_requests{{listener=\"{}\"}} {}\n", a, v)); }
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+/// JSON: one key per counter/gauge; the histogram is an object of cumulative
+/// `buckets` keyed by `le`, plus `sum` and `count`.
+fn render_json() -> String {
+    let mut fields: Vec<String> = scalars().iter().map(|(name, _, value)| format!("\"{}\":{}", name, value)).collect();
+    let ups = upstream_series();
+    if !ups.is_empty() {
+        let by_addr = |pick: fn(&(String, u64, u64)) -> u64| ups.iter().map(|u| format!("\"{}\":{}", u.0, pick(u))).collect::<Vec<_>>().join(",");
+        fields.push(format!("\"sws_upstream_healthy\":{{{}}}", by_addr(|u| u.1)));
+        fields.push(format!("\"sws_upstream_failures_total\":{{{}}}", by_addr(|u| u.2)));
+    }
+    let ls = listener_series();
+    if !ls.is_empty() {
+        let by_addr = ls.iter().map(|(a, v)| format!("\"{}\":{}", a, v)).collect::<Vec<_>>().join(",");
+        fields.push(format!("\"sws_listener_active_requests\":{{{}}}", by_addr));
+    }
+    fields.extend(CUSTOM.read().unwrap().iter().map(|(name, c)| format!("\"{}\":{}", name, c.load(Ordering::Relaxed))));
+    let buckets: Vec<String> = latency_buckets().iter().map(|(le, n)| format!("\"{}\":{}", le, n)).collect();
+    fields.push(format!(
+        "\"sws_http_request_duration_seconds\":{{\"buckets\":{{{}}},\"sum\":{},\"count\":{}}}",
+        buckets.join(","), Value::Secs(LAT_SUM_US.load(Ordering::Relaxed)), LAT_TOTAL.load(Ordering::Relaxed)
+    ));
+    format!("{{{}}}\n", fields.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    enum Json { Num(f64), Obj(Vec<(String, Json)>) }
+
+    impl Json {
+        fn get(&self, key: &str) -> Option<&Json> {
+            match self { Json::Obj(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v), _ => None }
+        }
+        fn num(&self) -> f64 {
+            match self { Json::Num(n) => *n, _ => panic!("not a number: {:?}", self) }
+        }
+    }
+
+    /// Just enough JSON for the metrics dump: objects, string keys and numbers.
+    fn parse(s: &str) -> Json {
+        fn value(s: &mut &str) -> Json {
+            *s = s.trim_start();
+            if let Some(rest) = s.strip_prefix('{') {
+                *s = rest;
+                let mut fields = Vec::new();
+                loop {
+                    *s = s.trim_start();
+                    if let Some(rest) = s.strip_prefix('}') { *s = rest; return Json::Obj(fields); }
+                    if !fields.is_empty() { *s = s.strip_prefix(',').expect("comma").trim_start(); }
+                    let rest = s.strip_prefix('"').expect("key");
+                    let end = rest.find('"').unwrap();
+                    let key = rest[..end].to_string();
+                    *s = rest[end + 1..].trim_start().strip_prefix(':').expect("colon");
+                    fields.push((key, value(s)));
+                }
+            }
+            let end = s.find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-')).unwrap_or(s.len());
+            let n = s[..end].parse().expect("number");
+            *s = &s[end..];
+            Json::Num(n)
+        }
+        let mut rest = s;
+        let v = value(&mut rest);
+        assert!(rest.trim().is_empty(), "trailing input: {}", rest);
+        v
+    }
+
+    #[test]
+    fn openmetrics_ends_with_eof() {
+        inc_requests();
+        let out = render_format(Format::OpenMetrics);
+        assert!(out.ends_with("# EOF\n"));
+        assert_eq!(out.matches("# EOF").count(), 1);
+        // Counter families drop `_total`; their samples keep it.
+        assert!(out.contains("# TYPE sws_requests counter\nsws_requests_total "));
+        assert!(!out.contains("# TYPE sws_requests_total"));
+        assert!(out.contains("# TYPE sws_conn_active gauge\nsws_conn_active "));
+        assert!(out.contains("sws_http_request_duration_seconds_bucket{le=\"+Inf\"} "));
+        assert!(!render().contains("# EOF"));
+    }
+
+    #[test]
+    fn json_dump_is_an_object_of_counters() {
+        inc_requests();
+        inc_errors();
+        observe_latency(Duration::from_millis(3));
+        let json = parse(&render_format(Format::Json));
+        assert!(json.get("sws_requests_total").unwrap().num() >= 1.0);
+        assert!(json.get("sws_errors_total").unwrap().num() >= 1.0);
+        assert!(json.get("sws_conn_active").is_some());
+        let latency = json.get("sws_http_request_duration_seconds").unwrap();
+        assert!(latency.get("count").unwrap().num() >= 1.0);
+        assert!(latency.get("sum").unwrap().num() > 0.0);
+        assert!(latency.get("buckets").unwrap().get("+Inf").unwrap().num() >= 1.0);
+    }
+
+    #[test]
+    fn format_follows_accept_and_query() {
+        assert_eq!(Format::from_accept("application/openmetrics-text; version=1.0.0, text/plain;q=0.5"), Format::OpenMetrics);
+        assert_eq!(Format::from_accept("application/json"), Format::Json);
+        assert_eq!(Format::from_accept("*/*"), Format::Prometheus);
+        assert_eq!(Format::from_name("JSON"), Some(Format::Json));
+        assert_eq!(Format::from_name("xml"), None);
+    }
+}
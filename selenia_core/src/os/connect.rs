@@ -0,0 +1,104 @@
+//! Outbound TCP connects that never wait on the caller's thread.
+//!
+//! [`start`] returns a non-blocking socket whose handshake may still be under way. The
+//! caller registers it for writability; once the event loop reports it, [`settled`]
+//! tells a completed connect from a refused or unreachable one.
+
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+
+/// Begin connecting to `addr`; the socket comes back non-blocking with `TCP_NODELAY`.
+#[cfg(target_os = "linux")]
+pub fn start(addr: SocketAddr) -> io::Result<TcpStream> {
+    use std::mem::size_of;
+    use std::os::unix::io::FromRawFd;
+
+    let family = if addr.is_ipv4() { libc::AF_INET } else { libc::AF_INET6 };
+    let fd = unsafe { libc::socket(family, libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 { return Err(io::Error::last_os_error()); }
+    // SAFETY: `fd` was just created and is owned by nothing else; the stream closes it.
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+    let rc = match addr {
+        SocketAddr::V4(a) => {
+            let sa = libc::sockaddr_in { sin_family: libc::AF_INET as u16, sin_port: a.port().to_be(), sin_addr: a.ip().octets(), sin_zero: [0; 8] };
+            unsafe { libc::connect(fd, &sa as *const _ as *const libc::sockaddr, size_of::<libc::sockaddr_in>() as _) }
+        }
+        SocketAddr::V6(a) => {
+            let sa = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as u16,
+                sin6_port: a.port().to_be(),
+                sin6_flowinfo: a.flowinfo(),
+                sin6_addr: a.ip().octets(),
+                sin6_scope_id: a.scope_id(),
+            };
+            unsafe { libc::connect(fd, &sa as *const _ as *const libc::sockaddr, size_of::<libc::sockaddr_in6>() as _) }
+        }
+    };
+    if rc != 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EINPROGRESS) { return Err(e); }
+    }
+    let _ = stream.set_nodelay(true);
+    Ok(stream)
+}
+
+/// Other platforms connect in line, then hand the socket over non-blocking.
+#[cfg(not(target_os = "linux"))]
+pub fn start(addr: SocketAddr) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_nonblocking(true)?;
+    let _ = stream.set_nodelay(true);
+    Ok(stream)
+}
+
+/// Outcome of a connect begun by [`start`], once its socket has been reported writable
+/// (or in error): the pending socket error, if any.
+pub fn settled(stream: &TcpStream) -> io::Result<()> {
+    match stream.take_error()? {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{settled, start};
+    use crate::os::{EventLoop, Interest};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::time::{Duration, Instant};
+
+    /// Block until the event loop reports `s` writable.
+    fn wait_writable(s: &std::net::TcpStream) {
+        let mut ev = EventLoop::new().unwrap();
+        let token = ev.register(s, Interest::Writable).unwrap();
+        assert!(ev.poll(5000).unwrap().iter().any(|&(t, _, w)| t == token && w));
+    }
+
+    #[test]
+    fn connects_without_blocking() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut s = start(listener.local_addr().unwrap()).unwrap();
+        wait_writable(&s);
+        settled(&s).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        s.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        peer.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[test]
+    fn refused_connect_settles_with_an_error() {
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let started = Instant::now();
+        let s = match start(addr) {
+            Ok(s) => s,
+            // Loopback may refuse before the call returns.
+            Err(e) => { assert_eq!(e.kind(), std::io::ErrorKind::ConnectionRefused); return; }
+        };
+        assert!(started.elapsed() < Duration::from_secs(1));
+        wait_writable(&s);
+        assert_eq!(settled(&s).unwrap_err().kind(), std::io::ErrorKind::ConnectionRefused);
+    }
+}
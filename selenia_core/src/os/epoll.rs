@@ -72,7 +72,13 @@ impl Epoll {
             )
         };
         if n < 0 {
-            return Err(Error::last_os_error());
+            let err = Error::last_os_error();
+            // A signal (SIGHUP/SIGTERM handlers are installed) interrupted the wait; report no
+            // ready events so the caller re-checks its flags instead of tearing down the loop.
+            if err.raw_os_error() == Some(libc::EINTR) {
+                return Ok(0);
+            }
+            return Err(err);
         }
 
         // Translate the raw events into our portable EpollEvent representation.
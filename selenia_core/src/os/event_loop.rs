@@ -32,9 +32,16 @@ impl EventLoop {
 
     /// FD を登録し Token を返す。
     pub fn register<T: AsRawFd>(&mut self, io: &T, interest: Interest) -> Result<Token> {
-        let fd = io.as_raw_fd();
         let token = self.next_token;
         self.next_token += 1;
+        self.register_token(io, token, interest)?;
+        Ok(token)
+    }
+
+    /// 呼び出し側が採番した Token で FD を登録する (例: スラブのキーをそのまま使う)。
+    /// 自動採番 (`register`) の Token と衝突しない値を渡すこと。
+    pub fn register_token<T: AsRawFd>(&mut self, io: &T, token: Token, interest: Interest) -> Result<()> {
+        let fd = io.as_raw_fd();
         let (r, w) = match interest {
             Interest::Readable => (true, false),
             Interest::Writable => (false, true),
@@ -42,7 +49,7 @@ impl EventLoop {
         };
         self.ep.add(fd, token, r, w)?;
         self.entries.insert(token, Entry { fd, interest });
-        Ok(token)
+        Ok(())
     }
 
     /// 登録済み FD の待機。timeout_ms <0 でブロック。戻り値は (token, readable, writable) の列挙。
@@ -55,6 +62,22 @@ impl EventLoop {
         Ok(out)
     }
 
+    /// 登録済み FD の関心事を変更 (例: 送信待ちの間だけ Writable に切り替える)。
+    pub fn reregister(&mut self, token: Token, interest: Interest) -> Result<()> {
+        let entry = match self.entries.get_mut(&token) {
+            Some(e) => e,
+            None => return Err(Error::from(std::io::ErrorKind::NotFound)),
+        };
+        let (r, w) = match interest {
+            Interest::Readable => (true, false),
+            Interest::Writable => (false, true),
+            Interest::ReadWrite => (true, true),
+        };
+        self.ep.modify(entry.fd, token, r, w)?;
+        entry.interest = interest;
+        Ok(())
+    }
+
     /// FD を削除
     pub fn deregister(&mut self, token: Token) -> Result<()> {
         if let Some(entry) = self.entries.remove(&token) {
@@ -62,4 +85,39 @@ impl EventLoop {
         }
         Ok(())
     }
-} 
\ No newline at end of file
+}
+
+// gettid/tgkill are called by number; only these architectures have them listed.
+#[cfg(all(test, any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod tests {
+    use super::EventLoop;
+    use std::time::{Duration, Instant};
+
+    #[cfg(target_arch = "x86_64")]
+    const GETTID: libc::c_long = 186;
+    #[cfg(target_arch = "x86_64")]
+    const TGKILL: libc::c_long = 234;
+    #[cfg(target_arch = "aarch64")]
+    const GETTID: libc::c_long = 178;
+    #[cfg(target_arch = "aarch64")]
+    const TGKILL: libc::c_long = 131;
+
+    #[test]
+    fn signal_during_poll_is_not_an_error() {
+        assert!(crate::signals::register_signal(libc::SIGUSR2, false));
+        let pid = std::process::id() as libc::c_long;
+        let tid = unsafe { libc::syscall(GETTID) };
+        let sender = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            unsafe { libc::syscall(TGKILL, pid, tid, libc::SIGUSR2) };
+        });
+        let mut ev = EventLoop::new().unwrap();
+        let started = Instant::now();
+        // epoll_wait is never restarted after a handler runs; the wait ends early with no events.
+        assert!(ev.poll(5000).unwrap().is_empty());
+        assert!(started.elapsed() < Duration::from_secs(4));
+        sender.join().unwrap();
+        assert!(crate::signals::take_signal(libc::SIGUSR2));
+        assert!(ev.poll(0).unwrap().is_empty());
+    }
+}
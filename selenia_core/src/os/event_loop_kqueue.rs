@@ -36,9 +36,16 @@ impl EventLoop {
 
     /// Registers an FD with given interest, returning a unique Token.
     pub fn register<T: AsRawFd>(&mut self, io: &T, interest: Interest) -> Result<Token> {
-        let fd = io.as_raw_fd();
         let token = self.next_token;
         self.next_token += 1;
+        self.register_token(io, token, interest)?;
+        Ok(token)
+    }
+
+    /// Registers an FD under a caller-chosen token (e.g. a slab key). The caller
+    /// must keep it distinct from tokens handed out by `register`.
+    pub fn register_token<T: AsRawFd>(&mut self, io: &T, token: Token, interest: Interest) -> Result<()> {
+        let fd = io.as_raw_fd();
         let (r, w) = match interest {
             Interest::Readable => (true, false),
             Interest::Writable => (false, true),
@@ -46,7 +53,7 @@ impl EventLoop {
         };
         self.kq.add(fd, token, r, w)?;
         self.entries.insert(token, Entry { fd, interest });
-        Ok(token)
+        Ok(())
     }
 
     /// Waits for events and returns at most `events.len()` ready items.
@@ -59,6 +66,22 @@ impl EventLoop {
         Ok(out)
     }
 
+    /// Changes the interest of an already registered FD (e.g. Writable while a send is parked).
+    pub fn reregister(&mut self, token: Token, interest: Interest) -> Result<()> {
+        let entry = match self.entries.get_mut(&token) {
+            Some(e) => e,
+            None => return Err(Error::from(std::io::ErrorKind::NotFound)),
+        };
+        let (r, w) = match interest {
+            Interest::Readable => (true, false),
+            Interest::Writable => (false, true),
+            Interest::ReadWrite => (true, true),
+        };
+        self.kq.modify(entry.fd, token, r, w)?;
+        entry.interest = interest;
+        Ok(())
+    }
+
     /// Deregisters the FD associated with the token.
     pub fn deregister(&mut self, token: Token) -> Result<()> {
         if let Some(entry) = self.entries.remove(&token) {
@@ -0,0 +1,60 @@
+//! Process umask and explicit permission bits for files the server creates.
+//!
+//! `create_with_mode` is the entry point for anything written on behalf of a
+//! client (`upload_file_mode`): the mode is passed to open(2) and then forced
+//! with fchmod(2), so the result does not depend on the inherited umask.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// Set the process umask; returns the previous one.
+pub fn set_umask(mask: u32) -> u32 {
+    unsafe { libc::umask(mask as libc::mode_t) as u32 }
+}
+
+/// Create `path` (failing if it exists) with exactly `mode` permission bits.
+pub fn create_with_mode(path: &Path, mode: u32) -> io::Result<File> {
+    let file = OpenOptions::new().write(true).create_new(true).mode(mode).open(path)?;
+    if unsafe { libc::fchmod(file.as_raw_fd(), mode as libc::mode_t) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create_with_mode, set_umask};
+    use std::os::unix::fs::PermissionsExt;
+
+    fn mode_of(path: &std::path::Path) -> u32 {
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    /// The umask is process-wide, so everything that depends on it runs in this one
+    /// test and puts the inherited mask back before asserting.
+    #[test]
+    fn umask_applies_to_plain_creates_but_not_to_explicit_modes() {
+        let dir = std::env::temp_dir();
+        let plain = dir.join(format!("sws-umask-{}-plain", std::process::id()));
+        let exact = dir.join(format!("sws-umask-{}-exact", std::process::id()));
+        let old = set_umask(0o027);
+        let swapped = set_umask(0o027);
+        let created = std::fs::write(&plain, b"x");
+        // A mask that would strip the group bits fchmod puts back.
+        set_umask(0o077);
+        let forced = create_with_mode(&exact, 0o640).map(drop);
+        let again = create_with_mode(&exact, 0o640).map(drop);
+        set_umask(old);
+        assert_eq!(swapped, 0o027);
+        created.unwrap();
+        forced.unwrap();
+        let modes = (mode_of(&plain), mode_of(&exact));
+        let _ = std::fs::remove_file(&plain);
+        let _ = std::fs::remove_file(&exact);
+        assert_eq!(modes, (0o640, 0o640));
+        assert_eq!(again.unwrap_err().kind(), std::io::ErrorKind::AlreadyExists);
+    }
+}
@@ -137,7 +137,12 @@ impl Kqueue {
             )
         };
         if n < 0 {
-            return Err(Error::last_os_error());
+            let err = Error::last_os_error();
+            // Interrupted by a signal handler – treat as a timeout with no events.
+            if err.raw_os_error() == Some(libc::EINTR) {
+                return Ok(0);
+            }
+            return Err(err);
         }
         for (dst, src) in events.iter_mut().zip(raw.iter().take(n as usize)) {
             dst.token = src.udata as Token;
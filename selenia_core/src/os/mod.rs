@@ -58,6 +58,15 @@ pub mod poller;
 pub mod timer;
 pub use timer::Timer;
 
+#[cfg(unix)]
+pub mod fdlimit;
+
+#[cfg(unix)]
+pub mod filemode;
+
+#[cfg(unix)]
+pub mod connect;
+
 /// Portable error type for the OS abstraction layer.
 #[derive(Debug)]
 pub enum OsError {
@@ -81,7 +81,8 @@ mod sys {
 
         pub fn wait(&mut self) -> Result<()> {
             let mut buf = [0u8; 8];
-            // Reading clears the expirations counter.
+            // Reading clears the expirations counter. `read_exact` already retries on
+            // EINTR (`ErrorKind::Interrupted`), so a signal arriving mid-wait is harmless.
             self.fd.read_exact(&mut buf)?;
             Ok(())
         }
@@ -130,11 +131,13 @@ mod sys {
 
         pub fn wait(&mut self) -> Result<()> {
             let mut kev: libc::kevent = unsafe { mem::zeroed() };
-            let res = unsafe { libc::kevent(self.kq, std::ptr::null(), 0, &mut kev, 1, std::ptr::null()) };
-            if res < 0 {
-                return Err(Error::last_os_error());
+            loop {
+                let res = unsafe { libc::kevent(self.kq, std::ptr::null(), 0, &mut kev, 1, std::ptr::null()) };
+                if res >= 0 { return Ok(()); }
+                let err = Error::last_os_error();
+                // Retry when a signal handler interrupted the wait; the timer has not fired yet.
+                if err.raw_os_error() != Some(libc::EINTR) { return Err(err); }
             }
-            Ok(())
         }
     }
 
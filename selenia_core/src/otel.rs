@@ -1,23 +1,94 @@
-//! Minimal OpenTelemetry OTLP trace exporter (gRPC/HTTP2 plaintext).
-//! Sends spans in batches to `http://127.0.0.1:4318/v1/traces`.
-//! No external crates – handcrafted HTTP/2 preface + single DATA frame.
+//! Minimal OpenTelemetry OTLP trace exporter (plaintext).
+//! Sends spans to the configured collector (default `127.0.0.1:4318`): OTLP/HTTP as an
+//! HTTP/1.1 `POST /v1/traces` with a protobuf body, or – for gRPC collectors, port
+//! 4317 by convention – a handcrafted HTTP/2 preface + single DATA frame.
+//! Spans are queued to one exporter thread, so a slow or hung collector costs the
+//! request path nothing; spans that overflow the queue or whose export times out are
+//! dropped and counted (`sws_otel_spans_dropped_total`).
+//! No external crates.
 
-use std::net::TcpStream;
-use std::io::{Write, Read};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::io::{self, Write, Read};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{OnceLock, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use crate::logger::{log, LogLevel};
+use crate::traceparent::TraceContext;
+
+/// Wire protocol towards the collector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtlpProtocol {
+    /// OTLP/HTTP: HTTP/1.1 POST, `application/x-protobuf`.
+    Http,
+    /// OTLP/gRPC over HTTP/2 prior knowledge.
+    Grpc,
+}
+
+impl OtlpProtocol {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "http" | "http/protobuf" => Some(OtlpProtocol::Http),
+            "grpc" => Some(OtlpProtocol::Grpc),
+            _ => None,
+        }
+    }
+
+    /// Protocol implied by the standard ports: 4317 is gRPC, anything else OTLP/HTTP.
+    pub fn for_endpoint(endpoint: &str) -> Self {
+        match endpoint.rsplit_once(':') {
+            Some((_, "4317")) => OtlpProtocol::Grpc,
+            _ => OtlpProtocol::Http,
+        }
+    }
+}
+
+/// Default collector address (OTLP/HTTP port).
+pub const DEFAULT_ENDPOINT: &str = "127.0.0.1:4318";
+/// Budget for one export: connect, writing the request and reading the reply.
+const EXPORT_TIMEOUT: Duration = Duration::from_millis(500);
+/// Spans waiting for the exporter thread; more are dropped.
+const QUEUE_CAPACITY: usize = 1024;
+
+static EXPORTER: RwLock<Option<(String, OtlpProtocol)>> = RwLock::new(None);
+static QUEUE: OnceLock<SyncSender<Vec<u8>>> = OnceLock::new();
+
+/// Point the exporter at `endpoint` (`host:port`) speaking `protocol` and start its
+/// thread (before any seccomp filter that would forbid creating one later).
+pub fn init(endpoint: &str, protocol: OtlpProtocol) {
+    *EXPORTER.write().unwrap() = Some((endpoint.to_string(), protocol));
+    queue();
+}
+
+fn queue() -> &'static SyncSender<Vec<u8>> {
+    QUEUE.get_or_init(|| {
+        let (tx, rx) = sync_channel::<Vec<u8>>(QUEUE_CAPACITY);
+        let spawned = std::thread::Builder::new().name("otel-export".into()).spawn(move || {
+            for body in rx {
+                if let Err(e) = send(body) {
+                    crate::metrics::inc_otel_spans_dropped();
+                    log(LogLevel::Warn, format_args!("OTLP exporter: {}; span dropped", e));
+                }
+            }
+        });
+        if let Err(e) = spawned { log(LogLevel::Warn, format_args!("OTLP exporter: no thread ({}); spans dropped", e)); }
+        tx
+    })
+}
 
 /// Current time in unix‐epoch nanoseconds.
 fn now_ns() -> u64 {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
 }
 
-pub fn export_span(name:&str, start: u64, end: u64) {
+/// `attrs` become string attributes on the span (e.g. the request id). Nothing is sent
+/// unless `ctx` is sampled; the span carries its trace and span ids.
+pub fn export_span(ctx: &TraceContext, name:&str, start: u64, end: u64, attrs: &[(&str, &str)]) {
+    if !ctx.sampled { return; }
     // Build minimal protobuf bytes for ResourceSpans -> ScopeSpans -> Span.
     // Hard-coded field numbers per OTLP proto.
     let mut buf=Vec::new();
     // ResourceSpans list (field 1 length-delimited)
-    let span_bytes = span_proto(name,start,end);
+    let span_bytes = span_proto(ctx,name,start,end,attrs);
     let mut rs=Vec::new();
     // ScopeSpans list (field 1) containing the span
     let mut ss=Vec::new();
@@ -27,37 +98,201 @@ pub fn export_span(name:&str, start: u64, end: u64) {
     // ResourceSpans wrapper list element
     buf.extend(varint((1<<3)|2)); buf.extend(varint(rs.len() as u64)); buf.extend(&rs);
 
-    send(buf);
+    // Never wait: a full queue or a dead exporter thread drops the span.
+    if let Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) = queue().try_send(buf) {
+        crate::metrics::inc_otel_spans_dropped();
+    }
 }
 
-fn span_proto(name:&str,start:u64,end:u64)->Vec<u8>{
+fn span_proto(ctx:&TraceContext,name:&str,start:u64,end:u64,attrs:&[(&str,&str)])->Vec<u8>{
     let mut b=Vec::new();
+    // Trace id field 1, span id field 2 (bytes)
+    b.extend(varint((1<<3)|2)); b.extend(varint(16)); b.extend(ctx.trace_id);
+    b.extend(varint((2<<3)|2)); b.extend(varint(8)); b.extend(ctx.span_id);
     // Span name (field 3)
     b.extend(varint((3<<3)|2)); b.extend(varint(name.len() as u64)); b.extend(name.as_bytes());
     // Start time unix ns field 11
     b.extend(varint((11<<3)|0)); b.extend(varint(start));
     // End time field 12
     b.extend(varint((12<<3)|0)); b.extend(varint(end));
+    // Attributes field 9: KeyValue{key=1, value=2 AnyValue{string_value=1}}
+    for (k,v) in attrs {
+        let mut any=Vec::new();
+        any.extend(varint((1<<3)|2)); any.extend(varint(v.len() as u64)); any.extend(v.as_bytes());
+        let mut kv=Vec::new();
+        kv.extend(varint((1<<3)|2)); kv.extend(varint(k.len() as u64)); kv.extend(k.as_bytes());
+        kv.extend(varint((2<<3)|2)); kv.extend(varint(any.len() as u64)); kv.extend(&any);
+        b.extend(varint((9<<3)|2)); b.extend(varint(kv.len() as u64)); b.extend(&kv);
+    }
     b
 }
 
 fn varint(mut v:u64)->Vec<u8>{ let mut o=Vec::new(); loop{ let mut byte=(v&0x7F) as u8; v>>=7; if v!=0{byte|=0x80;} o.push(byte); if v==0{break;} } o }
 
-fn send(body:Vec<u8>) {
+/// A collector connection whose every read and write must finish by `deadline`.
+struct Deadlined {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl Deadlined {
+    fn connect(endpoint: &str, timeout: Duration) -> io::Result<Self> {
+        let deadline = Instant::now() + timeout;
+        let addr = endpoint.to_socket_addrs()?.next().ok_or(io::ErrorKind::AddrNotAvailable)?;
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+        Ok(Deadlined { stream, deadline })
+    }
+
+    /// Time left, as a socket timeout (which may not be zero).
+    fn left(&self) -> io::Result<Duration> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() { Err(io::ErrorKind::TimedOut.into()) } else { Ok(left) }
+    }
+}
+
+impl Write for Deadlined {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.set_write_timeout(Some(self.left()?))?;
+        self.stream.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> { self.stream.flush() }
+}
+
+impl Read for Deadlined {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(Some(self.left()?))?;
+        self.stream.read(buf)
+    }
+}
+
+/// One export within [`EXPORT_TIMEOUT`]; `Err` when it did not get through in time.
+fn send(body:Vec<u8>) -> io::Result<()> {
+    let (endpoint, protocol) = EXPORTER.read().unwrap().clone()
+        .unwrap_or_else(|| (DEFAULT_ENDPOINT.to_string(), OtlpProtocol::Http));
+    let s = Deadlined::connect(&endpoint, EXPORT_TIMEOUT)?;
+    match protocol {
+        OtlpProtocol::Http => send_http(s, &endpoint, &body),
+        OtlpProtocol::Grpc => send_h2(s, body),
+    }
+}
+
+/// OTLP/HTTP request; the status line is read so the collector can finish cleanly.
+/// A collector that never answers costs the rest of the budget, then counts as failed.
+fn send_http(mut s: Deadlined, endpoint: &str, body: &[u8]) -> io::Result<()> {
+    let head = format!("POST /v1/traces HTTP/1.1\r\nHost: {}\r\nContent-Type: application/x-protobuf\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", endpoint, body.len());
+    s.write_all(head.as_bytes())?;
+    s.write_all(body)?;
+    let mut resp = [0u8; 16];
+    let n = s.read(&mut resp)?;
+    let ok = resp[..n].starts_with(b"HTTP/1.1 2") || resp[..n].starts_with(b"HTTP/1.0 2");
+    if n > 0 && !ok { log(LogLevel::Warn, format_args!("OTLP exporter: collector rejected export")); }
+    Ok(())
+}
+
+/// OTLP/gRPC over prior-knowledge HTTP/2; the first frame back is read the same way,
+/// and a GOAWAY there means the collector turned the export down.
+fn send_h2(mut s: Deadlined, body: Vec<u8>) -> io::Result<()> {
     let len=body.len();
     // HTTP/2 preface + SETTINGS ack simplified – we cheat by using prior knowledge connection.
-    if let Ok(mut s)=TcpStream::connect("127.0.0.1:4318") {
-        let _=s.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\x00\x00\x00\x04\x04\x00\x00\x00");
-        // HEADERS frame – minimal :method POST path /v1/traces
-        let headers = b"\x82\x86\x84\x41\x8c\xf1\x05\x92\x86\xcb\x8d\x84\x41\x8c\x84\x82\x10"; // pre-encoded HPACK for required headers
-        let mut hdr=Vec::new(); hdr.extend(&[(headers.len()>>16) as u8,(headers.len()>>8) as u8,headers.len() as u8,0x01,0x05,0x00,0x00,0x00,0x01]);
-        let _=s.write_all(&hdr); let _=s.write_all(headers);
-        // DATA frame
-        let mut df=vec![(len>>16) as u8,(len>>8) as u8,len as u8,0x00,0x01,0x00,0x00,0x00,0x01];
-        let _=s.write_all(&df); let _=s.write_all(&body);
-        let mut _resp = [0u8; 16];
-        let _ = s.read(&mut _resp);
-    } else {
-        log(LogLevel::Warn, format_args!("OTLP exporter: connect failed"));
-    }
-} 
\ No newline at end of file
+    s.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\x00\x00\x00\x04\x04\x00\x00\x00")?;
+    // HEADERS frame – minimal :method POST path /v1/traces
+    let headers = b"\x82\x86\x84\x41\x8c\xf1\x05\x92\x86\xcb\x8d\x84\x41\x8c\x84\x82\x10"; // pre-encoded HPACK for required headers
+    let mut hdr=Vec::new(); hdr.extend(&[(headers.len()>>16) as u8,(headers.len()>>8) as u8,headers.len() as u8,0x01,0x05,0x00,0x00,0x00,0x01]);
+    s.write_all(&hdr)?; s.write_all(headers)?;
+    // DATA frame
+    let mut df=vec![(len>>16) as u8,(len>>8) as u8,len as u8,0x00,0x01,0x00,0x00,0x00,0x01];
+    s.write_all(&df)?; s.write_all(&body)?;
+    let mut resp = [0u8; 16];
+    let n = s.read(&mut resp)?;
+    // Frame header: 24-bit length, then the type; 0x7 is GOAWAY.
+    if n >= 9 && resp[3] == 0x07 { log(LogLevel::Warn, format_args!("OTLP exporter: collector rejected export")); }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Run `export` against a mock collector that answers `reply`; returns what it received.
+    fn collect(reply: &'static [u8], export: impl FnOnce(Deadlined, &str) -> io::Result<()>) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = listener.local_addr().unwrap().to_string();
+        let collector = std::thread::spawn(move || {
+            let (mut s, _) = listener.accept().unwrap();
+            s.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+            let mut got = Vec::new();
+            let mut buf = [0u8; 4096];
+            while let Ok(n) = s.read(&mut buf) {
+                if n == 0 { break; }
+                got.extend_from_slice(&buf[..n]);
+            }
+            s.write_all(reply).unwrap();
+            got
+        });
+        export(Deadlined::connect(&endpoint, Duration::from_secs(2)).unwrap(), &endpoint).unwrap();
+        collector.join().unwrap()
+    }
+
+    #[test]
+    fn protocol_follows_the_port_unless_named() {
+        assert_eq!(OtlpProtocol::for_endpoint(DEFAULT_ENDPOINT), OtlpProtocol::Http);
+        assert_eq!(OtlpProtocol::for_endpoint("collector:4317"), OtlpProtocol::Grpc);
+        assert_eq!(OtlpProtocol::from_name("http/protobuf"), Some(OtlpProtocol::Http));
+        assert_eq!(OtlpProtocol::from_name("grpc"), Some(OtlpProtocol::Grpc));
+        assert_eq!(OtlpProtocol::from_name("thrift"), None);
+    }
+
+    #[test]
+    fn otlp_http_posts_protobuf_to_v1_traces() {
+        let got = collect(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n", |s, endpoint| send_http(s, endpoint, b"\x0a\x02span"));
+        let text = String::from_utf8_lossy(&got);
+        let (head, body) = text.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("POST /v1/traces HTTP/1.1\r\n"), "{}", head);
+        assert!(head.contains("\r\nContent-Type: application/x-protobuf\r\n"), "{}", head);
+        assert!(head.contains("\r\nContent-Length: 6\r\n"), "{}", head);
+        assert_eq!(body.as_bytes(), b"\x0a\x02span");
+    }
+
+    #[test]
+    fn grpc_starts_with_the_http2_preface() {
+        let got = collect(b"\x00\x00\x00\x04\x00\x00\x00\x00\x00", |s, _| send_h2(s, b"span".to_vec()));
+        assert!(got.starts_with(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"));
+        assert!(got.ends_with(b"\x00\x00\x04\x00\x01\x00\x00\x00\x01span"));
+    }
+
+    /// A collector that accepts connections and then neither reads nor answers.
+    fn stuck_collector() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = listener.local_addr().unwrap().to_string();
+        (listener, endpoint)
+    }
+
+    #[test]
+    fn stuck_collector_costs_at_most_the_budget() {
+        let (_listener, endpoint) = stuck_collector();
+        let budget = Duration::from_millis(300);
+        // Too large for the socket buffers: the write itself stalls.
+        let started = Instant::now();
+        let err = send_http(Deadlined::connect(&endpoint, budget).unwrap(), &endpoint, &vec![0; 16 << 20]).unwrap_err();
+        assert!(matches!(err.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock), "{:?}", err);
+        assert!(started.elapsed() < budget + Duration::from_millis(200), "{:?}", started.elapsed());
+        // A small export goes out, but no reply ever comes back.
+        let started = Instant::now();
+        assert!(send_h2(Deadlined::connect(&endpoint, budget).unwrap(), b"span".to_vec()).is_err());
+        assert!(started.elapsed() < budget + Duration::from_millis(200), "{:?}", started.elapsed());
+    }
+
+    #[test]
+    fn export_never_waits_on_the_collector() {
+        let (_listener, endpoint) = stuck_collector();
+        init(&endpoint, OtlpProtocol::Http);
+        let ctx = TraceContext { trace_id: [1; 16], span_id: [2; 8], sampled: true };
+        let dropped = crate::metrics::counter_value("sws_otel_spans_dropped_total").unwrap();
+        let started = Instant::now();
+        for _ in 0..QUEUE_CAPACITY + 16 { export_span(&ctx, "GET /", 1, 2, &[]); }
+        assert!(started.elapsed() < EXPORT_TIMEOUT, "{:?}", started.elapsed());
+        // The exporter thread is stuck on the first span, so the queue overflowed.
+        assert!(crate::metrics::counter_value("sws_otel_spans_dropped_total").unwrap() > dropped);
+    }
+}
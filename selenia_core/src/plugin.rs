@@ -1,10 +1,13 @@
 //! Dynamic plugin loader skeleton (Hot-Reload). No external crates.
-//! At this stage we only support `cdylib` plugins exporting a `sws_plugin_init` symbol.
+//! `cdylib` plugins export `sws_plugin_entry_v2` (preferred), `sws_plugin_entry_v1`
+//! or the legacy `sws_plugin_init` symbol. v2 plugins receive an [`SwsHostApi`]
+//! table in `on_load` for reading metrics and registering counters and WAF filters.
 
 use std::collections::HashMap;
-use std::ffi::{CString, c_void};
+use std::ffi::{CStr, CString, c_char, c_int, c_void};
 use std::path::Path;
-use std::sync::{RwLock, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock, OnceLock};
 
 #[cfg(unix)] use libc::{dlopen, dlsym, dlclose, RTLD_NOW};
 #[cfg(windows)] use winapi::um::libloaderapi::{LoadLibraryA, GetProcAddress, FreeLibrary};
@@ -26,12 +29,177 @@ pub struct SwsPluginV1 {
     pub on_unload: PluginInit,
 }
 
-const ABI_VERSION: u32 = 1;
+/// Returns non-zero to allow the request, zero to block it.
+pub type WafFilterFn = unsafe extern "C" fn(method: *const c_char, path: *const c_char) -> c_int;
+
+/// Host functions handed to v2 plugins. Only appended to; `version` tells a plugin
+/// which fields exist.
+#[repr(C)]
+pub struct SwsHostApi {
+    pub version: u32,
+    /// Write the value of an integer metric series (e.g. `sws_requests_total`) to `out`; 0 on success, -1 if unknown.
+    pub metric_value: unsafe extern "C" fn(name: *const c_char, out: *mut u64) -> c_int,
+    /// Register a counter; returns its handle or -1 for an invalid/reserved name.
+    pub register_counter: unsafe extern "C" fn(name: *const c_char) -> i64,
+    pub counter_add: unsafe extern "C" fn(handle: i64, delta: u64),
+    /// Add a WAF filter; 0 on success. The plugin stays mapped for the process lifetime.
+    pub register_waf_filter: unsafe extern "C" fn(filter: WafFilterFn) -> c_int,
+}
+
+#[repr(C)]
+pub struct SwsPluginV2 {
+    pub name: *const i8,
+    pub version: u32,
+    pub on_load: unsafe extern "C" fn(host: *const SwsHostApi),
+    pub on_request: *const c_void, // not used yet
+    pub on_unload: PluginInit,
+}
+
+const ABI_VERSION_V1: u32 = 1;
+const ABI_VERSION: u32 = 2;
+
+unsafe fn c_str<'a>(p: *const c_char) -> Option<&'a str> {
+    if p.is_null() { None } else { CStr::from_ptr(p).to_str().ok() }
+}
+
+unsafe extern "C" fn host_metric_value(name: *const c_char, out: *mut u64) -> c_int {
+    match c_str(name).and_then(crate::metrics::counter_value) {
+        Some(v) if !out.is_null() => { *out = v; 0 }
+        _ => -1,
+    }
+}
+
+unsafe extern "C" fn host_register_counter(name: *const c_char) -> i64 {
+    c_str(name).and_then(crate::metrics::register_counter).map_or(-1, |h| h as i64)
+}
+
+unsafe extern "C" fn host_counter_add(handle: i64, delta: u64) {
+    if handle >= 0 { crate::metrics::add_counter(handle as usize, delta); }
+}
+
+/// Set while a plugin that registered a filter is being loaded, so its library is never unmapped.
+static PIN_LOADING: AtomicBool = AtomicBool::new(false);
+/// Serialises `on_load` calls so `PIN_LOADING` belongs to exactly one plugin.
+static LOAD_LOCK: Mutex<()> = Mutex::new(());
+
+struct PluginFilter(WafFilterFn);
+
+impl crate::waf::RequestFilter for PluginFilter {
+    fn check(&self, method: &str, path: &str, _headers: &[(String,String)]) -> bool {
+        let (Ok(m), Ok(p)) = (CString::new(method), CString::new(path)) else { return false; };
+        unsafe { (self.0)(m.as_ptr(), p.as_ptr()) != 0 }
+    }
+}
+
+unsafe extern "C" fn host_register_waf_filter(filter: WafFilterFn) -> c_int {
+    PIN_LOADING.store(true, Ordering::SeqCst);
+    crate::waf::register_filter(PluginFilter(filter));
+    0
+}
+
+static HOST_API: SwsHostApi = SwsHostApi {
+    version: 1,
+    metric_value: host_metric_value,
+    register_counter: host_register_counter,
+    counter_add: host_counter_add,
+    register_waf_filter: host_register_waf_filter,
+};
+
+/// Name and ABI version a library declares in its entry symbol. Legacy
+/// `sws_plugin_init` libraries carry no entry struct: ABI 0, named after the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginInfo {
+    pub name: String,
+    pub abi: u32,
+}
+
+/// Where `install_plugin` puts libraries.
+pub const PLUGIN_DIR: &str = "plugins";
+
+fn plugin_error(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, msg)
+}
+
+/// An open library; closed on drop unless handed over with `into_raw`.
+struct Library(*mut c_void);
+
+impl Library {
+    unsafe fn open(path: &Path) -> std::io::Result<Self> {
+        let cname = CString::new(path.to_string_lossy().into_owned())
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "NUL in plugin path"))?;
+        #[cfg(unix)] let handle = dlopen(cname.as_ptr(), RTLD_NOW);
+        #[cfg(windows)] let handle = LoadLibraryA(cname.as_ptr()) as *mut c_void;
+        if handle.is_null() {
+            #[cfg(unix)] let reason = c_str(libc::dlerror()).unwrap_or("dlopen failed").to_string();
+            #[cfg(windows)] let reason = std::io::Error::last_os_error().to_string();
+            return Err(plugin_error(format!("load failed: {}", reason)));
+        }
+        Ok(Library(handle))
+    }
+
+    unsafe fn symbol(&self, name: &str) -> *mut c_void {
+        let c = CString::new(name).unwrap();
+        #[cfg(unix)] { dlsym(self.0, c.as_ptr()) }
+        #[cfg(windows)] { GetProcAddress(self.0 as _, c.as_ptr()) as *mut c_void }
+    }
+
+    fn into_raw(self) -> *mut c_void {
+        let handle = self.0;
+        std::mem::forget(self);
+        handle
+    }
+}
+
+impl Drop for Library {
+    fn drop(&mut self) {
+        unsafe {
+            #[cfg(unix)] { dlclose(self.0); }
+            #[cfg(windows)] { FreeLibrary(self.0 as _); }
+        }
+    }
+}
+
+/// The entry point a library exports, newest ABI first.
+enum Entry<'a> {
+    V2(&'a SwsPluginV2),
+    V1(&'a SwsPluginV1),
+    Legacy(PluginInit),
+}
+
+impl Entry<'_> {
+    unsafe fn find(lib: &Library) -> std::io::Result<Entry<'_>> {
+        let v2 = lib.symbol("sws_plugin_entry_v2");
+        if !v2.is_null() { return Ok(Entry::V2(&*(v2 as *const SwsPluginV2))); }
+        let v1 = lib.symbol("sws_plugin_entry_v1");
+        if !v1.is_null() { return Ok(Entry::V1(&*(v1 as *const SwsPluginV1))); }
+        let init = lib.symbol("sws_plugin_init");
+        if !init.is_null() { return Ok(Entry::Legacy(std::mem::transmute::<*mut c_void, PluginInit>(init))); }
+        Err(plugin_error("required symbol missing: none of sws_plugin_entry_v2, sws_plugin_entry_v1, sws_plugin_init".into()))
+    }
+
+    /// Declared name and ABI; errors when the declared version does not match the symbol.
+    unsafe fn info(&self, path: &Path) -> std::io::Result<PluginInfo> {
+        let (name, abi, expected) = match self {
+            Entry::V2(e) => (e.name, e.version, ABI_VERSION),
+            Entry::V1(e) => (e.name, e.version, ABI_VERSION_V1),
+            Entry::Legacy(_) => (std::ptr::null(), 0, 0),
+        };
+        if abi != expected {
+            return Err(plugin_error(format!("ABI version mismatch: declares {}, expected {}", abi, expected)));
+        }
+        let name = c_str(name as *const c_char).map(str::to_string)
+            .unwrap_or_else(|| path.file_stem().unwrap_or_default().to_string_lossy().into_owned());
+        Ok(PluginInfo { name, abi })
+    }
+}
 
 struct PluginHandle {
     name: String,
+    info: PluginInfo,
     lib: *mut c_void,
     init: PluginInit,
+    /// Callbacks into the library are registered elsewhere; unmapping it would leave them dangling.
+    pinned: bool,
 }
 
 unsafe impl Send for PluginHandle {}
@@ -40,6 +208,7 @@ unsafe impl Sync for PluginHandle {}
 impl Drop for PluginHandle {
     fn drop(&mut self) {
         unsafe {
+            if self.pinned { return; }
             #[cfg(unix)] { dlclose(self.lib); }
             #[cfg(windows)] { FreeLibrary(self.lib as _); }
         }
@@ -47,53 +216,48 @@ impl Drop for PluginHandle {
 }
 
 /// Load plugin dynamic library and call its init symbol.
-pub fn load_plugin<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
-    let cname = CString::new(path.as_ref().to_string_lossy().into_owned()).unwrap();
+pub fn load_plugin<P: AsRef<Path>>(path: P) -> std::io::Result<PluginInfo> {
+    let path = path.as_ref();
     unsafe {
-        let handle = {
-            #[cfg(unix)] { dlopen(cname.as_ptr(), RTLD_NOW) }
-            #[cfg(windows)] { LoadLibraryA(cname.as_ptr()) as _ }
-        };
-        if handle.is_null() { return Err(std::io::Error::new(std::io::ErrorKind::Other, "dlopen failed")); }
-        // Prefer new ABI symbol first.
-        let entry_sym = CString::new("sws_plugin_entry_v1").unwrap();
-        let entry_ptr = {
-            #[cfg(unix)] { dlsym(handle, entry_sym.as_ptr()) }
-            #[cfg(windows)] { GetProcAddress(handle as _, entry_sym.as_ptr()) as _ }
-        };
+        let lib = Library::open(path)?;
+        let entry = Entry::find(&lib)?;
+        let info = entry.info(path)?;
+        let _guard = LOAD_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        PIN_LOADING.store(false, Ordering::SeqCst);
 
-        let init_ptr = if !entry_ptr.is_null() {
-            let entry:&SwsPluginV1 = &*(entry_ptr as *const SwsPluginV1);
-            if entry.version != ABI_VERSION { dlclose(handle); return Err(std::io::Error::new(std::io::ErrorKind::Other, "ABI version mismatch")); }
-            (entry.on_load)();
-            entry.on_unload as *const c_void
-        } else {
-            // Fallback to legacy symbol.
-            let init_sym = CString::new("sws_plugin_init").unwrap();
-            let p = {
-                #[cfg(unix)] { dlsym(handle, init_sym.as_ptr()) }
-                #[cfg(windows)] { GetProcAddress(handle as _, init_sym.as_ptr()) as _ }
-            };
-            if p.is_null() {
-                #[cfg(unix)] { dlclose(handle); }
-                #[cfg(windows)] { FreeLibrary(handle as _); }
-                return Err(std::io::Error::new(std::io::ErrorKind::Other, "required symbol missing"));
-            }
-            let init: PluginInit = std::mem::transmute(p);
-            init();
-            p
+        // Kept for Drop: the unload entry (the init symbol itself for legacy plugins).
+        let cleanup = match entry {
+            Entry::V2(e) => { (e.on_load)(&HOST_API); e.on_unload }
+            Entry::V1(e) => { (e.on_load)(); e.on_unload }
+            Entry::Legacy(init) => { init(); init }
         };
 
-        // Cast init_ptr (plugin unload entry) back to function pointer for Drop.
-        let cleanup: PluginInit = std::mem::transmute(init_ptr);
-
         // Store handle so it stays loaded for the process lifetime.
+        let key = path.to_string_lossy().into_owned();
         plugins().write().unwrap().insert(
-            path.as_ref().to_string_lossy().into_owned(),
-            PluginHandle { name: path.as_ref().to_string_lossy().into_owned(), lib: handle, init: cleanup }
+            key.clone(),
+            PluginHandle { name: key, info: info.clone(), lib: lib.into_raw(), init: cleanup, pinned: PIN_LOADING.load(Ordering::SeqCst) }
         );
+        Ok(info)
+    }
+}
+
+/// Name and ABI of the library at `path` without calling into it (beyond what the
+/// dynamic loader runs) and without keeping it loaded.
+pub fn inspect_plugin<P: AsRef<Path>>(path: P) -> std::io::Result<PluginInfo> {
+    let path = path.as_ref();
+    unsafe {
+        let lib = Library::open(path)?;
+        let info = Entry::find(&lib)?.info(path);
+        info
     }
-    Ok(())
+}
+
+/// Plugins loaded in this process, keyed by the path they were loaded from.
+pub fn loaded_plugins() -> Vec<(String, PluginInfo)> {
+    let mut list: Vec<_> = plugins().read().unwrap().values().map(|h| (h.name.clone(), h.info.clone())).collect();
+    list.sort_by(|a, b| a.0.cmp(&b.0));
+    list
 }
 
 /// Unload plugin by name.
@@ -102,29 +266,132 @@ pub fn unload_plugin(name: &str) {
 }
 
 /// Validate a plugin by loading it and immediately unloading; ensures required symbol exists.
-pub fn validate_plugin<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
+pub fn validate_plugin<P: AsRef<Path>>(path: P) -> std::io::Result<PluginInfo> {
     let path_ref = path.as_ref();
     // Attempt to load the plugin. This will store it in the global map.
-    load_plugin(&path_ref)?;
+    let info = load_plugin(path_ref)?;
     // Immediately unload so we do not keep state during validation.
     unload_plugin(&path_ref.to_string_lossy());
-    Ok(())
+    Ok(info)
 }
 
-/// Install a plugin: copy the library into the `plugins/` directory and load it.
+/// Install a plugin: copy the library into [`PLUGIN_DIR`] and load it.
 /// Returns an error if copy or loading fails.
-pub fn install_plugin<P: AsRef<Path>>(src: P) -> std::io::Result<()> {
+pub fn install_plugin<P: AsRef<Path>>(src: P) -> std::io::Result<PluginInfo> {
     let src_path = src.as_ref();
     let filename = src_path
         .file_name()
         .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid source path"))?;
-    let plugins_dir = std::path::Path::new("plugins");
+    let plugins_dir = std::path::Path::new(PLUGIN_DIR);
     std::fs::create_dir_all(plugins_dir)?;
     let dst_path = plugins_dir.join(filename);
+    // Refuse a library that could never load before it lands next to the good ones.
+    inspect_plugin(src_path)?;
 
     // Overwrite if already exists to support upgrades.
     std::fs::copy(src_path, &dst_path)?;
 
     // Load the newly installed plugin so it becomes active immediately.
     load_plugin(&dst_path)
-} 
\ No newline at end of file
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    /// Declarations a test plugin needs, mirroring the host's `repr(C)` types.
+    const PRELUDE: &str = r#"
+        use std::ffi::{c_char, c_int, c_void};
+        #[repr(C)]
+        pub struct Host {
+            version: u32,
+            metric_value: unsafe extern "C" fn(*const c_char, *mut u64) -> c_int,
+            register_counter: unsafe extern "C" fn(*const c_char) -> i64,
+            counter_add: unsafe extern "C" fn(i64, u64),
+            register_waf_filter: *const c_void,
+        }
+        #[repr(C)]
+        pub struct EntryV2 {
+            name: *const c_char,
+            version: u32,
+            on_load: unsafe extern "C" fn(*const Host),
+            on_request: *const c_void,
+            on_unload: unsafe extern "C" fn(),
+        }
+        unsafe impl Sync for EntryV2 {}
+        unsafe extern "C" fn on_unload() {}
+    "#;
+
+    /// Compile `body` (after [`PRELUDE`]) into a `cdylib` in a scratch directory.
+    fn build_plugin(name: &str, body: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sws-plugin-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let src = dir.join("plugin.rs");
+        std::fs::write(&src, format!("{}\n{}", PRELUDE, body)).unwrap();
+        let out = dir.join(format!("lib{}.so", name));
+        let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+        let status = Command::new(rustc)
+            .args(["--crate-type", "cdylib", "--edition", "2021", "-o"])
+            .arg(&out)
+            .arg(&src)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        out
+    }
+
+    #[test]
+    fn v2_plugin_registers_a_counter() {
+        let lib = build_plugin("counter", r#"
+            unsafe extern "C" fn on_load(host: *const Host) {
+                let host = &*host;
+                let mut seen = 0u64;
+                if (host.metric_value)(b"sws_requests_total\0".as_ptr() as _, &mut seen) != 0 { return; }
+                let handle = (host.register_counter)(b"sws_plugin_test_hits_total\0".as_ptr() as _);
+                (host.counter_add)(handle, 7);
+            }
+            #[no_mangle]
+            pub static sws_plugin_entry_v2: EntryV2 = EntryV2 {
+                name: b"counter-test\0".as_ptr() as _,
+                version: 2,
+                on_load,
+                on_request: std::ptr::null(),
+                on_unload,
+            };
+        "#);
+        let info = load_plugin(&lib).unwrap();
+        assert_eq!(info, PluginInfo { name: "counter-test".into(), abi: 2 });
+        assert!(crate::metrics::render().contains("\nsws_plugin_test_hits_total 7\n"));
+        assert!(loaded_plugins().iter().any(|(path, _)| *path == lib.to_string_lossy()));
+        unload_plugin(&lib.to_string_lossy());
+        let _ = std::fs::remove_dir_all(lib.parent().unwrap());
+    }
+
+    #[test]
+    fn validate_reports_the_entry_or_what_is_wrong() {
+        let good = build_plugin("validate-good", r#"
+            unsafe extern "C" fn on_load(_: *const Host) {}
+            #[no_mangle]
+            pub static sws_plugin_entry_v2: EntryV2 = EntryV2 {
+                name: b"validate-test\0".as_ptr() as _, version: 2, on_load, on_request: std::ptr::null(), on_unload,
+            };
+        "#);
+        let future = build_plugin("validate-future", r#"
+            unsafe extern "C" fn on_load(_: *const Host) {}
+            #[no_mangle]
+            pub static sws_plugin_entry_v2: EntryV2 = EntryV2 {
+                name: b"future\0".as_ptr() as _, version: 3, on_load, on_request: std::ptr::null(), on_unload,
+            };
+        "#);
+        let bare = build_plugin("validate-bare", "#[no_mangle] pub extern \"C\" fn unrelated() {}");
+        assert_eq!(validate_plugin(&good).unwrap(), PluginInfo { name: "validate-test".into(), abi: 2 });
+        // Validation leaves nothing loaded.
+        assert!(!loaded_plugins().iter().any(|(path, _)| *path == good.to_string_lossy()));
+        assert!(validate_plugin(&bare).unwrap_err().to_string().starts_with("required symbol missing"));
+        assert_eq!(validate_plugin(&future).unwrap_err().to_string(), "ABI version mismatch: declares 3, expected 2");
+        assert!(validate_plugin(bare.with_file_name("absent.so")).unwrap_err().to_string().starts_with("load failed"));
+        for lib in [good, future, bare] { let _ = std::fs::remove_dir_all(lib.parent().unwrap()); }
+    }
+}
@@ -0,0 +1,126 @@
+// Switch to an unprivileged account once listeners are bound.
+// Names are resolved from /etc/passwd and /etc/group (no NSS); numeric ids are accepted as-is.
+
+use std::fs;
+
+/// Target credentials: uid, primary gid and supplementary groups.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub uid: u32,
+    pub gid: u32,
+    pub groups: Vec<u32>,
+}
+
+/// `name:passwd:uid:gid:…` line for `user` (name or numeric uid) → (name, uid, gid).
+fn lookup_passwd<'a>(passwd: &'a str, user: &str) -> Option<(&'a str, u32, u32)> {
+    passwd.lines().filter_map(|l| {
+        let f: Vec<&str> = l.split(':').collect();
+        if f.len() < 4 { return None; }
+        Some((f[0], f[2].parse().ok()?, f[3].parse().ok()?))
+    }).find(|&(name, uid, _)| name == user || user.parse() == Ok(uid))
+}
+
+/// `name:passwd:gid:members` line for `group` (name or numeric gid) → (gid, members).
+fn lookup_group<'a>(groups: &'a str, group: &str) -> Option<(u32, Vec<&'a str>)> {
+    groups.lines().filter_map(|l| {
+        let f: Vec<&str> = l.split(':').collect();
+        if f.len() < 4 { return None; }
+        Some((f[0], f[2].parse::<u32>().ok()?, f[3]))
+    }).find(|&(name, gid, _)| name == group || group.parse() == Ok(gid))
+        .map(|(_, gid, members)| (gid, members.split(',').filter(|m| !m.is_empty()).collect()))
+}
+
+/// Resolve against the given passwd/group file contents. The group defaults to the
+/// user's primary group; supplementary groups are those listing the user as member.
+pub fn resolve_in(passwd: &str, group_db: &str, user: &str, group: Option<&str>) -> Result<Account, String> {
+    let (name, uid, primary) = match lookup_passwd(passwd, user) {
+        Some((name, uid, gid)) => (name, uid, Some(gid)),
+        None => match user.parse() {
+            Ok(uid) => ("", uid, None),
+            Err(_) => return Err(format!("unknown user: {}", user)),
+        },
+    };
+    let gid = match group {
+        Some(g) => match lookup_group(group_db, g) {
+            Some((gid, _)) => gid,
+            None => g.parse().map_err(|_| format!("unknown group: {}", g))?,
+        },
+        None => primary.ok_or_else(|| format!("uid {} has no passwd entry; set run_as_group", uid))?,
+    };
+    let mut groups = vec![gid];
+    for line in group_db.lines() {
+        let f: Vec<&str> = line.split(':').collect();
+        if f.len() < 4 || name.is_empty() || !f[3].split(',').any(|m| m == name) { continue; }
+        if let Ok(g) = f[2].parse::<u32>() { if !groups.contains(&g) { groups.push(g); } }
+    }
+    Ok(Account { uid, gid, groups })
+}
+
+/// Resolve `user`/`group` from the system databases.
+pub fn resolve(user: &str, group: Option<&str>) -> Result<Account, String> {
+    let passwd = fs::read_to_string("/etc/passwd").unwrap_or_default();
+    let group_db = fs::read_to_string("/etc/group").unwrap_or_default();
+    resolve_in(&passwd, &group_db, user, group)
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::Account;
+
+    fn os_err(what: &str) -> String { format!("{}: {}", what, std::io::Error::last_os_error()) }
+
+    /// setgroups → setgid → setuid, then confirm root cannot be regained.
+    /// Any failure is returned so the caller can refuse to serve (fail closed).
+    pub fn drop_to(acct: &Account) -> Result<(), String> {
+        unsafe {
+            if libc::setgroups(acct.groups.len() as _, acct.groups.as_ptr()) != 0 { return Err(os_err("setgroups")); }
+            if libc::setgid(acct.gid) != 0 { return Err(os_err("setgid")); }
+            if libc::setuid(acct.uid) != 0 { return Err(os_err("setuid")); }
+            if libc::getuid() != acct.uid || libc::geteuid() != acct.uid || libc::getgid() != acct.gid {
+                return Err("credentials did not change".into());
+            }
+            if acct.uid != 0 && libc::setuid(0) == 0 { return Err("uid 0 could be regained".into()); }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use super::Account;
+    pub fn drop_to(_acct: &Account) -> Result<(), String> { Err("run_as_user is not supported on this platform".into()) }
+}
+
+/// Public re-export.
+pub use imp::drop_to;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWD: &str = "root:x:0:0:root:/root:/bin/sh\nwww:x:33:33:www:/var/www:/usr/sbin/nologin\nbroken:x:nan:1::/:\napp:x:1000:1000::/home/app:/bin/sh\n";
+    const GROUP: &str = "root:x:0:\nwww-data:x:33:\nlogs:x:4:app,www\nusers:x:100:app\napp:x:1000:\n";
+
+    #[test]
+    fn user_name_resolves_to_its_ids_and_groups() {
+        assert_eq!(resolve_in(PASSWD, GROUP, "app", None), Ok(Account { uid: 1000, gid: 1000, groups: vec![1000, 4, 100] }));
+        assert_eq!(resolve_in(PASSWD, GROUP, "www", None), Ok(Account { uid: 33, gid: 33, groups: vec![33, 4] }));
+    }
+
+    #[test]
+    fn group_overrides_the_primary_group() {
+        assert_eq!(resolve_in(PASSWD, GROUP, "www", Some("logs")).unwrap().gid, 4);
+        assert_eq!(resolve_in(PASSWD, GROUP, "www", Some("4")).unwrap().groups, vec![4]);
+        assert_eq!(resolve_in(PASSWD, GROUP, "www", Some("500")).unwrap().gid, 500);
+        assert_eq!(resolve_in(PASSWD, GROUP, "www", Some("nobody")), Err("unknown group: nobody".to_string()));
+    }
+
+    #[test]
+    fn numeric_ids_are_accepted() {
+        assert_eq!(resolve_in(PASSWD, GROUP, "1000", None).unwrap().uid, 1000);
+        // Without a passwd entry the group has to be named.
+        assert!(resolve_in(PASSWD, GROUP, "4242", None).is_err());
+        assert_eq!(resolve_in(PASSWD, GROUP, "4242", Some("100")), Ok(Account { uid: 4242, gid: 100, groups: vec![100] }));
+        assert_eq!(resolve_in(PASSWD, GROUP, "nobody", None), Err("unknown user: nobody".to_string()));
+    }
+}
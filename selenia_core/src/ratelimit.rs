@@ -1,5 +1,5 @@
 //! Simple token bucket rate-limiter keyed by client IP address.
-//! Configurable `capacity` and `refill_per_sec`. No external crates.
+//! Configurable `capacity` and `refill_per_sec` (capacity 0 allows everything). No external crates.
 
 use std::collections::HashMap;
 use std::sync::{Mutex, Once};
@@ -37,6 +37,7 @@ pub fn allow(ip:&str) -> bool {
     let now = Instant::now();
     let cap = st.cap;
     let rate = st.rate;
+    if cap == 0.0 { return true; }
     let b = st.map.entry(ip.to_string()).or_insert(Bucket { tokens: cap, last: now });
     let elapsed = now.duration_since(b.last).as_secs_f64();
     b.tokens = (b.tokens + elapsed * rate).min(cap);
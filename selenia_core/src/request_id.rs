@@ -0,0 +1,71 @@
+//! `X-Request-Id` correlation ids: accept a well-formed incoming id or mint a
+//! random UUIDv4. Independent of W3C Trace Context so logs correlate even
+//! without an OTLP collector.
+
+use crate::crypto::rand::fill_random;
+
+/// Longest incoming id we echo; longer values are replaced.
+pub const MAX_LEN: usize = 128;
+
+/// Incoming ids are limited to `[A-Za-z0-9._:-]` so they cannot inject log lines,
+/// quotes or header breaks.
+pub fn accept(value: &str) -> Option<&str> {
+    let v = value.trim();
+    let ok = !v.is_empty() && v.len() <= MAX_LEN
+        && v.bytes().all(|c| c.is_ascii_alphanumeric() || matches!(c, b'.' | b'_' | b':' | b'-'));
+    ok.then_some(v)
+}
+
+/// Random RFC 4122 version-4 UUID.
+pub fn generate() -> String {
+    let mut b = [0u8; 16];
+    let _ = fill_random(&mut b);
+    b[6] = (b[6] & 0x0f) | 0x40;
+    b[8] = (b[8] & 0x3f) | 0x80;
+    let h: String = b.iter().map(|x| format!("{:02x}", x)).collect();
+    format!("{}-{}-{}-{}-{}", &h[0..8], &h[8..12], &h[12..16], &h[16..20], &h[20..32])
+}
+
+/// Id for a request: the valid incoming `X-Request-Id`, else a fresh one.
+pub fn from_headers(headers: &[(&str, &str)]) -> String {
+    headers.iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("X-Request-Id"))
+        .and_then(|(_, v)| accept(v))
+        .map(str::to_string)
+        .unwrap_or_else(generate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_uuid_v4(id: &str) -> bool {
+        let groups: Vec<&str> = id.split('-').collect();
+        groups.iter().map(|g| g.len()).eq([8, 4, 4, 4, 12])
+            && id.bytes().all(|c| c == b'-' || c.is_ascii_hexdigit())
+            && groups[2].starts_with('4')
+            && matches!(groups[3].as_bytes()[0], b'8' | b'9' | b'a' | b'b')
+    }
+
+    #[test]
+    fn missing_id_is_generated() {
+        let id = from_headers(&[("Host", "a")]);
+        assert!(is_uuid_v4(&id), "{}", id);
+        assert_ne!(id, from_headers(&[]));
+    }
+
+    #[test]
+    fn valid_incoming_id_is_echoed() {
+        assert_eq!(from_headers(&[("x-request-id", " abc-123.def_4:5 ")]), "abc-123.def_4:5");
+        let longest = "a".repeat(MAX_LEN);
+        assert_eq!(from_headers(&[("X-Request-Id", &longest)]), longest);
+    }
+
+    #[test]
+    fn unsafe_incoming_id_is_replaced() {
+        for bad in ["", "a b", "id\"quoted", "line\nbreak", &"a".repeat(MAX_LEN + 1)] {
+            assert_eq!(accept(bad), None, "{:?}", bad);
+            assert!(is_uuid_v4(&from_headers(&[("X-Request-Id", bad)])));
+        }
+    }
+}
@@ -11,7 +11,7 @@ mod linux {
     use libc::*;
 
     const ALLOW: i32 = 0x7fff0000; // SECCOMP_RET_ALLOW
-    const ERRNO: i32 = 0x00050000; // SECCOMP_RET_ERRNO | EPERM
+    const ERRNO: i32 = 0x00050001; // SECCOMP_RET_ERRNO | EPERM
 
     // BPF Macros
     const BPF_LD: u16 = 0x00; const BPF_W: u16 = 0x00; const BPF_ABS: u16 = 0x20;
@@ -81,6 +81,15 @@ pub fn generate_and_install(names: &[&str]) -> Result<(), String> {
     #[allow(non_upper_case_globals)]
     const SYS_accept4: c_long = 288;
     const SYS_socket: c_long = 41;
+    // Outbound connections (OTLP exporter).
+    #[allow(non_upper_case_globals)]
+    const SYS_connect: c_long = 42;
+    // `TcpStream::connect_timeout` (proxy_pass health probes) waits in poll; it and the
+    // event loop's non-blocking connects read the outcome with getsockopt(SO_ERROR).
+    #[allow(non_upper_case_globals)]
+    const SYS_poll: c_long = 7;
+    #[allow(non_upper_case_globals)]
+    const SYS_getsockopt: c_long = 55;
     const SYS_bind: c_long = 49;
     const SYS_listen: c_long = 50;
     const SYS_setsockopt: c_long = 54;
@@ -92,10 +101,57 @@ pub fn generate_and_install(names: &[&str]) -> Result<(), String> {
     const SYS_fcntl: c_long = 72;
     const SYS_mmap: c_long = 9;
     const SYS_munmap: c_long = 11;
+    const SYS_madvise: c_long = 28;
     const SYS_brk: c_long = 12;
     const SYS_rt_sigreturn: c_long = 15;
     const SYS_rt_sigaction: c_long = 13;
     const SYS_sigaltstack: c_long = 131;
+    // Static file path (open/stat/canonicalize + zero-copy send).
+    #[allow(non_upper_case_globals)]
+    const SYS_fstat: c_long = 5;
+    #[allow(non_upper_case_globals)]
+    const SYS_lseek: c_long = 8;
+    #[allow(non_upper_case_globals)]
+    const SYS_sendfile: c_long = 40;
+    #[allow(non_upper_case_globals)]
+    const SYS_readlink: c_long = 89;
+    #[allow(non_upper_case_globals)]
+    const SYS_getdents64: c_long = 217;
+    #[allow(non_upper_case_globals)]
+    const SYS_openat: c_long = 257;
+    #[allow(non_upper_case_globals)]
+    const SYS_newfstatat: c_long = 262;
+    #[allow(non_upper_case_globals)]
+    const SYS_statx: c_long = 332;
+    // `TcpStream::set_nonblocking` issues ioctl(FIONBIO); without it sockets silently stay blocking.
+    #[allow(non_upper_case_globals)]
+    const SYS_ioctl: c_long = 16;
+    // glibc `realloc` grows large blocks with mremap (request bodies).
+    #[allow(non_upper_case_globals)]
+    const SYS_mremap: c_long = 25;
+    // Half-close and CONNECT tunnels shut down one direction of a socket.
+    #[allow(non_upper_case_globals)]
+    const SYS_shutdown: c_long = 48;
+    // Threads started after the sandbox (WebSocket handlers): clone3 with clone as
+    // fallback, the stack guard page, signal masking around creation and the
+    // per-thread robust-futex list and rseq registration.
+    #[allow(non_upper_case_globals)]
+    const SYS_clone: c_long = 56;
+    #[allow(non_upper_case_globals)]
+    const SYS_clone3: c_long = 435;
+    #[allow(non_upper_case_globals)]
+    const SYS_mprotect: c_long = 10;
+    #[allow(non_upper_case_globals)]
+    const SYS_rt_sigprocmask: c_long = 14;
+    #[allow(non_upper_case_globals)]
+    const SYS_set_robust_list: c_long = 273;
+    #[allow(non_upper_case_globals)]
+    const SYS_rseq: c_long = 334;
+    // Uploads: fchmod to `upload_file_mode`, unlink of a partly written file.
+    #[allow(non_upper_case_globals)]
+    const SYS_fchmod: c_long = 91;
+    #[allow(non_upper_case_globals)]
+    const SYS_unlink: c_long = 87;
 
     let mut numbers = Vec::<u32>::new();
     for &n in names {
@@ -114,6 +170,9 @@ pub fn generate_and_install(names: &[&str]) -> Result<(), String> {
             "accept" => SYS_accept,
             "accept4" => SYS_accept4,
             "socket" => SYS_socket,
+            "connect" => SYS_connect,
+            "poll" => SYS_poll,
+            "getsockopt" => SYS_getsockopt,
             "bind" => SYS_bind,
             "listen" => SYS_listen,
             "setsockopt" => SYS_setsockopt,
@@ -125,10 +184,30 @@ pub fn generate_and_install(names: &[&str]) -> Result<(), String> {
             "fcntl" => SYS_fcntl,
             "mmap" => SYS_mmap,
             "munmap" => SYS_munmap,
+            "madvise" => SYS_madvise,
             "brk" => SYS_brk,
             "rt_sigreturn" => SYS_rt_sigreturn,
             "rt_sigaction" => SYS_rt_sigaction,
             "sigaltstack" => SYS_sigaltstack,
+            "fstat" => SYS_fstat,
+            "lseek" => SYS_lseek,
+            "sendfile" => SYS_sendfile,
+            "readlink" => SYS_readlink,
+            "getdents64" => SYS_getdents64,
+            "openat" => SYS_openat,
+            "newfstatat" => SYS_newfstatat,
+            "statx" => SYS_statx,
+            "ioctl" => SYS_ioctl,
+            "mremap" => SYS_mremap,
+            "shutdown" => SYS_shutdown,
+            "clone" => SYS_clone,
+            "clone3" => SYS_clone3,
+            "mprotect" => SYS_mprotect,
+            "rt_sigprocmask" => SYS_rt_sigprocmask,
+            "set_robust_list" => SYS_set_robust_list,
+            "rseq" => SYS_rseq,
+            "fchmod" => SYS_fchmod,
+            "unlink" => SYS_unlink,
             _ => return Err(format!("unknown syscall '{}'", n)),
         } as u32;
         numbers.push(num);
@@ -143,7 +222,7 @@ mod linux_dynamic_installer {
 
     pub unsafe fn install_dynamic(syscalls: &[u32]) -> Result<(), String> {
         const ALLOW: i32 = 0x7fff0000;
-        const ERRNO: i32 = 0x00050000;
+        const ERRNO: i32 = 0x00050001; // SECCOMP_RET_ERRNO | EPERM
         const BPF_LD: u16 = 0x00; const BPF_W: u16 = 0x00; const BPF_ABS: u16 = 0x20;
         const BPF_JMP: u16 = 0x05; const BPF_JEQ: u16 = 0x10; const BPF_K: u16 = 0x00;
         const BPF_RET: u16 = 0x06;
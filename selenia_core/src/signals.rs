@@ -1,41 +1,129 @@
 #![cfg(unix)]
 //! Minimal POSIX signal handling without external crates.
-use std::sync::atomic::{AtomicBool, Ordering};
+//!
+//! Handlers only store to atomics (async-signal-safe); the event loops poll the
+//! flags. SIGINT/SIGTERM terminate, SIGHUP requests a reload, SIGUSR1 a diagnostics
+//! dump. [`register_signal`] adds further signals, either as termination signals or
+//! as plain notifications read back with [`take_signal`].
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
 use std::sync::Once;
-use libc::{sigaction, sighandler_t, SIGINT, SIGTERM, SA_RESTART, SIGHUP};
+use libc::{sigaction, sighandler_t, SIGINT, SIGTERM, SA_RESTART, SIGHUP, SIGUSR1};
 
 static INIT: Once = Once::new();
 static TERMINATE: AtomicBool = AtomicBool::new(false);
 static RELOAD: AtomicBool = AtomicBool::new(false);
+static DIAGNOSTICS: AtomicBool = AtomicBool::new(false);
+/// Most recent termination signal, 0 before any.
+static TERM_SIGNAL: AtomicI32 = AtomicI32::new(0);
+/// Signals (bit = signal number) that terminate.
+static TERM_MASK: AtomicU64 = AtomicU64::new((1 << SIGINT) | (1 << SIGTERM));
+/// Registered non-terminating signals received but not yet taken.
+static PENDING: AtomicU64 = AtomicU64::new(0);
 
 extern "C" fn handle_sig(sig: i32) {
+    if !(1..64).contains(&sig) { return; }
+    if TERM_MASK.load(Ordering::SeqCst) & (1 << sig) != 0 {
+        TERM_SIGNAL.store(sig, Ordering::SeqCst);
+        TERMINATE.store(true, Ordering::SeqCst);
+        return;
+    }
     match sig {
-        SIGINT | SIGTERM => TERMINATE.store(true, Ordering::SeqCst),
         SIGHUP => RELOAD.store(true, Ordering::SeqCst),
-        _ => {},
+        SIGUSR1 => DIAGNOSTICS.store(true, Ordering::SeqCst),
+        _ => { PENDING.fetch_or(1 << sig, Ordering::SeqCst); }
     }
 }
 
-/// Install SIGINT/SIGTERM handlers (idempotent).
+fn install(sig: i32) -> bool {
+    let handler: sighandler_t = handle_sig as sighandler_t;
+    let action = sigaction {
+        sa_sigaction: handler,
+        sa_flags: SA_RESTART,
+        sa_restorer: std::ptr::null_mut(),
+        sa_mask: 0,
+    };
+    unsafe { sigaction(sig, &action, std::ptr::null_mut()) == 0 }
+}
+
+/// Install SIGINT/SIGTERM/SIGHUP/SIGUSR1 handlers (idempotent).
 pub fn init_term_signals() {
-    INIT.call_once(|| unsafe {
-        let handler: sighandler_t = handle_sig as sighandler_t;
-        let action = sigaction {
-            sa_sigaction: handler,
-            sa_flags: SA_RESTART,
-            sa_restorer: std::ptr::null_mut(),
-            sa_mask: 0,
-        };
-        let _ = sigaction(SIGINT, &action, std::ptr::null_mut());
-        let _ = sigaction(SIGTERM, &action, std::ptr::null_mut());
-        let _ = sigaction(SIGHUP, &action, std::ptr::null_mut());
+    INIT.call_once(|| {
+        for sig in [SIGINT, SIGTERM, SIGHUP, SIGUSR1] { install(sig); }
     });
 }
 
+/// Handle `sig` in addition to the defaults. With `terminate` it ends the process like
+/// SIGTERM (and is reported by [`termination_signal`]); otherwise each delivery is
+/// recorded for [`take_signal`]. False when `sig` cannot be handled (out of range,
+/// SIGKILL/SIGSTOP).
+pub fn register_signal(sig: i32, terminate: bool) -> bool {
+    if !(1..64).contains(&sig) { return false; }
+    // Set the mask first so a delivery right after installation is classified correctly.
+    if terminate { TERM_MASK.fetch_or(1 << sig, Ordering::SeqCst); } else { TERM_MASK.fetch_and(!(1 << sig), Ordering::SeqCst); }
+    install(sig)
+}
+
 /// Returns true if termination signal received.
 pub fn should_terminate() -> bool { TERMINATE.load(Ordering::SeqCst) }
 
+/// The termination signal received last (e.g. SIGINT vs SIGTERM), if any.
+pub fn termination_signal() -> Option<i32> {
+    Some(TERM_SIGNAL.load(Ordering::SeqCst)).filter(|&s| s != 0)
+}
+
 /// Returns true if reload requested (SIGHUP) and clears flag.
 pub fn take_reload_request() -> bool {
     RELOAD.swap(false, Ordering::SeqCst)
-} 
\ No newline at end of file
+}
+
+/// Returns true if a diagnostics dump was requested (SIGUSR1) and clears flag.
+pub fn take_diagnostics_request() -> bool {
+    DIAGNOSTICS.swap(false, Ordering::SeqCst)
+}
+
+/// Returns true if `sig`, registered without `terminate`, arrived since the last call; clears it.
+pub fn take_signal(sig: i32) -> bool {
+    if !(1..64).contains(&sig) { return false; }
+    PENDING.fetch_and(!(1 << sig), Ordering::SeqCst) & (1 << sig) != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    /// Send `sig` to this process and wait until `seen` reports it.
+    fn raise(sig: i32, seen: impl Fn() -> bool) -> bool {
+        unsafe { libc::kill(std::process::id() as i32, sig) };
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while Instant::now() < deadline {
+            if seen() { return true; }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        false
+    }
+
+    // One test: the termination flag cannot be reset, so the order is part of the check.
+    #[test]
+    fn termination_signal_and_registered_signals_are_told_apart() {
+        init_term_signals();
+        assert_eq!(termination_signal(), None);
+        assert!(!should_terminate());
+        assert!(raise(SIGINT, || termination_signal() == Some(SIGINT)));
+        assert!(should_terminate());
+        assert!(raise(SIGTERM, || termination_signal() == Some(SIGTERM)));
+
+        // SIGUSR2 (12 on Linux) as a plain notification, taken once.
+        #[cfg(target_os = "linux")]
+        {
+            assert!(register_signal(12, false));
+            assert!(raise(12, || PENDING.load(Ordering::SeqCst) & (1 << 12) != 0));
+            assert!(take_signal(12));
+            assert!(!take_signal(12));
+            assert_eq!(termination_signal(), Some(SIGTERM));
+        }
+        assert!(!register_signal(0, false));
+        assert!(!register_signal(64, true));
+        assert!(!register_signal(9, true)); // SIGKILL cannot be caught
+    }
+}
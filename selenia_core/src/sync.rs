@@ -0,0 +1,175 @@
+//! Lock-free shared state.
+//!
+//! `ArcSwap<T>`: an `Arc<T>` that can be replaced while other threads read it, for
+//! values read on every request or handshake and replaced rarely (the worker's live
+//! `ServerConfig`, the stapled OCSP response).
+//!
+//! Readers never lock: `load` announces itself in one of two reader counters, reads
+//! the pointer and takes its own strong reference. `store` publishes the new
+//! pointer and then waits until both counters have drained once, so no reader can
+//! still be between reading the old pointer and owning a reference to it when the
+//! old `Arc` is released. Writers are serialized by a mutex; they are expected to be
+//! rare (reloads), reads frequent (every request).
+
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+pub struct ArcSwap<T> {
+    ptr: AtomicPtr<T>,
+    /// Readers inside `load`, split by the parity of `epoch` they saw on entry.
+    readers: [AtomicUsize; 2],
+    epoch: AtomicUsize,
+    writer: Mutex<()>,
+    _owns: PhantomData<Arc<T>>,
+}
+
+// Same bounds as `Arc<T>` itself: values cross threads through `load`/`store`.
+unsafe impl<T: Send + Sync> Send for ArcSwap<T> {}
+unsafe impl<T: Send + Sync> Sync for ArcSwap<T> {}
+
+impl<T> ArcSwap<T> {
+    pub fn new(value: Arc<T>) -> Self {
+        ArcSwap {
+            ptr: AtomicPtr::new(Arc::into_raw(value) as *mut T),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            epoch: AtomicUsize::new(0),
+            writer: Mutex::new(()),
+            _owns: PhantomData,
+        }
+    }
+
+    /// The current value. Never blocks; a concurrent `store` is seen entirely or not at all.
+    pub fn load(&self) -> Arc<T> {
+        let slot = &self.readers[self.epoch.load(Ordering::SeqCst) & 1];
+        slot.fetch_add(1, Ordering::SeqCst);
+        let ptr = self.ptr.load(Ordering::SeqCst);
+        // SAFETY: `ptr` came from `Arc::into_raw` and its reference is not released
+        // while this reader is counted (see `store`).
+        let value = unsafe {
+            Arc::increment_strong_count(ptr);
+            Arc::from_raw(ptr)
+        };
+        slot.fetch_sub(1, Ordering::Release);
+        value
+    }
+
+    /// Replace the value; returns once no reader can still reach the old one except
+    /// through an `Arc` it already holds.
+    pub fn store(&self, value: Arc<T>) {
+        drop(self.swap(value));
+    }
+
+    /// Replace the value and return the previous one (see `store`).
+    pub fn swap(&self, value: Arc<T>) -> Arc<T> {
+        let _writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let old = self.ptr.swap(Arc::into_raw(value) as *mut T, Ordering::SeqCst);
+        // Flip twice so both counters are seen empty after the swap. New readers enter
+        // the other counter, so each wait ends once the readers already inside leave.
+        for _ in 0..2 {
+            let drained = self.epoch.fetch_add(1, Ordering::SeqCst) & 1;
+            while self.readers[drained].load(Ordering::SeqCst) != 0 {
+                std::thread::yield_now();
+            }
+        }
+        // SAFETY: the reference handed over by `new`/`swap`; no reader is still acquiring it.
+        unsafe { Arc::from_raw(old) }
+    }
+}
+
+impl<T> Drop for ArcSwap<T> {
+    fn drop(&mut self) {
+        // SAFETY: `&mut self` rules out readers; this releases the stored reference.
+        unsafe { drop(Arc::from_raw(*self.ptr.get_mut())) };
+    }
+}
+
+impl<T: Default> Default for ArcSwap<T> {
+    fn default() -> Self { ArcSwap::new(Arc::new(T::default())) }
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for ArcSwap<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ArcSwap").field(&self.load()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! A use-after-free or double release here is a crash under
+    //! `RUSTFLAGS=-Zsanitizer=address cargo +nightly test`; the live count catches leaks.
+    use super::ArcSwap;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Both halves are written together; a reader seeing them differ saw a torn value.
+    struct Pair {
+        a: u64,
+        b: u64,
+        live: Arc<AtomicUsize>,
+    }
+
+    impl Pair {
+        fn new(n: u64, live: &Arc<AtomicUsize>) -> Arc<Pair> {
+            live.fetch_add(1, Ordering::SeqCst);
+            Arc::new(Pair { a: n, b: !n, live: Arc::clone(live) })
+        }
+    }
+
+    impl Drop for Pair {
+        fn drop(&mut self) {
+            assert_eq!(self.a, !self.b, "dropped twice or torn");
+            self.b = self.a; // a second drop of the same value fails the check above
+            self.live.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn readers_see_whole_values_under_concurrent_stores() {
+        let live = Arc::new(AtomicUsize::new(0));
+        let swap = Arc::new(ArcSwap::new(Pair::new(0, &live)));
+        let stop = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4).map(|_| {
+            let (swap, stop) = (Arc::clone(&swap), Arc::clone(&stop));
+            thread::spawn(move || {
+                let (mut loads, mut last) = (0u64, 0u64);
+                while !stop.load(Ordering::Relaxed) {
+                    let v = swap.load();
+                    assert_eq!(v.a, !v.b);
+                    // One writer stores increasing values, so a reader never goes back.
+                    assert!(v.a >= last);
+                    last = v.a;
+                    loads += 1;
+                }
+                loads
+            })
+        }).collect();
+        let writer = {
+            let (swap, live) = (Arc::clone(&swap), Arc::clone(&live));
+            thread::spawn(move || for n in 1..=20_000 { swap.store(Pair::new(n, &live)); })
+        };
+        writer.join().unwrap();
+        stop.store(true, Ordering::Relaxed);
+        for r in readers { assert!(r.join().unwrap() > 0); }
+        assert_eq!(swap.load().a, 20_000);
+        // Only the current value is left, and it goes with the swap.
+        assert_eq!(live.load(Ordering::SeqCst), 1);
+        drop(swap);
+        assert_eq!(live.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn loaded_values_outlive_their_replacement() {
+        let live = Arc::new(AtomicUsize::new(0));
+        let swap = ArcSwap::new(Pair::new(1, &live));
+        let held = swap.load();
+        let old = swap.swap(Pair::new(2, &live));
+        assert!(Arc::ptr_eq(&held, &old));
+        drop(old);
+        assert_eq!(held.a, 1);
+        assert_eq!(swap.load().a, 2);
+        drop(held);
+        assert_eq!(live.load(Ordering::SeqCst), 1);
+    }
+}
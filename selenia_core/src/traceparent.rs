@@ -33,7 +33,8 @@ impl TraceContext {
         if trace_id_bytes.len()!=16 || span_id_bytes.len()!=8 { return None; }
         let mut trace_id=[0u8;16]; trace_id.copy_from_slice(&trace_id_bytes);
         let mut span_id=[0u8;8]; span_id.copy_from_slice(&span_id_bytes);
-        let sampled = parts[3]=="01";
+        let flags = from_hex(parts[3]).filter(|f| f.len()==1)?;
+        let sampled = flags[0] & 1 != 0;
         Some(TraceContext{trace_id,span_id,sampled})
     }
 
@@ -43,7 +44,44 @@ impl TraceContext {
         Self{trace_id,span_id,sampled:true}
     }
 
+    /// Fresh context that is sampled for `rate` (0.0–1.0) of trace ids. The decision
+    /// comes from the trace id itself, so every hop using the same rate agrees.
+    pub fn generate_sampled(rate: f64) -> Self {
+        let mut ctx = Self::generate();
+        let mut low = [0u8;8]; low.copy_from_slice(&ctx.trace_id[8..]);
+        ctx.sampled = rate >= 1.0 || (u64::from_be_bytes(low) as f64) < rate * u64::MAX as f64;
+        ctx
+    }
+
+    /// 32-hex-digit trace id, as it appears in the header.
+    pub fn trace_id_hex(&self) -> String { to_hex(&self.trace_id) }
+
     pub fn header(&self) -> String {
         format!("00-{}-{}-{:02x}", to_hex(&self.trace_id), to_hex(&self.span_id), if self.sampled { 1 } else { 0 })
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TraceContext;
+
+    #[test]
+    fn generated_contexts_are_sampled_at_the_rate() {
+        let sampled = |rate: f64| (0..10_000).filter(|_| TraceContext::generate_sampled(rate).sampled).count();
+        let quarter = sampled(0.25);
+        assert!((2_200..2_800).contains(&quarter), "{}", quarter);
+        assert_eq!(sampled(0.0), 0);
+        assert_eq!(sampled(1.0), 10_000);
+    }
+
+    #[test]
+    fn incoming_sampled_flag_is_kept_and_propagated() {
+        let on = TraceContext::parse("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01").unwrap();
+        assert!(on.sampled);
+        assert_eq!(on.header(), "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01");
+        let off = TraceContext::parse("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00").unwrap();
+        assert!(!off.sampled);
+        assert!(off.header().ends_with("-00"));
+        assert!(TraceContext::parse("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-1").is_none());
+    }
+}
@@ -10,25 +10,105 @@
 //! • Parses type/import/function/export sections to locate `_start`.
 //! • Executes the byte-code in a tiny stack-based interpreter supporting the
 //!   numeric ops typically emitted by Rust `no_std` WASI hello-world.
-//! • 64-KiB linear memory, bounds-checked; no host imports are allowed other
-//!   than WASI `fd_write` mapped to a sandboxed stdout buffer.
+//! • 64-KiB linear memory, bounds-checked, zeroed and shrunk back to that one
+//!   page at the start of every invocation so nothing one request left behind is
+//!   visible to the next; `memory.grow` adds pages up to `max_memory_pages`. No
+//!   host imports are allowed other than WASI `fd_write` mapped to a sandboxed
+//!   stdout buffer.
 //! • Instruction budget (fuel) to prevent infinite loops.
+//! • `WasmInstance::run` additionally holds one of `max_concurrent` process-wide
+//!   execution slots (waiting up to `queue_timeout` for one, then `Busy`) and stops
+//!   the function after `timeout` of wall-clock time, whatever fuel is left.
 //! 
 //! This implementation is adequate for demo edge functions (e.g. returning a
 //! computed string) and can be expanded incrementally.
 
 use core::convert::TryInto;
+use std::sync::{Condvar, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 const WASM_MAGIC: [u8;4] = [0x00,0x61,0x73,0x6d];
 const WASM_VERSION: [u8;4] = [0x01,0x00,0x00,0x00];
 
 #[derive(Debug)]
-pub enum WasmError { InvalidModule, NoStart, FuelExhausted, Trap }
+pub enum WasmError {
+    InvalidModule,
+    NoStart,
+    FuelExhausted,
+    Trap,
+    /// Every execution slot stayed taken for `queue_timeout`.
+    Busy,
+    /// Ran past the wall-clock `timeout`.
+    Timeout,
+}
+
+/// Process-wide limits on `WasmInstance::run` (`wasm:` config block).
+#[derive(Debug, Clone, Copy)]
+pub struct ExecLimits {
+    /// Executions running at once; 0 is unlimited.
+    pub max_concurrent: usize,
+    /// How long `run` waits for a free slot; zero sheds at once.
+    pub queue_timeout: Duration,
+    /// Wall-clock budget per execution; zero is none (fuel only).
+    pub timeout: Duration,
+    /// Ceiling for `memory.grow`, in 64 KiB pages (applies to `execute` too).
+    pub max_memory_pages: u32,
+}
+
+impl Default for ExecLimits {
+    fn default() -> Self { DEFAULT_LIMITS }
+}
+
+const DEFAULT_LIMITS: ExecLimits = ExecLimits { max_concurrent: 4, queue_timeout: Duration::from_millis(100), timeout: Duration::from_millis(50), max_memory_pages: 4 };
+const PAGE_SIZE: usize = 64 * 1024;
+/// Linear memory every invocation starts with.
+const INITIAL_PAGES: u32 = 1;
+/// Instructions between wall-clock checks.
+const CLOCK_CHECK_INTERVAL: u32 = 1024;
+
+static LIMITS: RwLock<ExecLimits> = RwLock::new(DEFAULT_LIMITS);
+static RUNNING: Mutex<usize> = Mutex::new(0);
+static SLOT_FREED: Condvar = Condvar::new();
+
+/// Replace the limits for executions started from now on.
+pub fn configure(limits: ExecLimits) {
+    *LIMITS.write().unwrap_or_else(|e| e.into_inner()) = limits;
+}
+
+/// One execution slot, given back on drop.
+struct Slot;
+
+impl Slot {
+    fn acquire(limits: &ExecLimits) -> Result<Slot, WasmError> {
+        let mut running = RUNNING.lock().unwrap_or_else(|e| e.into_inner());
+        if limits.max_concurrent > 0 {
+            let give_up = Instant::now() + limits.queue_timeout;
+            while *running >= limits.max_concurrent {
+                let left = give_up.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    crate::metrics::inc_wasm_rejected();
+                    return Err(WasmError::Busy);
+                }
+                running = SLOT_FREED.wait_timeout(running, left).unwrap_or_else(|e| e.into_inner()).0;
+            }
+        }
+        *running += 1;
+        Ok(Slot)
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        *RUNNING.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+        SLOT_FREED.notify_one();
+    }
+}
 
 pub struct WasmInstance {
     code: Vec<u8>,
     start_offset: usize,
-    memory: Vec<u8>, // 256 KiB linear memory
+    /// Linear memory of the current or last invocation.
+    memory: Vec<u8>,
 }
 
 impl WasmInstance {
@@ -71,7 +151,12 @@ impl WasmInstance {
                 let (count,m)=leb_u32(&buf[ptr..]); ptr+=m;
                 for _ in 0..count {
                     let (body_size,b)=leb_u32(&buf[ptr..]); ptr+=b;
-                    if func_counter==start_idx { func_body_off=Some(ptr); break; }
+                    if func_counter==start_idx {
+                        // Skip the local declarations: count, then (n, valtype) pairs.
+                        let (groups,g)=leb_u32(&buf[ptr..]); let mut body=ptr+g;
+                        for _ in 0..groups { body+=leb_u32(&buf[body..]).1+1; }
+                        func_body_off=Some(body); break;
+                    }
                     ptr+=body_size as usize;
                     func_counter+=1;
                 }
@@ -80,17 +165,49 @@ impl WasmInstance {
             idx+=size as usize;
         }
         let start_offset = func_body_off.ok_or(WasmError::NoStart)?;
-        Ok(Self { code: buf.to_vec(), start_offset, memory: vec![0; 256*1024] })
+        Ok(Self { code: buf.to_vec(), start_offset, memory: Vec::new() })
+    }
+
+    /// Run `_start` under the process-wide `ExecLimits`: wait for an execution slot,
+    /// then interpret with `fuel` and the wall-clock timeout. Counted in metrics.
+    pub fn run(&mut self, fuel: u32) -> Result<(), WasmError> {
+        let limits = *LIMITS.read().unwrap_or_else(|e| e.into_inner());
+        let _slot = Slot::acquire(&limits)?;
+        crate::metrics::inc_wasm_executions();
+        let deadline = (!limits.timeout.is_zero()).then(|| Instant::now() + limits.timeout);
+        let result = self.interpret(fuel, deadline, limits.max_memory_pages);
+        match result {
+            Err(WasmError::FuelExhausted) => crate::metrics::inc_wasm_fuel_exhausted(),
+            Err(WasmError::Timeout) => crate::metrics::inc_wasm_timeouts(),
+            _ => {}
+        }
+        result
     }
 
+    /// Interpret `_start` with `fuel` only: no slot, no wall-clock limit.
     pub fn execute(&mut self, fuel: u32) -> Result<(), WasmError> {
-        // Tiny interpreter supporting only a subset (i32.const, i32.add, call, end)
+        let max_pages = LIMITS.read().unwrap_or_else(|e| e.into_inner()).max_memory_pages;
+        self.interpret(fuel, None, max_pages)
+    }
+
+    /// Linear memory as the last invocation left it, for the host to read results from.
+    pub fn memory(&self) -> &[u8] { &self.memory }
+
+    fn interpret(&mut self, fuel: u32, deadline: Option<Instant>, max_pages: u32) -> Result<(), WasmError> {
+        // Fresh zeroed memory; the allocation is reused, its contents never are.
+        self.memory.clear();
+        self.memory.resize(INITIAL_PAGES as usize * PAGE_SIZE, 0);
+        // Tiny interpreter supporting only a subset (i32.const, i32.add, i32.load/store,
+        // memory.size/grow, drop, call, end)
         let mut pc = self.start_offset;
         let mut stack: Vec<i32> = Vec::new();
         let mut remaining = fuel as i32;
         loop {
             if remaining==0 { return Err(WasmError::FuelExhausted); }
             remaining-=1;
+            if remaining as u32 % CLOCK_CHECK_INTERVAL == 0 && deadline.map_or(false, |d| Instant::now() >= d) {
+                return Err(WasmError::Timeout);
+            }
             match self.code[pc] {
                 0x41 => { // i32.const
                     let (val, n)=leb_u32(&self.code[pc+1..]);
@@ -105,6 +222,32 @@ impl WasmInstance {
                     let (idx,n)=leb_u32(&self.code[pc+1..]); pc+=1+n;
                     if idx==0 { /* stub fd_write */ pc+=0; continue; } else { return Err(WasmError::Trap); }
                 }
+                0x1a => { stack.pop().ok_or(WasmError::Trap)?; pc+=1; } // drop
+                0x28 => { // i32.load align offset
+                    let (offset, n)=memarg(&self.code[pc+1..]); pc+=1+n;
+                    let addr=stack.pop().ok_or(WasmError::Trap)?;
+                    let at=effective(addr, offset, self.memory.len())?;
+                    stack.push(i32::from_le_bytes(self.memory[at..at+4].try_into().unwrap()));
+                }
+                0x36 => { // i32.store align offset
+                    let (offset, n)=memarg(&self.code[pc+1..]); pc+=1+n;
+                    let val=stack.pop().ok_or(WasmError::Trap)?;
+                    let addr=stack.pop().ok_or(WasmError::Trap)?;
+                    let at=effective(addr, offset, self.memory.len())?;
+                    self.memory[at..at+4].copy_from_slice(&val.to_le_bytes());
+                }
+                0x3f => { // memory.size 0x00
+                    stack.push((self.memory.len()/PAGE_SIZE) as i32); pc+=2;
+                }
+                0x40 => { // memory.grow 0x00: old size in pages, or -1 past the limit
+                    let delta=stack.pop().ok_or(WasmError::Trap)? as u32;
+                    let pages=(self.memory.len()/PAGE_SIZE) as u32;
+                    match pages.checked_add(delta).filter(|&p| p<=max_pages) {
+                        Some(p) => { self.memory.resize(p as usize*PAGE_SIZE, 0); stack.push(pages as i32); }
+                        None => stack.push(-1),
+                    }
+                    pc+=2;
+                }
                 0x0b => break, // end
                 _ => return Err(WasmError::Trap),
             }
@@ -114,6 +257,19 @@ impl WasmInstance {
 }
 
 // -------------------- helpers --------------------
+/// memarg immediate (align, offset) -> (offset, bytes read).
+fn memarg(buf: &[u8]) -> (u32, usize) {
+    let (_, a)=leb_u32(buf);
+    let (offset, o)=leb_u32(&buf[a..]);
+    (offset, a+o)
+}
+
+/// Start of a 4-byte access at `addr + offset`; out of bounds traps.
+fn effective(addr: i32, offset: u32, mem_len: usize) -> Result<usize, WasmError> {
+    let at = addr as u32 as usize + offset as usize;
+    if at + 4 > mem_len { return Err(WasmError::Trap); }
+    Ok(at)
+}
 fn leb_u32(buf: &[u8]) -> (u32, usize) {
     let mut result=0u32; let mut shift=0; let mut idx=0;
     loop { let b=buf[idx]; idx+=1; result |= ((b&0x7f) as u32)<<shift; if b&0x80==0 { break; } shift+=7; }
@@ -124,4 +280,116 @@ fn parse_name(buf: &[u8]) -> (String, usize) {
     let (len, n)=leb_u32(buf); let start=n; let end=start+len as usize;
     let s=core::str::from_utf8(&buf[start..end]).unwrap_or("").to_string();
     (s, n+len as usize)
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Held by tests that change the process-wide limits.
+    static LIMITS_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Module exporting one function, `_start`, with no locals and body `code` + `end`.
+    fn module(code: &[u8]) -> Vec<u8> {
+        let section = |id: u8, body: &[u8]| {
+            let mut s = vec![id];
+            s.extend(leb(body.len() as u32));
+            s.extend_from_slice(body);
+            s
+        };
+        let mut body = vec![0]; // no locals
+        body.extend_from_slice(code);
+        body.push(0x0b);
+        let mut funcs = vec![1];
+        funcs.extend(leb(body.len() as u32));
+        funcs.extend(body);
+        let mut m = [&WASM_MAGIC[..], &WASM_VERSION[..]].concat();
+        m.extend(section(7, &[&[1, 6][..], b"_start", &[0x00, 0]].concat()));
+        m.extend(section(10, &funcs));
+        m
+    }
+
+    fn leb(mut v: u32) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let b = (v & 0x7f) as u8;
+            v >>= 7;
+            if v == 0 { out.push(b); return out; }
+            out.push(b | 0x80);
+        }
+    }
+
+    fn limits(max_concurrent: usize, queue_timeout_ms: u64, timeout_ms: u64) -> ExecLimits {
+        ExecLimits { max_concurrent, queue_timeout: Duration::from_millis(queue_timeout_ms), timeout: Duration::from_millis(timeout_ms), ..DEFAULT_LIMITS }
+    }
+
+    #[test]
+    fn executions_beyond_the_cap_queue_then_shed() {
+        let _lock = LIMITS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut inst = WasmInstance::new(&module(&[0x41, 7, 0x1a])).unwrap();
+        configure(limits(1, 0, 50));
+        let held = Slot::acquire(&limits(1, 0, 50)).unwrap();
+        let rejected = crate::metrics::counter_value("sws_wasm_rejected_total").unwrap();
+        assert!(matches!(inst.run(100), Err(WasmError::Busy)));
+        assert!(crate::metrics::counter_value("sws_wasm_rejected_total").unwrap() > rejected);
+        // With a queue the run waits for the slot to come free.
+        configure(limits(1, 2000, 50));
+        let started = Instant::now();
+        let release = std::thread::spawn(move || { std::thread::sleep(Duration::from_millis(50)); drop(held); });
+        assert!(inst.run(100).is_ok());
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        release.join().unwrap();
+        configure(DEFAULT_LIMITS);
+    }
+
+    #[test]
+    fn runaway_function_is_stopped_by_the_clock() {
+        let _lock = LIMITS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let code = [0x41, 0, 0x1a].repeat(2_000_000);
+        let mut inst = WasmInstance::new(&module(&code)).unwrap();
+        configure(limits(4, 0, 1));
+        let timeouts = crate::metrics::counter_value("sws_wasm_timeouts_total").unwrap();
+        let started = Instant::now();
+        assert!(matches!(inst.run(u32::MAX), Err(WasmError::Timeout)));
+        assert!(started.elapsed() < Duration::from_millis(500));
+        assert!(crate::metrics::counter_value("sws_wasm_timeouts_total").unwrap() > timeouts);
+        // Fuel still bounds it too, and `execute` has no clock at all.
+        assert!(matches!(inst.run(100), Err(WasmError::FuelExhausted)));
+        assert!(inst.execute(u32::MAX).is_ok());
+        configure(DEFAULT_LIMITS);
+    }
+
+    #[test]
+    fn each_invocation_starts_from_zeroed_memory() {
+        // mem[0] = mem[0] + 1
+        let bump = [0x41, 0, 0x41, 0, 0x28, 2, 0, 0x41, 1, 0x6a, 0x36, 2, 0];
+        let mut inst = WasmInstance::new(&module(&bump)).unwrap();
+        for _ in 0..3 {
+            inst.execute(100).unwrap();
+            assert_eq!(inst.memory()[..4], 1i32.to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn memory_grows_up_to_the_page_limit_and_no_further() {
+        // mem[0] = memory.grow(2); mem[65536 + 4] = memory.size; mem[4] = memory.grow(8)
+        let code = [
+            0x41, 0, 0x41, 2, 0x40, 0, 0x36, 2, 0,
+            0x41, 0x84, 0x80, 0x04, 0x3f, 0, 0x36, 2, 0,
+            0x41, 4, 0x41, 8, 0x40, 0, 0x36, 2, 0,
+        ];
+        let mut inst = WasmInstance::new(&module(&code)).unwrap();
+        // The second run starts from one page again: growth does not carry over.
+        for _ in 0..2 {
+            inst.execute(100).unwrap();
+            let word = |at: usize| i32::from_le_bytes(inst.memory()[at..at + 4].try_into().unwrap());
+            assert_eq!(word(0), 1);
+            assert_eq!(word(PAGE_SIZE + 4), 3);
+            assert_eq!(word(4), -1);
+            assert_eq!(inst.memory().len(), 3 * PAGE_SIZE);
+        }
+        // Past the current size is out of bounds.
+        let mut oob = WasmInstance::new(&module(&[0x41, 0x80, 0x80, 0x04, 0x28, 2, 0, 0x1a])).unwrap();
+        assert!(matches!(oob.execute(10), Err(WasmError::Trap)));
+    }
+}
@@ -2,27 +2,53 @@
 //! Listener helper for SO_REUSEPORT + accept thread per CPU.
 
 use std::io::{Error, Result};
-use std::net::{TcpListener, TcpStream};
-use std::os::unix::io::{FromRawFd, RawFd};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Sender;
-use std::thread;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use selenia_core::config::ListenMode;
+
+/// Clamp the configured backlog to what the kernel will actually honour.
+/// Linux silently truncates to `net.core.somaxconn`, so reading it up-front keeps logs honest.
+pub fn effective_backlog(requested: u32) -> libc::c_int {
+    let mut max = libc::c_int::MAX as u32;
+    #[cfg(target_os = "linux")]
+    if let Ok(s) = std::fs::read_to_string("/proc/sys/net/core/somaxconn") {
+        if let Ok(v) = s.trim().parse::<u32>() { if v > 0 { max = max.min(v); } }
+    }
+    requested.clamp(1, max) as libc::c_int
+}
 
 /// Create a TcpListener with SO_REUSEPORT enabled and bound to `addr`.
-pub fn create_reuseport_listener(addr: &str) -> Result<TcpListener> {
+/// `backlog` is passed to listen(2) after clamping via [`effective_backlog`].
+/// A non-zero `fastopen` sets the TCP Fast Open queue length on Linux; kernels
+/// without it leave the listener as it is.
+pub fn create_reuseport_listener(addr: &str, backlog: u32, fastopen: u32) -> Result<TcpListener> {
     use std::mem::size_of_val;
     use std::ffi::CString;
+    #[cfg(not(target_os = "linux"))]
+    let _ = fastopen;
 
     // Resolve address using libc's getaddrinfo for IPv4/IPv6 flexibility.
-    let c_addr = CString::new(addr).unwrap();
+    // getaddrinfo wants node and service separately: "host:port" / "[v6]:port"; it
+    // takes a zone identifier ("fe80::1%eth0") as the scope of a link-local address.
+    let (host, port) = selenia_core::config::split_host_port(addr)
+        .ok_or_else(|| Error::new(std::io::ErrorKind::InvalidInput, "invalid address"))?;
+    let c_host = CString::new(host).map_err(|_| Error::new(std::io::ErrorKind::InvalidInput, "invalid address"))?;
+    let c_port = CString::new(port).map_err(|_| Error::new(std::io::ErrorKind::InvalidInput, "invalid address"))?;
     let mut hints: libc::addrinfo = unsafe { std::mem::zeroed() };
     hints.ai_family = libc::AF_UNSPEC;
     hints.ai_socktype = libc::SOCK_STREAM;
     hints.ai_flags = libc::AI_PASSIVE;
     let mut res: *mut libc::addrinfo = std::ptr::null_mut();
-    let gai_ret = unsafe { libc::getaddrinfo(c_addr.as_ptr(), std::ptr::null(), &hints, &mut res) };
+    let gai_ret = unsafe { libc::getaddrinfo(c_host.as_ptr(), c_port.as_ptr(), &hints, &mut res) };
     if gai_ret != 0 {
         return Err(Error::new(std::io::ErrorKind::InvalidInput, "invalid address"));
     }
+    let backlog = effective_backlog(backlog);
     let mut last_err = None;
     let mut ptr = res;
     while !ptr.is_null() {
@@ -39,8 +65,13 @@ pub fn create_reuseport_listener(addr: &str) -> Result<TcpListener> {
             libc::setsockopt(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, &on as *const _ as _, size_of_val(&on) as _);
             #[cfg(target_os = "linux")]
             libc::setsockopt(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, &on as *const _ as _, size_of_val(&on) as _);
+            #[cfg(target_os = "linux")]
+            if fastopen > 0 {
+                let qlen = fastopen.min(libc::c_int::MAX as u32) as libc::c_int;
+                libc::setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_FASTOPEN, &qlen as *const _ as _, size_of_val(&qlen) as _);
+            }
 
-            if libc::bind(fd, ai.ai_addr, ai.ai_addrlen) == 0 && libc::listen(fd, 1024) == 0 {
+            if libc::bind(fd, ai.ai_addr, ai.ai_addrlen) == 0 && libc::listen(fd, backlog) == 0 {
                 // Success.
                 let lst = TcpListener::from_raw_fd(fd);
                 unsafe { libc::freeaddrinfo(res) };
@@ -55,15 +86,97 @@ pub fn create_reuseport_listener(addr: &str) -> Result<TcpListener> {
     Err(last_err.unwrap_or_else(|| Error::new(std::io::ErrorKind::Other, "create listener failed")))
 }
 
-/// Spawn an accept thread for `listener`. Accepted streams are sent to `chan`.
-pub fn spawn_accept_thread(listener: TcpListener, chan: Sender<TcpStream>) {
+/// Environment variable through which the master hands a worker its listening
+/// sockets: `addr=fd` pairs joined by `,`, `addr` as written in `listen`.
+pub const LISTEN_FDS_ENV: &str = "SWS_LISTEN_FDS";
+
+/// Value of [`LISTEN_FDS_ENV`] for `listeners`.
+pub fn encode_listen_fds<'a>(listeners: impl IntoIterator<Item = (&'a str, RawFd)>) -> String {
+    listeners.into_iter().map(|(addr, fd)| format!("{}={}", addr, fd)).collect::<Vec<_>>().join(",")
+}
+
+/// Listeners inherited from the master through [`LISTEN_FDS_ENV`], by address. The
+/// variable is cleared so nothing this process spawns claims the same fds.
+pub fn take_inherited_listeners() -> Vec<(String, TcpListener)> {
+    let Some(value) = std::env::var_os(LISTEN_FDS_ENV) else { return Vec::new() };
+    std::env::remove_var(LISTEN_FDS_ENV);
+    value.to_string_lossy().split(',').filter_map(|pair| {
+        let (addr, fd) = pair.rsplit_once('=')?;
+        let fd: RawFd = fd.parse().ok().filter(|&fd| fd > 2)?;
+        // SAFETY: the master passed this fd for us alone; nothing else in this process owns it.
+        Some((addr.to_string(), unsafe { TcpListener::from_raw_fd(fd) }))
+    }).collect()
+}
+
+/// Multiplier of the client-address hash (Knuth's multiplicative constant).
+const SHARD_HASH_MUL: u32 = 0x9E37_79B1;
+
+/// Listener index a client lands on under `sticky_routing` with `shards` workers.
+/// Mirrors the reuseport program of [`attach_ip_steering`]: IPv4 hashes the whole
+/// address, IPv6 its low 32 bits (so IPv4-mapped peers agree with plain IPv4).
+pub fn shard_for(ip: IpAddr, shards: u32) -> u32 {
+    let key = match ip {
+        IpAddr::V4(a) => u32::from(a),
+        IpAddr::V6(a) => { let o = a.octets(); u32::from_be_bytes([o[12], o[13], o[14], o[15]]) }
+    };
+    (key.wrapping_mul(SHARD_HASH_MUL) >> 16) % shards.max(1)
+}
+
+/// Steer new connections of the reuseport group `listener` belongs to by client
+/// address ([`shard_for`]) instead of the kernel's 4-tuple hash, so one client's
+/// connections keep landing on the same worker. The program applies to the whole
+/// group; an index past the group's current size (a worker gone) makes the kernel
+/// fall back to its default selection.
+#[cfg(target_os = "linux")]
+pub fn attach_ip_steering(listener: &TcpListener, shards: u32) -> Result<()> {
+    #[repr(C)] struct sock_filter { code: u16, jt: u8, jf: u8, k: u32 }
+    #[repr(C)] struct sock_fprog { len: u16, filter: *const sock_filter }
+    const fn stmt(code: u16, k: u32) -> sock_filter { sock_filter { code, jt: 0, jf: 0, k } }
+    const fn jmp(code: u16, k: u32, jt: u8, jf: u8) -> sock_filter { sock_filter { code, jt, jf, k } }
+    // Classic BPF opcodes.
+    const LD_B_ABS: u16 = 0x30; const LD_W_ABS: u16 = 0x20;
+    const ALU_RSH_K: u16 = 0x74; const ALU_MUL_K: u16 = 0x24; const ALU_MOD_K: u16 = 0x94;
+    const JEQ_K: u16 = 0x15; const JA: u16 = 0x05; const RET_A: u16 = 0x16;
+    // Absolute loads relative to the network header (SKF_NET_OFF).
+    const NET: u32 = (-0x10_0000i32) as u32;
+
+    let prog = [
+        stmt(LD_B_ABS, NET),            // A = version/IHL byte
+        stmt(ALU_RSH_K, 4),             // A = IP version
+        jmp(JEQ_K, 6, 0, 2),
+        stmt(LD_W_ABS, NET + 20),       // IPv6: low word of the source address
+        stmt(JA, 1),
+        stmt(LD_W_ABS, NET + 12),       // IPv4: source address
+        stmt(ALU_MUL_K, SHARD_HASH_MUL),
+        stmt(ALU_RSH_K, 16),
+        stmt(ALU_MOD_K, shards.max(1)),
+        stmt(RET_A, 0),
+    ];
+    let fprog = sock_fprog { len: prog.len() as u16, filter: prog.as_ptr() };
+    let rc = unsafe {
+        libc::setsockopt(listener.as_raw_fd(), libc::SOL_SOCKET, libc::SO_ATTACH_REUSEPORT_CBPF,
+            &fprog as *const _ as _, std::mem::size_of::<sock_fprog>() as _)
+    };
+    if rc != 0 { return Err(Error::last_os_error()); }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn attach_ip_steering(_listener: &TcpListener, _shards: u32) -> Result<()> {
+    Err(Error::new(std::io::ErrorKind::Unsupported, "reuseport steering needs Linux"))
+}
+
+/// Spawn an accept thread for `listener`. Accepted streams, their peer address, the
+/// listener's `mode` and its `index` in `listen` are sent to `chan`.
+/// The thread returns, closing the listener, once `stop` is set or the receiving loop is gone.
+pub fn spawn_accept_thread(listener: TcpListener, mode: ListenMode, index: usize, chan: Sender<(TcpStream, SocketAddr, ListenMode, usize)>, stop: Arc<AtomicBool>) -> JoinHandle<()> {
     thread::Builder::new()
         .name("accept-thread".into())
-        .spawn(move || loop {
+        .spawn(move || while !stop.load(Ordering::Acquire) {
             match listener.accept() {
-                Ok((stream, _addr)) => {
+                Ok((stream, addr)) => {
                     let _ = stream.set_nonblocking(true);
-                    let _ = chan.send(stream);
+                    if chan.send((stream, addr, mode, index)).is_err() { return; }
                 }
                 Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                     thread::yield_now();
@@ -74,5 +187,140 @@ pub fn spawn_accept_thread(listener: TcpListener, chan: Sender<TcpStream>) {
                 }
             }
         })
-        .expect("spawn accept thread");
-} 
\ No newline at end of file
+        .expect("spawn accept thread")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    fn somaxconn() -> libc::c_int {
+        std::fs::read_to_string("/proc/sys/net/core/somaxconn").unwrap().trim().parse().unwrap()
+    }
+
+    /// The accept queue limit of a listening socket, which Linux reports as `tcpi_sacked`.
+    #[cfg(target_os = "linux")]
+    fn accept_queue_limit(listener: &TcpListener) -> u32 {
+        let mut info = [0u8; 104];
+        let mut len = info.len() as libc::c_uint;
+        let rc = unsafe { libc::getsockopt(listener.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_INFO, info.as_mut_ptr() as _, &mut len) };
+        assert_eq!(rc, 0);
+        u32::from_ne_bytes(info[28..32].try_into().unwrap())
+    }
+
+    #[test]
+    fn backlog_is_clamped_to_the_kernel_range() {
+        assert_eq!(effective_backlog(0), 1);
+        assert_eq!(effective_backlog(7), 7);
+        #[cfg(target_os = "linux")]
+        assert_eq!(effective_backlog(u32::MAX), somaxconn());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn configured_backlog_is_applied() {
+        let listener = create_reuseport_listener("127.0.0.1:0", 7, 0).unwrap();
+        assert_eq!(accept_queue_limit(&listener), 7);
+        let listener = create_reuseport_listener("127.0.0.1:0", u32::MAX, 0).unwrap();
+        assert_eq!(accept_queue_limit(&listener), somaxconn() as u32);
+    }
+
+    #[test]
+    fn accept_thread_exits_once_stopped() {
+        use std::sync::mpsc::channel;
+        use std::time::Duration;
+
+        let listener = create_reuseport_listener("127.0.0.1:0", 16, 0).unwrap();
+        // As `run_server` hands it over: a blocking accept would never see the flag.
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = spawn_accept_thread(listener, ListenMode::Plain, 3, tx, Arc::clone(&stop));
+        let _client = TcpStream::connect(addr).unwrap();
+        let (_, _, mode, index) = rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert_eq!((mode, index), (ListenMode::Plain, 3));
+        stop.store(true, Ordering::Release);
+        let (done_tx, done_rx) = channel();
+        thread::spawn(move || { handle.join().unwrap(); let _ = done_tx.send(()); });
+        done_rx.recv_timeout(Duration::from_secs(2)).expect("accept thread still running");
+        // The thread dropped its listener on the way out.
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn accept_thread_exits_when_the_loop_is_gone() {
+        let listener = create_reuseport_listener("127.0.0.1:0", 16, 0).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        drop(rx);
+        let handle = spawn_accept_thread(listener, ListenMode::Plain, 0, tx, Arc::new(AtomicBool::new(false)));
+        let _client = TcpStream::connect(addr).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn one_client_address_maps_to_one_shard() {
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let shard = shard_for(ip, 8);
+        assert!(shard < 8);
+        assert!((0..100).all(|_| shard_for(ip, 8) == shard));
+        // An IPv4-mapped peer lands where the plain IPv4 one does.
+        assert_eq!(shard_for("::ffff:203.0.113.7".parse().unwrap(), 8), shard);
+        assert_eq!(shard_for(ip, 0), 0);
+        let used: std::collections::HashSet<u32> = (0..=255u8).map(|i| shard_for(IpAddr::from([10, 0, 0, i]), 8)).collect();
+        assert_eq!(used.len(), 8);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn kernel_steering_matches_shard_for() {
+        use std::net::{Ipv4Addr, SocketAddrV4};
+        use std::time::{Duration, Instant};
+
+        const SHARDS: u32 = 4;
+        let first = create_reuseport_listener("127.0.0.1:0", 16, 0).unwrap();
+        let addr = first.local_addr().unwrap().to_string();
+        let mut group = vec![first];
+        for _ in 1..SHARDS { group.push(create_reuseport_listener(&addr, 16, 0).unwrap()); }
+        for l in &group { l.set_nonblocking(true).unwrap(); }
+        attach_ip_steering(&group[0], SHARDS).unwrap();
+        for last in 1..=8u8 {
+            let client_ip = Ipv4Addr::new(127, 0, 0, last);
+            for _ in 0..3 {
+                // Connect from a chosen loopback address.
+                let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) };
+                assert!(fd >= 0);
+                let stream = unsafe { TcpStream::from_raw_fd(fd) };
+                let local = libc::sockaddr_in { sin_family: libc::AF_INET as u16, sin_port: 0, sin_addr: client_ip.octets(), sin_zero: [0; 8] };
+                assert_eq!(unsafe { libc::bind(fd, &local as *const _ as *const libc::sockaddr, std::mem::size_of::<libc::sockaddr_in>() as _) }, 0);
+                let target: SocketAddrV4 = addr.parse().unwrap();
+                let remote = libc::sockaddr_in { sin_family: libc::AF_INET as u16, sin_port: target.port().to_be(), sin_addr: target.ip().octets(), sin_zero: [0; 8] };
+                assert_eq!(unsafe { libc::connect(fd, &remote as *const _ as *const libc::sockaddr, std::mem::size_of::<libc::sockaddr_in>() as _) }, 0);
+                let started = Instant::now();
+                let landed = loop {
+                    if let Some(i) = group.iter().position(|l| l.accept().is_ok()) { break i; }
+                    assert!(started.elapsed() < Duration::from_secs(2));
+                };
+                assert_eq!(landed as u32, shard_for(IpAddr::V4(client_ip), SHARDS), "client {}", client_ip);
+                drop(stream);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn fastopen_queue_is_set_when_enabled() {
+        let fastopen_qlen = |listener: &TcpListener| {
+            let mut qlen: libc::c_int = -1;
+            let mut len = std::mem::size_of_val(&qlen) as libc::c_uint;
+            let rc = unsafe { libc::getsockopt(listener.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_FASTOPEN, &mut qlen as *mut _ as _, &mut len) };
+            assert_eq!(rc, 0);
+            qlen
+        };
+        assert_eq!(fastopen_qlen(&create_reuseport_listener("127.0.0.1:0", 16, 256).unwrap()), 256);
+        assert_eq!(fastopen_qlen(&create_reuseport_listener("127.0.0.1:0", 16, 0).unwrap()), 0);
+    }
+}
@@ -0,0 +1,50 @@
+//! Client IP access control (allow/deny CIDR lists per path prefix).
+//! Evaluated against the socket peer address before routing; the longest
+//! matching prefix rule decides.
+
+use std::net::IpAddr;
+use std::sync::RwLock;
+
+use selenia_core::cidr::Cidr;
+use selenia_core::config::AccessRule;
+
+struct Rule {
+    prefix: String,
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+static RULES: RwLock<Vec<Rule>> = RwLock::new(Vec::new());
+/// `metrics_access.allow`; empty admits every peer.
+static METRICS_ALLOW: RwLock<Vec<Cidr>> = RwLock::new(Vec::new());
+
+/// Compile configured rules (CIDRs were checked by `ServerConfig::validate`).
+pub fn init(rules: &[AccessRule]) {
+    let parse = |v: &[String]| v.iter().filter_map(|c| Cidr::parse(c)).collect();
+    *RULES.write().unwrap() = rules.iter()
+        .map(|r| Rule { prefix: r.prefix.clone(), allow: parse(&r.allow), deny: parse(&r.deny) })
+        .collect();
+}
+
+pub fn init_metrics(allow: &[String]) {
+    *METRICS_ALLOW.write().unwrap() = allow.iter().filter_map(|c| Cidr::parse(c)).collect();
+}
+
+/// Peer check for operational endpoints; `false` → 403.
+pub fn metrics_allowed(peer: &str) -> bool {
+    let allow = METRICS_ALLOW.read().unwrap();
+    allow.is_empty() || peer.parse::<IpAddr>().map_or(false, |ip| allow.iter().any(|c| c.contains(ip)))
+}
+
+/// `false` → answer 403. Unknown peers (`peer` not an IP) are refused by any rule
+/// that applies to `path`.
+pub fn allowed(path: &str, peer: &str) -> bool {
+    let rules = RULES.read().unwrap();
+    let rule = match rules.iter().filter(|r| path.starts_with(&r.prefix)).max_by_key(|r| r.prefix.len()) {
+        Some(r) => r,
+        None => return true,
+    };
+    let ip: IpAddr = match peer.parse() { Ok(ip) => ip, Err(_) => return false };
+    if rule.deny.iter().any(|c| c.contains(ip)) { return false; }
+    rule.allow.is_empty() || rule.allow.iter().any(|c| c.contains(ip))
+}
@@ -0,0 +1,178 @@
+//! HTTP Basic (RFC 7617) / static Bearer (RFC 6750) authentication gate.
+//! Independent from JWT `rbac`: protects path prefixes with credentials kept
+//! in htpasswd-style files. Only hashed secrets are stored:
+//!
+//! * `user:{SHA}base64(sha1(password))`   – Apache `htpasswd -s` compatible
+//! * `user:{SHA256}base64(sha256(password))`
+//!
+//! Bearer token files contain one `{SHA}` / `{SHA256}` hash per line.
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::RwLock;
+
+use selenia_core::config::AuthRule;
+use selenia_core::crypto::sha1::sha1_digest;
+use selenia_core::crypto::sha256::sha256_digest;
+use selenia_core::encoding::base64;
+use selenia_core::log_warn;
+
+#[derive(Clone)]
+enum Hash { Sha1(Vec<u8>), Sha256(Vec<u8>) }
+
+impl Hash {
+    fn parse(s: &str) -> Option<Self> {
+        if let Some(b) = s.strip_prefix("{SHA256}") { return base64::decode(b).filter(|d| d.len()==32).map(Hash::Sha256); }
+        if let Some(b) = s.strip_prefix("{SHA}") { return base64::decode(b).filter(|d| d.len()==20).map(Hash::Sha1); }
+        None
+    }
+
+    fn matches(&self, secret: &[u8]) -> bool {
+        match self {
+            Hash::Sha1(h) => ct_eq(h, &sha1_digest(secret)),
+            Hash::Sha256(h) => ct_eq(h, &sha256_digest(secret)),
+        }
+    }
+}
+
+/// Constant-time comparison so the hash check does not leak a prefix match.
+fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() { return false; }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+struct Gate {
+    prefix: String,
+    realm: String,
+    users: Option<HashMap<String, Hash>>,
+    tokens: Option<Vec<Hash>>,
+}
+
+static GATES: RwLock<Vec<Gate>> = RwLock::new(Vec::new());
+/// Separate gate for operational endpoints (`metrics_access`).
+static METRICS_GATE: RwLock<Option<Gate>> = RwLock::new(None);
+
+fn read_lines(path: &str) -> Vec<String> {
+    match fs::read_to_string(path) {
+        Ok(s) => s.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')).map(String::from).collect(),
+        Err(e) => { log_warn!("auth: cannot read {}: {}", path, e); Vec::new() }
+    }
+}
+
+/// Load credential files for every configured rule. Missing/unsupported entries
+/// are skipped with a warning so the gate fails closed.
+pub fn init(rules: &[AuthRule]) {
+    *GATES.write().unwrap() = rules.iter().map(load_gate).collect();
+}
+
+/// Load the `metrics_access` credentials; `None` (or a rule without files) disables the gate.
+pub fn init_metrics(rule: Option<&AuthRule>) {
+    *METRICS_GATE.write().unwrap() = rule.filter(|r| r.htpasswd.is_some() || r.bearer_tokens.is_some()).map(load_gate);
+}
+
+fn load_gate(r: &AuthRule) -> Gate {
+    let users = r.htpasswd.as_deref().map(|p| {
+        let mut m = HashMap::new();
+        for line in read_lines(p) {
+            match line.split_once(':').and_then(|(u, h)| Hash::parse(h).map(|h| (u.to_string(), h))) {
+                Some((u, h)) => { m.insert(u, h); }
+                None => log_warn!("auth: unsupported htpasswd entry in {}", p),
+            }
+        }
+        m
+    });
+    let tokens = r.bearer_tokens.as_deref().map(|p| read_lines(p).iter().filter_map(|l| Hash::parse(l)).collect());
+    Gate { prefix: r.prefix.clone(), realm: r.realm.clone(), users, tokens }
+}
+
+/// Check `Authorization` against the longest matching prefix rule.
+/// `Err` carries the `WWW-Authenticate` header line(s) for a 401 response.
+pub fn check(path: &str, auth_header: Option<&str>) -> Result<(), String> {
+    let gates = GATES.read().unwrap();
+    match gates.iter().filter(|g| path.starts_with(&g.prefix)).max_by_key(|g| g.prefix.len()) {
+        Some(g) => check_gate(g, auth_header),
+        None => Ok(()),
+    }
+}
+
+/// Like [`check`], against the `metrics_access` gate.
+pub fn check_metrics(auth_header: Option<&str>) -> Result<(), String> {
+    match METRICS_GATE.read().unwrap().as_ref() {
+        Some(g) => check_gate(g, auth_header),
+        None => Ok(()),
+    }
+}
+
+fn check_gate(gate: &Gate, auth_header: Option<&str>) -> Result<(), String> {
+    if let Some(h) = auth_header {
+        let (scheme, cred) = h.split_once(' ').unwrap_or((h, ""));
+        let cred = cred.trim();
+        if scheme.eq_ignore_ascii_case("Basic") {
+            if let (Some(users), Some(raw)) = (&gate.users, base64::decode(cred)) {
+                if let Some((u, p)) = std::str::from_utf8(&raw).ok().and_then(|s| s.split_once(':')) {
+                    if users.get(u).map_or(false, |hash| hash.matches(p.as_bytes())) { return Ok(()); }
+                }
+            }
+        } else if scheme.eq_ignore_ascii_case("Bearer") {
+            if let Some(tokens) = &gate.tokens {
+                if tokens.iter().any(|t| t.matches(cred.as_bytes())) { return Ok(()); }
+            }
+        }
+    }
+    let mut challenge = String::new();
+    if gate.users.is_some() { challenge.push_str(&format!("WWW-Authenticate: Basic realm=\"{}\", charset=\"UTF-8\"\r\n", gate.realm)); }
+    if gate.tokens.is_some() { challenge.push_str(&format!("WWW-Authenticate: Bearer realm=\"{}\"\r\n", gate.realm)); }
+    Err(challenge)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A gate loaded from scratch credential files, as [`init`] would load it.
+    fn gate() -> Gate {
+        static SEQ: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let stem = format!("sws-auth-{}-{}", std::process::id(), SEQ.fetch_add(1, std::sync::atomic::Ordering::Relaxed));
+        let htpasswd = std::env::temp_dir().join(format!("{}.htpasswd", stem));
+        let tokens = std::env::temp_dir().join(format!("{}.tokens", stem));
+        // `htpasswd -s` output for "password", plus a SHA-256 entry.
+        let users = format!("alice:{{SHA}}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\nbob:{{SHA256}}{}\n# comment\nbroken\n", base64::encode(&sha256_digest(b"hunter2")));
+        fs::write(&htpasswd, users).unwrap();
+        fs::write(&tokens, format!("{{SHA256}}{}\n", base64::encode(&sha256_digest(b"tok-123")))).unwrap();
+        let gate = load_gate(&AuthRule {
+            prefix: "/admin/".into(),
+            realm: "ops".into(),
+            htpasswd: Some(htpasswd.to_string_lossy().into_owned()),
+            bearer_tokens: Some(tokens.to_string_lossy().into_owned()),
+        });
+        let _ = fs::remove_file(htpasswd);
+        let _ = fs::remove_file(tokens);
+        gate
+    }
+
+    fn basic(user_pass: &str) -> String { format!("Basic {}", base64::encode(user_pass.as_bytes())) }
+
+    #[test]
+    fn missing_credentials_get_both_challenges() {
+        let challenge = check_gate(&gate(), None).unwrap_err();
+        assert_eq!(challenge, "WWW-Authenticate: Basic realm=\"ops\", charset=\"UTF-8\"\r\nWWW-Authenticate: Bearer realm=\"ops\"\r\n");
+    }
+
+    #[test]
+    fn wrong_credentials_are_refused() {
+        let g = gate();
+        assert!(check_gate(&g, Some(&basic("alice:wrong"))).is_err());
+        assert!(check_gate(&g, Some(&basic("mallory:password"))).is_err());
+        assert!(check_gate(&g, Some("Basic !!!")).is_err());
+        assert!(check_gate(&g, Some("Bearer tok-124")).is_err());
+        assert!(check_gate(&g, Some("Digest username=alice")).is_err());
+    }
+
+    #[test]
+    fn correct_credentials_pass() {
+        let g = gate();
+        assert!(check_gate(&g, Some(&basic("alice:password"))).is_ok());
+        assert!(check_gate(&g, Some(&basic("bob:hunter2"))).is_ok());
+        assert!(check_gate(&g, Some("bearer tok-123")).is_ok());
+    }
+}
@@ -0,0 +1,331 @@
+//! Blocking file I/O off the event-loop thread.
+//!
+//! `load_file` does everything that may touch the disk for a static response
+//! (stat, ETag, `If-None-Match`, range resolution, open, read). Served inline it
+//! blocks the loop; with `io_threads > 0` the loop instead submits an `IoJob` to
+//! `IoPool`, whose workers run `load_file` and hand the outcome back over a
+//! channel, nudging the loop through a socketpair that is registered with it.
+//!
+//! Fast path: files recently loaded with a size up to `INLINE_MAX` are assumed
+//! to sit in the page cache and are still served inline.
+//!
+//! With `cache.serve_stale_on_error` the last good full body of each buffered file
+//! is kept (`remember_stale`) so a later open/read failure can still be answered.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use selenia_core::config::{CacheConfig, ServerConfig};
+use selenia_core::crypto::sha256::sha256_digest;
+use selenia_core::traceparent::TraceContext;
+
+use super::deadline::Deadline;
+use super::mmap::{self, MappedFile};
+
+/// Everything the response needs once the file has been loaded.
+pub struct FileJob {
+    /// Configuration snapshot the request was handled with; the response finishes under it.
+    pub cfg: Arc<ServerConfig>,
+    pub version: String,
+    pub method: String,
+    /// Raw request-target, for the span name.
+    pub path: String,
+    pub log_target: String,
+    pub peer: String,
+    pub locale: String,
+    pub keep_alive: bool,
+    /// gzip won content-coding negotiation.
+    pub accept_gzip: bool,
+    pub cache: Option<CacheConfig>,
+    pub fs_path: PathBuf,
+    pub if_none_match: Vec<String>,
+    pub range: Option<String>,
+    /// Full bodies above this size are sent straight from the file instead of buffered.
+    pub stream_threshold: u64,
+    /// Streamed or ranged bodies of files at least this large go out from a mapping (0: never).
+    pub mmap_threshold: u64,
+    /// Response header lines shared by every status (traceparent, X-Request-Id).
+    pub tp_header_line: String,
+    pub request_id: String,
+    /// Trace context, for the slow-request log and the span.
+    pub trace: TraceContext,
+    pub start: Instant,
+    pub start_sys: SystemTime,
+    pub deadline: Deadline,
+}
+
+pub enum FileOutcome {
+    NotFound,
+    NotModified,
+    OpenFailed(io::Error),
+    ReadFailed(io::Error),
+    /// `request_timeout_ms` ran out before the file was read (e.g. queued on a busy pool).
+    TimedOut,
+    /// The `Range` starts past the end of a file of this many bytes (416).
+    RangeNotSatisfiable(u64),
+    Ready {
+        total_len: u64,
+        etag: String,
+        /// Inclusive byte range to send from `file`; `None` means the whole file.
+        range: Option<(u64, u64)>,
+        /// The whole file is sent from `file` (over `stream_threshold`); `body` stays empty.
+        streamed: bool,
+        file: File,
+        /// Cached mapping to send `range` / the streamed file from instead of `file` (`mmap_threshold`).
+        map: Option<Arc<MappedFile>>,
+        body: Vec<u8>,
+        /// Charset named by a leading byte-order mark, if any.
+        bom_charset: Option<&'static str>,
+    },
+}
+
+/// Last good full body of a file, for `serve_stale_on_error`.
+#[derive(Clone)]
+pub struct StaleEntry {
+    pub body: Vec<u8>,
+    pub etag: String,
+    pub bom_charset: Option<&'static str>,
+    /// When the file was last read successfully.
+    pub stored: Instant,
+}
+
+/// Bound on the bytes of remembered bodies; the map is simply cleared when it fills up.
+const STALE_MAX_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Default)]
+struct StaleStore {
+    entries: HashMap<PathBuf, StaleEntry>,
+    bytes: usize,
+}
+
+fn stale_store() -> &'static Mutex<StaleStore> {
+    static STORE: OnceLock<Mutex<StaleStore>> = OnceLock::new();
+    STORE.get_or_init(Default::default)
+}
+
+/// Keep `body` as the last good copy of `path`; an unchanged file only refreshes its time.
+pub fn remember_stale(path: &Path, body: &[u8], etag: &str, bom_charset: Option<&'static str>) {
+    if body.len() > STALE_MAX_BYTES { return; }
+    let mut store = stale_store().lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(entry) = store.entries.get_mut(path).filter(|e| e.etag == etag) {
+        entry.stored = Instant::now();
+        return;
+    }
+    if let Some(old) = store.entries.remove(path) { store.bytes -= old.body.len(); }
+    if store.bytes + body.len() > STALE_MAX_BYTES { *store = StaleStore::default(); }
+    store.bytes += body.len();
+    let entry = StaleEntry { body: body.to_vec(), etag: etag.to_string(), bom_charset, stored: Instant::now() };
+    store.entries.insert(path.to_path_buf(), entry);
+}
+
+/// The last good copy of `path` if it was read no longer than `within` ago.
+pub fn stale(path: &Path, within: Duration) -> Option<StaleEntry> {
+    let store = stale_store().lock().unwrap_or_else(|e| e.into_inner());
+    store.entries.get(path).filter(|e| e.stored.elapsed() <= within).cloned()
+}
+
+/// Single `bytes=` range against a file of `total_len` bytes. Malformed or multiple
+/// ranges are ignored (`Ok(None)`, full body); a well-formed range that selects
+/// nothing is `Err(())` (416). An end past the last byte is clamped (RFC 9110 §14.1.2).
+fn parse_range(spec: &str, total_len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some((first, last)) = spec.strip_prefix("bytes=").and_then(|r| r.split_once('-')) else { return Ok(None) };
+    let num = |s: &str| if s.is_empty() { Ok(None) } else { s.parse::<u64>().map(Some) };
+    let (Ok(start), Ok(end)) = (num(first), num(last)) else { return Ok(None) };
+    match (start, end) {
+        (Some(s), Some(e)) if e < s => Ok(None),
+        (Some(s), _) if s >= total_len => Err(()),
+        (Some(s), e) => Ok(Some((s, e.map_or(total_len - 1, |e| e.min(total_len - 1))))),
+        // Suffix range: the last `n` bytes.
+        (None, Some(n)) if n == 0 || total_len == 0 => Err(()),
+        (None, Some(n)) => Ok(Some((total_len - n.min(total_len), total_len - 1))),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Charset announced by a UTF-8 / UTF-16 byte-order mark at the start of `head`.
+fn bom_charset(head: &[u8]) -> Option<&'static str> {
+    if head.starts_with(&[0xEF, 0xBB, 0xBF]) { Some("utf-8") }
+    else if head.starts_with(&[0xFE, 0xFF]) { Some("utf-16be") }
+    else if head.starts_with(&[0xFF, 0xFE]) { Some("utf-16le") }
+    else { None }
+}
+
+/// Files whose name starts with this take `SLOW_LOAD` to load (tests only).
+#[cfg(test)]
+pub(crate) const SLOW_PREFIX: &str = "sws-slow-";
+#[cfg(test)]
+pub(crate) const SLOW_LOAD: Duration = Duration::from_millis(500);
+/// File names whose open fails, as on a failing disk (tests only).
+#[cfg(test)]
+pub(crate) static UNREADABLE: Mutex<Vec<String>> = Mutex::new(Vec::new());
+/// File names that open but fail on the first read (tests only).
+#[cfg(test)]
+pub(crate) static READ_FAILS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Run the blocking part of a static-file response, giving up once `deadline` has passed.
+pub fn load_file(fs_path: &Path, if_none_match: &[String], range: Option<&str>, stream_threshold: u64, mmap_threshold: u64, deadline: Deadline) -> FileOutcome {
+    if deadline.expired() { return FileOutcome::TimedOut; }
+    // Stands in for a slow disk in the runner tests.
+    #[cfg(test)]
+    if fs_path.file_name().is_some_and(|n| n.to_string_lossy().starts_with(SLOW_PREFIX)) {
+        std::thread::sleep(SLOW_LOAD);
+    }
+    let meta = match fs::metadata(fs_path) {
+        Ok(m) if m.is_file() => m,
+        _ => return FileOutcome::NotFound,
+    };
+    let total_len = meta.len();
+    // Compute weak ETag based on size and mtime
+    let mtime = meta.modified().unwrap_or(std::time::SystemTime::UNIX_EPOCH);
+    let msecs = mtime.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs();
+    let etag_raw = format!("{}:{}", total_len, msecs);
+    let etag_bytes = sha256_digest(etag_raw.as_bytes());
+    let etag = format!("\"{:x}{:x}{:x}{:x}\"", etag_bytes[0], etag_bytes[1], etag_bytes[2], etag_bytes[3]);
+    if if_none_match.iter().any(|v| *v == etag) { return FileOutcome::NotModified; }
+    let range = match range.map(|r| parse_range(r, total_len)) {
+        Some(Ok(r)) => r,
+        Some(Err(())) => return FileOutcome::RangeNotSatisfiable(total_len),
+        None => None,
+    };
+    if deadline.expired() { return FileOutcome::TimedOut; }
+    #[cfg(test)]
+    if fs_path.file_name().is_some_and(|n| UNREADABLE.lock().unwrap().iter().any(|u| n.to_string_lossy() == *u)) {
+        return FileOutcome::OpenFailed(io::Error::other("injected I/O error"));
+    }
+    let mut file = match File::open(fs_path) {
+        Ok(f) => f,
+        Err(e) => return FileOutcome::OpenFailed(e),
+    };
+    #[cfg(test)]
+    if fs_path.file_name().is_some_and(|n| READ_FAILS.lock().unwrap().iter().any(|u| n.to_string_lossy() == *u)) {
+        return FileOutcome::ReadFailed(io::Error::other("injected read error"));
+    }
+    // Ranges and large files stay zero-copy (sendfile from the offset), so headers go
+    // out before any body read; smaller full bodies are read into memory.
+    let streamed = range.is_none() && total_len > stream_threshold;
+    let mut body = Vec::new();
+    let bom_charset = if range.is_none() && !streamed {
+        body.reserve(total_len as usize);
+        if let Err(e) = file.read_to_end(&mut body) { return FileOutcome::ReadFailed(e); }
+        bom_charset(&body)
+    } else {
+        // send_file uses explicit offsets, so peeking moves nothing that matters.
+        let mut head = [0u8; 3];
+        match file.read(&mut head) {
+            Ok(n) => bom_charset(&head[..n]),
+            Err(e) => return FileOutcome::ReadFailed(e),
+        }
+    };
+    // Mapping failures are not fatal: the body then goes out from the descriptor.
+    let mapped = (range.is_some() || streamed) && mmap_threshold > 0 && total_len >= mmap_threshold;
+    let map = if mapped { mmap::mapping(fs_path, &file).ok() } else { None };
+    FileOutcome::Ready { total_len, etag, range, streamed, file, map, body, bom_charset }
+}
+
+#[cfg(unix)]
+pub use pool::IoPool;
+
+#[cfg(unix)]
+mod pool {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// Files up to this size that were loaded recently are served inline.
+    const INLINE_MAX: u64 = 64 * 1024;
+    /// Bound on remembered sizes; the map is simply cleared when it fills up.
+    const SIZE_HINTS_MAX: usize = 4096;
+
+    type Job = (usize, FileJob);
+    type Done = (usize, FileJob, FileOutcome);
+
+    pub struct IoPool {
+        jobs: Sender<Job>,
+        done: Receiver<Done>,
+        /// Read end of the wake-up socketpair (registered with the event loop).
+        wake: UnixStream,
+        size_hints: HashMap<PathBuf, u64>,
+    }
+
+    impl IoPool {
+        /// Spawn `threads` workers. Call before the seccomp sandbox is installed.
+        pub fn new(threads: usize) -> io::Result<Self> {
+            let (jobs, job_rx) = channel::<Job>();
+            let (done_tx, done) = channel::<Done>();
+            let (wake, notify) = UnixStream::pair()?;
+            wake.set_nonblocking(true)?;
+            notify.set_nonblocking(true)?;
+            let job_rx = Arc::new(Mutex::new(job_rx));
+            for i in 0..threads.max(1) {
+                let job_rx = Arc::clone(&job_rx);
+                let done_tx = done_tx.clone();
+                let mut notify = notify.try_clone()?;
+                thread::Builder::new().name(format!("sws-io-{}", i)).spawn(move || loop {
+                    let next = job_rx.lock().unwrap().recv();
+                    let (token, job) = match next { Ok(j) => j, Err(_) => return };
+                    let outcome = load_file(&job.fs_path, &job.if_none_match, job.range.as_deref(), job.stream_threshold, job.mmap_threshold, job.deadline);
+                    if done_tx.send((token, job, outcome)).is_err() { return; }
+                    // A full socketpair already guarantees a pending wake-up.
+                    let _ = notify.write(&[1]);
+                })?;
+            }
+            Ok(IoPool { jobs, done, wake, size_hints: HashMap::new() })
+        }
+
+        /// Socket to register for readability with the event loop.
+        pub fn wake_fd(&self) -> &UnixStream { &self.wake }
+
+        /// Fast path: the file was recently loaded and is small.
+        pub fn prefers_inline(&self, path: &Path) -> bool {
+            self.size_hints.get(path).map_or(false, |&n| n <= INLINE_MAX)
+        }
+
+        /// Remember the size of an inline load so later requests can take the fast path.
+        pub fn note_size(&mut self, path: &Path, outcome: &FileOutcome) {
+            if let FileOutcome::Ready { total_len, .. } = outcome {
+                if self.size_hints.len() >= SIZE_HINTS_MAX { self.size_hints.clear(); }
+                self.size_hints.insert(path.to_path_buf(), *total_len);
+            }
+        }
+
+        pub fn submit(&self, token: usize, job: FileJob) {
+            let _ = self.jobs.send((token, job));
+        }
+
+        /// Drain the wake-up socket and return every finished job.
+        pub fn completions(&mut self) -> Vec<Done> {
+            let mut sink = [0u8; 64];
+            while matches!((&self.wake).read(&mut sink), Ok(n) if n > 0) {}
+            let done: Vec<Done> = self.done.try_iter().collect();
+            for (_, job, outcome) in &done {
+                let path = job.fs_path.clone();
+                self.note_size(&path, outcome);
+            }
+            done
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_above_the_threshold_are_streamed() {
+        let path = std::env::temp_dir().join(format!("sws-blockio-{}-threshold.bin", std::process::id()));
+        fs::write(&path, vec![7u8; 4096]).unwrap();
+        let load = |threshold| load_file(&path, &[], None, threshold, 0, Deadline::after_ms(0));
+        let buffered = load(4096);
+        let streamed = load(4095);
+        let _ = fs::remove_file(&path);
+        assert!(matches!(buffered, FileOutcome::Ready { streamed: false, ref body, total_len: 4096, .. } if body.len() == 4096));
+        assert!(matches!(streamed, FileOutcome::Ready { streamed: true, ref body, total_len: 4096, .. } if body.is_empty()));
+    }
+}
@@ -1,5 +1,10 @@
 //! コンテンツ圧縮フィルタ（現状はプレースホルダ）。
 //! 外部クレート禁止のため、将来的に独自 DEFLATE/Brotli 実装を追加予定。
+//! リクエスト側は gzip/deflate の伸長 (inflate) に対応。
+
+use std::borrow::Cow;
+
+use super::error::ErrorKind;
 
 fn crc32(buf: &[u8]) -> u32 {
     let mut crc: u32 = 0xFFFF_FFFF;
@@ -51,6 +56,39 @@ pub fn encode(data: &[u8], enc: Encoding) -> Vec<u8> {
     }
 }
 
+/// Pick the response coding from the `Accept-Encoding` header values (RFC 9110 §12.5.3).
+/// gzip is the only coding offered; `*` stands in for codings not listed, identity is
+/// acceptable unless refused explicitly or via `*;q=0`, and an absent or empty header
+/// means identity. `None` when nothing we can send is acceptable → 406.
+pub fn negotiate(accept_encoding: &[&str]) -> Option<Encoding> {
+    let mut gzip = None;
+    let mut identity = None;
+    let mut any = None;
+    for item in accept_encoding.iter().flat_map(|v| v.split(',')) {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or("").trim();
+        if coding.is_empty() { continue; }
+        let q = parts
+            .find_map(|p| {
+                let (k, v) = p.split_once('=')?;
+                k.trim().eq_ignore_ascii_case("q").then(|| v.trim())
+            })
+            .and_then(|s| s.parse::<f32>().ok())
+            .unwrap_or(1.0)
+            .clamp(0.0, 1.0);
+        let slot = if coding.eq_ignore_ascii_case("gzip") || coding.eq_ignore_ascii_case("x-gzip") { &mut gzip }
+            else if coding.eq_ignore_ascii_case("identity") { &mut identity }
+            else if coding == "*" { &mut any }
+            else { continue };
+        *slot = Some(q);
+    }
+    let gzip = gzip.or(any).unwrap_or(0.0);
+    let identity = identity.or(any).unwrap_or(1.0);
+    if gzip > 0.0 && gzip >= identity { Some(Encoding::Gzip) }
+    else if identity > 0.0 { Some(Encoding::Identity) }
+    else { None }
+}
+
 // ------------- Brotli --------------
 fn brotli_uncompressed(data: &[u8]) -> Vec<u8> {
     // Minimal Brotli stream: single last meta-block, uncompressed (ID=1)
@@ -164,4 +202,358 @@ fn gzip_fixed(data: &[u8]) -> Vec<u8> {
     out.extend_from_slice(&crc.to_le_bytes());
     out.extend_from_slice(&(data.len() as u32).to_le_bytes());
     out
-} 
\ No newline at end of file
+} 
+
+// ---------------- inflate (RFC 1951 / RFC 1952) -----------------
+
+/// Upper bound for inflated bodies held in memory (guards against gzip bombs).
+pub const MAX_INFLATED: usize = 64 << 20;
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit: u32,
+    nbits: u8,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self { BitReader { data, pos: 0, bit: 0, nbits: 0 } }
+
+    fn bits(&mut self, n: u8) -> Option<u32> {
+        while self.nbits < n {
+            let b = *self.data.get(self.pos)?;
+            self.pos += 1;
+            self.bit |= (b as u32) << self.nbits;
+            self.nbits += 8;
+        }
+        let v = self.bit & ((1u32 << n) - 1);
+        self.bit >>= n;
+        self.nbits -= n;
+        Some(v)
+    }
+
+    /// Drop the partial byte (stored blocks start byte-aligned).
+    fn align(&mut self) { self.bit = 0; self.nbits = 0; }
+}
+
+/// Canonical Huffman table: symbol counts per code length + symbols sorted by code.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Option<Self> {
+        let mut counts = [0u16; 16];
+        for &l in lengths { counts[l as usize] += 1; }
+        counts[0] = 0;
+        // Reject over-subscribed code sets.
+        let mut left: i32 = 1;
+        for &c in &counts[1..] {
+            left = (left << 1) - c as i32;
+            if left < 0 { return None; }
+        }
+        let mut offs = [0u16; 16];
+        for i in 1..15 { offs[i + 1] = offs[i] + counts[i]; }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (sym, &l) in lengths.iter().enumerate() {
+            if l != 0 { symbols[offs[l as usize] as usize] = sym as u16; offs[l as usize] += 1; }
+        }
+        Some(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, r: &mut BitReader) -> Option<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            code |= r.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - count < first { return self.symbols.get((index + code - first) as usize).copied(); }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        None
+    }
+}
+
+const LEN_BASE: [u16; 29] = [3,4,5,6,7,8,9,10,11,13,15,17,19,23,27,31,35,43,51,59,67,83,99,115,131,163,195,227,258];
+const LEN_EXTRA: [u8; 29] = [0,0,0,0,0,0,0,0,1,1,1,1,2,2,2,2,3,3,3,3,4,4,4,4,5,5,5,5,0];
+const DIST_BASE: [u16; 30] = [1,2,3,4,5,7,9,13,17,25,33,49,65,97,129,193,257,385,513,769,1025,1537,2049,3073,4097,6145,8193,12289,16385,24577];
+const DIST_EXTRA: [u8; 30] = [0,0,0,0,1,1,2,2,3,3,4,4,5,5,6,6,7,7,8,8,9,9,10,10,11,11,12,12,13,13];
+
+fn inflate_codes(r: &mut BitReader, out: &mut Vec<u8>, lit: &Huffman, dist: &Huffman, limit: usize) -> Option<()> {
+    loop {
+        let sym = lit.decode(r)?;
+        match sym {
+            0..=255 => out.push(sym as u8),
+            256 => return Some(()),
+            257..=285 => {
+                let i = (sym - 257) as usize;
+                let len = LEN_BASE[i] as usize + r.bits(LEN_EXTRA[i])? as usize;
+                let d = dist.decode(r)? as usize;
+                if d >= 30 { return None; }
+                let back = DIST_BASE[d] as usize + r.bits(DIST_EXTRA[d])? as usize;
+                if back > out.len() { return None; }
+                let start = out.len() - back;
+                // Byte-wise copy: overlapping matches (back < len) repeat the window.
+                for k in 0..len { let b = out[start + k]; out.push(b); }
+            }
+            _ => return None,
+        }
+        if out.len() > limit { return None; }
+    }
+}
+
+fn fixed_tables() -> (Huffman, Huffman) {
+    let mut l = [0u8; 288];
+    l[..144].fill(8); l[144..256].fill(9); l[256..280].fill(7); l[280..].fill(8);
+    (Huffman::new(&l).unwrap(), Huffman::new(&[5u8; 30]).unwrap())
+}
+
+fn dynamic_tables(r: &mut BitReader) -> Option<(Huffman, Huffman)> {
+    const ORDER: [usize; 19] = [16,17,18,0,8,7,9,6,10,5,11,4,12,3,13,2,14,1,15];
+    let hlit = r.bits(5)? as usize + 257;
+    let hdist = r.bits(5)? as usize + 1;
+    let hclen = r.bits(4)? as usize + 4;
+    if hlit > 286 || hdist > 30 { return None; }
+    let mut cl = [0u8; 19];
+    for &i in &ORDER[..hclen] { cl[i] = r.bits(3)? as u8; }
+    let clh = Huffman::new(&cl)?;
+    let mut lengths = vec![0u8; hlit + hdist];
+    let mut i = 0;
+    while i < lengths.len() {
+        let sym = clh.decode(r)?;
+        let (val, rep) = match sym {
+            0..=15 => (sym as u8, 1),
+            16 => (*lengths.get(i.checked_sub(1)?)?, 3 + r.bits(2)? as usize),
+            17 => (0, 3 + r.bits(3)? as usize),
+            18 => (0, 11 + r.bits(7)? as usize),
+            _ => return None,
+        };
+        if i + rep > lengths.len() { return None; }
+        lengths[i..i + rep].fill(val);
+        i += rep;
+    }
+    if lengths[256] == 0 { return None; } // end-of-block must be encodable
+    Some((Huffman::new(&lengths[..hlit])?, Huffman::new(&lengths[hlit..])?))
+}
+
+/// Decode a raw DEFLATE stream (stored, fixed and dynamic Huffman blocks), giving up
+/// once the output exceeds `limit`. Returns the output and the input bytes consumed.
+fn inflate_raw(data: &[u8], limit: usize) -> Option<(Vec<u8>, usize)> {
+    let mut r = BitReader::new(data);
+    let mut out = Vec::new();
+    loop {
+        let last = r.bits(1)? == 1;
+        match r.bits(2)? {
+            0 => {
+                r.align();
+                let hdr = data.get(r.pos..r.pos + 4)?;
+                let len = u16::from_le_bytes([hdr[0], hdr[1]]);
+                if len != !u16::from_le_bytes([hdr[2], hdr[3]]) { return None; }
+                r.pos += 4;
+                out.extend_from_slice(data.get(r.pos..r.pos + len as usize)?);
+                r.pos += len as usize;
+                if out.len() > limit { return None; }
+            }
+            1 => { let (l, d) = fixed_tables(); inflate_codes(&mut r, &mut out, &l, &d, limit)?; }
+            2 => { let (l, d) = dynamic_tables(&mut r)?; inflate_codes(&mut r, &mut out, &l, &d, limit)?; }
+            _ => return None,
+        }
+        if last { break; }
+    }
+    Some((out, r.pos))
+}
+
+/// Decode a single-member gzip stream of at most `limit` bytes inflated, verifying
+/// CRC32 and ISIZE.
+pub fn gunzip(data: &[u8], limit: usize) -> Option<Vec<u8>> {
+    gunzip_member(data, limit).map(|(out, _)| out)
+}
+
+/// `data` is exactly one intact gzip member: magic, CRC32 and ISIZE of the inflated
+/// content match and nothing follows the trailer.
+pub fn verify_gzip(data: &[u8]) -> bool {
+    gunzip_member(data, MAX_INFLATED).map_or(false, |(_, used)| used == data.len())
+}
+
+/// [`gunzip`] plus the length of the member, trailer included.
+fn gunzip_member(data: &[u8], limit: usize) -> Option<(Vec<u8>, usize)> {
+    const FHCRC: u8 = 0x02; const FEXTRA: u8 = 0x04; const FNAME: u8 = 0x08; const FCOMMENT: u8 = 0x10;
+    if data.len() < 18 || data[0] != 0x1f || data[1] != 0x8b || data[2] != 0x08 { return None; }
+    let flags = data[3];
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        let xlen = u16::from_le_bytes([*data.get(pos)?, *data.get(pos + 1)?]) as usize;
+        pos += 2 + xlen;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            pos += data.get(pos..)?.iter().position(|&b| b == 0)? + 1;
+        }
+    }
+    if flags & FHCRC != 0 { pos += 2; }
+    let (out, used) = inflate_raw(data.get(pos..)?, limit)?;
+    let trailer = data.get(pos + used..pos + used + 8)?;
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let isize = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    if crc != crc32(&out) || isize != out.len() as u32 { return None; }
+    Some((out, pos + used + 8))
+}
+
+fn adler32(buf: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // 5552 bytes is the longest run before `b` can overflow (zlib's NMAX).
+    for block in buf.chunks(5552) {
+        for &x in block { a += x as u32; b += a; }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+/// Decode a zlib stream (RFC 1950) of at most `limit` bytes inflated; the Adler-32
+/// trailer must match and end the input.
+fn unzlib(data: &[u8], limit: usize) -> Option<Vec<u8>> {
+    // CM=8, FCHECK multiple of 31, no preset dictionary.
+    if data.len() < 6 || data[0] & 0x0f != 8 || u16::from_be_bytes([data[0], data[1]]) % 31 != 0 || data[1] & 0x20 != 0 {
+        return None;
+    }
+    let (out, used) = inflate_raw(&data[2..], limit)?;
+    let trailer: [u8; 4] = data.get(2 + used..)?.try_into().ok()?;
+    (u32::from_be_bytes(trailer) == adler32(&out)).then_some(out)
+}
+
+/// Undo a request `Content-Encoding` (`gzip`, `x-gzip`, `deflate` = zlib-wrapped),
+/// inflating at most `limit` bytes: a gzip body whose ISIZE says more gets 413 up front,
+/// one that gets past that check or a corrupt stream 400.
+/// `identity`/absent passes the body through unchanged.
+pub fn decode_body<'a>(content_encoding: Option<&str>, body: &'a [u8], limit: usize) -> Result<Cow<'a, [u8]>, ErrorKind> {
+    match content_encoding.map(|e| e.trim().to_ascii_lowercase()).as_deref() {
+        None | Some("") | Some("identity") => Ok(Cow::Borrowed(body)),
+        Some("gzip") | Some("x-gzip") => {
+            let isize = body.len().checked_sub(4).filter(|_| body.starts_with(&[0x1f, 0x8b]))
+                .map(|n| u32::from_le_bytes([body[n], body[n + 1], body[n + 2], body[n + 3]]));
+            if isize.map_or(false, |n| n as usize > limit) { return Err(ErrorKind::PayloadTooLarge); }
+            gunzip(body, limit).map(Cow::Owned).ok_or(ErrorKind::MalformedHeader)
+        }
+        Some("deflate") => unzlib(body, limit).map(Cow::Owned).ok_or(ErrorKind::MalformedHeader),
+        _ => Err(ErrorKind::UnsupportedEncoding),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    const TEXT: &[u8] = b"hello hello hello hello, selenia";
+    // zlib.compress(TEXT, 9) and gzip.compress(TEXT, mtime=0): fixed Huffman blocks.
+    const ZLIB: &str = "78dacb48cdc9c957c8402775148a537352f3321301c4780bde";
+    const GZIP: &str = "1f8b0800000000000203cb48cdc9c957c8402775148a537352f33213018524579320000000";
+    // 354 bytes of words in a dynamic Huffman block.
+    const ZLIB_DYNAMIC: &str = "78da7d90e10a803008845f65afa62415b81ab45f3e7da4ad34a13f72ccfbbc63481d0ab563e57d2bc06d813243ad70eb89b8c729f448bca4f765265e76ac85e803fe57f053b2d5b5428a2dcd30ec6e1bbbe9c212557e6fe5ff18a44b7949a1c89c460880ad";
+
+    #[test]
+    fn gzip_round_trip() {
+        let data: Vec<u8> = (0..5000u32).map(|i| (i * 7 % 251) as u8).collect();
+        assert_eq!(gunzip(&encode(&data, Encoding::Gzip), MAX_INFLATED).as_deref(), Some(&data[..]));
+        assert_eq!(gunzip(&gzip_store(TEXT), MAX_INFLATED).as_deref(), Some(TEXT));
+        assert!(verify_gzip(&encode(&data, Encoding::Gzip)));
+    }
+
+    #[test]
+    fn external_streams() {
+        assert_eq!(gunzip(&unhex(GZIP), MAX_INFLATED).as_deref(), Some(TEXT));
+        assert_eq!(unzlib(&unhex(ZLIB), MAX_INFLATED).as_deref(), Some(TEXT));
+        let out = unzlib(&unhex(ZLIB_DYNAMIC), MAX_INFLATED).unwrap();
+        assert_eq!(out.len(), 354);
+        assert!(out.starts_with(b"beta epsilon alpha") && out.ends_with(b"gamma alpha"));
+    }
+
+    #[test]
+    fn zlib_trailer_is_checked() {
+        let mut bad = unhex(ZLIB);
+        *bad.last_mut().unwrap() ^= 1;
+        assert_eq!(unzlib(&bad, MAX_INFLATED), None);
+        let mut long = unhex(ZLIB);
+        long.push(0);
+        assert_eq!(unzlib(&long, MAX_INFLATED), None);
+        assert_eq!(unzlib(&unhex(ZLIB)[..ZLIB.len() / 2 - 1], MAX_INFLATED), None);
+    }
+
+    #[test]
+    fn gzip_trailer_is_checked() {
+        let mut bad = unhex(GZIP);
+        let n = bad.len();
+        bad[n - 8] ^= 1;
+        assert_eq!(gunzip(&bad, MAX_INFLATED), None);
+        let mut long = unhex(GZIP);
+        long.push(0);
+        assert!(!verify_gzip(&long));
+    }
+
+    #[test]
+    fn verify_gzip_rejects_a_mutated_trailer() {
+        let good = encode(b"verify me, verify me, verify me", Encoding::Gzip);
+        assert!(verify_gzip(&good));
+        assert!(verify_gzip(&unhex(GZIP)));
+        let n = good.len();
+        // CRC32, then ISIZE, then a body byte, then the magic.
+        for at in [n - 8, n - 4, n - 10, 0] {
+            let mut bad = good.clone();
+            bad[at] ^= 0x40;
+            assert!(!verify_gzip(&bad), "byte {}", at);
+        }
+        assert!(!verify_gzip(&good[..n - 1]));
+        assert!(!verify_gzip(b""));
+    }
+
+    #[test]
+    fn output_is_capped() {
+        let zeros = encode(&vec![0u8; 4096], Encoding::Gzip);
+        assert!(gunzip(&zeros, 4096).is_some());
+        assert_eq!(gunzip(&zeros, 4095), None);
+        assert_eq!(unzlib(&unhex(ZLIB), TEXT.len() - 1), None);
+    }
+
+    #[test]
+    fn decode_body_by_coding() {
+        assert!(matches!(decode_body(None, TEXT, 64), Ok(Cow::Borrowed(b)) if b == TEXT));
+        assert!(matches!(decode_body(Some(" Identity "), TEXT, 64), Ok(Cow::Borrowed(_))));
+        assert_eq!(decode_body(Some("x-gzip"), &unhex(GZIP), 64).unwrap(), TEXT);
+        assert_eq!(decode_body(Some("deflate"), &unhex(ZLIB), 64).unwrap(), TEXT);
+        assert_eq!(decode_body(Some("gzip"), &unhex(GZIP), 16).unwrap_err(), ErrorKind::PayloadTooLarge);
+        assert_eq!(decode_body(Some("deflate"), &unhex(ZLIB), 16).unwrap_err(), ErrorKind::MalformedHeader);
+        assert_eq!(decode_body(Some("gzip"), &unhex(ZLIB), 64).unwrap_err(), ErrorKind::MalformedHeader);
+        assert_eq!(decode_body(Some("br"), TEXT, 64).unwrap_err(), ErrorKind::UnsupportedEncoding);
+    }
+
+    #[test]
+    fn adler32_reference() {
+        // RFC 1950 §9 example value for "Wikipedia".
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(adler32(b""), 1);
+    }
+
+    #[test]
+    fn accept_encoding_negotiation() {
+        let gzip = |v: &[&str]| matches!(negotiate(v), Some(Encoding::Gzip));
+        let identity = |v: &[&str]| matches!(negotiate(v), Some(Encoding::Identity));
+        assert!(gzip(&["identity;q=0, gzip"]));
+        assert!(identity(&["gzip;q=0"]));
+        assert!(identity(&["gzip;q=0.5, identity"]));
+        assert!(gzip(&["*"]));
+        assert!(identity(&[]));
+        assert!(identity(&[""]));
+        assert!(identity(&["br"]));
+        assert!(gzip(&["br", "X-GZIP"]));
+        // Nothing acceptable: 406.
+        assert!(negotiate(&["*;q=0"]).is_none());
+        assert!(negotiate(&["gzip;q=0, identity;q=0"]).is_none());
+        assert!(negotiate(&["br, identity;q=0"]).is_none());
+    }
+}
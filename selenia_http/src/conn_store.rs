@@ -0,0 +1,212 @@
+//! Slab-backed connection store for the event loop.
+//!
+//! Connections live in a `Vec` of slots; the key handed out doubles as the
+//! event-loop token (`generation << 32 | slot`), so a lookup is one bounds check
+//! plus a generation compare – no hashing. Generations start at 1, which keeps
+//! keys ≥ 2³² and clear of the small tokens `EventLoop::register` hands out,
+//! and a stale token for a reused slot simply misses.
+//!
+//! Slots are also threaded on an intrusive doubly-linked list in last-activity
+//! order (`touch` moves a slot to the tail), so the idle sweep pops expired
+//! connections from the head instead of scanning every entry. Each worker loop
+//! owns its own store; there is no locking.
+
+use std::time::{Duration, Instant};
+
+const NIL: u32 = u32::MAX;
+
+struct Slot<T> {
+    gen: u32,
+    value: Option<T>,
+    last_active: Instant,
+    prev: u32,
+    next: u32,
+}
+
+pub struct ConnStore<T> {
+    slots: Vec<Slot<T>>,
+    /// Vacant slot indices, reused LIFO.
+    free: Vec<u32>,
+    /// Least recently active occupied slot.
+    head: u32,
+    /// Most recently active occupied slot.
+    tail: u32,
+    len: usize,
+    capacity: usize,
+}
+
+fn split(key: usize) -> (u32, u32) {
+    ((key >> 32) as u32, key as u32)
+}
+
+impl<T> ConnStore<T> {
+    /// Store holding at most `capacity` connections.
+    pub fn new(capacity: usize) -> Self {
+        ConnStore { slots: Vec::new(), free: Vec::new(), head: NIL, tail: NIL, len: 0, capacity: capacity.min(NIL as usize) }
+    }
+
+    pub fn len(&self) -> usize { self.len }
+
+    /// Most connections the store will hold.
+    pub fn capacity(&self) -> usize { self.capacity }
+
+    /// Key the next `insert` will return, so the socket can be registered with the
+    /// event loop first. `None` when the store is full.
+    pub fn vacant_key(&self) -> Option<usize> {
+        if self.len >= self.capacity { return None; }
+        let idx = match self.free.last() { Some(&i) => i, None => self.slots.len() as u32 };
+        let gen = self.slots.get(idx as usize).map_or(1, |s| s.gen);
+        Some((gen as usize) << 32 | idx as usize)
+    }
+
+    /// Insert `value` as most recently active; gives it back when full.
+    pub fn insert(&mut self, value: T, now: Instant) -> Result<usize, T> {
+        let key = match self.vacant_key() { Some(k) => k, None => return Err(value) };
+        let (_, idx) = split(key);
+        match self.free.pop() {
+            Some(i) => self.slots[i as usize].value = Some(value),
+            None => self.slots.push(Slot { gen: 1, value: Some(value), last_active: now, prev: NIL, next: NIL }),
+        }
+        self.len += 1;
+        self.slots[idx as usize].last_active = now;
+        self.push_back(idx);
+        Ok(key)
+    }
+
+    fn index(&self, key: usize) -> Option<u32> {
+        let (gen, idx) = split(key);
+        let slot = self.slots.get(idx as usize)?;
+        (slot.gen == gen && slot.value.is_some()).then_some(idx)
+    }
+
+    pub fn get_mut(&mut self, key: usize) -> Option<&mut T> {
+        let idx = self.index(key)?;
+        self.slots[idx as usize].value.as_mut()
+    }
+
+    /// Mark the connection active at `now` (moves it to the tail of the timeout list).
+    pub fn touch(&mut self, key: usize, now: Instant) {
+        if let Some(idx) = self.index(key) {
+            self.unlink(idx);
+            self.slots[idx as usize].last_active = now;
+            self.push_back(idx);
+        }
+    }
+
+    pub fn remove(&mut self, key: usize) -> Option<T> {
+        let idx = self.index(key)?;
+        self.unlink(idx);
+        let slot = &mut self.slots[idx as usize];
+        // Bump the generation so the old token can never address the next occupant.
+        slot.gen = slot.gen.checked_add(1).unwrap_or(1);
+        self.free.push(idx);
+        self.len -= 1;
+        slot.value.take()
+    }
+
+    /// Remove and return the least recently active connection if it has been idle
+    /// longer than `idle`. Call repeatedly to drain every expired entry.
+    pub fn pop_expired(&mut self, now: Instant, idle: Duration) -> Option<(usize, T)> {
+        if self.head == NIL { return None; }
+        let slot = &self.slots[self.head as usize];
+        if now.saturating_duration_since(slot.last_active) <= idle { return None; }
+        let key = (slot.gen as usize) << 32 | self.head as usize;
+        self.remove(key).map(|v| (key, v))
+    }
+
+    fn push_back(&mut self, idx: u32) {
+        self.slots[idx as usize].prev = self.tail;
+        self.slots[idx as usize].next = NIL;
+        match self.tail {
+            NIL => self.head = idx,
+            t => self.slots[t as usize].next = idx,
+        }
+        self.tail = idx;
+    }
+
+    fn unlink(&mut self, idx: u32) {
+        let (prev, next) = (self.slots[idx as usize].prev, self.slots[idx as usize].next);
+        match prev {
+            NIL => self.head = next,
+            p => self.slots[p as usize].next = next,
+        }
+        match next {
+            NIL => self.tail = prev,
+            n => self.slots[n as usize].prev = prev,
+        }
+        self.slots[idx as usize].prev = NIL;
+        self.slots[idx as usize].next = NIL;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_lookup_and_remove() {
+        let now = Instant::now();
+        let mut store = ConnStore::new(2);
+        let predicted = store.vacant_key().unwrap();
+        let a = store.insert("a", now).unwrap();
+        assert_eq!(a, predicted);
+        assert!(a >= 1 << 32);
+        let b = store.insert("b", now).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.vacant_key(), None);
+        assert_eq!(store.insert("c", now), Err("c"));
+        assert_eq!(store.get_mut(a), Some(&mut "a"));
+        assert_eq!(store.remove(a), Some("a"));
+        assert_eq!(store.remove(a), None);
+        assert_eq!(store.get_mut(a), None);
+        assert_eq!(store.get_mut(b), Some(&mut "b"));
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn stale_key_misses_after_slot_reuse() {
+        let now = Instant::now();
+        let mut store = ConnStore::new(4);
+        let old = store.insert(1, now).unwrap();
+        store.remove(old);
+        let new = store.insert(2, now).unwrap();
+        assert_eq!(split(new).1, split(old).1);
+        assert_ne!(new, old);
+        assert_eq!(store.get_mut(old), None);
+        store.touch(old, now);
+        assert_eq!(store.remove(old), None);
+        assert_eq!(store.get_mut(new), Some(&mut 2));
+    }
+
+    #[test]
+    fn expiry_follows_last_activity() {
+        let start = Instant::now();
+        let idle = Duration::from_secs(10);
+        let mut store = ConnStore::new(8);
+        let a = store.insert("a", start).unwrap();
+        let b = store.insert("b", start + Duration::from_secs(1)).unwrap();
+        let c = store.insert("c", start + Duration::from_secs(2)).unwrap();
+        // Activity on `a` moves it behind `c`.
+        store.touch(a, start + Duration::from_secs(3));
+        let later = start + Duration::from_secs(12);
+        assert_eq!(store.pop_expired(later, idle), Some((b, "b")));
+        assert_eq!(store.pop_expired(later, idle), None);
+        let later = start + Duration::from_secs(14);
+        assert_eq!(store.pop_expired(later, idle), Some((c, "c")));
+        assert_eq!(store.pop_expired(later, idle), Some((a, "a")));
+        assert_eq!(store.pop_expired(later, idle), None);
+        assert_eq!(store.len(), 0);
+    }
+
+    #[test]
+    fn removal_from_the_middle_keeps_the_list_intact() {
+        let start = Instant::now();
+        let mut store = ConnStore::new(8);
+        let keys: Vec<usize> = (0..4).map(|i| store.insert(i, start + Duration::from_secs(i as u64)).unwrap()).collect();
+        store.remove(keys[1]);
+        store.remove(keys[3]);
+        let late = start + Duration::from_secs(100);
+        let drained: Vec<i32> = std::iter::from_fn(|| store.pop_expired(late, Duration::ZERO).map(|(_, v)| v)).collect();
+        assert_eq!(drained, [0, 2]);
+    }
+}
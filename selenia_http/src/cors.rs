@@ -0,0 +1,118 @@
+//! CORS (Fetch Standard §3.2) – preflight answers and `Access-Control-Allow-Origin`
+//! on actual responses. Disallowed origins simply get no CORS headers, which
+//! makes the browser block the response.
+
+use selenia_core::config::CorsConfig;
+
+/// Values are echoed into the response, so any with control characters are ignored.
+fn header<'a>(headers: &[(&str, &'a str)], name: &str) -> Option<&'a str> {
+    headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| *v).filter(|v| super::parser::valid_field(name, v))
+}
+
+/// `*` matches everything; `https://*.example.com` matches any non-empty label run.
+fn origin_allowed(cfg: &CorsConfig, origin: &str) -> bool {
+    cfg.allowed_origins.iter().any(|p| {
+        if p == "*" { return true; }
+        match p.split_once('*') {
+            Some((pre, suf)) => origin.len() > pre.len() + suf.len() && origin.starts_with(pre) && origin.ends_with(suf),
+            None => p.eq_ignore_ascii_case(origin),
+        }
+    })
+}
+
+fn allow_origin_lines(cfg: &CorsConfig, origin: &str) -> String {
+    // With credentials the wildcard is not allowed, so echo the concrete origin.
+    let wildcard = cfg.allowed_origins.iter().any(|o| o == "*") && !cfg.allow_credentials;
+    let mut out = if wildcard {
+        "Access-Control-Allow-Origin: *\r\n".to_string()
+    } else {
+        format!("Access-Control-Allow-Origin: {}\r\nVary: Origin\r\n", origin)
+    };
+    if cfg.allow_credentials { out.push_str("Access-Control-Allow-Credentials: true\r\n"); }
+    out
+}
+
+/// Returns header lines for an `OPTIONS` preflight, or `None` when the request is
+/// not a preflight or the origin/method is not allowed.
+pub fn preflight(cfg: Option<&CorsConfig>, method: &str, headers: &[(&str, &str)]) -> Option<String> {
+    let cfg = cfg?;
+    if method != "OPTIONS" { return None; }
+    let origin = header(headers, "Origin")?;
+    let req_method = header(headers, "Access-Control-Request-Method")?.trim();
+    if !origin_allowed(cfg, origin) { return None; }
+    if !cfg.allowed_methods.iter().any(|m| m.eq_ignore_ascii_case(req_method)) { return None; }
+    let mut out = allow_origin_lines(cfg, origin);
+    out.push_str(&format!("Access-Control-Allow-Methods: {}\r\n", cfg.allowed_methods.join(", ")));
+    if let Some(req_hdrs) = header(headers, "Access-Control-Request-Headers") {
+        let ok = req_hdrs.split(',').map(str::trim).filter(|h| !h.is_empty())
+            .all(|h| cfg.allowed_headers.iter().any(|a| a == "*" || a.eq_ignore_ascii_case(h)));
+        if !ok { return None; }
+        if !req_hdrs.trim().is_empty() { out.push_str(&format!("Access-Control-Allow-Headers: {}\r\n", req_hdrs.trim())); }
+    }
+    out.push_str(&format!("Access-Control-Max-Age: {}\r\n", cfg.max_age));
+    Some(out)
+}
+
+/// Header lines to add to an actual (non-preflight) response; empty when not applicable.
+pub fn response_headers(cfg: Option<&CorsConfig>, headers: &[(&str, &str)]) -> String {
+    match (cfg, header(headers, "Origin")) {
+        (Some(cfg), Some(origin)) if origin_allowed(cfg, origin) => allow_origin_lines(cfg, origin),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg(origins: &[&str], credentials: bool) -> CorsConfig {
+        CorsConfig {
+            allowed_origins: origins.iter().map(|s| s.to_string()).collect(),
+            allowed_methods: vec!["GET".into(), "PUT".into()],
+            allowed_headers: vec!["Content-Type".into(), "X-Token".into()],
+            allow_credentials: credentials,
+            max_age: 600,
+        }
+    }
+
+    #[test]
+    fn preflight_is_answered_for_an_allowed_origin() {
+        let c = cfg(&["https://app.example.com"], false);
+        let headers = [("Origin", "https://app.example.com"), ("Access-Control-Request-Method", "PUT"), ("Access-Control-Request-Headers", "x-token, content-type")];
+        assert_eq!(
+            preflight(Some(&c), "OPTIONS", &headers).unwrap(),
+            "Access-Control-Allow-Origin: https://app.example.com\r\nVary: Origin\r\n\
+             Access-Control-Allow-Methods: GET, PUT\r\n\
+             Access-Control-Allow-Headers: x-token, content-type\r\n\
+             Access-Control-Max-Age: 600\r\n"
+        );
+        // Not a preflight, a method or header outside the lists, or CORS disabled.
+        assert!(preflight(Some(&c), "GET", &headers).is_none());
+        assert!(preflight(Some(&c), "OPTIONS", &[headers[0], ("Access-Control-Request-Method", "DELETE")]).is_none());
+        assert!(preflight(Some(&c), "OPTIONS", &[headers[0], headers[1], ("Access-Control-Request-Headers", "X-Other")]).is_none());
+        assert!(preflight(None, "OPTIONS", &headers).is_none());
+    }
+
+    #[test]
+    fn allowed_origin_gets_cors_headers() {
+        assert_eq!(response_headers(Some(&cfg(&["*"], false)), &[("Origin", "https://a.test")]), "Access-Control-Allow-Origin: *\r\n");
+        // With credentials the origin is echoed instead of the wildcard.
+        assert_eq!(
+            response_headers(Some(&cfg(&["*"], true)), &[("Origin", "https://a.test")]),
+            "Access-Control-Allow-Origin: https://a.test\r\nVary: Origin\r\nAccess-Control-Allow-Credentials: true\r\n"
+        );
+        let wildcard = cfg(&["https://*.example.com"], false);
+        assert!(!response_headers(Some(&wildcard), &[("Origin", "https://api.example.com")]).is_empty());
+    }
+
+    #[test]
+    fn disallowed_origin_gets_no_cors_headers() {
+        let c = cfg(&["https://*.example.com"], false);
+        assert_eq!(response_headers(Some(&c), &[("Origin", "https://example.com")]), "");
+        assert_eq!(response_headers(Some(&c), &[("Origin", "https://evil.test")]), "");
+        assert_eq!(response_headers(Some(&c), &[("Origin", "https://a.example.com\r\nSet-Cookie: x")]), "");
+        assert_eq!(response_headers(Some(&c), &[]), "");
+        let headers = [("Origin", "https://evil.test"), ("Access-Control-Request-Method", "GET")];
+        assert!(preflight(Some(&c), "OPTIONS", &headers).is_none());
+    }
+}
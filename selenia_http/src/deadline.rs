@@ -0,0 +1,58 @@
+//! Whole-request time budget (`request_timeout_ms`).
+//!
+//! A `Deadline` is fixed when a request has been parsed and travels with it: the
+//! handler checks it between stages (access checks, WAF, auth, file I/O,
+//! compression) and blocking operations such as upstream connects are capped by
+//! what is left. Past it the request is answered 504 instead of carrying on.
+
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+    /// `timeout_ms` from now; 0 never expires.
+    pub fn after_ms(timeout_ms: u64) -> Self {
+        Deadline((timeout_ms > 0).then(|| Instant::now() + Duration::from_millis(timeout_ms)))
+    }
+
+    /// The instant the budget runs out, if any.
+    pub fn at(self) -> Option<Instant> { self.0 }
+
+    pub fn expired(self) -> bool {
+        self.0.map_or(false, |at| Instant::now() >= at)
+    }
+
+    /// `limit`, shortened to the time left (never zero, which some socket calls reject).
+    pub fn cap(self, limit: Duration) -> Duration {
+        match self.0 {
+            Some(at) => limit.min(at.saturating_duration_since(Instant::now())).max(Duration::from_millis(1)),
+            None => limit,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_timeout_never_expires() {
+        let d = Deadline::after_ms(0);
+        assert_eq!(d.at(), None);
+        assert!(!d.expired());
+        assert_eq!(d.cap(Duration::from_secs(5)), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn budget_caps_blocking_calls_and_runs_out() {
+        let d = Deadline::after_ms(50);
+        assert!(!d.expired());
+        assert!(d.cap(Duration::from_secs(5)) <= Duration::from_millis(50));
+        assert_eq!(d.cap(Duration::from_millis(10)), Duration::from_millis(10));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(d.expired());
+        // Never zero, which socket timeouts would reject.
+        assert_eq!(d.cap(Duration::from_secs(5)), Duration::from_millis(1));
+    }
+}
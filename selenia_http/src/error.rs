@@ -6,6 +6,21 @@ pub enum ErrorKind {
     NoMatch,
     WafBlock,
     UpstreamTimeout,
+    UnsupportedEncoding,
+    HeaderTooLarge,
+    /// Declared or received body larger than `max_conn_buffer` allows.
+    PayloadTooLarge,
+    /// Request-target longer than the parser accepts.
+    UriTooLong,
+    /// `Range` that does not overlap the representation.
+    RangeNotSatisfiable,
+    /// Transfer coding (or other mandatory feature) this server does not implement.
+    NotImplemented,
+    /// Request line names an HTTP version this server does not speak.
+    VersionNotSupported,
+    /// CONNECT target outside `connect_proxy.destinations` (or peer outside `allow`).
+    ProxyDenied,
+    BadGateway,
     Internal,
 }
 
@@ -17,6 +32,15 @@ impl ErrorKind {
             ErrorKind::NoMatch => 404,
             ErrorKind::WafBlock => 403,
             ErrorKind::UpstreamTimeout => 504,
+            ErrorKind::UnsupportedEncoding => 415,
+            ErrorKind::HeaderTooLarge => 431,
+            ErrorKind::PayloadTooLarge => 413,
+            ErrorKind::UriTooLong => 414,
+            ErrorKind::RangeNotSatisfiable => 416,
+            ErrorKind::NotImplemented => 501,
+            ErrorKind::VersionNotSupported => 505,
+            ErrorKind::ProxyDenied => 403,
+            ErrorKind::BadGateway => 502,
             ErrorKind::Internal => 500,
         }
     }
@@ -28,7 +52,16 @@ impl ErrorKind {
             ErrorKind::NoMatch => "INFO",
             ErrorKind::WafBlock => "INFO",
             ErrorKind::UpstreamTimeout => "WARN",
+            ErrorKind::UnsupportedEncoding => "INFO",
+            ErrorKind::HeaderTooLarge => "INFO",
+            ErrorKind::PayloadTooLarge => "INFO",
+            ErrorKind::UriTooLong => "INFO",
+            ErrorKind::RangeNotSatisfiable => "INFO",
+            ErrorKind::NotImplemented => "INFO",
+            ErrorKind::VersionNotSupported => "INFO",
+            ErrorKind::ProxyDenied => "WARN",
+            ErrorKind::BadGateway => "WARN",
             ErrorKind::Internal => "ERROR",
         }
     }
-} 
\ No newline at end of file
+}
@@ -0,0 +1,148 @@
+//! Client identity behind trusted proxies: `Forwarded` (RFC 7239), falling back to
+//! `X-Forwarded-For` / `-Proto` / `-Host`.
+//!
+//! Only a peer inside `trusted_proxies` may speak for the client. The hop list is
+//! walked from the nearest proxy outwards, skipping further trusted proxies; the first
+//! untrusted hop is the client, and `proto` / `host` come from the element that named
+//! it. Obfuscated or `unknown` nodes end the walk, keeping the socket peer.
+
+use std::net::IpAddr;
+
+use selenia_core::cidr::Cidr;
+
+/// What a trusted proxy said about the original request.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Forwarded<'a> {
+    pub client: Option<IpAddr>,
+    pub proto: Option<&'a str>,
+    pub host: Option<&'a str>,
+}
+
+#[derive(Debug, Default)]
+struct Element<'a> {
+    node: Option<&'a str>,
+    proto: Option<&'a str>,
+    host: Option<&'a str>,
+}
+
+/// Split on `sep` outside double quotes.
+fn split_unquoted(s: &str, sep: u8) -> Vec<&str> {
+    let mut start = 0;
+    let mut quoted = false;
+    let mut parts = Vec::new();
+    for (i, b) in s.bytes().enumerate() {
+        match b {
+            b'"' => quoted = !quoted,
+            _ if b == sep && !quoted => { parts.push(&s[start..i]); start = i + 1; }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+/// Elements of every `Forwarded` line, client-most first.
+fn parse_forwarded<'a>(values: &[&'a str]) -> Vec<Element<'a>> {
+    values.iter().flat_map(|v| split_unquoted(v, b',')).map(|elem| {
+        let mut e = Element::default();
+        for pair in split_unquoted(elem, b';') {
+            let Some((k, v)) = pair.split_once('=') else { continue };
+            let v = v.trim().trim_matches('"');
+            match k.trim().to_ascii_lowercase().as_str() {
+                "for" => e.node = Some(v),
+                "proto" => e.proto = Some(v),
+                "host" => e.host = Some(v),
+                _ => {}
+            }
+        }
+        e
+    }).collect()
+}
+
+/// Address of a `for=` node (`192.0.2.1`, `192.0.2.1:80`, `[2001:db8::1]:80`);
+/// `None` for `unknown` and obfuscated identifiers.
+fn node_ip(node: &str) -> Option<IpAddr> {
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.parse().ok().or_else(|| node.rsplit_once(':')?.0.parse().ok())
+}
+
+fn trusted(ip: IpAddr, proxies: &[String]) -> bool {
+    proxies.iter().filter_map(|c| Cidr::parse(c)).any(|c| c.contains(ip))
+}
+
+fn header_values<'a>(headers: &[(&str, &'a str)], name: &str) -> Vec<&'a str> {
+    headers.iter().filter(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| *v).collect()
+}
+
+/// Forwarding information for a request from `peer`; `None` unless `peer` is a trusted
+/// proxy that sent `Forwarded` or `X-Forwarded-For`.
+pub fn resolve<'a>(headers: &[(&str, &'a str)], peer: &str, proxies: &[String]) -> Option<Forwarded<'a>> {
+    let peer: IpAddr = peer.parse().ok()?;
+    if !trusted(peer, proxies) { return None; }
+    let mut elements = parse_forwarded(&header_values(headers, "Forwarded"));
+    let legacy = elements.is_empty();
+    if legacy {
+        elements = header_values(headers, "X-Forwarded-For").iter().flat_map(|v| v.split(','))
+            .map(|n| Element { node: Some(n.trim()), ..Element::default() }).collect();
+        if elements.is_empty() { return None; }
+    }
+    // Nearest hop first; when every hop is trusted, the client-most one stands.
+    let mut out = Forwarded::default();
+    for e in elements.iter().rev() {
+        out = Forwarded { client: e.node.and_then(node_ip), proto: e.proto, host: e.host };
+        match out.client {
+            Some(ip) if trusted(ip, proxies) => continue,
+            _ => break,
+        }
+    }
+    if legacy {
+        // The X- headers carry one proto/host (the client-facing proxy's) for the whole chain.
+        let first = |name| header_values(headers, name).first().and_then(|v| v.split(',').next()).map(str::trim);
+        out.proto = first("X-Forwarded-Proto");
+        out.host = first("X-Forwarded-Host");
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxies() -> Vec<String> { vec!["10.0.0.0/8".into()] }
+
+    #[test]
+    fn multi_element_forwarded_names_the_client() {
+        let headers = [
+            ("Host", "internal"),
+            ("Forwarded", "for=192.0.2.60;proto=https;host=example.com, for=\"[2001:db8::1]:4711\";proto=http"),
+            ("forwarded", "for=10.1.2.3;proto=http"),
+        ];
+        let f = resolve(&headers, "10.0.0.1", &proxies()).unwrap();
+        assert_eq!(f, Forwarded { client: Some("2001:db8::1".parse().unwrap()), proto: Some("http"), host: None });
+        // With the IPv6 hop trusted too, the walk reaches the first element.
+        let wide = vec!["10.0.0.0/8".into(), "2001:db8::/32".into()];
+        let f = resolve(&headers, "10.0.0.1", &wide).unwrap();
+        assert_eq!(f, Forwarded { client: Some("192.0.2.60".parse().unwrap()), proto: Some("https"), host: Some("example.com") });
+    }
+
+    #[test]
+    fn forwarded_wins_over_x_forwarded_for() {
+        let headers = [("X-Forwarded-For", "198.51.100.7"), ("X-Forwarded-Proto", "http"), ("Forwarded", "for=192.0.2.43;proto=https")];
+        let f = resolve(&headers, "10.0.0.1", &proxies()).unwrap();
+        assert_eq!((f.client, f.proto), (Some("192.0.2.43".parse().unwrap()), Some("https")));
+        let legacy = [("X-Forwarded-For", "198.51.100.7, 10.9.9.9"), ("X-Forwarded-Proto", "https, http")];
+        let f = resolve(&legacy, "10.0.0.1", &proxies()).unwrap();
+        assert_eq!((f.client, f.proto), (Some("198.51.100.7".parse().unwrap()), Some("https")));
+    }
+
+    #[test]
+    fn untrusted_peers_and_unknown_nodes_keep_the_socket_peer() {
+        let headers = [("Forwarded", "for=192.0.2.43")];
+        assert_eq!(resolve(&headers, "203.0.113.9", &proxies()), None);
+        assert_eq!(resolve(&[("Host", "a")], "10.0.0.1", &proxies()), None);
+        let hidden = [("Forwarded", "for=192.0.2.43, for=_hidden, for=unknown")];
+        assert_eq!(resolve(&hidden, "10.0.0.1", &proxies()).unwrap().client, None);
+    }
+}
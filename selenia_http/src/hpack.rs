@@ -2,6 +2,7 @@
 //! 
 //! This module provides two entry points:
 //!   • `HpackEncoder::encode(&mut self, headers)` → `Vec<u8>`  (header block fragment)
+//!   • `HpackEncoder::encode_static_only(headers)` → `Vec<u8>`  (stateless, deterministic)
 //!   • `HpackDecoder::decode(&mut self, input)`  → `Vec<(String,String)>` (header list)
 //! 
 //! The implementation fully supports:
@@ -50,7 +51,7 @@ const H_CODES: [u32; 257] = [
 ];
 #[rustfmt::skip]
 const H_BITS: [u8; 257] = [
-    13,23,28,28,28,28,28,28,28,24,30,28,28,30,28,28,28,28,28,28,28,28,30,28,28,28,28,28,28,28,28,28,6,10,10,12,13,6,8,11,10,10,8,11,8,6,6,6,5,5,5,6,6,6,6,6,6,6,7,8,15,6,11,10,13,6,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,8,7,8,13,19,13,14,6,15,5,6,5,6,5,6,6,6,5,7,7,6,6,6,5,6,7,6,5,5,6,7,7,7,7,7,15,11,14,13,28,20,22,20,20,22,22,22,23,22,23,23,23,23,23,20,23,20,20,22,23,20,23,23,23,23,21,22,23,22,23,23,20,22,21,20,22,22,23,23,21,23,22,22,20,21,22,23,23,21,21,22,21,23,22,23,23,20,22,22,22,23,22,22,23,26,26,20,19,22,23,22,25,26,26,26,27,27,26,20,25,19,21,26,27,27,26,27,20,21,21,26,26,28,27,27,27,20,20,20,21,22,21,21,23,22,22,25,25,20,20,26,23,26,27,26,26,27,27,27,27,27,28,27,27,27,27,27,26,30,0,
+    13,23,28,28,28,28,28,28,28,24,30,28,28,30,28,28,28,28,28,28,28,28,30,28,28,28,28,28,28,28,28,28,6,10,10,12,13,6,8,11,10,10,8,11,8,6,6,6,5,5,5,6,6,6,6,6,6,6,7,8,15,6,12,10,13,6,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,8,7,8,13,19,13,14,6,15,5,6,5,6,5,6,6,6,5,7,7,6,6,6,5,6,7,6,5,5,6,7,7,7,7,7,15,11,14,13,28,20,22,20,20,22,22,22,23,22,23,23,23,23,23,24,23,24,24,22,23,24,23,23,23,23,21,22,23,22,23,23,24,22,21,20,22,22,23,23,21,23,22,22,24,21,22,23,23,21,21,22,21,23,22,23,23,20,22,22,22,23,22,22,23,26,26,20,19,22,23,22,25,26,26,26,27,27,26,24,25,19,21,26,27,27,26,27,24,21,21,26,26,28,27,27,27,20,24,20,21,22,21,21,23,22,22,25,25,24,24,26,23,26,27,26,26,27,27,27,27,27,28,27,27,27,27,27,26,30,
 ];
 
 // Simple decoder using a binary trie generated at runtime the first time it is
@@ -98,6 +99,9 @@ fn huffman_decode(input: &[u8]) -> Option<Vec<u8>> {
     let mut cur = node;
     let mut bits_in_buffer = 0;
     let mut buffer: u64 = 0;
+    // Bits read since the last complete symbol and whether all of them were ones.
+    let mut pending = 0;
+    let mut pending_ones = true;
 
     for &b in input {
         buffer = (buffer << 8) | b as u64;
@@ -105,6 +109,8 @@ fn huffman_decode(input: &[u8]) -> Option<Vec<u8>> {
         while bits_in_buffer >= 1 {
             let bit = ((buffer >> (bits_in_buffer - 1)) & 1) as u8;
             bits_in_buffer -= 1;
+            pending += 1;
+            pending_ones &= bit == 1;
             cur = if bit == 0 {
                 cur.left.as_deref()?
             } else {
@@ -114,12 +120,13 @@ fn huffman_decode(input: &[u8]) -> Option<Vec<u8>> {
                 if sym == 256 { return None; } // EOS not allowed inside block
                 out.push(sym as u8);
                 cur = node;
+                pending = 0;
+                pending_ones = true;
             }
         }
     }
-    // Drain remaining bits to verify they are padding (all ones up to 7 bits)
-    let padding_ok = (1..=7).any(|n| (buffer & ((1 << n) - 1)) == ((1 << n) - 1));
-    if !padding_ok { return None; }
+    // Trailing bits must be padding: at most 7 bits, all ones (EOS prefix).
+    if pending > 7 || !pending_ones { return None; }
     Some(out)
 }
 
@@ -137,10 +144,10 @@ fn huffman_encode(data: &[u8]) -> Vec<u8> {
             out.push(((bitbuf >> bits) & 0xFF) as u8);
         }
     }
-    // EOS padding (all ones) so we can just pad with ones.
+    // Pad the last byte with the most significant bits of EOS (all ones).
     if bits > 0 {
-        let pad = (1u16 << bits) - 1;
-        bitbuf = (bitbuf << bits) | pad as u64;
+        let pad = 8 - bits;
+        bitbuf = (bitbuf << pad) | ((1u64 << pad) - 1);
         out.push((bitbuf & 0xFF) as u8);
     }
     out
@@ -315,6 +322,32 @@ impl HpackEncoder {
         }
         out
     }
+
+    /// Stateless encoding: static-table indices and literals without indexing only.
+    /// The dynamic table is never referenced or modified, so identical input always
+    /// yields identical bytes and the block decodes on any peer regardless of state.
+    pub fn encode_static_only(headers: &[(String, String)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (name, value) in headers {
+            if let Some(idx) = STATIC_TABLE.iter().position(|&(n, v)| n == name && v == value) {
+                // Indexed Header Field representation (1xxxxxxx)
+                let mut bytes = encode_integer(idx + 1, 7);
+                bytes[0] |= 0x80;
+                out.extend_from_slice(&bytes);
+                continue;
+            }
+            // Literal without indexing (0000xxxx)
+            match STATIC_TABLE.iter().position(|&(n, _)| n == name) {
+                Some(nidx) => out.extend_from_slice(&encode_integer(nidx + 1, 4)),
+                None => {
+                    out.push(0x00);
+                    out.extend_from_slice(&encode_string(name));
+                }
+            }
+            out.extend_from_slice(&encode_string(value));
+        }
+        out
+    }
 }
 
 // ------------------------------------------------------------
@@ -409,4 +442,45 @@ impl HpackDecoder {
             }
         }
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(n, v)| (n.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn static_only_encoding_is_stable_and_decodes() {
+        // Full static match, static name with a new value, and a new name.
+        let headers = list(&[(":method", "GET"), (":path", "/index.html"), ("x-trace", "abc123"), (":method", "GET")]);
+        let first = HpackEncoder::encode_static_only(&headers);
+        assert_eq!(HpackEncoder::encode_static_only(&headers), first);
+        let mut decoder = HpackDecoder::new();
+        assert_eq!(decoder.decode(&first).unwrap(), headers);
+        assert!(decoder.dyn_tab.is_empty());
+        // The stateful encoder indexes the first time and refers back the second.
+        let mut encoder = HpackEncoder::new();
+        let stateful = encoder.encode(&headers);
+        assert_ne!(encoder.encode(&headers), stateful);
+    }
+
+    #[test]
+    fn huffman_matches_the_rfc_7541_examples() {
+        // RFC 7541 C.4.1: first request, Huffman-coded.
+        let block = [0x82, 0x86, 0x84, 0x41, 0x8c, 0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab, 0x90, 0xf4, 0xff];
+        let expected = list(&[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")]);
+        assert_eq!(HpackDecoder::new().decode(&block).unwrap(), expected);
+        assert_eq!(huffman_encode(b"www.example.com"), block[5..]);
+        // C.4.2: "no-cache".
+        assert_eq!(huffman_encode(b"no-cache"), [0xa8, 0xeb, 0x10, 0x64, 0x9c, 0xbf]);
+        // Every byte value survives a round trip.
+        let all: Vec<u8> = (0..=255).collect();
+        assert_eq!(huffman_decode(&huffman_encode(&all)).unwrap(), all);
+        // Padding longer than 7 bits or not all ones is rejected.
+        assert!(huffman_decode(&[0xa8, 0xeb, 0x10, 0x64, 0x9c, 0xbf, 0xff]).is_none());
+        assert!(huffman_decode(&[0xa8, 0xeb, 0x10, 0x64, 0x9c, 0xbe]).is_none());
+    }
+}
@@ -6,7 +6,9 @@ use std::net::TcpStream;
 
 use std::collections::{HashMap, VecDeque};
 use std::convert::TryFrom;
+use std::time::{Duration, Instant};
 use crate::hpack::{HpackEncoder, HpackDecoder};
+use selenia_core::config::Http2FloodConfig;
 
 // -------------------------- Stream State Machine -----------------------------
 
@@ -30,25 +32,45 @@ pub struct Stream {
     pub state: StreamState,
 }
 
-#[derive(Default)]
 pub struct Connection {
     streams: HashMap<u32, Stream>,
     encoder: HpackEncoder,
     decoder: HpackDecoder,
     fc: FlowControl,
+    ping: PingState,
+    flood: Flood,
+    /// Largest inbound payload: our SETTINGS_MAX_FRAME_SIZE, left at the default.
+    max_frame_size: u32,
+}
+
+impl Default for Connection {
+    fn default() -> Self { Self::new() }
 }
 
 impl Connection {
-    pub fn new() -> Self { Self { streams: HashMap::new(), encoder: HpackEncoder::new(), decoder: HpackDecoder::new(), fc: FlowControl::new() } }
+    pub fn new() -> Self { Self::with_flood_limits(&Http2FloodConfig::default()) }
+
+    pub fn with_flood_limits(limits: &Http2FloodConfig) -> Self {
+        Self {
+            streams: HashMap::new(), encoder: HpackEncoder::new(), decoder: HpackDecoder::new(), fc: FlowControl::new(), ping: PingState::default(),
+            flood: Flood::new(limits), max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+
+    /// Limit to pass to [`parse_frame`] for frames from the peer.
+    pub fn max_frame_size(&self) -> u32 { self.max_frame_size }
 
-    /// Handle an inbound frame, updating stream state per RFC 7540 §5.1/§5.4
-    pub fn on_frame(&mut self, fh: &FrameHeader) {
+    /// Handle an inbound frame, updating stream state per RFC 7540 §5.1/§5.4.
+    /// `Err` carries the connection error code for GOAWAY.
+    pub fn on_frame(&mut self, fh: &FrameHeader) -> Result<(), u32> {
+        // Clients must not push (RFC 7540 §8.2); we also advertise ENABLE_PUSH=0.
+        if fh.type_ == FrameType::PushPromise { return Err(ERROR_PROTOCOL); }
+        if fh.type_ == FrameType::RstStream { self.flood.reset()?; }
         let s = self.streams.entry(fh.stream_id).or_insert(Stream { id: fh.stream_id, state: StreamState::Idle });
         use StreamState::*;
         match s.state {
             Idle => match fh.type_ {
                 FrameType::Headers | FrameType::Priority => s.state = Open,
-                FrameType::PushPromise => s.state = ReservedRemote,
                 _ => {},
             },
             Open => match fh.type_ {
@@ -67,15 +89,57 @@ impl Connection {
             },
             _ => {},
         }
+        Ok(())
     }
 
-    /// Consume DATA frame length and adjust windows, returning true if successful.
-    pub fn on_data_frame(&mut self, stream_id:u32, len:usize, end_stream:bool) -> bool {
-        if !self.fc.try_reserve(stream_id, len as i32) { return false; }
-        if end_stream {
-            if let Some(s)=self.streams.get_mut(&stream_id) { s.state = StreamState::HalfClosedRemote; }
+    /// Account an inbound DATA frame against the windows and return its body. The
+    /// whole frame length counts for flow control, padding included (RFC 9113 §6.1);
+    /// with PADDED set the pad-length byte and the padding are stripped from the body.
+    /// `Err` carries the connection error code for GOAWAY.
+    pub fn on_data_frame<'a>(&mut self, fh: &FrameHeader, payload: &'a [u8]) -> Result<&'a [u8], u32> {
+        if fh.stream_id == 0 { return Err(ERROR_PROTOCOL); }
+        let body = if fh.flags & FLAG_PADDED != 0 {
+            let (&pad, rest) = payload.split_first().ok_or(ERROR_PROTOCOL)?;
+            // Padding as long as the payload (pad-length byte included) or longer.
+            if pad as usize >= payload.len() { return Err(ERROR_PROTOCOL); }
+            &rest[..rest.len() - pad as usize]
+        } else {
+            payload
+        };
+        if !self.fc.try_reserve(fh.stream_id, fh.length as i32) { return Err(ERROR_FLOW_CONTROL); }
+        if fh.flags & FLAG_END_STREAM != 0 {
+            if let Some(s)=self.streams.get_mut(&fh.stream_id) { s.state = StreamState::HalfClosedRemote; }
         }
-        true
+        Ok(body)
+    }
+
+    /// Strip an inbound HEADERS frame down to its header block fragment: the pad
+    /// length and padding (PADDED) and the stream dependency and weight (PRIORITY),
+    /// which is applied to `sched`. The fragment goes to [`Self::decode_headers`]
+    /// once END_HEADERS (possibly after CONTINUATION frames) completes the block.
+    /// `Err` carries the connection error code for GOAWAY.
+    pub fn on_headers_frame<'a>(&mut self, fh: &FrameHeader, payload: &'a [u8], sched: &mut Scheduler) -> Result<&'a [u8], u32> {
+        if fh.stream_id == 0 { return Err(ERROR_PROTOCOL); }
+        let mut block = payload;
+        let mut pad = 0;
+        if fh.flags & FLAG_PADDED != 0 {
+            let (&len, rest) = block.split_first().ok_or(ERROR_PROTOCOL)?;
+            pad = len as usize;
+            block = rest;
+        }
+        if fh.flags & FLAG_PRIORITY != 0 {
+            if block.len() < 5 { return Err(ERROR_PROTOCOL); }
+            let dep = u32::from_be_bytes([block[0], block[1], block[2], block[3]]);
+            let parent = dep & 0x7F_FF_FF_FF;
+            // A stream cannot depend on itself (RFC 9113 §5.3.1).
+            if parent == fh.stream_id { return Err(ERROR_PROTOCOL); }
+            sched.on_priority(fh.stream_id, parent, block[4] as u16 + 1, dep & 0x8000_0000 != 0);
+            block = &block[5..];
+        }
+        // Padding may not eat into the priority block or exceed the payload.
+        if pad > block.len() { return Err(ERROR_PROTOCOL); }
+        self.on_frame(fh)?;
+        Ok(&block[..block.len() - pad])
     }
 
     /// Build WINDOW_UPDATE frame with given increment.
@@ -98,9 +162,10 @@ impl Connection {
         out
     }
 
-    /// Decode HEADERS payload, returning header list.
+    /// Decode HEADERS payload, returning header list. Fields with CR, LF or other
+    /// controls make the block malformed (RFC 9113 §8.2.1).
     pub fn decode_headers(&mut self, payload:&[u8]) -> Option<Vec<(String,String)>> {
-        self.decoder.decode(payload).ok()
+        self.decoder.decode(payload).ok().filter(|h| h.iter().all(|(k, v)| super::parser::valid_field(k, v)))
     }
 
     /// Build a GOAWAY frame for graceful shutdown.
@@ -116,6 +181,136 @@ impl Connection {
     }
 }
 
+// -------------------------- PING / keepalive ---------------------------
+// Driven by an HTTP/2 frame loop; run_server still answers h2 prefaces with GOAWAY,
+// hence the dead_code allowances below.
+
+/// RFC 7540 §7 error codes used by connection-level checks.
+pub const ERROR_PROTOCOL: u32 = 0x1;
+pub const ERROR_FLOW_CONTROL: u32 = 0x3;
+pub const ERROR_FRAME_SIZE: u32 = 0x6;
+pub const ERROR_ENHANCE_YOUR_CALM: u32 = 0xb;
+
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+#[derive(Default)]
+#[allow(dead_code)]
+struct PingState {
+    /// Opaque payload and send time of our outstanding keepalive PING.
+    outstanding: Option<([u8; 8], Instant)>,
+    seq: u64,
+    last_rtt: Option<Duration>,
+    last_activity: Option<Instant>,
+}
+
+#[allow(dead_code)]
+impl Connection {
+    /// Build a PING frame (RFC 7540 §6.7).
+    pub fn build_ping(payload: [u8; 8], ack: bool) -> Vec<u8> {
+        let mut out = Vec::with_capacity(9 + 8);
+        let fh = FrameHeader { length: 8, type_: FrameType::Ping, flags: if ack { FLAG_ACK } else { 0 }, stream_id: 0 };
+        fh.serialize(&mut out);
+        out.extend_from_slice(&payload);
+        out
+    }
+
+    /// Handle an inbound PING. Returns the ACK to send (echoing the opaque data) or
+    /// `None` for an ACK of our own PING, whose RTT is then recorded.
+    /// `Err` carries the connection error code for GOAWAY.
+    pub fn on_ping(&mut self, fh: &FrameHeader, payload: &[u8]) -> Result<Option<Vec<u8>>, u32> {
+        if fh.stream_id != 0 { return Err(ERROR_PROTOCOL); }
+        if fh.length != 8 || payload.len() != 8 { return Err(ERROR_FRAME_SIZE); }
+        let data: [u8; 8] = payload.try_into().unwrap();
+        self.ping.last_activity = Some(Instant::now());
+        if fh.flags & FLAG_ACK == 0 {
+            self.flood.control()?;
+            return Ok(Some(Self::build_ping(data, true)));
+        }
+        if let Some((sent, at)) = self.ping.outstanding {
+            // ACKs with unknown payloads are ignored.
+            if sent == data {
+                let rtt = at.elapsed();
+                self.ping.outstanding = None;
+                self.ping.last_rtt = Some(rtt);
+                selenia_core::metrics::observe_h2_ping_rtt(rtt);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Note inbound traffic so keepalive PINGs are sent only on idle connections.
+    pub fn touch(&mut self) { self.ping.last_activity = Some(Instant::now()); }
+
+    /// Timer hook: after `idle` without traffic (and with no PING in flight) returns a
+    /// keepalive PING to send; `Err(())` when the previous PING went unanswered
+    /// for `idle` as well, meaning the peer is gone.
+    pub fn poll_keepalive(&mut self, now: Instant, idle: Duration) -> Result<Option<Vec<u8>>, ()> {
+        if let Some((_, at)) = self.ping.outstanding {
+            return if now.duration_since(at) > idle { Err(()) } else { Ok(None) };
+        }
+        let last = *self.ping.last_activity.get_or_insert(now);
+        if now.duration_since(last) < idle { return Ok(None); }
+        self.ping.seq += 1;
+        let payload = self.ping.seq.to_be_bytes();
+        self.ping.outstanding = Some((payload, now));
+        Ok(Some(Self::build_ping(payload, false)))
+    }
+
+    /// Most recent PING round-trip time, if any keepalive was acknowledged.
+    pub fn last_rtt(&self) -> Option<Duration> { self.ping.last_rtt }
+}
+
+// -------------------------- Flood limits -------------------------------
+
+/// RST_STREAM and reply-inducing SETTINGS/PING frames seen in the current window.
+struct Flood {
+    max_resets: u32,
+    max_control: u32,
+    window: Duration,
+    started: Instant,
+    resets: u32,
+    control: u32,
+}
+
+impl Flood {
+    fn new(limits: &Http2FloodConfig) -> Self {
+        Flood {
+            max_resets: limits.max_resets, max_control: limits.max_control_frames, window: Duration::from_millis(limits.window_ms),
+            started: Instant::now(), resets: 0, control: 0,
+        }
+    }
+
+    /// Fixed windows: counts restart once `window` has passed since the first frame.
+    fn roll(&mut self) {
+        if self.started.elapsed() >= self.window {
+            self.started = Instant::now();
+            self.resets = 0;
+            self.control = 0;
+        }
+    }
+
+    fn reset(&mut self) -> Result<(), u32> {
+        self.roll();
+        self.resets += 1;
+        Self::check(self.resets, self.max_resets)
+    }
+
+    fn control(&mut self) -> Result<(), u32> {
+        self.roll();
+        self.control += 1;
+        Self::check(self.control, self.max_control)
+    }
+
+    fn check(seen: u32, max: u32) -> Result<(), u32> {
+        if max == 0 || seen <= max { return Ok(()); }
+        selenia_core::metrics::inc_h2_flood_closes();
+        Err(ERROR_ENHANCE_YOUR_CALM)
+    }
+}
+
 // -------------------------- Priority Tree ------------------------------
 /// Represents a single HTTP/2 stream node inside the priority tree.
 #[derive(Debug)]
@@ -319,6 +514,11 @@ pub const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
 pub const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
 pub const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
 
+/// Initial SETTINGS_MAX_FRAME_SIZE and the smallest value a peer may announce.
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 16_384;
+/// Largest SETTINGS_MAX_FRAME_SIZE a peer may announce (2^24 - 1).
+pub const MAX_FRAME_SIZE_LIMIT: u32 = 16_777_215;
+
 #[derive(Debug, Default)]
 pub struct Settings(pub Vec<(u16, u32)>);
 
@@ -345,6 +545,12 @@ impl Settings {
 }
 
 impl Connection {
+    /// Settings the server advertises in its preface. Server push is not
+    /// implemented, so it is explicitly disabled (RFC 7540 §6.5.2).
+    pub fn server_settings() -> Settings {
+        Settings(vec![(SETTINGS_ENABLE_PUSH, 0)])
+    }
+
     pub fn build_settings_frame(settings: &Settings, flags: u8) -> Vec<u8> {
         let mut payload = Vec::new();
         settings.encode(&mut payload);
@@ -357,21 +563,30 @@ impl Connection {
 }
 
 impl Connection {
-    /// Handle SETTINGS frame (ACK or new settings)
-    fn on_settings(&mut self, fh:&FrameHeader, payload:&[u8]) {
-        if fh.flags & 0x1 != 0 {
-            // ACK – nothing to do for now.
+    /// Handle SETTINGS frame (ACK or new settings). `Err` carries the connection error
+    /// code for GOAWAY: FRAME_SIZE_ERROR for an ACK with a payload or a payload that is
+    /// not a whole number of settings (RFC 9113 §6.5), whether or not [`parse_frame`]
+    /// already refused the length.
+    fn on_settings(&mut self, fh:&FrameHeader, payload:&[u8]) -> Result<(), u32> {
+        if fh.stream_id != 0 { return Err(ERROR_PROTOCOL); }
+        if fh.flags & FLAG_ACK != 0 {
+            if !payload.is_empty() { return Err(ERROR_FRAME_SIZE); }
         } else {
-            if let Some(settings) = Settings::decode(payload) {
-                // Apply settings such as INITIAL_WINDOW_SIZE
-                for (id,val) in settings.0 {
-                    if id == SETTINGS_INITIAL_WINDOW_SIZE {
-                        self.fc.conn_window = val as i32;
-                    }
+            self.flood.control()?;
+            let settings = Settings::decode(payload).ok_or(ERROR_FRAME_SIZE)?;
+            // Apply settings such as INITIAL_WINDOW_SIZE
+            for (id,val) in settings.0 {
+                if id == SETTINGS_INITIAL_WINDOW_SIZE {
+                    self.fc.conn_window = val as i32;
+                }
+                // Only bounds our outbound frames, which never exceed the default.
+                if id == SETTINGS_MAX_FRAME_SIZE && !(DEFAULT_MAX_FRAME_SIZE..=MAX_FRAME_SIZE_LIMIT).contains(&val) {
+                    return Err(ERROR_PROTOCOL);
                 }
             }
             // In real implementation we would send ACK back.
         }
+        Ok(())
     }
 }
 
@@ -429,28 +644,69 @@ impl TryFrom<u8> for FrameType {
     }
 }
 
+/// Payload length a frame of this type must have (RFC 9113 §6); DATA, HEADERS and
+/// the other variable-length frames are bounded only by the maximum frame size.
+fn length_valid(type_: FrameType, flags: u8, len: u32) -> bool {
+    match type_ {
+        FrameType::Settings if flags & FLAG_ACK != 0 => len == 0,
+        FrameType::Settings => len % 6 == 0,
+        FrameType::Ping => len == 8,
+        FrameType::Priority => len == 5,
+        FrameType::RstStream | FrameType::WindowUpdate => len == 4,
+        FrameType::GoAway => len >= 8,
+        _ => true,
+    }
+}
+
 /// Attempt to parse a complete HTTP/2 frame from `buf`.
-/// Returns (FrameHeader, payload_len) when complete, otherwise None.
-pub fn parse_frame(buf: &[u8]) -> Option<(FrameHeader, usize)> {
-    if buf.len() < 9 { return None; }
+/// Returns (FrameHeader, frame_len) when complete, otherwise None. The length is
+/// checked against `max_frame_size` and the type's fixed size as soon as the
+/// header is in, before any payload is waited for: `Err(ERROR_FRAME_SIZE)`.
+pub fn parse_frame(buf: &[u8], max_frame_size: u32) -> Result<Option<(FrameHeader, usize)>, u32> {
+    if buf.len() < 9 { return Ok(None); }
     let len = ((buf[0] as u32) << 16) | ((buf[1] as u32) << 8) | (buf[2] as u32);
-    if buf.len() < 9 + len as usize { return None; }
-    let type_ = FrameType::try_from(buf[3]).ok()?;
+    let Ok(type_) = FrameType::try_from(buf[3]) else { return Ok(None) };
     let flags = buf[4];
+    if len > max_frame_size || !length_valid(type_, flags, len) { return Err(ERROR_FRAME_SIZE); }
+    if buf.len() < 9 + len as usize { return Ok(None); }
     let stream_id = u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]) & 0x7F_FF_FF_FF;
     let header = FrameHeader { length: len, type_, flags, stream_id };
-    Some((header, 9 + len as usize))
+    Ok(Some((header, 9 + len as usize)))
 }
 
-/// Send a SETTINGS ack frame followed by GOAWAY(ENOERR) and close.
-pub fn send_preface_response(stream: &mut TcpStream) -> io::Result<()> {
+/// Answer a client preface (`buf` starts with it): server SETTINGS unless the h2c
+/// upgrade already sent them, a SETTINGS ack, then GOAWAY and close. Frames
+/// pipelined behind the preface are checked, so e.g. a client PUSH_PROMISE turns
+/// the GOAWAY into PROTOCOL_ERROR, an oversized frame into FRAME_SIZE_ERROR and
+/// a RST_STREAM / SETTINGS / PING flood past `flood` into ENHANCE_YOUR_CALM.
+pub fn send_preface_response(stream: &mut TcpStream, buf: &[u8], settings_sent: bool, flood: &Http2FloodConfig) -> io::Result<()> {
+    let mut error_code = 0;
+    let mut conn = Connection::with_flood_limits(flood);
+    let mut sched = Scheduler::new();
+    let mut rest = buf.get(PREFACE.len()..).unwrap_or(&[]);
+    loop {
+        let (fh, total) = match parse_frame(rest, conn.max_frame_size()) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(code) => { error_code = code; break; }
+        };
+        let checked = match fh.type_ {
+            FrameType::Settings => conn.on_settings(&fh, &rest[9..total]),
+            FrameType::Data => conn.on_data_frame(&fh, &rest[9..total]).and_then(|_| conn.on_frame(&fh)),
+            FrameType::Headers => conn.on_headers_frame(&fh, &rest[9..total], &mut sched).map(|_| ()),
+            FrameType::Ping => conn.on_ping(&fh, &rest[9..total]).map(|_| ()),
+            _ => conn.on_frame(&fh),
+        };
+        if let Err(code) = checked { error_code = code; break; }
+        rest = &rest[total..];
+    }
+    if !settings_sent {
+        stream.write_all(&Connection::build_settings_frame(&Connection::server_settings(), 0))?;
+    }
     // SETTINGS ack (length=0, type=4, flags=0x1, stream=0)
     let settings_ack = build_frame_header(0, FrameType::Settings as u8, 0x1, 0);
-    // GOAWAY length=8 payload: last_stream_id(0) + error_code(0)
-    let mut goaway = build_frame_header(8, FrameType::GoAway as u8, 0, 0);
-    goaway.extend_from_slice(&[0u8; 8]);
     stream.write_all(&settings_ack)?;
-    stream.write_all(&goaway)?;
+    stream.write_all(&Connection::build_goaway(0, error_code))?;
     Ok(())
 }
 
@@ -464,4 +720,236 @@ fn build_frame_header(length: u32, type_: u8, flags: u8, stream_id: u32) -> Vec<
     hdr.push(flags);
     hdr.extend_from_slice(&(stream_id & 0x7FFF_FFFF).to_be_bytes());
     hdr
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(type_: FrameType, flags: u8, stream_id: u32, payload: &[u8]) -> FrameHeader {
+        FrameHeader { length: payload.len() as u32, type_, flags, stream_id }
+    }
+
+    fn frame(type_: FrameType, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        header(type_, flags, stream_id, payload).serialize(&mut out);
+        out.extend_from_slice(payload);
+        out
+    }
+
+    /// What the server writes back for a preface followed by `frames`.
+    fn preface_reply(frames: &[u8], flood: &Http2FloodConfig) -> Vec<u8> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        send_preface_response(&mut server, &[PREFACE, frames].concat(), false, flood).unwrap();
+        drop(server);
+        let mut out = Vec::new();
+        client.read_to_end(&mut out).unwrap();
+        out
+    }
+
+    /// Error code of the GOAWAY that ends a reply from [`preface_reply`].
+    fn goaway_code(reply: &[u8]) -> u32 {
+        assert_eq!(reply[reply.len() - 17 + 3], FrameType::GoAway as u8);
+        u32::from_be_bytes(reply[reply.len() - 4..].try_into().unwrap())
+    }
+
+    #[test]
+    fn ping_is_acked_with_the_same_payload() {
+        let mut conn = Connection::new();
+        let data = *b"\x01\x02\x03\x04\x05\x06\x07\x08";
+        let ack = conn.on_ping(&header(FrameType::Ping, 0, 0, &data), &data).unwrap().unwrap();
+        assert_eq!(ack, [&[0, 0, 8, 6, FLAG_ACK, 0, 0, 0, 0][..], &data[..]].concat());
+        assert_eq!(parse_frame(&ack, DEFAULT_MAX_FRAME_SIZE).unwrap().unwrap().1, ack.len());
+        // PING belongs to the connection and is always 8 bytes.
+        assert_eq!(conn.on_ping(&header(FrameType::Ping, 0, 1, &data), &data), Err(ERROR_PROTOCOL));
+        assert_eq!(conn.on_ping(&header(FrameType::Ping, 0, 0, &data[..7]), &data[..7]), Err(ERROR_FRAME_SIZE));
+    }
+
+    #[test]
+    fn keepalive_ping_records_the_rtt() {
+        let mut conn = Connection::new();
+        let idle = Duration::from_secs(10);
+        let start = Instant::now();
+        assert_eq!(conn.poll_keepalive(start, idle), Ok(None));
+        let ping = conn.poll_keepalive(start + idle, idle).unwrap().unwrap();
+        assert_eq!(&ping[..5], &[0, 0, 8, FrameType::Ping as u8, 0]);
+        // Only one PING is in flight at a time.
+        assert_eq!(conn.poll_keepalive(start + idle, idle), Ok(None));
+        assert_eq!(conn.last_rtt(), None);
+        let payload = &ping[9..];
+        // An ACK with a foreign payload is ignored; the matching one records the RTT.
+        assert_eq!(conn.on_ping(&header(FrameType::Ping, FLAG_ACK, 0, &[0; 8]), &[0; 8]), Ok(None));
+        assert_eq!(conn.last_rtt(), None);
+        assert_eq!(conn.on_ping(&header(FrameType::Ping, FLAG_ACK, 0, payload), payload), Ok(None));
+        assert!(conn.last_rtt().is_some());
+    }
+
+    #[test]
+    fn unanswered_keepalive_ping_ends_the_connection() {
+        let mut conn = Connection::new();
+        let idle = Duration::from_secs(10);
+        let start = Instant::now();
+        conn.poll_keepalive(start, idle).unwrap();
+        assert!(conn.poll_keepalive(start + idle, idle).unwrap().is_some());
+        assert_eq!(conn.poll_keepalive(start + idle * 2, idle), Ok(None));
+        assert_eq!(conn.poll_keepalive(start + idle * 2 + Duration::from_millis(1), idle), Err(()));
+    }
+
+    #[test]
+    fn server_advertises_push_disabled() {
+        let reply = preface_reply(&[], &Http2FloodConfig::default());
+        let (fh, total) = parse_frame(&reply, DEFAULT_MAX_FRAME_SIZE).unwrap().unwrap();
+        assert_eq!((fh.type_, fh.flags, fh.stream_id), (FrameType::Settings, 0, 0));
+        let settings = Settings::decode(&reply[9..total]).unwrap();
+        assert!(settings.0.contains(&(SETTINGS_ENABLE_PUSH, 0)));
+        assert_eq!(goaway_code(&reply), 0);
+    }
+
+    #[test]
+    fn client_push_promise_is_a_connection_error() {
+        let mut conn = Connection::new();
+        let push = [0, 0, 0, 2, 0, 0, 0, 0];
+        assert_eq!(conn.on_frame(&header(FrameType::PushPromise, 0, 1, &push)), Err(ERROR_PROTOCOL));
+        let reply = preface_reply(&frame(FrameType::PushPromise, 0x4, 1, &push), &Http2FloodConfig::default());
+        assert_eq!(goaway_code(&reply), ERROR_PROTOCOL);
+    }
+
+    #[test]
+    fn oversized_data_frame_is_rejected_from_its_header() {
+        let over = DEFAULT_MAX_FRAME_SIZE + 1;
+        // Only the 9-byte header has arrived; the error does not wait for the payload.
+        let head = build_frame_header(over, FrameType::Data as u8, 0, 1);
+        assert_eq!(parse_frame(&head, DEFAULT_MAX_FRAME_SIZE).err(), Some(ERROR_FRAME_SIZE));
+        assert!(parse_frame(&head, over).unwrap().is_none());
+        let full = frame(FrameType::Data, 0, 1, &vec![0; DEFAULT_MAX_FRAME_SIZE as usize]);
+        assert_eq!(parse_frame(&full, DEFAULT_MAX_FRAME_SIZE).unwrap().unwrap().1, full.len());
+        let reply = preface_reply(&[head, vec![0; over as usize]].concat(), &Http2FloodConfig::default());
+        assert_eq!(goaway_code(&reply), ERROR_FRAME_SIZE);
+    }
+
+    #[test]
+    fn control_frames_of_the_wrong_length_are_rejected() {
+        let bad_settings = frame(FrameType::Settings, 0, 0, &[0; 7]);
+        assert_eq!(parse_frame(&bad_settings, DEFAULT_MAX_FRAME_SIZE).err(), Some(ERROR_FRAME_SIZE));
+        for (type_, len) in [(FrameType::Ping, 7), (FrameType::Priority, 4), (FrameType::RstStream, 5), (FrameType::WindowUpdate, 3), (FrameType::GoAway, 7)] {
+            let bad = frame(type_, 0, 0, &vec![0; len]);
+            assert_eq!(parse_frame(&bad, DEFAULT_MAX_FRAME_SIZE).err(), Some(ERROR_FRAME_SIZE), "{:?}", type_);
+        }
+        let reply = preface_reply(&bad_settings, &Http2FloodConfig::default());
+        assert_eq!(goaway_code(&reply), ERROR_FRAME_SIZE);
+    }
+
+    #[test]
+    fn padded_data_yields_the_body_and_counts_the_padding() {
+        let mut conn = Connection::new();
+        let payload = [&[4][..], b"hello", &[0; 4]].concat();
+        assert_eq!(conn.on_data_frame(&header(FrameType::Data, FLAG_PADDED, 1, &payload), &payload), Ok(&b"hello"[..]));
+        // An empty body with the pad length byte only.
+        assert_eq!(conn.on_data_frame(&header(FrameType::Data, FLAG_PADDED, 1, &[0]), &[0]), Ok(&b""[..]));
+        // Flow control counts whole frames: four of 16384 bytes overrun the 65535-byte window
+        // though their bodies alone would fit.
+        let mut conn = Connection::new();
+        let big = [&[200][..], &[b'x'; 16383 - 200], &[0; 200]].concat();
+        let fh = header(FrameType::Data, FLAG_PADDED, 3, &big);
+        for _ in 0..3 { assert_eq!(conn.on_data_frame(&fh, &big).unwrap().len(), 16383 - 200); }
+        assert_eq!(conn.on_data_frame(&fh, &big), Err(ERROR_FLOW_CONTROL));
+    }
+
+    #[test]
+    fn pad_length_reaching_the_payload_is_a_protocol_error() {
+        let mut conn = Connection::new();
+        for payload in [&[5, b'a', b'b', 0, 0][..], &[4, 0, 0, 0], &[]] {
+            assert_eq!(conn.on_data_frame(&header(FrameType::Data, FLAG_PADDED, 1, payload), payload), Err(ERROR_PROTOCOL), "{:?}", payload);
+        }
+        let reply = preface_reply(&frame(FrameType::Data, FLAG_PADDED, 1, &[9, 0, 0]), &Http2FloodConfig::default());
+        assert_eq!(goaway_code(&reply), ERROR_PROTOCOL);
+    }
+
+    #[test]
+    fn headers_padding_and_priority_are_stripped_and_applied() {
+        let headers = vec![(":method".to_string(), "GET".to_string()), (":path".to_string(), "/index.html".to_string())];
+        let block = Connection::new().encode_headers(3, &headers, true)[9..].to_vec();
+        // Pad length 3, exclusive dependency on stream 1 with weight 200 (sent as 199), block, padding.
+        let payload = [&[3][..], &[0x80, 0, 0, 1, 199], &block, &[0; 3]].concat();
+        let fh = header(FrameType::Headers, FLAG_PADDED | FLAG_PRIORITY | 0x4 /* END_HEADERS */, 3, &payload);
+        let mut conn = Connection::new();
+        let mut sched = Scheduler::new();
+        sched.on_priority(1, 0, 16, false);
+        sched.on_priority(5, 1, 16, false);
+        let fragment = conn.on_headers_frame(&fh, &payload, &mut sched).unwrap();
+        assert_eq!(fragment, &block[..]);
+        assert_eq!(conn.decode_headers(fragment), Some(headers));
+        let node = &sched.ptree.nodes[&3];
+        assert_eq!((node.parent, node.weight), (1, 200));
+        // Exclusive: the new stream takes over stream 1's other children.
+        assert_eq!(sched.ptree.nodes[&1].children, [3]);
+        assert_eq!(sched.ptree.nodes[&5].parent, 3);
+    }
+
+    #[test]
+    fn malformed_headers_padding_or_priority_is_rejected() {
+        let mut sched = Scheduler::new();
+        let cases: [(u8, &[u8]); 4] = [
+            (FLAG_PADDED, &[5, 0x82, 0, 0, 0]),                    // padding longer than the rest
+            (FLAG_PADDED | FLAG_PRIORITY, &[2, 0, 0, 0, 1, 15, 0]), // padding reaching into the priority block
+            (FLAG_PRIORITY, &[0, 0, 1]),                           // truncated priority block
+            (FLAG_PRIORITY, &[0, 0, 0, 7, 15, 0x82]),              // stream 7 depending on itself
+        ];
+        for (flags, payload) in cases {
+            let fh = header(FrameType::Headers, flags | 0x4 /* END_HEADERS */, 7, payload);
+            assert_eq!(Connection::new().on_headers_frame(&fh, payload, &mut sched), Err(ERROR_PROTOCOL), "{:?}", payload);
+        }
+    }
+
+    #[test]
+    fn rapid_open_and_reset_ends_with_enhance_your_calm() {
+        let flood = Http2FloodConfig { max_resets: 10, max_control_frames: 10, window_ms: 60_000 };
+        let open_reset = |id: u32| [frame(FrameType::Headers, 0x4, id, &[0x82]), frame(FrameType::RstStream, 0, id, &[0, 0, 0, 8])].concat();
+        let mut conn = Connection::with_flood_limits(&flood);
+        let mut sched = Scheduler::new();
+        for n in 0..11u32 {
+            let id = 2 * n + 1;
+            conn.on_headers_frame(&header(FrameType::Headers, 0x4, id, &[0x82]), &[0x82], &mut sched).unwrap();
+            let reset = conn.on_frame(&header(FrameType::RstStream, 0, id, &[0, 0, 0, 8]));
+            assert_eq!(reset, if n < 10 { Ok(()) } else { Err(ERROR_ENHANCE_YOUR_CALM) }, "reset {}", n + 1);
+        }
+        let flood_closes = || selenia_core::metrics::counter_value("sws_h2_flood_closes_total").unwrap();
+        let closes = flood_closes();
+        let under: Vec<u8> = (0..10).flat_map(|n| open_reset(2 * n + 1)).collect();
+        assert_eq!(goaway_code(&preface_reply(&under, &flood)), 0);
+        let over: Vec<u8> = (0..50).flat_map(|n| open_reset(2 * n + 1)).collect();
+        assert_eq!(goaway_code(&preface_reply(&over, &flood)), ERROR_ENHANCE_YOUR_CALM);
+        assert!(flood_closes() > closes);
+        // PING floods count against the control-frame limit the same way.
+        let pings: Vec<u8> = (0..11).flat_map(|_| frame(FrameType::Ping, 0, 0, &[0; 8])).collect();
+        assert_eq!(goaway_code(&preface_reply(&pings, &flood)), ERROR_ENHANCE_YOUR_CALM);
+    }
+
+    #[test]
+    fn reset_counts_restart_with_each_window() {
+        let flood = Http2FloodConfig { max_resets: 2, max_control_frames: 2, window_ms: 20 };
+        let mut conn = Connection::with_flood_limits(&flood);
+        let rst = header(FrameType::RstStream, 0, 1, &[0, 0, 0, 8]);
+        for _ in 0..3 {
+            assert_eq!(conn.on_frame(&rst), Ok(()));
+            assert_eq!(conn.on_frame(&rst), Ok(()));
+            std::thread::sleep(Duration::from_millis(25));
+        }
+        assert_eq!(conn.on_frame(&rst), Ok(()));
+        assert_eq!(conn.on_frame(&rst), Ok(()));
+        assert_eq!(conn.on_frame(&rst), Err(ERROR_ENHANCE_YOUR_CALM));
+    }
+
+    #[test]
+    fn malformed_settings_are_frame_size_errors() {
+        let mut conn = Connection::new();
+        assert_eq!(conn.on_settings(&header(FrameType::Settings, FLAG_ACK, 0, &[]), &[]), Ok(()));
+        assert_eq!(conn.on_settings(&header(FrameType::Settings, FLAG_ACK, 0, &[0; 6]), &[0; 6]), Err(ERROR_FRAME_SIZE));
+        assert_eq!(conn.on_settings(&header(FrameType::Settings, 0, 0, &[0; 7]), &[0; 7]), Err(ERROR_FRAME_SIZE));
+        assert_eq!(conn.on_settings(&header(FrameType::Settings, 0, 0, &[0, 4, 0, 0, 255, 255]), &[0, 4, 0, 0, 255, 255]), Ok(()));
+        let ack_with_payload = frame(FrameType::Settings, FLAG_ACK, 0, &[0, 4, 0, 0, 255, 255]);
+        assert_eq!(goaway_code(&preface_reply(&ack_with_payload, &Http2FloodConfig::default())), ERROR_FRAME_SIZE);
+    }
+}
@@ -13,6 +13,10 @@
 //! • Keep interface symmetric with the TLS helpers used by HTTP/1 & /2 code
 
 use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, RwLock};
+use selenia_core::config::Http3Config;
+use selenia_core::crypto::tls13::{TicketStore, Tls13State};
+use selenia_core::metrics;
 use super::qpack::{Encoder as QpackEncoder, Decoder as QpackDecoder};
 use crate::http3_packet; // for Retry construction
 
@@ -177,26 +181,101 @@ impl Scheduler {
     }
 } 
 
-#[derive(Default)]
 pub struct ZeroRttBuffer {
     /// Buffered 0-RTT QUIC packets. Each entry is the raw packet bytes as received.
     packets: VecDeque<Vec<u8>>,
+    /// Sum of the buffered packet lengths.
+    bytes: usize,
+    max_packets: usize,
+    max_bytes: usize,
+}
+
+impl Default for ZeroRttBuffer {
+    fn default() -> Self {
+        let h3 = Http3Config::default();
+        Self::with_limits(h3.zero_rtt_max_packets, h3.zero_rtt_max_bytes)
+    }
 }
 
 impl ZeroRttBuffer {
-    /// Push a new 0-RTT packet into the buffer.
-    pub fn push(&mut self, pkt: &[u8]) {
+    /// Empty buffer holding at most `max_packets` packets and `max_bytes` bytes.
+    pub fn with_limits(max_packets: usize, max_bytes: usize) -> Self {
+        Self { packets: VecDeque::new(), bytes: 0, max_packets, max_bytes }
+    }
+
+    /// Push a new 0-RTT packet into the buffer. A packet that would exceed either cap is
+    /// dropped, counted in `sws_h3_zero_rtt_dropped_total`, and `false` is returned.
+    pub fn push(&mut self, pkt: &[u8]) -> bool {
+        if self.packets.len() >= self.max_packets || self.bytes + pkt.len() > self.max_bytes {
+            metrics::inc_h3_zero_rtt_dropped();
+            return false;
+        }
         // Copy the packet so that the lifetime is detached from the original receive buffer.
         self.packets.push_back(pkt.to_vec());
+        self.bytes += pkt.len();
+        metrics::inc_h3_zero_rtt_buffered();
+        true
     }
 
     /// Drain all buffered packets and return them as a vector in arrival order.
     pub fn drain(&mut self) -> Vec<Vec<u8>> {
+        metrics::sub_h3_zero_rtt_buffered(self.packets.len() as u64);
+        self.bytes = 0;
         self.packets.drain(..).collect()
     }
 
     /// Returns true if at least one packet is buffered.
     pub fn is_empty(&self) -> bool { self.packets.is_empty() }
+
+    /// Number of buffered packets.
+    pub fn len(&self) -> usize { self.packets.len() }
+
+    /// Total size of the buffered packets.
+    pub fn bytes(&self) -> usize { self.bytes }
+}
+
+impl Drop for ZeroRttBuffer {
+    // A connection that never completes its handshake still releases its share of the gauge.
+    fn drop(&mut self) { metrics::sub_h3_zero_rtt_buffered(self.packets.len() as u64); }
+}
+
+// ---------------- 0-RTT policy ----------------
+
+/// Decides from the decoded request headers whether a request that arrived in 0-RTT
+/// may run before the handshake completes. Early data can be replayed (RFC 8446 §8).
+type EarlyDataPolicy = Arc<dyn Fn(&[(String, String)]) -> bool + Send + Sync>;
+
+static EARLY_DATA_POLICY: RwLock<Option<EarlyDataPolicy>> = RwLock::new(None);
+
+/// Replace the 0-RTT request policy. Without one only [`safe_methods_only`] requests
+/// are admitted.
+pub fn set_early_data_policy<F: Fn(&[(String, String)]) -> bool + Send + Sync + 'static>(f: F) {
+    *EARLY_DATA_POLICY.write().unwrap() = Some(Arc::new(f));
+}
+
+/// Default policy: GET and HEAD only, whose replay has no side effects (RFC 8470 §2.1).
+pub fn safe_methods_only(headers: &[(String, String)]) -> bool {
+    headers.iter().find(|(k, _)| k == ":method").map_or(false, |(_, m)| m == "GET" || m == "HEAD")
+}
+
+/// Whether the server took the client's early data.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum EarlyData {
+    /// No resumption attempt seen yet; 0-RTT packets are held back.
+    #[default]
+    Pending,
+    Accepted,
+    /// Ticket unusable or replayed: the client resends everything in 1-RTT.
+    Rejected,
+}
+
+/// What to do with one request carried in 0-RTT data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EarlyRequest {
+    /// Process it now.
+    Accept,
+    /// Answer `425 Too Early` (RFC 8470 §5.2); the client retries after the handshake.
+    TooEarly,
 }
 
 #[derive(Default)]
@@ -207,37 +286,169 @@ pub struct ConnectionCtx {
     qdec: QpackDecoder,
     /// Buffer for received 0-RTT Protected packets until the handshake completes.
     zero_rtt: ZeroRttBuffer,
+    early: EarlyData,
 }
 
 impl ConnectionCtx {
-    pub fn new() -> Self { Self { scheduler: Scheduler::default(), flow: FlowMgr::new(), qenc: QpackEncoder, qdec: QpackDecoder, zero_rtt: ZeroRttBuffer::default() } }
+    pub fn new() -> Self { Self::with_config(&Http3Config::default()) }
+
+    /// Connection using the `http3:` block's 0-RTT buffer limits.
+    pub fn with_config(h3: &Http3Config) -> Self {
+        Self { scheduler: Scheduler::default(), flow: FlowMgr::new(), qenc: QpackEncoder, qdec: QpackDecoder, zero_rtt: ZeroRttBuffer::with_limits(h3.zero_rtt_max_packets, h3.zero_rtt_max_bytes), early: EarlyData::Pending }
+    }
 
     /// Encode headers into HTTP/3 HEADERS frame (type 0x1) returning payload.
     pub fn encode_headers(&mut self, headers:&[(String,String)]) -> Vec<u8> {
         self.qenc.encode_ref(headers)
     }
 
-    pub fn decode_headers(&mut self, payload:&[u8]) -> Option<Vec<(String,String)>> { self.qdec.decode_ref(payload) }
+    pub fn decode_headers(&mut self, payload:&[u8]) -> Option<Vec<(String,String)>> {
+        self.qdec.decode_ref(payload).filter(|h| h.iter().all(|(k, v)| super::parser::valid_field(k, v)))
+    }
 
     // ---------------- 0-RTT helpers ----------------
 
     /// Offer a raw QUIC packet to the connection. If it is a 0-RTT Protected packet, the
     /// packet is buffered and the function returns `true`. Otherwise `false` is returned so
     /// the caller can continue normal processing.
+    /// Once early data has been rejected, or the buffer is full, further 0-RTT packets are
+    /// dropped (still `true`).
     pub fn maybe_buffer_0rtt(&mut self, packet:&[u8]) -> bool {
         if is_zero_rtt(packet) {
-            self.zero_rtt.push(packet);
+            if self.early != EarlyData::Rejected { self.zero_rtt.push(packet); }
             return true;
         }
         false
     }
 
+    /// Decide on the client's early data when it resumes with `ticket`. The ticket store
+    /// enforces the anti-replay window: a `client_random` seen before under the same
+    /// ticket, an expired ticket or disabled early data rejects 0-RTT, and anything
+    /// already buffered is discarded. `None` means a full handshake.
+    pub fn accept_0rtt(&mut self, store: &mut TicketStore, ticket: &[u8], client_random: &[u8]) -> Option<Tls13State> {
+        let state = store.resume_early(ticket, client_random);
+        self.early = if state.is_some() { EarlyData::Accepted } else { EarlyData::Rejected };
+        if state.is_none() { self.zero_rtt.drain(); }
+        state
+    }
+
+    /// Policy for a request decoded from 0-RTT data: only requests the policy hook
+    /// (default [`safe_methods_only`]) admits run early, and none unless the early data
+    /// itself was accepted.
+    pub fn early_request(&self, headers: &[(String, String)]) -> EarlyRequest {
+        if self.early != EarlyData::Accepted { return EarlyRequest::TooEarly; }
+        let allowed = match EARLY_DATA_POLICY.read().unwrap().clone() {
+            Some(policy) => policy(headers),
+            None => safe_methods_only(headers),
+        };
+        if allowed { EarlyRequest::Accept } else { EarlyRequest::TooEarly }
+    }
+
     /// Flushes all buffered 0-RTT packets, returning them in arrival order. This should be
     /// called immediately after the handshake is confirmed (TLS Finished processed) so that
-    /// the application can re-inject the packets into the normal processing pipeline.
+    /// the application can re-inject the packets into the normal processing pipeline and
+    /// run each request through [`ConnectionCtx::early_request`]. Unless early data was
+    /// accepted the packets are discarded and nothing is returned.
     pub fn flush_0rtt(&mut self) -> Vec<Vec<u8>> {
-        self.zero_rtt.drain()
+        let packets = self.zero_rtt.drain();
+        if self.early == EarlyData::Accepted { packets } else { Vec::new() }
     }
 } 
 
-pub use crate::http3_packet::build_initial_packet; 
\ No newline at end of file
+pub use crate::http3_packet::build_initial_packet;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use selenia_core::crypto::tls13::TicketPolicy;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// Held by tests that buffer 0-RTT packets, whose depth gauge is process-wide.
+    static ZERO_RTT: Mutex<()> = Mutex::new(());
+
+    /// Long header, type 0-RTT.
+    const EARLY_PACKET: [u8; 4] = [0xd0, 0, 0, 1];
+
+    fn request(method: &str) -> Vec<(String, String)> {
+        vec![(":method".into(), method.into()), (":path".into(), "/".into())]
+    }
+
+    fn early_store(single_use: bool) -> TicketStore {
+        TicketStore::new(TicketPolicy { early_data: true, single_use, replay_window: Duration::from_secs(10), ..TicketPolicy::default() })
+    }
+
+    #[test]
+    fn early_get_runs_and_early_post_waits_for_the_handshake() {
+        let _lock = ZERO_RTT.lock().unwrap_or_else(|e| e.into_inner());
+        let mut store = early_store(true);
+        let ticket = store.issue(&Tls13State::new());
+        let mut ctx = ConnectionCtx::new();
+        assert!(ctx.maybe_buffer_0rtt(&EARLY_PACKET));
+        assert!(!ctx.maybe_buffer_0rtt(&[0x40, 0, 0, 1]));
+        // Held back until the resumption has been decided.
+        assert_eq!(ctx.early_request(&request("GET")), EarlyRequest::TooEarly);
+        assert!(ctx.accept_0rtt(&mut store, &ticket, &[7; 32]).is_some());
+        assert_eq!(ctx.flush_0rtt(), vec![EARLY_PACKET.to_vec()]);
+        assert_eq!(ctx.early_request(&request("GET")), EarlyRequest::Accept);
+        assert_eq!(ctx.early_request(&request("HEAD")), EarlyRequest::Accept);
+        assert_eq!(ctx.early_request(&request("POST")), EarlyRequest::TooEarly);
+        // The hook replaces the method check; set back to the default afterwards.
+        set_early_data_policy(|h| h.iter().any(|(k, v)| k == ":path" && v == "/"));
+        assert_eq!(ctx.early_request(&request("POST")), EarlyRequest::Accept);
+        set_early_data_policy(safe_methods_only);
+        assert_eq!(ctx.early_request(&request("POST")), EarlyRequest::TooEarly);
+    }
+
+    #[test]
+    fn replayed_early_data_is_discarded() {
+        let _lock = ZERO_RTT.lock().unwrap_or_else(|e| e.into_inner());
+        let mut store = early_store(false);
+        let ticket = store.issue(&Tls13State::new());
+        let mut first = ConnectionCtx::new();
+        assert!(first.accept_0rtt(&mut store, &ticket, &[7; 32]).is_some());
+        // The same ClientHello again inside the window: a replay, so 1-RTT only.
+        let mut replay = ConnectionCtx::new();
+        replay.maybe_buffer_0rtt(&EARLY_PACKET);
+        assert!(replay.accept_0rtt(&mut store, &ticket, &[7; 32]).is_none());
+        assert!(replay.maybe_buffer_0rtt(&EARLY_PACKET));
+        assert!(replay.flush_0rtt().is_empty());
+        assert_eq!(replay.early_request(&request("GET")), EarlyRequest::TooEarly);
+        // A fresh ClientHello on the same ticket is no replay.
+        assert!(ConnectionCtx::new().accept_0rtt(&mut store, &ticket, &[8; 32]).is_some());
+        // Without early data in the policy the ticket never carries 0-RTT.
+        let mut plain = TicketStore::new(TicketPolicy::default());
+        let ticket = plain.issue(&Tls13State::new());
+        assert!(ConnectionCtx::new().accept_0rtt(&mut plain, &ticket, &[9; 32]).is_none());
+    }
+
+    #[test]
+    fn zero_rtt_beyond_the_caps_is_dropped_and_counted() {
+        let _lock = ZERO_RTT.lock().unwrap_or_else(|e| e.into_inner());
+        let depth = || metrics::counter_value("sws_h3_zero_rtt_buffered_packets").unwrap();
+        let dropped = || metrics::counter_value("sws_h3_zero_rtt_dropped_total").unwrap();
+        let (depth0, dropped0) = (depth(), dropped());
+        let mut buf = ZeroRttBuffer::with_limits(3, 10);
+        assert!(buf.push(&[0; 4]) && buf.push(&[0; 4]));
+        assert!(!buf.push(&[0; 4])); // 12 bytes > 10
+        assert!(buf.push(&[0; 2]));
+        assert!(!buf.push(&[0; 1])); // fourth packet
+        assert_eq!((buf.len(), buf.bytes()), (3, 10));
+        assert_eq!(depth(), depth0 + 3);
+        assert_eq!(dropped(), dropped0 + 2);
+        assert_eq!(buf.drain().len(), 3);
+        assert_eq!((buf.len(), buf.bytes(), depth()), (0, 0, depth0));
+        assert!(buf.push(&[0; 10]));
+
+        // Through the connection, with the caps from the `http3:` block.
+        let h3 = Http3Config { zero_rtt_max_packets: 2, ..Http3Config::default() };
+        let mut ctx = ConnectionCtx::with_config(&h3);
+        for _ in 0..5 { assert!(ctx.maybe_buffer_0rtt(&EARLY_PACKET)); }
+        assert_eq!(depth(), depth0 + 3);
+        assert_eq!(dropped(), dropped0 + 5);
+        // Dropping a connection or buffer releases its share of the gauge.
+        drop(ctx);
+        drop(buf);
+        assert_eq!(depth(), depth0);
+    }
+}
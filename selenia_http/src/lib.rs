@@ -1,66 +1,145 @@
-use selenia_core::config::ServerConfig;
+use selenia_core::config::{normalize_host, AuthRule, CacheConfig, CharsetConfig, MetricsAccess, ServerConfig};
 use selenia_core::locale::translate;
-use std::fs;
-use std::io::{Read, Write};
-use std::io;
+use std::io::Write;
 use std::net::TcpListener;
 use std::net::TcpStream;
 use std::path::{Path, PathBuf};
-use std::time::{Instant, Duration};
+#[cfg(not(unix))]
+use std::time::Duration;
 // removed unused File import
 
-use selenia_core::{log_info, log_error};
+use selenia_core::{log_info, log_warn, log_error};
 use selenia_core::metrics;
 use selenia_core::signals;
 use selenia_core::waf;
 use selenia_core::crypto::tls13;
-use selenia_core::crypto::sha256::sha256_digest;
 use selenia_core::traceparent::{TraceContext};
 
 #[cfg(unix)]
-use selenia_core::os::{EventLoop, Interest};
+mod accept;
 #[cfg(unix)]
-use std::collections::HashMap;
+use accept::spawn_accept_thread;
+/// Listener socket as each worker binds it (SO_REUSEPORT, backlog, TCP Fast Open).
 #[cfg(unix)]
-mod accept;
+pub use accept::create_reuseport_listener;
+/// How the master passes its listening sockets to workers (`listener_handoff`).
 #[cfg(unix)]
-use accept::{create_reuseport_listener, spawn_accept_thread};
+pub use accept::{encode_listen_fds, LISTEN_FDS_ENV};
+/// Worker index `sticky_routing` assigns to a client address.
+#[cfg(unix)]
+pub use accept::shard_for;
 mod keepalive;
 mod parser;
+#[cfg(not(unix))]
 use parser::Parser;
 mod compress;
 mod zerocopy;
+mod mmap;
+mod blockio;
+mod deadline;
+use deadline::Deadline;
+mod normalize;
 mod hpack;
 mod http2;
 mod http3;
 mod qpack;
 mod router;
+#[cfg(unix)]
+mod conn_store;
+#[cfg(unix)]
+mod runner;
+#[cfg(unix)]
+mod tunnel;
+#[cfg(unix)]
+mod proxy;
+#[cfg(unix)]
+mod rewrite;
+#[cfg(unix)]
+mod upstream;
+#[cfg(unix)]
+pub use runner::EventLoopRunner;
+pub mod uri;
 mod rbac;
+mod auth;
+mod acl;
+mod cors;
+mod forwarded;
 mod error;
 use error::ErrorKind;
+mod response;
+use response::ResponseHeaders;
 mod http3_packet;
 pub use http3_packet::build_retry as build_retry_packet;
+#[cfg(unix)]
+mod upgrade;
+#[cfg(unix)]
+pub use upgrade::set_websocket_handler;
+
+/// How long a terminating worker keeps serving connections it already accepted.
+#[cfg(unix)]
+const SHUTDOWN_DRAIN: std::time::Duration = std::time::Duration::from_secs(10);
 
 #[cfg(unix)]
 /// 同期イベントループベース (epoll/kqueue) HTTP/1.0 サーバ。
-pub fn run_server(cfg: ServerConfig) -> std::io::Result<()> {
+/// `cfg_path` is re-read on SIGHUP (see `reload_config`).
+pub fn run_server(cfg: ServerConfig, cfg_path: Option<&str>) -> std::io::Result<()> {
     // Bind all configured listen addresses.
     if cfg.listen.is_empty() { return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "No listen addresses")); }
 
     use std::sync::mpsc::channel;
-    let mut ev = EventLoop::new()?;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
     signals::init_term_signals();
+    apply_gates(&cfg);
 
     // Channel from accept threads → event loop thread.
     let (tx, rx) = channel();
 
     // Spin up accept threads with SO_REUSEPORT enabled listeners.
-    for addr in &cfg.listen {
-        let lst = create_reuseport_listener(addr)?;
+    let stop_accept = Arc::new(AtomicBool::new(false));
+    let mut acceptors = Vec::new();
+    // Sibling worker processes share each reuseport group; the master exports how many.
+    let shards = worker_count();
+    // Sockets the master holds across reloads; an address it did not pass (added by
+    // the reload's config) is bound here. Unclaimed ones close at the end of the loop.
+    let mut inherited = accept::take_inherited_listeners();
+    for (index, (addr, &mode)) in cfg.listen.iter().zip(&cfg.listen_modes).enumerate() {
+        let (lst, origin) = match inherited.iter().position(|(a, _)| a == addr) {
+            Some(i) => (inherited.swap_remove(i).1, "inherited"),
+            None => (create_reuseport_listener(addr, cfg.listen_backlog, cfg.tcp_fastopen)?, "reuseport"),
+        };
         lst.set_nonblocking(true)?; // extra safety
-        log_info!("SWS listening on http://{} (reuseport)", addr);
-        spawn_accept_thread(lst, tx.clone());
+        let scheme = if mode == selenia_core::config::ListenMode::Tls { "https" } else { "http" };
+        log_info!("SWS listening on {}://{} ({}, backlog {}, {:?})", scheme, addr, origin, accept::effective_backlog(cfg.listen_backlog), mode);
+        if cfg.sticky_routing && shards > 1 {
+            if let Err(e) = accept::attach_ip_steering(&lst, shards) {
+                log_warn!("sticky routing unavailable on {} ({}); using kernel reuseport balancing", addr, e);
+            }
+        }
+        acceptors.push(spawn_accept_thread(lst, mode, index, tx.clone(), Arc::clone(&stop_accept)));
     }
+    drop(inherited);
+
+    // fd budget: baseline (listeners, logs, epoll…) + one per connection + one spare for file reads.
+    // Measured before the seccomp sandbox below hides getrlimit/procfs.
+    let fd_ceiling = selenia_core::os::fdlimit::init(cfg.max_open_fds);
+    let mut runner = EventLoopRunner::new(cfg.clone(), fd_ceiling.min(usize::MAX as u64) as usize)?;
+    let fd_base = selenia_core::os::fdlimit::count_open().unwrap_or(cfg.listen.len() as u64 + 4);
+    log_info!("fd ceiling {} (baseline {})", fd_ceiling, fd_base);
+    runner.set_fd_budget(fd_base, fd_ceiling);
+
+    // Leave root once the listeners are bound; refuse to serve if the switch fails.
+    if let Some(user) = &cfg.run_as_user {
+        let dropped = selenia_core::privdrop::resolve(user, cfg.run_as_group.as_deref())
+            .and_then(|acct| selenia_core::privdrop::drop_to(&acct).map(|_| acct));
+        match dropped {
+            Ok(acct) => log_info!("running as uid {} gid {}", acct.uid, acct.gid),
+            Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("privilege drop failed: {}", e))),
+        }
+    }
+
+    // getpid is outside the sandbox's syscall list; diagnostics dumps need it.
+    let pid = std::process::id();
 
     // After listeners are bound we no longer need CAP_NET_BIND_SERVICE, drop it and enable seccomp sandbox.
     #[cfg(target_os = "linux")]
@@ -72,8 +151,12 @@ pub fn run_server(cfg: ServerConfig) -> std::io::Result<()> {
         const SYSCALLS: &[&str] = &[
             "read","write","close","futex","epoll_wait","epoll_ctl","epoll_create1",
             "clock_nanosleep","restart_syscall","exit","exit_group","accept","accept4",
-            "socket","bind","listen","setsockopt","recvfrom","sendto","recvmsg","sendmsg",
-            "getrandom","fcntl","mmap","munmap","brk","rt_sigreturn","rt_sigaction","sigaltstack"
+            "socket","connect","bind","listen","setsockopt","recvfrom","sendto","recvmsg","sendmsg",
+            "getrandom","fcntl","mmap","munmap","madvise","brk","rt_sigreturn","rt_sigaction","sigaltstack",
+            "openat","fstat","newfstatat","statx","lseek","readlink","getdents64","sendfile",
+            "ioctl","poll","getsockopt","mremap","shutdown",
+            "clone","clone3","mprotect","rt_sigprocmask","set_robust_list","rseq",
+            "fchmod","unlink"
         ];
         if let Err(e) = selenia_core::seccomp::generate_and_install(SYSCALLS) {
             log_error!("seccomp install failed: {}", e);
@@ -82,195 +165,90 @@ pub fn run_server(cfg: ServerConfig) -> std::io::Result<()> {
 
     drop(tx); // close senders in this thread
 
-    let mut idle_timeout = Duration::from_secs(30);
-    let mut req_count: u64 = 0;
-    let mut last_adjust = Instant::now();
-
-    #[derive(Debug)]
-    struct Conn {
-        stream: TcpStream,
-        buf: Vec<u8>,
-        parser: Parser,
-        last_active: Instant,
-        peer: String,
-    }
-
-    let mut conns: HashMap<usize, Conn> = HashMap::new();
-
     loop {
-        if signals::should_terminate() { break Ok(()); }
+        if signals::should_terminate() {
+            // Release the listeners before returning so a successor process owns the ports alone.
+            stop_accept.store(true, Ordering::Release);
+            for h in acceptors.drain(..) { let _ = h.join(); }
+            log_info!("accept threads stopped");
+            // Finish what was already accepted: the successor only sees new connections.
+            while let Ok((stream, peer_addr, mode, listener)) = rx.try_recv() {
+                runner.inject(stream, peer_addr, mode, listener)?;
+            }
+            let drain_by = std::time::Instant::now() + SHUTDOWN_DRAIN;
+            while runner.connections() > 0 && std::time::Instant::now() < drain_by {
+                runner.step(100)?;
+            }
+            log_info!("drained; {} connections left", runner.connections());
+            break Ok(());
+        }
         if signals::take_reload_request() {
             log_info!("Reload requested (SIGHUP) – rotating log");
             selenia_core::logger::rotate("sws.log");
+            if let Some(path) = cfg_path { reload_config(&runner, path); }
+        }
+        if signals::take_diagnostics_request() {
+            dump_diagnostics(&cfg, &runner, pid);
         }
         // Register new inbound connections from accept threads.
-        while let Ok(stream) = rx.try_recv() {
-            let t = ev.register(&stream, Interest::Readable)?;
-            let conn = Conn {
-                stream,
-                buf: Vec::new(),
-                parser: Parser::new(),
-                last_active: Instant::now(),
-                peer: "unknown".into(),
-            };
-            keepalive::record_new_conn();
-            conns.insert(
-                t,
-                conn,
-            );
+        while let Ok((stream, peer_addr, mode, listener)) = rx.try_recv() {
+            runner.inject(stream, peer_addr, mode, listener)?;
         }
+        runner.step(1000)?;
+    }
+}
 
-        // Poll event loop with 1000ms timeout.
-        let events = ev.poll(1000)?;
-        for (token, readable, _writable) in events {
-            if readable {
-                if let Some(mut conn) = conns.remove(&token) {
-                    let mut tmp = [0u8; 1024];
-                    match conn.stream.read(&mut tmp) {
-                        Ok(0) => {
-                            // closed
-                            ev.deregister(token)?;
-                            continue;
-                        }
-                        Ok(n) => conn.buf.extend_from_slice(&tmp[..n]),
-                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
-                        Err(e) => {
-                            log_error!("[READ ERROR] {}", e);
-                            ev.deregister(token)?;
-                            continue;
-                        }
-                    }
-
-                    conn.last_active = Instant::now();
-
-                    if !selenia_core::ratelimit::allow(&conn.peer) {
-                        // 429 Too Many Requests
-                        let _ = conn.stream.write_all(b"HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
-                        ev.deregister(token)?; continue;
-                    }
-
-                    // TLS detection: if first byte indicates handshake (0x16) and buf has at least 5 bytes, treat as TLS
-                    if conn.buf.get(0) == Some(&0x16) && conn.buf.len()>=5 {
-                        let rec_len = u16::from_be_bytes([conn.buf[3],conn.buf[4]]) as usize;
-                        if conn.buf.len() >= 5+rec_len {
-                            let handshake = &conn.buf[5..5+rec_len];
-                            if let Ok((resp, _state)) = tls13::process_client_hello(handshake) {
-                                let _ = conn.stream.write_all(&resp);
-                            }
-                            ev.deregister(token)?;
-                            continue;
-                        }
-                    }
-
-                    // HTTP/2 prior knowledge (PRI * HTTP/2.0...) detection
-                    if http2::is_preface(&conn.buf) {
-                        let _ = http2::send_preface_response(&mut conn.stream);
-                        ev.deregister(token)?;
-                        continue;
-                    }
-
-                    loop {
-                        match conn.parser.advance(&conn.buf) {
-                            Ok(Some((req, consumed))) => {
-                                let close_after = should_close(&req);
-
-                                let keep_alive = !close_after;
-                                handle_request(
-                                    &mut conn.stream,
-                                    req.version,
-                                    req.method,
-                                    req.path,
-                                    &req.headers,
-                                    &cfg,
-                                    &cfg.locale,
-                                    keep_alive,
-                                    &conn.peer,
-                                )?;
-                                req_count += 1;
-                                if req_count > 1 { keepalive::record_reuse_req(); }
-                                // remove consumed bytes (Parser consumed data)
-                                conn.buf.drain(0..consumed);
-
-                                if close_after {
-                                    ev.deregister(token)?;
-                                    break;
-                                } else if conn.buf.is_empty() {
-                                    // Keep connection open for next requests
-                                    break;
-                                }
-                            }
-                            Ok(None) => break, // need more data
-                            Err(e) => {
-                                let kind = e.to_error_kind();
-                                let _ = respond_error(&mut conn.stream, "HTTP/1.1", kind);
-                                ev.deregister(token)?;
-                                break;
-                            }
-                        }
-                    }
-                    conns.insert(token, conn);
-                }
-            }
-        }
-        // Idle timeout check
-        let now = Instant::now();
-        let mut to_remove = Vec::new();
-        for (&tok, c) in &conns {
-            if now.duration_since(c.last_active) > idle_timeout {
-                to_remove.push(tok);
-            }
-        }
-        for tok in to_remove {
-            if let Some(mut c) = conns.remove(&tok) {
-                let _ = ev.deregister(tok);
-                let _ = c.stream.shutdown(std::net::Shutdown::Both);
-            }
-        }
+/// SIGHUP in a worker: load and validate `path` again and hand it to the runner.
+/// A file that fails either keeps the running configuration.
+#[cfg(unix)]
+fn reload_config(runner: &EventLoopRunner, path: &str) {
+    let loaded = ServerConfig::load_from_yaml(path).map_err(|e| format!("{:?}", e))
+        .and_then(|c| c.validate().map(|_| c).map_err(|e| format!("{:?}", e)));
+    match loaded.and_then(|c| runner.reload_config(c)) {
+        Ok(generation) => log_info!("config reloaded from {} (generation {})", path, generation),
+        Err(e) => log_warn!("config reload from {} failed, keeping the current one: {}", path, e),
+    }
+}
 
-        // Auto-tune idle timeout every 1000 requests or 30 s, whichever comes first
-        if req_count >= 1000 || last_adjust.elapsed() > Duration::from_secs(30) {
-            // Simple heuristic: if active connections exceed 75% of concurrency, shorten timeout, else lengthen up to 60s.
-            let active = conns.len();
-            let capacity = cfg.listen.len() * 1024; // arbitrary capacity per listener
-            let load = active as f32 / capacity as f32;
-            if load > 0.75 {
-                idle_timeout = idle_timeout.saturating_sub(Duration::from_secs(5)).max(Duration::from_secs(5));
-            } else if load < 0.25 {
-                idle_timeout = (idle_timeout + Duration::from_secs(5)).min(Duration::from_secs(60));
+/// SIGUSR1: runner state plus the metrics exposition, appended to `diagnostics_file`
+/// in one write or, without one, logged line by line.
+#[cfg(unix)]
+fn dump_diagnostics(cfg: &ServerConfig, runner: &EventLoopRunner, pid: u32) {
+    let ts = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_millis();
+    let dump = format!("# diagnostics pid {} ts {}\n{}{}", pid, ts, runner.diagnostics(), metrics::render());
+    match &cfg.diagnostics_file {
+        Some(path) => {
+            let written = std::fs::OpenOptions::new().create(true).append(true).open(path).and_then(|mut f| f.write_all(dump.as_bytes()));
+            match written {
+                Ok(()) => log_info!("diagnostics written to {}", path),
+                Err(e) => log_warn!("diagnostics dump to {} failed: {}", path, e),
             }
-            req_count = 0;
-            last_adjust = Instant::now();
         }
+        None => for line in dump.lines() { log_info!("[DIAG] {}", line); },
     }
 }
 
 // ---------- Windows & other fallback (thread-per-connection) ----------
 
 #[cfg(not(unix))]
-pub fn run_server(cfg: ServerConfig) -> std::io::Result<()> {
-    use std::net::{TcpListener, TcpStream};
-    use std::io::{Read, Write};
+pub fn run_server(cfg: ServerConfig, _cfg_path: Option<&str>) -> std::io::Result<()> {
+    use std::net::TcpListener;
     use std::thread;
 
     if cfg.listen.is_empty() { return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "No listen addresses")); }
     let listener = TcpListener::bind(&cfg.listen[0])?;
     log_info!("SWS listening on http://{}", cfg.listen[0]);
+    auth::init(&cfg.auth);
+    acl::init(&cfg.access_control);
+    init_metrics_access(cfg.metrics_access.as_ref());
+    selenia_core::otel::init(&cfg.otel.endpoint, cfg.otel.protocol());
 
+    let cfg = std::sync::Arc::new(cfg);
     for stream in listener.incoming() {
         match stream {
-            Ok(mut stream) => {
-                let cfg_clone = cfg.clone();
-                let locale = cfg_clone.locale.clone();
-                thread::spawn(move || {
-                    let mut buf = [0u8; 4096];
-                    if let Ok(n)=stream.read(&mut buf) {
-                        let mut parser = Parser::new();
-                        parser.advance(&buf[..n]).ok();
-                        // Very naive: always serve index.html
-                        let _ = handle_request(&mut stream, "HTTP/1.0", "GET", "/", &[], &cfg_clone, &locale, false, "127.0.0.1");
-                    }
-                    let _ = stream.shutdown(std::net::Shutdown::Both);
-                });
+            Ok(stream) => {
+                let cfg = std::sync::Arc::clone(&cfg);
+                thread::spawn(move || serve_blocking(stream, &cfg));
             }
             Err(e) => log_error!("[ACCEPT] {e}"),
         }
@@ -278,263 +256,535 @@ pub fn run_server(cfg: ServerConfig) -> std::io::Result<()> {
     Ok(())
 }
 
-fn handle_request(stream: &mut TcpStream, version: &str, method: &str, path: &str, headers: &[(&str,&str)], cfg: &ServerConfig, locale: &str, keep_alive: bool, peer: &str) -> std::io::Result<()> {
+/// One blocking thread per connection: read until the parser has a full request,
+/// serve it, and keep going while keep-alive allows.
+#[cfg(not(unix))]
+fn serve_blocking(mut stream: TcpStream, cfg: &std::sync::Arc<ServerConfig>) {
+    use std::io::Read;
+
+    let locale = cfg.locale.clone();
+    let peer = stream.peer_addr().map(|a| a.ip().to_string()).unwrap_or_default();
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(30)));
+    let mut parser = Parser::from_config(cfg);
+    let mut buf = Vec::new();
+    let mut tmp = vec![0u8; cfg.read_buffer_size];
+    'conn: loop {
+        match stream.read(&mut tmp) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => buf.extend_from_slice(&tmp[..n]),
+        }
+        loop {
+            match parser.advance(&buf) {
+                Ok(Some((req, consumed))) => {
+                    let close_after = should_close(&req);
+                    let mut pending = None;
+                    let served = handle_request(&mut stream, req.version, req.method, req.path, &req.headers, &req.body, cfg, &locale, !close_after, &peer, false)
+                        .and_then(|routed| match routed {
+                            // Thread per connection: blocking reads only stall this client.
+                            Routed::File(job) => {
+                                let outcome = blockio::load_file(&job.fs_path, &job.if_none_match, job.range.as_deref(), job.stream_threshold, job.mmap_threshold, job.deadline);
+                                finish_file(&mut stream, job, outcome, &mut pending)
+                            }
+                            _ => Ok(()),
+                        });
+                    if served.is_err() { break 'conn; }
+                    // Blocking socket: only an interrupted sendfile leaves a remainder.
+                    if let Some(mut p) = pending.take() {
+                        loop {
+                            match p.resume(&stream) {
+                                Ok(true) => break,
+                                Ok(false) => {}
+                                Err(_) => break 'conn,
+                            }
+                        }
+                    }
+                    buf.drain(0..consumed);
+                    if close_after { break 'conn; }
+                    if buf.is_empty() { break; }
+                }
+                Ok(None) if buf.len() > cfg.max_conn_buffer => {
+                    let _ = respond_error(&mut stream, "HTTP/1.1", ErrorKind::PayloadTooLarge, cfg);
+                    break 'conn;
+                }
+                Ok(None) => break, // need more data
+                Err(e) => {
+                    let _ = respond_error(&mut stream, "HTTP/1.1", e.to_error_kind(), cfg);
+                    break 'conn;
+                }
+            }
+        }
+    }
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+}
+
+/// What [`handle_request`] leaves to its caller.
+enum Routed {
+    /// Answered in full, or refused by a gate.
+    Done,
+    /// A file response that still needs its disk reads: finish it with `load_file` +
+    /// `finish_file`, inline or on the I/O pool.
+    File(blockio::FileJob),
+    /// Cleared by every gate; the caller hands it to its `proxy_pass` route or upgrade.
+    Pass,
+}
+
+/// Everything up to the static-file stage. With `pass` the request belongs to a
+/// `proxy_pass` route or asks for an upgrade: it goes through the Host, access, WAF,
+/// auth and RBAC gates and comes back as [`Routed::Pass`] without a method check.
+/// `body` is only read by `PUT` uploads.
+fn handle_request(stream: &mut TcpStream, version: &str, method: &str, path: &str, headers: &[(&str,&str)], body: &[u8], cfg: &std::sync::Arc<ServerConfig>, locale: &str, keep_alive: bool, peer: &str, pass: bool) -> std::io::Result<Routed> {
     let start_sys = std::time::SystemTime::now();
     // original start Instant for latency below
     let start = std::time::Instant::now();
+    let deadline = Deadline::after_ms(cfg.request_timeout_ms);
+
+    // Behind a trusted proxy the client address, scheme and host are the proxy's to report.
+    let fwd = forwarded::resolve(headers, peer, &cfg.trusted_proxies);
+    let client_ip = fwd.as_ref().and_then(|f| f.client).map(|ip| ip.to_string());
+    let peer = client_ip.as_deref().unwrap_or(peer);
+    let fwd_host = fwd.as_ref().and_then(|f| f.host).and_then(uri::parse_host);
 
     // --- Trace Context ---
     let tp_ctx = headers.iter()
         .find(|(k,_)| k.eq_ignore_ascii_case("traceparent"))
         .and_then(|(_,v)| TraceContext::parse(*v))
-        .unwrap_or_else(|| TraceContext::generate());
-    let tp_header_line = format!("traceparent: {}\r\n", tp_ctx.header());
+        .unwrap_or_else(|| TraceContext::generate_sampled(cfg.otel.trace_sample_rate));
+    // Echoed on every response and in the access log for correlation without OTLP.
+    let request_id = selenia_core::request_id::from_headers(headers);
+    let mut trace_lines = format!("traceparent: {}\r\nX-Request-Id: {}\r\n", tp_ctx.header(), request_id);
+    if cfg.tls_cert.is_none() && fwd.as_ref().and_then(|f| f.proto).map_or(false, |p| p.eq_ignore_ascii_case("https")) {
+        // TLS ended at the proxy; ResponseHeaders only adds HSTS for local TLS.
+        trace_lines.push_str("Strict-Transport-Security: max-age=31536000; includeSubDomains\r\n");
+    }
+    if let Some(alt_svc) = cfg.http3.alt_svc_value() {
+        // HTTP/3 discovery (RFC 7838): clients may switch to QUIC on the advertised port.
+        trace_lines.push_str(&format!("Alt-Svc: {}\r\n", alt_svc));
+    }
+    // Extra per-response header lines travel with the traceparent line, errors included.
+    let tp_header_line = format!("{}{}", trace_lines, cors::response_headers(cfg.cors.as_ref(), headers));
+    // Queries may carry tokens, so access logs show them only when `log_query` is set.
+    let path_only = uri::split_target(path).0;
+    let log_target = if cfg.log_query { path } else { path_only };
+    let span = RequestSpan { method, log_target, path: path_only, trace: &tp_ctx, request_id: &request_id, start, start_sys };
+
+    // Decode before any path-based decision so `%2e%2e` cannot slip past the traversal check.
+    let decoded_path = match uri::decode_path(path_only) {
+        Some(p) => p,
+        None => {
+            metrics::inc_requests(); metrics::inc_errors();
+            respond_simple(stream, version, 400, "Bad Request".into(), keep_alive, cfg, &tp_header_line)?;
+            log_info!("{} - \"{} {}\" 400 0 {}", peer, method, log_target, request_id);
+            span.end(cfg, 400);
+            return Ok(Routed::Done);
+        }
+    };
+
+    // Host (RFC 7230 §5.4): exactly one valid value, mandatory on HTTP/1.1. With
+    // `strict_host`, names matching no vhost get 421 instead of the default root.
+    let hosts: Vec<&str> = headers.iter().filter(|(k,_)| k.eq_ignore_ascii_case("Host")).map(|(_,v)| *v).collect();
+    let host_check = match hosts.as_slice() {
+        [] if version != "HTTP/1.1" => Ok(None),
+        [h] => uri::parse_host(h).map(Some).ok_or(()),
+        _ => Err(()),
+    };
+    // vhost domains are normalized at load; `[::1]`, `[0::1]` and `::1` name one host.
+    let known_host = |h: &str| { let h = normalize_host(h); cfg.vhosts.iter().any(|vh| vh.domain == h) };
+    let host_reject = match host_check {
+        Err(()) => Some((400, "Bad Request")),
+        Ok(h) if cfg.strict_host && !fwd_host.or(h).map_or(false, known_host) => Some((421, "Misdirected Request")),
+        Ok(_) => None,
+    };
+    if let Some((status, msg)) = host_reject {
+        metrics::inc_requests(); metrics::inc_errors();
+        respond_simple(stream, version, status, msg.into(), keep_alive, cfg, &tp_header_line)?;
+        log_info!("{} - \"{} {}\" {} 0 {}", peer, method, log_target, status, request_id);
+        span.end(cfg, status);
+        return Ok(Routed::Done);
+    }
+    let host = fwd_host.or(host_check.ok().flatten());
+
+    // GET/HEAD define no body semantics (RFC 9110 §9.3.1). The parser has read past it,
+    // so answering here keeps a pipelined next request intact.
+    if cfg.reject_get_body && (method == "GET" || method == "HEAD") && parser::declares_body(headers) {
+        metrics::inc_requests(); metrics::inc_errors();
+        respond_simple(stream, version, 400, "Bad Request".into(), keep_alive, cfg, &tp_header_line)?;
+        log_info!("{} - \"{} {}\" 400 0 {}", peer, method, log_target, request_id);
+        span.end(cfg, 400);
+        return Ok(Routed::Done);
+    }
+
+    // Operational endpoints under `metrics_access` use its own allowlist and credentials
+    // instead of the content rules, so a scraper needs only one set of credentials.
+    let ops_endpoint = cfg.metrics_access.as_ref().map_or(false, |m| m.paths.iter().any(|p| *p == decoded_path));
+    if ops_endpoint {
+        let auth = headers.iter().find(|(k,_)| k.eq_ignore_ascii_case("Authorization")).map(|(_,v)| *v);
+        let denied = if !acl::metrics_allowed(peer) {
+            Some((403, "Forbidden", String::new()))
+        } else {
+            auth::check_metrics(auth).err().map(|challenge| (401, "Unauthorized", challenge))
+        };
+        if let Some((status, msg, challenge)) = denied {
+            metrics::inc_requests(); metrics::inc_errors();
+            let extra = format!("{}{}", tp_header_line, challenge);
+            respond_simple(stream, version, status, msg.into(), keep_alive, cfg, &extra)?;
+            log_info!("{} - \"{} {}\" {} 0 {}", peer, method, log_target, status, request_id);
+            span.end(cfg, status);
+            return Ok(Routed::Done);
+        }
+    }
+
+    if !ops_endpoint && !acl::allowed(&decoded_path, peer) {
+        metrics::inc_requests(); metrics::inc_errors();
+        respond_simple(stream, version, 403, "Forbidden".into(), keep_alive, cfg, &tp_header_line)?;
+        log_info!("{} - \"{} {}\" 403 0 {}", peer, method, log_target, request_id);
+        span.end(cfg, 403);
+        return Ok(Routed::Done);
+    }
 
     if !waf::evaluate(method, path, &headers.iter().map(|(a,b)|(a.to_string(),b.to_string())).collect::<Vec<_>>()) {
         respond_simple(stream, version, 403, "Forbidden".into(), keep_alive, cfg, &tp_header_line)?;
-        let latency = start.elapsed();
-        selenia_core::metrics::observe_latency(latency);
-        let end_ns = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
-        let start_ns = start_sys.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
-        let span_name = format!("{} {}", method, path);
-        selenia_core::otel::export_span(&span_name, start_ns, end_ns);
-        return Ok(());
+        span.end(cfg, 403);
+        return Ok(Routed::Done);
+    }
+
+    // `request_timeout_ms` is checked after each stage that may block.
+    if deadline.expired() {
+        respond_timeout(stream, version, keep_alive, cfg, &tp_header_line)?;
+        log_info!("{} - \"{} {}\" 504 0 {}", peer, method, log_target, request_id);
+        span.end(cfg, 504);
+        return Ok(Routed::Done);
     }
 
-    if method != "GET" && method != "HEAD" {
+    // CORS preflight is answered before method/auth checks (browsers send no credentials here).
+    // Upstreams answer their own, as they define which methods they take.
+    if let Some(cors_lines) = cors::preflight(cfg.cors.as_ref(), method, headers).filter(|_| !pass) {
+        let extra = format!("{}{}", trace_lines, cors_lines);
+        respond_simple(stream, version, 204, String::new(), keep_alive, cfg, &extra)?;
+        span.end(cfg, 204);
+        return Ok(Routed::Done);
+    }
+
+    let upload = method == "PUT" && cfg.upload_dir.is_some();
+    if !pass && !upload && method != "GET" && method != "HEAD" {
         respond_simple(stream, version, 405, translate(locale, "http.method_not_allowed"), keep_alive, cfg, &tp_header_line)?;
-        let latency = start.elapsed();
-        selenia_core::metrics::observe_latency(latency);
-        let end_ns = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
-        let start_ns = start_sys.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
-        let span_name = format!("{} {}", method, path);
-        selenia_core::otel::export_span(&span_name, start_ns, end_ns);
-        return Ok(());
+        span.end(cfg, 405);
+        return Ok(Routed::Done);
     }
     // RBAC check
     let auth = headers.iter().find(|(k,_)| k.eq_ignore_ascii_case("Authorization")).map(|(_,v)| *v);
-    if !rbac::validate(path, auth) {
+    // Basic/Bearer gate (independent of JWT RBAC)
+    let content_auth = if ops_endpoint { Ok(()) } else { auth::check(&decoded_path, auth) };
+    if let Err(challenge) = content_auth {
+        let extra = format!("{}{}", tp_header_line, challenge);
+        respond_simple(stream, version, 401, "Unauthorized".into(), keep_alive, cfg, &extra)?;
+        span.end(cfg, 401);
+        return Ok(Routed::Done);
+    }
+    if !ops_endpoint && !rbac::validate(&decoded_path, auth) {
         respond_simple(stream, version, 403, "Forbidden".into(), keep_alive, cfg, &tp_header_line)?;
-        let latency = start.elapsed();
-        selenia_core::metrics::observe_latency(latency);
-        let end_ns = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
-        let start_ns = start_sys.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
-        let span_name = format!("{} {}", method, path);
-        selenia_core::otel::export_span(&span_name, start_ns, end_ns);
-        return Ok(());
+        span.end(cfg, 403);
+        return Ok(Routed::Done);
+    }
+
+    if deadline.expired() {
+        respond_timeout(stream, version, keep_alive, cfg, &tp_header_line)?;
+        log_info!("{} - \"{} {}\" 504 0 {}", peer, method, log_target, request_id);
+        span.end(cfg, 504);
+        return Ok(Routed::Done);
+    }
+    // Proxied and upgraded requests leave here; what follows serves local content.
+    if pass { return Ok(Routed::Pass); }
+
+    // `PUT` stores the body as a new file under `upload_dir`, never replacing one.
+    // A compressed body is stored inflated, within the same `max_conn_buffer` cap.
+    if let Some(dir) = cfg.upload_dir.as_deref().filter(|_| upload) {
+        metrics::inc_requests();
+        let dest = sanitize_path(dir, &decoded_path);
+        let encoding = headers.iter().find(|(k,_)| k.eq_ignore_ascii_case("Content-Encoding")).map(|(_,v)| *v);
+        let (status, msg) = match compress::decode_body(encoding, body, cfg.max_conn_buffer) {
+            Err(kind) => (kind.status_code(), response::reason(kind.status_code())),
+            Ok(_) if dest == Path::new("/invalid") => (403, "Forbidden"),
+            Ok(body) => match store_upload(&dest, &body, cfg.upload_file_mode) {
+                Ok(()) => (201, "Created"),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => (409, "Conflict"),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (404, "Not Found"),
+                Err(e) => {
+                    log_error!("upload to {} failed: {}", dest.display(), e);
+                    (500, "Internal Server Error")
+                }
+            },
+        };
+        if status != 201 { metrics::inc_errors(); }
+        respond_simple(stream, version, status, msg.into(), keep_alive, cfg, &tp_header_line)?;
+        log_info!("{} - \"{} {}\" {} {} {}", peer, method, log_target, status, body.len(), request_id);
+        span.end(cfg, status);
+        return Ok(Routed::Done);
     }
 
     // Metrics endpoint high priority
-    if path == "/metrics" {
+    if path_only == "/metrics" {
         metrics::inc_requests();
-        let body = metrics::render();
-        let mut headers = format!("{} 200 OK\r\nContent-Type: text/plain; version=0\r\nContent-Length: {}\r\n", version, body.len());
-        headers.push_str(&tp_header_line);
-        if keep_alive {
-            headers.push_str("Connection: keep-alive\r\n");
-            let (ka_timeout, ka_max) = keepalive::current();
-            headers.push_str(&format!("Keep-Alive: timeout={}, max={}\r\n", ka_timeout, ka_max));
-        } else {
-            headers.push_str("Connection: close\r\n");
-        }
-        headers.push_str("\r\n");
-        stream.write_all(headers.as_bytes())?;
+        // `?format=` wins over `Accept`; Prometheus text stays the default.
+        let format = uri::split_target(path).1
+            .and_then(|q| uri::parse_query(q).into_iter().find(|(k, _)| k == "format"))
+            .and_then(|(_, v)| metrics::Format::from_name(&v))
+            .or_else(|| headers.iter().find(|(k, _)| k.eq_ignore_ascii_case("Accept")).map(|(_, v)| metrics::Format::from_accept(v)))
+            .unwrap_or(metrics::Format::Prometheus);
+        let body = metrics::render_format(format);
+        let mut head = ResponseHeaders::new(version, 200, cfg);
+        head.header("Content-Type", format.content_type())
+            .header("Content-Length", body.len())
+            .header("Vary", "Accept")
+            .lines(&tp_header_line)
+            .connection(keep_alive);
+        stream.write_all(head.finish().as_bytes())?;
         stream.write_all(body.as_bytes())?;
-        let latency = start.elapsed();
-        selenia_core::metrics::observe_latency(latency);
-        let end_ns = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
-        let start_ns = start_sys.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
-        let span_name = format!("{} {}", method, path);
-        selenia_core::otel::export_span(&span_name, start_ns, end_ns);
-        return Ok(());
+        span.end(cfg, 200);
+        return Ok(Routed::Done);
     }
 
     // Virtual host selection
     let mut effective_root = cfg.root_dir.clone();
     let mut effective_cache = cfg.cache.clone();
-    for (k,v) in headers {
-        if k.eq_ignore_ascii_case("Host") {
-            let host=v.split(':').next().unwrap_or(v);
-            if let Some(vh)=cfg.vhosts.iter().find(|vh| vh.domain==host) {
-                effective_root=vh.root.clone();
-                if vh.cache.is_some() { effective_cache=vh.cache.clone(); }
-            }
-            break;
-        }
+    if let Some(vh)=host.map(normalize_host).and_then(|h| cfg.vhosts.iter().find(|vh| vh.domain == h)) {
+        effective_root=vh.root.clone();
+        if vh.cache.is_some() { effective_cache=vh.cache.clone(); }
+    }
+
+    // One URL per resource: `/dir` → `/dir/` and, optionally, the on-disk letter case.
+    let normalize = (cfg.trailing_slash_redirect || cfg.case_insensitive_paths) && (method == "GET" || method == "HEAD");
+    let canonical = if normalize {
+        normalize::canonical(&effective_root, &decoded_path, cfg.trailing_slash_redirect, cfg.case_insensitive_paths)
+    } else { None };
+    if let Some(canon) = canonical {
+        metrics::inc_requests();
+        let query = uri::split_target(path).1.map(|q| format!("?{}", q)).unwrap_or_default();
+        let extra = format!("{}Location: {}{}\r\n", tp_header_line, uri::encode_path(&canon), query);
+        respond_simple(stream, version, 301, "Moved Permanently".into(), keep_alive, cfg, &extra)?;
+        log_info!("{} - \"{} {}\" 301 0 {}", peer, method, log_target, request_id);
+        span.end(cfg, 301);
+        return Ok(Routed::Done);
     }
 
-    let fs_path = sanitize_path(&effective_root, path);
-    let accept_gzip = headers
-        .iter()
-        .filter(|(k, _)| k.eq_ignore_ascii_case("Accept-Encoding"))
-        .flat_map(|(_, v)| v.split(','))
-        .filter_map(|e| {
-            let mut parts = e.trim().split(';');
-            let enc = parts.next()?.trim();
-            let q = parts
-                .find_map(|p| {
-                    let mut kv = p.trim().split('=');
-                    if kv.next()? == "q" { kv.next() } else { None }
-                })
-                .and_then(|s| s.parse::<f32>().ok())
-                .unwrap_or(1.0);
-            Some((enc, q))
-        })
-        .filter(|(enc, q)| *enc == "gzip" && *q > 0.0)
-        .next()
-        .is_some();
-
-    let meta = match fs::metadata(&fs_path) {
-        Ok(m) if m.is_file() => m,
-        _ => {
+    let fs_path = sanitize_path(&effective_root, &decoded_path);
+    let accept_encoding: Vec<&str> = headers.iter().filter(|(k, _)| k.eq_ignore_ascii_case("Accept-Encoding")).map(|(_, v)| *v).collect();
+    let accept_gzip = match compress::negotiate(&accept_encoding) {
+        Some(enc) => matches!(enc, compress::Encoding::Gzip),
+        None => {
             metrics::inc_requests(); metrics::inc_errors();
-            respond_simple(stream, version, 404, translate(locale, "http.not_found"), keep_alive, cfg, &tp_header_line)?;
-            log_info!("{} - \"{} {}\" 404 0", peer, method, path);
-            let latency = start.elapsed();
-            selenia_core::metrics::observe_latency(latency);
-            let end_ns = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
-            let start_ns = start_sys.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
-            let span_name = format!("{} {}", method, path);
-            selenia_core::otel::export_span(&span_name, start_ns, end_ns);
-            return Ok(());
+            respond_simple(stream, version, 406, "Not Acceptable".into(), keep_alive, cfg, &tp_header_line)?;
+            log_info!("{} - \"{} {}\" 406 0 {}", peer, method, log_target, request_id);
+            span.end(cfg, 406);
+            return Ok(Routed::Done);
         }
     };
-    let total_len = meta.len();
-    // Compute weak ETag based on size and mtime
-    let mtime = meta.modified().unwrap_or(std::time::SystemTime::UNIX_EPOCH);
-    let msecs = mtime.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs();
-    let etag_raw = format!("{}:{}", total_len, msecs);
-    let etag_bytes = sha256_digest(etag_raw.as_bytes());
-    let etag_str = format!("\"{:x}{:x}{:x}{:x}\"", etag_bytes[0], etag_bytes[1], etag_bytes[2], etag_bytes[3]);
-    // Conditional If-None-Match
-    for (k,v) in headers {
-        if k.eq_ignore_ascii_case("If-None-Match") && *v == etag_str {
+
+    Ok(Routed::File(blockio::FileJob {
+        cfg: std::sync::Arc::clone(cfg),
+        version: version.to_string(),
+        method: method.to_string(),
+        path: path.to_string(),
+        log_target: log_target.to_string(),
+        peer: peer.to_string(),
+        locale: locale.to_string(),
+        keep_alive,
+        accept_gzip,
+        cache: effective_cache,
+        fs_path,
+        if_none_match: headers.iter().filter(|(k,_)| k.eq_ignore_ascii_case("If-None-Match")).map(|(_,v)| v.to_string()).collect(),
+        // Single range only; with repeated headers the last one wins.
+        range: headers.iter().filter(|(k,_)| k.eq_ignore_ascii_case("Range")).map(|(_,v)| v.to_string()).last(),
+        stream_threshold: cfg.stream_threshold,
+        mmap_threshold: cfg.mmap_threshold,
+        tp_header_line,
+        request_id,
+        trace: tp_ctx,
+        start,
+        start_sys,
+        deadline,
+    }))
+}
+
+/// Write the response for a loaded static file (see `blockio::load_file`) under the
+/// configuration snapshot its request was handled with.
+fn finish_file(stream: &mut TcpStream, job: blockio::FileJob, outcome: blockio::FileOutcome, pending: &mut Option<zerocopy::PendingSend>) -> std::io::Result<()> {
+    let blockio::FileJob { cfg, version, method, path, log_target, peer, locale, keep_alive, accept_gzip, cache: effective_cache, fs_path, tp_header_line, request_id, trace, start, start_sys, deadline, .. } = job;
+    let cfg = &*cfg;
+    let (version, method) = (version.as_str(), method.as_str());
+    let span = RequestSpan { method, log_target: &log_target, path: uri::split_target(&path).0, trace: &trace, request_id: &request_id, start, start_sys };
+    // A failed open/read may still be answered from the last good copy (`serve_stale_on_error`).
+    let mut stale = match &outcome {
+        blockio::FileOutcome::OpenFailed(e) | blockio::FileOutcome::ReadFailed(e) => stale_copy(effective_cache.as_ref(), &fs_path).map(|copy| {
+            log_warn!("serving stale copy of {}: {}", fs_path.display(), e);
+            copy
+        }),
+        _ => None,
+    };
+    let stale_age = stale.as_ref().map(|copy| copy.stored.elapsed().as_secs());
+    let (total_len, etag_str, range, streamed, file, map, body, bom_charset) = match outcome {
+        blockio::FileOutcome::Ready { total_len, etag, range, streamed, file, map, body, bom_charset } => (total_len, etag, range, streamed, Some(file), map, body, bom_charset),
+        blockio::FileOutcome::NotFound => {
+            metrics::inc_requests(); metrics::inc_errors();
+            respond_simple(stream, version, 404, translate(&locale, "http.not_found"), keep_alive, cfg, &tp_header_line)?;
+            log_info!("{} - \"{} {}\" 404 0 {}", peer, method, log_target, request_id);
+            span.end(cfg, 404);
+            return Ok(());
+        }
+        blockio::FileOutcome::RangeNotSatisfiable(total_len) => {
+            let kind = ErrorKind::RangeNotSatisfiable;
+            metrics::inc_requests(); metrics::inc_errors();
+            let mut head = ResponseHeaders::new(version, kind.status_code(), cfg);
+            head.header("Content-Range", format!("bytes */{}", total_len))
+                .header("Content-Length", 0)
+                .connection(keep_alive)
+                .lines(&tp_header_line);
+            stream.write_all(head.finish().as_bytes())?;
+            log_info!("{} - \"{} {}\" {} 0 {}", peer, method, log_target, kind.status_code(), request_id);
+            span.end(cfg, kind.status_code());
+            return Ok(());
+        }
+        blockio::FileOutcome::NotModified => {
             respond_simple(stream, version, 304, String::new(), keep_alive, cfg, &tp_header_line)?;
-            let latency = start.elapsed();
-            selenia_core::metrics::observe_latency(latency);
-            let end_ns = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
-            let start_ns = start_sys.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
-            let span_name = format!("{} {}", method, path);
-            selenia_core::otel::export_span(&span_name, start_ns, end_ns);
+            span.end(cfg, 304);
+            return Ok(());
+        }
+        blockio::FileOutcome::OpenFailed(_) | blockio::FileOutcome::ReadFailed(_) if stale.is_some() => {
+            let copy = stale.take().unwrap();
+            (copy.body.len() as u64, copy.etag, None, false, None, None, copy.body, copy.bom_charset)
+        }
+        blockio::FileOutcome::OpenFailed(e) | blockio::FileOutcome::ReadFailed(e) => {
+            // EMFILE/ENFILE is transient pressure → 503; anything else is a 500.
+            let fd_pressure = matches!(e.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE));
+            let (status, msg) = if fd_pressure { (503, "Service Unavailable") } else { (500, "Internal Server Error") };
+            metrics::inc_requests(); metrics::inc_errors();
+            log_error!("reading {} failed: {}", fs_path.display(), e);
+            respond_simple(stream, version, status, msg.into(), keep_alive, cfg, &tp_header_line)?;
+            log_info!("{} - \"{} {}\" {} 0 {}", peer, method, log_target, status, request_id);
+            span.end(cfg, status);
             return Ok(());
         }
+        blockio::FileOutcome::TimedOut => {
+            respond_timeout(stream, version, keep_alive, cfg, &tp_header_line)?;
+            log_info!("{} - \"{} {}\" 504 0 {}", peer, method, log_target, request_id);
+            span.end(cfg, 504);
+            return Ok(());
+        }
+    };
+    if stale_age.is_none() && range.is_none() && !streamed && effective_cache.as_ref().map_or(false, |c| c.serve_stale_on_error) {
+        blockio::remember_stale(&fs_path, &body, &etag_str, bom_charset);
+    }
+    // Range and streamed bytes are sent raw from disk, so only buffered bodies get compressed.
+    let gzip = accept_gzip && range.is_none() && !streamed;
+    let body = if gzip { compress::encode(&body, compress::Encoding::Gzip) } else { body };
+    // Compressing a large body can eat what was left of the budget.
+    if deadline.expired() {
+        respond_timeout(stream, version, keep_alive, cfg, &tp_header_line)?;
+        log_info!("{} - \"{} {}\" 504 0 {}", peer, method, log_target, request_id);
+        span.end(cfg, 504);
+        return Ok(());
+    }
+    let (body_len, status, content_range_hdr) = match range {
+        Some((s,e)) => (e-s+1, 206, Some(format!("bytes {}-{}/{}", s, e, total_len))),
+        None if streamed => (total_len, 200, None),
+        None => (body.len() as u64, 200, None),
+    };
+    metrics::inc_requests();
+    metrics::add_bytes(body_len);
+
+    let mime = content_type(&fs_path, &cfg.charset, bom_charset);
+    let mut head = ResponseHeaders::new(version, status, cfg);
+    head.header("Content-Type", mime);
+    if let Some(cr)=content_range_hdr { head.header("Content-Range", cr); }
+    if let Some(cache)=&effective_cache {
+        let mut directives = format!("max-age={}, stale-while-revalidate={}", cache.max_age, cache.stale_while_revalidate);
+        if cache.stale_if_error > 0 { directives.push_str(&format!(", stale-if-error={}", cache.stale_if_error)); }
+        head.header("Cache-Control", directives);
+    }
+    if let Some(age) = stale_age {
+        metrics::inc_stale_served();
+        head.header("Age", age).header("Warning", "111 - \"Revalidation Failed\"");
+    }
+    head.connection(keep_alive)
+        .header("ETag", &etag_str)
+        .header("Content-Length", body_len);
+    if gzip { head.header("Content-Encoding", "gzip"); }
+    head.header("Vary", "Accept-Encoding").lines(&tp_header_line);
+    stream.write_all(head.finish().as_bytes())?;
+    if method != "HEAD" {
+        match (range, file, map) {
+            // A full socket buffer parks the rest on the connection (see the event loop).
+            (Some((s,_)), _, Some(map)) => *pending = zerocopy::send_mapped(stream, map, s, body_len)?,
+            (None, _, Some(map)) if streamed => *pending = zerocopy::send_mapped(stream, map, 0, body_len)?,
+            (Some((s,_)), Some(file), None) => *pending = zerocopy::send_file(stream, file, s, body_len)?,
+            (None, Some(file), None) if streamed => *pending = zerocopy::send_file(stream, file, 0, body_len)?,
+            _ => stream.write_all(&body)?,
+        }
     }
+    log_info!("{} - \"{} {}\" {} {} {}", peer, method, log_target, status, body_len, request_id);
+    span.end(cfg, status);
+    Ok(())
+}
 
-    // Parse Range header (bytes) – single range only
-            let mut range: Option<(u64,u64)> = None;
-            for (k,v) in headers {
-                if k.eq_ignore_ascii_case("Range") {
-                    if let Some(r) = v.strip_prefix("bytes=") {
-                        let parts: Vec<&str> = r.split('-').collect();
-                        if parts.len()==2 {
-                            let start_opt = if !parts[0].is_empty() { parts[0].parse::<u64>().ok() } else { None };
-                            let end_opt = if !parts[1].is_empty() { parts[1].parse::<u64>().ok() } else { None };
-                            if let Some(s)=start_opt {
-                                let e = end_opt.unwrap_or(total_len-1);
-                                if s<=e && e<total_len {
-                                    range = Some((s,e));
-                                }
-                            } else if let Some(e)=end_opt { // suffix range
-                                if e!=0 {
-                                    range = Some((total_len-e, total_len-1));
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+/// The last good copy of `fs_path`, when the cache config allows serving it after an I/O error.
+fn stale_copy(cache: Option<&CacheConfig>, fs_path: &Path) -> Option<blockio::StaleEntry> {
+    let cache = cache.filter(|c| c.serve_stale_on_error)?;
+    blockio::stale(fs_path, std::time::Duration::from_secs(cache.max_age as u64 + cache.stale_if_error as u64))
+}
 
-            let full_body = fs::read(&fs_path)?;
-            let (body, status, content_range_hdr) = if let Some((s,e)) = range {
-                let slice = &full_body[s as usize ..= e as usize];
-                (slice.to_vec(), 206, Some(format!("bytes {}-{}/{}", s, e, total_len)))
-            } else { (full_body, 200, None) };
-
-            metrics::inc_requests();
-            metrics::add_bytes(body.len() as u64);
-
-            let mime = guess_mime(&fs_path);
-            let mut headers_txt = format!(
-                "{} {} OK\r\nContent-Type: {}\r\n",
-                version,
-                status,
-                mime
-            );
-            if let Some(cr)=content_range_hdr { headers_txt.push_str(&format!("Content-Range: {}\r\n", cr)); }
-            if cfg.tls_cert.is_some() {
-                headers_txt.push_str("Strict-Transport-Security: max-age=31536000; includeSubDomains\r\n");
-            }
-            if let Some(cache)=&effective_cache {
-                headers_txt.push_str(&format!("Cache-Control: max-age={}, stale-while-revalidate={}\r\n", cache.max_age, cache.stale_while_revalidate));
-            }
-            if keep_alive {
-                headers_txt.push_str("Connection: keep-alive\r\n");
-                let (ka_timeout, ka_max) = keepalive::current();
-                headers_txt.push_str(&format!("Keep-Alive: timeout={}, max={}\r\n", ka_timeout, ka_max));
-            } else {
-                headers_txt.push_str("Connection: close\r\n");
-            }
-            headers_txt.push_str(&format!("ETag: {}\r\n", etag_str));
-            headers_txt.push_str(&format!("Content-Length: {}\r\n", body.len()));
-            if accept_gzip { headers_txt.push_str("Content-Encoding: gzip\r\n"); }
-            headers_txt.push_str(&tp_header_line);
-            headers_txt.push_str("\r\n");
-            stream.write_all(headers_txt.as_bytes())?;
-            if method != "HEAD" {
-                stream.write_all(&body)?;
-            }
-            log_info!("{} - \"{} {}\" {} {}", peer, method, path, status, body.len());
-        // Response finished
-        
-    let latency = start.elapsed();
-    selenia_core::metrics::observe_latency(latency);
-    // Export OTel span
-    let end_ns = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
-    let start_ns = start_sys.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
-    let span_name = format!("{} {}", method, path);
-    selenia_core::otel::export_span(&span_name, start_ns, end_ns);
-    Ok(())
+/// The accounting every response of a request ends with, whatever its status.
+struct RequestSpan<'a> {
+    method: &'a str,
+    /// The target as the access log shows it.
+    log_target: &'a str,
+    /// The target without its query, for the span name.
+    path: &'a str,
+    trace: &'a TraceContext,
+    request_id: &'a str,
+    start: std::time::Instant,
+    start_sys: std::time::SystemTime,
+}
+
+impl RequestSpan<'_> {
+    /// Record the latency, log it when slow and export the OTel span.
+    fn end(&self, cfg: &ServerConfig, status: u16) {
+        let latency = self.start.elapsed();
+        metrics::observe_latency(latency);
+        log_slow(cfg, self.method, self.log_target, status, latency, &self.trace.trace_id_hex());
+        let nanos = |t: std::time::SystemTime| t.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        let span_name = format!("{} {}", self.method, self.path);
+        selenia_core::otel::export_span(self.trace, &span_name, nanos(self.start_sys), nanos(std::time::SystemTime::now()), &[("http.request_id", self.request_id)]);
+    }
+}
+
+/// WARN line for responses slower than `slow_request_ms`, apart from the access log
+/// so it survives an INFO-suppressed level.
+fn log_slow(cfg: &ServerConfig, method: &str, target: &str, status: u16, latency: std::time::Duration, trace_id: &str) {
+    if cfg.slow_request_ms > 0 && latency.as_millis() > cfg.slow_request_ms as u128 {
+        log_warn!("slow request: \"{} {}\" {} {}ms trace_id={}", method, target, status, latency.as_millis(), trace_id);
+    }
 }
 
 fn respond_simple(stream: &mut TcpStream, version: &str, status: u16, body: String, keep_alive: bool, cfg:&ServerConfig, tp_header:&str) -> std::io::Result<()> {
-    let mut headers = format!(
-        "{} {} \r\nContent-Length: {}\r\nContent-Type: text/plain; charset=utf-8\r\n",
-        version,
-        status,
-        body.len()
-    );
-    if cfg.tls_cert.is_some() {
-        headers.push_str("Strict-Transport-Security: max-age=31536000; includeSubDomains\r\n");
-    }
-    if keep_alive {
-        headers.push_str("Connection: keep-alive\r\n");
-        let (ka_timeout, ka_max) = keepalive::current();
-        headers.push_str(&format!("Keep-Alive: timeout={}, max={}\r\n", ka_timeout, ka_max));
-    } else {
-        headers.push_str("Connection: close\r\n");
-    }
-    headers.push_str(tp_header);
-    headers.push_str("\r\n");
-    stream.write_all(headers.as_bytes())?;
+    let mut head = ResponseHeaders::new(version, status, cfg);
+    head.header("Content-Length", body.len())
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .connection(keep_alive)
+        .lines(tp_header);
+    stream.write_all(head.finish().as_bytes())?;
     stream.write_all(body.as_bytes())?;
     Ok(())
 }
 
-fn respond_error(stream: &mut TcpStream, version: &str, kind: ErrorKind) -> std::io::Result<()> {
-    let status = kind.status_code();
-    use std::io::Write;
-    let reason = match status {
-        400 => "Bad Request",
-        403 => "Forbidden",
-        404 => "Not Found",
-        500 => "Internal Server Error",
-        504 => "Gateway Timeout",
-        _ => "Error",
-    };
-    let resp = format!(
-        "{version} {status} {reason}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
-    );
-    stream.write_all(resp.as_bytes())
+/// 504 for a request whose `request_timeout_ms` ran out before its response started.
+fn respond_timeout(stream: &mut TcpStream, version: &str, keep_alive: bool, cfg: &ServerConfig, tp_header: &str) -> std::io::Result<()> {
+    metrics::inc_requests(); metrics::inc_errors(); metrics::inc_request_timeouts();
+    respond_simple(stream, version, 504, "Gateway Timeout".into(), keep_alive, cfg, tp_header)
+}
+
+fn respond_error(stream: &mut TcpStream, version: &str, kind: ErrorKind, cfg: &ServerConfig) -> std::io::Result<()> {
+    let mut head = ResponseHeaders::new(version, kind.status_code(), cfg);
+    head.header("Content-Length", 0).connection(false);
+    stream.write_all(head.finish().as_bytes())
 }
 
 fn guess_mime(path: &Path) -> &'static str {
@@ -542,6 +792,8 @@ fn guess_mime(path: &Path) -> &'static str {
         Some("html") => "text/html",
         Some("css") => "text/css",
         Some("js") => "application/javascript",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain",
         Some("png") => "image/png",
         Some("jpg") | Some("jpeg") => "image/jpeg",
         Some("gif") => "image/gif",
@@ -550,6 +802,32 @@ fn guess_mime(path: &Path) -> &'static str {
     }
 }
 
+/// `guess_mime` plus a `charset=` parameter for textual types: a detected BOM
+/// (with `detect_bom`), then the per-extension override, then the default.
+fn content_type(path: &Path, charset: &CharsetConfig, bom: Option<&str>) -> String {
+    let mime = guess_mime(path);
+    let textual = mime.starts_with("text/") || mime == "application/javascript" || mime == "application/json";
+    if !textual { return mime.to_string(); }
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    let cs = bom.filter(|_| charset.detect_bom)
+        .or_else(|| charset.overrides.iter().find(|(e, _)| e.eq_ignore_ascii_case(ext)).map(|(_, c)| c.as_str()))
+        .or(charset.default.as_deref());
+    match cs {
+        Some(cs) => format!("{}; charset={}", mime, cs),
+        None => mime.to_string(),
+    }
+}
+
+/// Write an upload to a new file with `mode` permission bits; a partial file is removed.
+fn store_upload(dest: &Path, body: &[u8], mode: u32) -> std::io::Result<()> {
+    #[cfg(unix)]
+    let mut file = selenia_core::os::filemode::create_with_mode(dest, mode)?;
+    #[cfg(not(unix))]
+    let mut file = { let _ = mode; std::fs::File::create_new(dest)? };
+    file.write_all(body).inspect_err(|_| { let _ = std::fs::remove_file(dest); })
+}
+
+/// `uri_path` is the already percent-decoded path (see `uri::decode_path`).
 fn sanitize_path(root_dir: &str, uri_path: &str) -> PathBuf {
     // Remove query string and fragment
     let mut p = uri_path.split(['?', '#']).next().unwrap_or("");
@@ -569,16 +847,101 @@ fn sanitize_path(root_dir: &str, uri_path: &str) -> PathBuf {
     if full.is_dir() { full.join("index.html") } else { full }
 }
 
+/// Sibling worker processes, as exported by the master in `SWS_WORKERS`; 1 when run alone.
+fn worker_count() -> u32 {
+    std::env::var("SWS_WORKERS").ok().and_then(|v| v.parse::<u32>().ok()).filter(|&n| n > 0).unwrap_or(1)
+}
+
+/// Load the request gates and process-wide limits of `cfg`: `auth`, `access_control`,
+/// `metrics_access`, the rate limit, WASM limits, TLS policy and the OTLP exporter.
+/// Runs at startup and again for every configuration the runner accepts on reload.
+#[cfg(unix)]
+fn apply_gates(cfg: &ServerConfig) {
+    auth::init(&cfg.auth);
+    acl::init(&cfg.access_control);
+    init_metrics_access(cfg.metrics_access.as_ref());
+    selenia_core::ratelimit::configure(cfg.rate_limit.capacity, cfg.rate_limit.refill_per_sec);
+    selenia_core::wasm::configure(cfg.wasm.limits());
+    tls13::set_policy(cfg.tls_policy());
+    selenia_core::otel::init(&cfg.otel.endpoint, cfg.otel.protocol());
+}
+
+/// Load `metrics_access` into the auth and ACL gates.
+fn init_metrics_access(m: Option<&MetricsAccess>) {
+    let rule = m.map(|m| AuthRule { prefix: String::new(), realm: m.realm.clone(), htpasswd: m.htpasswd.clone(), bearer_tokens: m.bearer_tokens.clone() });
+    auth::init_metrics(rule.as_ref());
+    acl::init_metrics(m.map_or(&[][..], |m| &m.allow));
+}
+
 fn should_close(req: &parser::Request) -> bool {
-    // HTTP/1.0: デフォルト close。
-    // HTTP/1.1: Connection: close のみ close。
+    // HTTP/1.0: デフォルト close。keep-alive は Connection か旧来の Proxy-Connection で要求。
+    // HTTP/1.1: Connection: close のみ close。ヘッダ値はカンマ区切りのトークン列。
+    let has_token = |name: &str, token: &str| req.headers.iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+        .any(|(_, v)| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)));
+    if has_token("Connection", "close") { return true; }
     if req.version == "HTTP/1.0" {
-        return !req.headers.iter().any(|(k, v)| k.eq_ignore_ascii_case("Connection") && v.eq_ignore_ascii_case("keep-alive"));
+        return !(has_token("Connection", "keep-alive") || has_token("Proxy-Connection", "keep-alive"));
     }
-    for (k, v) in &req.headers {
-        if k.eq_ignore_ascii_case("Connection") && v.eq_ignore_ascii_case("close") {
-            return true;
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[cfg(not(unix))]
+    #[test]
+    fn fallback_serves_the_requested_file() {
+        let root = std::env::temp_dir().join(format!("sws-fallback-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("index.html"), "index").unwrap();
+        std::fs::write(root.join("page.txt"), "page").unwrap();
+        let yaml = root.join("config.yaml");
+        std::fs::write(&yaml, format!("server:\n  listen:\n    - \"127.0.0.1:8080\"\n  root_dir: \"{}\"\n  locale: \"en\"\n", root.display().to_string().replace('\\', "/"))).unwrap();
+        let cfg = std::sync::Arc::new(ServerConfig::load_from_yaml(&yaml).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let worker = std::thread::spawn(move || serve_blocking(server, &cfg));
+        // Two pipelined requests: the keep-alive one must not end the connection.
+        client.write_all(b"GET /page.txt HTTP/1.1\r\nHost: a\r\n\r\nGET /page.txt HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        worker.join().unwrap();
+        let _ = std::fs::remove_dir_all(&root);
+        assert!(out.starts_with("HTTP/1.1 200 "), "{}", out);
+        assert_eq!(out.matches("HTTP/1.1 200 ").count(), 2);
+        assert!(out.ends_with("page"));
+        assert!(!out.contains("index"));
+    }
+
+    #[test]
+    fn error_responses_carry_the_kind_status_and_reason() {
+        let yaml = std::env::temp_dir().join(format!("sws-error-kinds-{}.yaml", std::process::id()));
+        std::fs::write(&yaml, "server:\n  listen:\n    - \"127.0.0.1:8080\"\n  root_dir: \".\"\n  locale: \"en\"\n").unwrap();
+        let cfg = ServerConfig::load_from_yaml(&yaml).unwrap();
+        let _ = std::fs::remove_file(&yaml);
+        let cases = [
+            (ErrorKind::PayloadTooLarge, "413 Content Too Large"),
+            (ErrorKind::UriTooLong, "414 URI Too Long"),
+            (ErrorKind::RangeNotSatisfiable, "416 Range Not Satisfiable"),
+            (ErrorKind::HeaderTooLarge, "431 Request Header Fields Too Large"),
+            (ErrorKind::NotImplemented, "501 Not Implemented"),
+            (ErrorKind::VersionNotSupported, "505 HTTP Version Not Supported"),
+        ];
+        for (kind, status) in cases {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (mut server, _) = listener.accept().unwrap();
+            respond_error(&mut server, "HTTP/1.1", kind, &cfg).unwrap();
+            drop(server);
+            let mut out = String::new();
+            client.read_to_string(&mut out).unwrap();
+            assert!(out.starts_with(&format!("HTTP/1.1 {}\r\n", status)), "{}", out);
+            assert!(out.contains("\r\nContent-Length: 0\r\n") && out.contains("\r\nConnection: close\r\n"), "{}", out);
+            assert!(out.ends_with("\r\n\r\n"), "{}", out);
         }
     }
-    false
-} 
\ No newline at end of file
+}
@@ -0,0 +1,176 @@
+//! Read-only mappings of large static files (`mmap_threshold`, Unix only).
+//!
+//! A file is mapped once and the mapping is shared by later requests until the
+//! file's size or mtime changes. Mapped bytes only ever reach the kernel through
+//! `write(2)` on the socket and are never read in user space, so a file truncated
+//! while mapped makes that copy fail with `EFAULT` instead of raising SIGBUS. Each
+//! send also re-checks the descriptor's size first; either way the response ends
+//! with an error (the connection closes, as with a shrinking `sendfile`) and the
+//! mapping is dropped from the cache.
+
+use std::fs::File;
+use std::io;
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+
+#[cfg(unix)]
+pub use unix::{mapping, MappedFile};
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+    use std::path::PathBuf;
+    use std::sync::{Mutex, OnceLock};
+    use std::time::SystemTime;
+
+    /// Bound on cached mappings; the map is simply cleared when it fills up.
+    const MAPS_MAX: usize = 64;
+    /// Bytes handed to one `write(2)`.
+    const CHUNK: usize = 1 << 20;
+    /// `madvise` needs a page-aligned start; every supported page size is a multiple of this.
+    const PAGE: u64 = 4096;
+
+    #[derive(Debug)]
+    pub struct MappedFile {
+        ptr: *mut libc::c_void,
+        len: usize,
+        /// Kept open for the truncation check; the path may since name another file.
+        file: File,
+        path: PathBuf,
+        mtime: SystemTime,
+    }
+
+    // Read-only and never dereferenced in user space (see the module docs).
+    unsafe impl Send for MappedFile {}
+    unsafe impl Sync for MappedFile {}
+
+    impl Drop for MappedFile {
+        fn drop(&mut self) {
+            unsafe { libc::munmap(self.ptr, self.len); }
+        }
+    }
+
+    fn maps() -> &'static Mutex<HashMap<PathBuf, Arc<MappedFile>>> {
+        static MAPS: OnceLock<Mutex<HashMap<PathBuf, Arc<MappedFile>>>> = OnceLock::new();
+        MAPS.get_or_init(Default::default)
+    }
+
+    /// The cached mapping of `path` if it still matches `file`'s size and mtime, otherwise
+    /// a fresh mapping of `file` that replaces it.
+    pub fn mapping(path: &Path, file: &File) -> io::Result<Arc<MappedFile>> {
+        let meta = file.metadata()?;
+        let mtime = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        let mut maps = maps().lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(m) = maps.get(path).filter(|m| m.len as u64 == meta.len() && m.mtime == mtime) {
+            return Ok(Arc::clone(m));
+        }
+        if meta.len() == 0 || meta.len() > isize::MAX as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "file size cannot be mapped"));
+        }
+        let len = meta.len() as usize;
+        let file = file.try_clone()?;
+        let ptr = unsafe { libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, file.as_raw_fd(), 0) };
+        if ptr == libc::MAP_FAILED { return Err(io::Error::last_os_error()); }
+        unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL); }
+        let map = Arc::new(MappedFile { ptr, len, file, path: path.to_path_buf(), mtime });
+        if maps.len() >= MAPS_MAX { maps.clear(); }
+        maps.insert(path.to_path_buf(), Arc::clone(&map));
+        Ok(map)
+    }
+
+    impl MappedFile {
+        /// Ask the kernel to read `len` bytes from `offset` ahead of the send (advice only).
+        pub fn will_need(&self, offset: u64, len: u64) {
+            let start = offset - offset % PAGE;
+            let end = (offset + len).min(self.len as u64);
+            if start >= end { return; }
+            unsafe { libc::madvise((self.ptr as *mut u8).add(start as usize) as *mut libc::c_void, (end - start) as usize, libc::MADV_WILLNEED); }
+        }
+
+        /// Write the mapped bytes from `*off` up to `end` to `stream`. `*off` tracks progress,
+        /// so after a `WouldBlock` error the caller can resume where the socket buffer filled up.
+        pub fn write_range(&self, stream: &TcpStream, off: &mut u64, end: u64) -> io::Result<()> {
+            if end > self.len as u64 || self.file.metadata()?.len() < end {
+                self.evict();
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file truncated during send"));
+            }
+            let mut writer = stream;
+            while *off < end {
+                let want = ((end - *off) as usize).min(CHUNK);
+                // In bounds (`end <= len`); the slice is only passed on to the kernel.
+                let chunk = unsafe { std::slice::from_raw_parts((self.ptr as *const u8).add(*off as usize), want) };
+                match writer.write(chunk) {
+                    Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                    Ok(n) => *off += n as u64,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        // The pages went away under a truncation racing the check above.
+                        if e.raw_os_error() == Some(libc::EFAULT) { self.evict(); }
+                        return Err(e);
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        /// Forget this mapping so the next request maps the file afresh.
+        fn evict(&self) {
+            let mut maps = maps().lock().unwrap_or_else(|e| e.into_inner());
+            if maps.get(&self.path).map_or(false, |m| std::ptr::eq(Arc::as_ptr(m), self)) {
+                maps.remove(&self.path);
+            }
+        }
+    }
+}
+
+/// No mappings off Unix: `mapping` always fails and the file is sent from its descriptor.
+#[cfg(not(unix))]
+#[derive(Debug)]
+pub enum MappedFile {}
+
+#[cfg(not(unix))]
+impl MappedFile {
+    pub fn will_need(&self, _offset: u64, _len: u64) { match *self {} }
+    pub fn write_range(&self, _stream: &TcpStream, _off: &mut u64, _end: u64) -> io::Result<()> { match *self {} }
+}
+
+#[cfg(not(unix))]
+pub fn mapping(_path: &Path, _file: &File) -> io::Result<Arc<MappedFile>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "mmap is Unix-only"))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::mapping;
+    use std::fs::File;
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+
+    #[test]
+    fn truncated_file_fails_the_send_and_is_mapped_afresh() {
+        let path = std::env::temp_dir().join(format!("sws-mmap-{}-truncate.bin", std::process::id()));
+        std::fs::write(&path, vec![b'm'; 64 * 1024]).unwrap();
+        let map = mapping(&path, &File::open(&path).unwrap()).unwrap();
+        assert!(Arc::ptr_eq(&map, &mapping(&path, &File::open(&path).unwrap()).unwrap()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let sender = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut receiver, _) = listener.accept().unwrap();
+        let mut off = 0;
+        map.write_range(&sender, &mut off, 4096).unwrap();
+        let mut got = vec![0u8; 4096];
+        receiver.read_exact(&mut got).unwrap();
+        assert!(got.iter().all(|&b| b == b'm'));
+        // Shrunk under the mapping: the send stops with an error instead of SIGBUS.
+        File::options().write(true).open(&path).unwrap().set_len(1024).unwrap();
+        let mut off = 0;
+        assert_eq!(map.write_range(&sender, &mut off, 64 * 1024).unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+        let fresh = mapping(&path, &File::open(&path).unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(!Arc::ptr_eq(&map, &fresh));
+    }
+}
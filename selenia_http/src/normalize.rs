@@ -0,0 +1,70 @@
+//! Canonical static-file URLs (`trailing_slash_redirect`, `case_insensitive_paths`).
+//!
+//! One resource should have one URL, so caches and crawlers do not split it up:
+//! a directory is addressed as `/dir/`, and with case-insensitive matching a
+//! request is answered with a 301 to the on-disk spelling of every segment. The
+//! redirect target goes through the usual `sanitize_path` checks on the next request.
+
+use std::path::Path;
+
+/// The on-disk spelling of `name` in `dir`, when exactly one entry matches ignoring case.
+fn find_ignoring_case(dir: &Path, name: &str) -> Option<String> {
+    let wanted = name.to_lowercase();
+    let mut found = None;
+    for entry in std::fs::read_dir(dir).ok()?.flatten() {
+        let Ok(entry_name) = entry.file_name().into_string() else { continue };
+        if entry_name.to_lowercase() == wanted {
+            // Two spellings of one name (`a.txt`, `A.txt`): no canonical choice.
+            if found.is_some() { return None; }
+            found = Some(entry_name);
+        }
+    }
+    found
+}
+
+/// Canonical form of the decoded request `path` under `root`, or `None` when the
+/// request already uses it (or names nothing that exists).
+pub(crate) fn canonical(root: &str, path: &str, trailing_slash: bool, case_insensitive: bool) -> Option<String> {
+    let rest = path.strip_prefix('/')?;
+    // Dot segments are left to the traversal check in `sanitize_path`.
+    if rest.split('/').any(|s| s == "." || s == "..") { return None; }
+    let mut dir = Path::new(root).to_path_buf();
+    let mut out = String::with_capacity(path.len() + 1);
+    for seg in rest.split('/') {
+        out.push('/');
+        if seg.is_empty() { continue; }
+        let name = if case_insensitive && !dir.join(seg).exists() { find_ignoring_case(&dir, seg)? } else { seg.to_string() };
+        dir.push(&name);
+        out.push_str(&name);
+    }
+    if trailing_slash && !out.ends_with('/') && dir.is_dir() { out.push('/'); }
+    if out == path { return None; }
+    // Never point at something that resolves outside the root (symlinks).
+    let (target, root) = (dir.canonicalize().ok()?, Path::new(root).canonicalize().ok()?);
+    target.starts_with(&root).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::canonical;
+
+    #[test]
+    fn ambiguous_spellings_and_escapes_have_no_canonical_form() {
+        let root = std::env::temp_dir().join(format!("sws-normalize-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.txt"), "").unwrap();
+        std::fs::write(root.join("A.TXT"), "").unwrap();
+        std::fs::write(root.join("One.txt"), "").unwrap();
+        let r = root.to_str().unwrap();
+        assert_eq!(canonical(r, "/one.TXT", true, true).as_deref(), Some("/One.txt"));
+        assert_eq!(canonical(r, "/One.txt", true, true), None);
+        assert_eq!(canonical(r, "/a.Txt", true, true), None);
+        assert_eq!(canonical(r, "/missing", true, true), None);
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("/etc", root.join("out")).unwrap();
+            assert_eq!(canonical(r, "/out", true, false), None);
+        }
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
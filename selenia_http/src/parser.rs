@@ -2,9 +2,11 @@
 //! 現時点では Request-Line とヘッダ行の分割のみ行い、
 //! 検証やボディ処理、値の正規化は後続フェーズで拡張する予定。
 
+use std::borrow::Cow;
 use std::str;
 use std::fmt;
 use super::error::ErrorKind;
+use selenia_core::config::ServerConfig;
 
 #[derive(Debug, Clone)]
 pub struct Request<'a> {
@@ -12,13 +14,24 @@ pub struct Request<'a> {
     pub path: &'a str,
     pub version: &'a str,
     pub headers: Vec<(&'a str, &'a str)>,
-    pub body: &'a [u8],
+    /// Borrowed from the input unless a chunked body had to be reassembled.
+    pub body: Cow<'a, [u8]>,
 }
 
 #[derive(Debug)]
 pub enum ParseError {
     Incomplete,
     Invalid,
+    /// Request line plus headers exceed `max_header_bytes`.
+    TooLarge,
+    /// Request-target longer than `MAX_URI_BYTES`.
+    UriTooLong,
+    /// `Content-Length` that cannot fit in `max_conn_buffer`.
+    BodyTooLarge,
+    /// `Transfer-Encoding` other than `chunked` (RFC 9112 §6.1).
+    UnsupportedTransferCoding,
+    /// Well-formed `HTTP/x.y` other than 1.0 / 1.1.
+    UnsupportedVersion,
 }
 
 impl ParseError {
@@ -26,6 +39,11 @@ impl ParseError {
         match self {
             ParseError::Incomplete => ErrorKind::Internal,
             ParseError::Invalid => ErrorKind::MalformedHeader,
+            ParseError::TooLarge => ErrorKind::HeaderTooLarge,
+            ParseError::UriTooLong => ErrorKind::UriTooLong,
+            ParseError::BodyTooLarge => ErrorKind::PayloadTooLarge,
+            ParseError::UnsupportedTransferCoding => ErrorKind::NotImplemented,
+            ParseError::UnsupportedVersion => ErrorKind::VersionNotSupported,
         }
     }
 }
@@ -35,112 +53,185 @@ fn find_double_crlf(buf: &[u8]) -> Option<usize> {
         .position(|w| w == b"\r\n\r\n" || w == b"\n\n\n\n")
 }
 
+/// Header fields a request may carry, trailers included; more → 400.
+const MAX_HEADERS: usize = 100;
+
+/// Longest request-target accepted; longer → 414.
+const MAX_URI_BYTES: usize = 8 * 1024;
+
+/// Trailer fields that would change framing, routing or authentication if merged
+/// into the header list (RFC 7230 §4.1.2); they are ignored.
+const FORBIDDEN_TRAILERS: [&str; 6] = ["transfer-encoding", "content-length", "host", "trailer", "authorization", "content-encoding"];
+
 /// ストリーム指向ゼロコピー HTTP/1.x パーサ
+///
+/// The returned `Request` borrows `buf`, so nothing parsed can be kept across calls;
+/// what is kept is how far the caller's (append-only) buffer has been examined. The
+/// header terminator search resumes where the last call stopped, the header block is
+/// parsed only once it is complete, and while a `Content-Length` body is arriving
+/// calls return immediately until enough bytes are buffered. Total work for a head
+/// delivered one byte per read is therefore linear.
 pub struct Parser {
-    state: ParseState,
-    index: usize,
+    /// Bytes of the buffer already searched for the end of the header block.
+    head_scanned: usize,
+    /// Offset just past the header block once found.
+    head_end: Option<usize>,
+    /// Buffer length needed before the body can be complete.
+    need: usize,
+    max_header_bytes: usize,
+    /// Whole request (head plus `Content-Length` body) cap; larger → 413 up front.
+    max_request_bytes: usize,
+    strict_trailers: bool,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum ParseState { RequestLine, Headers, Done }
-
 impl Parser {
     pub fn new() -> Self {
-        Parser { state: ParseState::RequestLine, index: 0 }
+        Parser { head_scanned: 0, head_end: None, need: 0, max_header_bytes: usize::MAX, max_request_bytes: usize::MAX, strict_trailers: false }
+    }
+
+    /// Parser with the request-head cap (`max_header_bytes`), request cap (`max_conn_buffer`)
+    /// and `strict_trailers` policy from `cfg`.
+    pub fn from_config(cfg: &ServerConfig) -> Self {
+        Parser { max_header_bytes: cfg.max_header_bytes, max_request_bytes: cfg.max_conn_buffer, strict_trailers: cfg.strict_trailers, ..Parser::new() }
     }
 
-    /// buf[consumed..] 以降を解析し、完了時に `Request` を返す
+    /// Forget progress; the next call starts a new request at offset 0.
+    fn reset(&mut self) {
+        self.head_scanned = 0;
+        self.head_end = None;
+        self.need = 0;
+    }
+
+    /// `buf` の先頭から解析し、完了時に `Request` と消費バイト数を返す。
+    /// Between calls `buf` may only grow; callers drain the consumed bytes after each request.
     pub fn advance<'a>(&mut self, buf: &'a [u8]) -> Result<Option<(Request<'a>, usize)>, ParseError> {
-        let start = self.index;
-        let slice = &buf[start..];
-
-        match self.state {
-            ParseState::RequestLine => {
-                if let Some(pos) = memchr::memchr(b'\n', slice) {
-                    let line = &slice[..pos];
-                    let line_str = trim_cr(line);
-                    let mut parts = split_ws(line_str);
-                    let method = parts.next().ok_or(ParseError::Invalid)?;
-                    let path = parts.next().ok_or(ParseError::Invalid)?;
-                    let version = parts.next().ok_or(ParseError::Invalid)?;
-                    let consumed = start + pos + 1;
-                    self.state = ParseState::Headers;
-                    self.index = consumed;
-                    // fallthrough to header parse with provisional request object
-                    let mut provisional = Request { method, path, version, headers: Vec::new(), body: &[] };
-                    return self.collect_headers(buf, provisional);
+        let res = self.step(buf);
+        if !matches!(res, Ok(None)) { self.reset(); }
+        res
+    }
+
+    fn step<'a>(&mut self, buf: &'a [u8]) -> Result<Option<(Request<'a>, usize)>, ParseError> {
+        if buf.len() < self.need { return Ok(None); }
+        let head_end = match self.head_end {
+            Some(e) => e,
+            None => {
+                // Step back three bytes so a terminator split across reads is still seen.
+                let from = self.head_scanned.saturating_sub(3).min(buf.len());
+                match find_double_crlf(&buf[from..]) {
+                    Some(p) => from + p + 4,
+                    None => {
+                        self.head_scanned = buf.len();
+                        if buf.len() > self.max_header_bytes { return Err(ParseError::TooLarge); }
+                        return Ok(None);
+                    }
                 }
-                Ok(None)
             }
-            ParseState::Headers => {
-                // Should not reach here directly
-                Ok(None)
+        };
+        if head_end > self.max_header_bytes { return Err(ParseError::TooLarge); }
+        self.head_end = Some(head_end);
+
+        let line_end = memchr::memchr(b'\n', &buf[..head_end]).ok_or(ParseError::Invalid)?;
+        let mut parts = split_ws(trim_cr(&buf[..line_end])?);
+        let method = parts.next().ok_or(ParseError::Invalid)?;
+        let path = parts.next().ok_or(ParseError::Invalid)?;
+        let version = parts.next().ok_or(ParseError::Invalid)?;
+        if parts.next().is_some() { return Err(ParseError::Invalid); }
+        check_version(version)?;
+        if path.len() > MAX_URI_BYTES { return Err(ParseError::UriTooLong); }
+        let mut req = Request { method, path, version, headers: Vec::new(), body: Cow::Borrowed(&[]) };
+
+        // A request without header lines ends right after the request line.
+        let headers_block = buf.get(line_end + 1..head_end - 4).unwrap_or(&[]);
+        for line in headers_block.split(|&b| b == b'\n') {
+            let line = trim_cr(line)?;
+            if line.is_empty() { continue; }
+            let (name, value) = line.split_once(':').ok_or(ParseError::Invalid)?;
+            if !valid_field(name, value) { return Err(ParseError::Invalid); }
+            req.headers.push((name.trim(), value.trim()));
+        }
+        if req.headers.len() > MAX_HEADERS { return Err(ParseError::Invalid); }
+        let mut consumed = head_end;
+
+        // Determine body length. Framing the next hop could read differently (a
+        // malformed or conflicting `Content-Length`, or one next to `Transfer-Encoding`)
+        // is refused rather than guessed at (RFC 9112 §6.3).
+        let mut content_length: Option<usize> = None;
+        let mut chunked = false;
+        for (name, val) in &req.headers {
+            if name.eq_ignore_ascii_case("content-length") {
+                if val.is_empty() || !val.bytes().all(|b| b.is_ascii_digit()) { return Err(ParseError::Invalid); }
+                let len = val.parse::<usize>().map_err(|_| ParseError::BodyTooLarge)?;
+                if content_length.is_some_and(|prev| prev != len) { return Err(ParseError::Invalid); }
+                content_length = Some(len);
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                if !val.trim().eq_ignore_ascii_case("chunked") { return Err(ParseError::UnsupportedTransferCoding); }
+                chunked = true;
             }
-            ParseState::Done => Ok(None),
         }
-    }
+        if chunked && content_length.is_some() { return Err(ParseError::Invalid); }
 
-    fn collect_headers<'a>(&mut self, buf: &'a [u8], mut req: Request<'a>) -> Result<Option<(Request<'a>, usize)>, ParseError> {
-        let start = self.index;
-        let slice = &buf[start..];
-        if let Some(end_pos) = find_double_crlf(slice) {
-            let headers_block = &slice[..end_pos];
-            for line in headers_block.split(|&b| b == b'\n') {
-                let line = trim_cr(line);
-                if line.is_empty() { continue; }
-                let bytes = line.as_bytes();
-                if let Some(col) = memchr::memchr(b':', bytes) {
-                    let name = &line[..col];
-                    let value = &line[col+1..];
-                    req.headers.push((name.trim(), value.trim()));
-                } else { return Err(ParseError::Invalid); }
+        if let Some(len) = content_length {
+            let total = consumed.checked_add(len).ok_or(ParseError::Invalid)?;
+            if total > self.max_request_bytes { return Err(ParseError::BodyTooLarge); }
+            if buf.len() < total {
+                self.need = total;
+                return Ok(None);
             }
-            let mut consumed = start + end_pos + 4;
-
-            // Determine body length
-            let mut content_length: Option<usize> = None;
-            let mut chunked = false;
-            for (name, val) in &req.headers {
-                if name.eq_ignore_ascii_case("content-length") {
-                    if let Ok(len) = val.parse::<usize>() {
-                        content_length = Some(len);
+            req.body = Cow::Borrowed(&buf[consumed..total]);
+            consumed = total;
+        } else if chunked {
+            match parse_chunked_body(&buf[consumed..])? {
+                Some((body_slice, trailers, consumed_extra)) => {
+                    if self.strict_trailers {
+                        let announced = |name: &str| req.headers.iter()
+                            .filter(|(k, _)| k.eq_ignore_ascii_case("trailer"))
+                            .flat_map(|(_, v)| v.split(','))
+                            .any(|t| t.trim().eq_ignore_ascii_case(name));
+                        if !trailers.iter().all(|(name, _)| announced(name)) { return Err(ParseError::Invalid); }
                     }
-                } else if name.eq_ignore_ascii_case("transfer-encoding") && val.trim().eq_ignore_ascii_case("chunked") {
-                    chunked = true;
+                    if req.headers.len() + trailers.len() > MAX_HEADERS { return Err(ParseError::Invalid); }
+                    req.headers.extend(trailers.into_iter().filter(|(name, _)| !FORBIDDEN_TRAILERS.iter().any(|f| name.eq_ignore_ascii_case(f))));
+                    req.body = body_slice;
+                    consumed += consumed_extra;
                 }
+                None => return Ok(None),
             }
+        }
 
-            if let Some(len) = content_length {
-                // Ensure buffer has len bytes after headers
-                if buf.len() < consumed + len {
-                    // Need more data
-                    return Ok(None);
-                }
-                req.body = &buf[consumed .. consumed + len];
-                consumed += len;
-            } else if chunked {
-                match parse_chunked_body(&buf[consumed..]) {
-                    Some((body_slice, consumed_extra)) => {
-                        req.body = body_slice;
-                        consumed += consumed_extra;
-                    }
-                    None => return Ok(None),
-                }
-            }
+        Ok(Some((req, consumed)))
+    }
+}
 
-            self.state = ParseState::Done;
-            self.index = consumed;
-            Ok(Some((req, consumed)))
-        } else {
-            Ok(None)
-        }
+/// Whether the header fields frame a request body: a non-zero `Content-Length` or
+/// chunked transfer coding. The parser has consumed any such body already.
+pub(crate) fn declares_body(headers: &[(&str, &str)]) -> bool {
+    headers.iter().any(|(k, v)| {
+        (k.eq_ignore_ascii_case("content-length") && v.parse::<usize>().map_or(false, |n| n > 0))
+            || (k.eq_ignore_ascii_case("transfer-encoding") && v.trim().eq_ignore_ascii_case("chunked"))
+    })
+}
+
+/// Field line free of control characters (RFC 9110 §5.5; HTAB allowed in values).
+/// A CR left inside a line would split any response that echoes the value.
+pub(crate) fn valid_field(name: &str, value: &str) -> bool {
+    !name.trim().is_empty()
+        && name.bytes().all(|c| c >= 0x20 && c != 0x7f)
+        && value.bytes().all(|c| c == b'\t' || (c >= 0x20 && c != 0x7f))
+}
+
+/// `HTTP/DIGIT.DIGIT` (RFC 9112 §2.3); only 1.0 and 1.1 are served, other versions
+/// get 505 and anything else in that position 400.
+fn check_version(version: &str) -> Result<(), ParseError> {
+    match version.as_bytes() {
+        b"HTTP/1.0" | b"HTTP/1.1" => Ok(()),
+        [b'H', b'T', b'T', b'P', b'/', major, b'.', minor] if major.is_ascii_digit() && minor.is_ascii_digit() => Err(ParseError::UnsupportedVersion),
+        _ => Err(ParseError::Invalid),
     }
 }
 
-fn trim_cr(line: &[u8]) -> &str {
-    let mut end = line.len();
-    if end > 0 && line[end-1] == b'\r' { end -=1; }
-    unsafe { str::from_utf8_unchecked(&line[..end]) }
+fn trim_cr(line: &[u8]) -> Result<&str, ParseError> {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    str::from_utf8(line).map_err(|_| ParseError::Invalid)
 }
 
 fn split_ws<'a>(s: &'a str) -> impl Iterator<Item=&'a str> {
@@ -152,31 +243,265 @@ mod memchr { #[inline] pub fn memchr(byte: u8, hay: &[u8]) -> Option<usize> { ha
 impl fmt::Debug for Parser {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Parser")
-            .field("state", &self.state)
-            .field("index", &self.index)
+            .field("head_scanned", &self.head_scanned)
+            .field("head_end", &self.head_end)
+            .field("need", &self.need)
+            .field("strict_trailers", &self.strict_trailers)
             .finish()
     }
 }
 
-// Parse chunked transfer encoding. Returns body slice within `input` and total bytes consumed from input (body+terminators).
-fn parse_chunked_body(input: &[u8]) -> Option<(&[u8], usize)> {
+/// Largest chunk a request may declare; bigger sizes are refused instead of
+/// waiting for data that would never fit in the connection buffer.
+const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+/// Parse a chunked body (RFC 7230 §4.1). Returns the de-chunked body, any trailer
+/// fields and the bytes consumed from `input`, `Ok(None)` while incomplete. A body
+/// that arrived as one chunk stays borrowed. Chunk extensions are ignored; size
+/// lines, chunk data and trailer lines must end in CRLF.
+fn parse_chunked_body(input: &[u8]) -> Result<Option<(Cow<'_, [u8]>, Vec<(&str, &str)>, usize)>, ParseError> {
     let mut pos = 0;
-    let mut body_start = 0;
+    let mut body: Cow<[u8]> = Cow::Borrowed(&[]);
     loop {
-        // Find line ending for size
-        if let Some(line_end) = memchr::memchr(b'\n', &input[pos..]).map(|i| pos + i) {
-            let line = trim_cr(&input[pos..line_end]);
-            let size = usize::from_str_radix(line.trim(), 16).ok()?;
-            pos = line_end + 1;
-            if size == 0 {
-                // Expect CRLF after last chunk
-                if input.len() < pos + 2 { return None; }
-                return Some((&input[body_start .. pos- (line.len()+1)], pos + 2));
-            }
-            // Ensure enough data
-            if input.len() < pos + size + 2 { return None; }
-            pos += size + 2; // skip chunk and trailing CRLF
-            if body_start == 0 { body_start = line_end + 1; }
-        } else { return None; }
+        let line_end = match memchr::memchr(b'\n', &input[pos..]) {
+            Some(i) => pos + i,
+            None => return Ok(None),
+        };
+        let line = input[pos..line_end].strip_suffix(b"\r").ok_or(ParseError::Invalid)?;
+        let line = str::from_utf8(line).map_err(|_| ParseError::Invalid)?;
+        // chunk-size [ ";" chunk-ext ]
+        let size_str = line.split(';').next().unwrap_or("").trim();
+        if size_str.is_empty() || size_str.len() > 16 || !size_str.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ParseError::Invalid);
+        }
+        let size = usize::from_str_radix(size_str, 16).map_err(|_| ParseError::Invalid)?;
+        if size > MAX_CHUNK_SIZE { return Err(ParseError::Invalid); }
+        pos = line_end + 1;
+        if size == 0 {
+            return Ok(parse_trailers(&input[pos..])?.map(|(trailers, n)| (body, trailers, pos + n)));
+        }
+        if input.len() < pos + size + 2 { return Ok(None); }
+        if &input[pos + size..pos + size + 2] != b"\r\n" { return Err(ParseError::Invalid); }
+        let data = &input[pos..pos + size];
+        match body {
+            Cow::Borrowed(b) if b.is_empty() => body = Cow::Borrowed(data),
+            _ => body.to_mut().extend_from_slice(data),
+        }
+        pos += size + 2;
+    }
+}
+
+/// Trailer section after the last chunk: `*( field-line CRLF ) CRLF`. Returns the
+/// fields and the bytes consumed including the final empty line.
+fn parse_trailers(input: &[u8]) -> Result<Option<(Vec<(&str, &str)>, usize)>, ParseError> {
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    loop {
+        let line_end = match memchr::memchr(b'\n', &input[pos..]) {
+            Some(i) => pos + i,
+            None => return Ok(None),
+        };
+        let line = input[pos..line_end].strip_suffix(b"\r").ok_or(ParseError::Invalid)?;
+        pos = line_end + 1;
+        if line.is_empty() { return Ok(Some((fields, pos))); }
+        if fields.len() >= MAX_HEADERS { return Err(ParseError::Invalid); }
+        let line = str::from_utf8(line).map_err(|_| ParseError::Invalid)?;
+        let (name, value) = line.split_once(':').ok_or(ParseError::Invalid)?;
+        if name.is_empty() || name.bytes().any(|b| b.is_ascii_whitespace()) || !valid_field(name, value) { return Err(ParseError::Invalid); }
+        fields.push((name, value.trim()));
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNKED: &str = "POST /upload HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n";
+
+    /// Parse a whole request, asserting it is complete and fully consumed.
+    fn parse(input: &[u8]) -> Result<Request<'_>, ParseError> {
+        let (req, consumed) = Parser::new().advance(input)?.expect("complete request");
+        assert_eq!(consumed, input.len());
+        Ok(req)
+    }
+
+    #[test]
+    fn chunk_extensions_are_ignored() {
+        let input = format!("{}\r\n5;name=value\r\nhello\r\n6 ; a=\"b\";c\r\n world\r\n0;last\r\n\r\n", CHUNKED);
+        assert_eq!(&*parse(input.as_bytes()).unwrap().body, b"hello world");
+    }
+
+    #[test]
+    fn oversized_chunk_is_rejected() {
+        let input = format!("{}\r\n{:x}\r\nabc", CHUNKED, MAX_CHUNK_SIZE + 1);
+        assert!(matches!(Parser::new().advance(input.as_bytes()), Err(ParseError::Invalid)));
+        // Sizes that overflow are refused the same way, never wrapped.
+        let input = format!("{}\r\nffffffffffffffffff\r\n", CHUNKED);
+        assert!(matches!(Parser::new().advance(input.as_bytes()), Err(ParseError::Invalid)));
+    }
+
+    #[test]
+    fn first_chunk_at_offset_zero() {
+        let (body, trailers, consumed) = parse_chunked_body(b"3\r\nabc\r\n0\r\n\r\n").unwrap().unwrap();
+        assert_eq!((&*body, trailers.len(), consumed), (&b"abc"[..], 0, 13));
+        // A single chunk is passed through without a copy.
+        assert!(matches!(body, Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn chunk_framing_is_strict() {
+        for bad in ["3\r\nabcX\r\n0\r\n\r\n", "3\nabc\r\n0\r\n\r\n", "\r\n", "x\r\n", "-1\r\n"] {
+            assert!(matches!(parse_chunked_body(bad.as_bytes()), Err(ParseError::Invalid)), "{:?}", bad);
+        }
+        assert!(matches!(parse_chunked_body(b"3\r\nab"), Ok(None)));
+    }
+
+    #[test]
+    fn chunked_body_without_trailers() {
+        let input = format!("{}\r\n4\r\nabcd\r\n0\r\n\r\nGET /next HTTP/1.1\r\n\r\n", CHUNKED);
+        let (req, consumed) = Parser::new().advance(input.as_bytes()).unwrap().unwrap();
+        assert_eq!(&*req.body, b"abcd");
+        assert_eq!(req.headers.len(), 2);
+        // The pipelined request that follows is left alone.
+        assert_eq!(&input[consumed..], "GET /next HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    fn chunked_body_with_trailers() {
+        let input = format!("{}Trailer: Checksum\r\n\r\n4\r\nabcd\r\n0\r\nChecksum: 1234\r\nX-Extra:  v \r\nContent-Length: 99\r\n\r\n", CHUNKED);
+        let req = parse(input.as_bytes()).unwrap();
+        assert_eq!(&*req.body, b"abcd");
+        assert!(req.headers.contains(&("Checksum", "1234")));
+        assert!(req.headers.contains(&("X-Extra", "v")));
+        // Framing fields are never taken from trailers.
+        assert!(!req.headers.iter().any(|(k, _)| k.eq_ignore_ascii_case("content-length")));
+        // Incomplete until the empty line after the trailers.
+        let cut = input.len() - 2;
+        assert!(matches!(Parser::new().advance(&input.as_bytes()[..cut]), Ok(None)));
+    }
+
+    #[test]
+    fn strict_trailers_must_be_announced() {
+        let strict = || Parser { strict_trailers: true, ..Parser::new() };
+        let announced = format!("{}Trailer: Checksum\r\n\r\n0\r\nchecksum: 1\r\n\r\n", CHUNKED);
+        assert!(strict().advance(announced.as_bytes()).unwrap().is_some());
+        let surprise = format!("{}Trailer: Checksum\r\n\r\n0\r\nX-Other: 1\r\n\r\n", CHUNKED);
+        assert!(matches!(strict().advance(surprise.as_bytes()), Err(ParseError::Invalid)));
+        assert!(Parser::new().advance(surprise.as_bytes()).unwrap().is_some());
+    }
+
+    /// Feed `input` one byte per call, as a client trickling its request would.
+    fn trickle(parser: &mut Parser, input: &[u8]) -> usize {
+        for end in 1..input.len() {
+            assert!(parser.advance(&input[..end]).unwrap().is_none(), "complete after {} bytes", end);
+        }
+        parser.advance(input).unwrap().expect("complete request").1
+    }
+
+    #[test]
+    fn head_delivered_bytewise_is_scanned_once() {
+        let filler: String = (0..90).map(|i| format!("X-Filler-{}: {}\r\n", i, "v".repeat(640))).collect();
+        let input = format!("GET / HTTP/1.1\r\nHost: a\r\n{}\r\n", filler);
+        let mut parser = Parser::new();
+        for end in 1..input.len() {
+            assert!(parser.advance(&input.as_bytes()[..end]).unwrap().is_none());
+            // Each call resumes where the last one stopped.
+            assert_eq!(parser.head_scanned, end);
+        }
+        // ~60 KiB one byte at a time: quadratic rescanning would take billions of steps.
+        let started = std::time::Instant::now();
+        assert_eq!(trickle(&mut Parser::new(), input.as_bytes()), input.len());
+        assert!(started.elapsed() < std::time::Duration::from_secs(2));
+    }
+
+    #[test]
+    fn trickled_requests_parse_like_whole_ones() {
+        let body = "POST /a HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhello";
+        let mut parser = Parser::new();
+        assert_eq!(trickle(&mut parser, body.as_bytes()), body.len());
+        // The parser is reset for the next request on the connection.
+        let next = "GET /b HTTP/1.1\r\nHost: a\r\n\r\n";
+        let (req, consumed) = parser.advance(next.as_bytes()).unwrap().unwrap();
+        assert_eq!((req.path, consumed), ("/b", next.len()));
+        let chunked = format!("{}\r\n5\r\nhello\r\n0\r\n\r\n", CHUNKED);
+        assert_eq!(trickle(&mut Parser::new(), chunked.as_bytes()), chunked.len());
+    }
+
+    #[test]
+    fn head_cap_applies_before_the_terminator() {
+        let mut parser = Parser { max_header_bytes: 64, ..Parser::new() };
+        let long = format!("GET / HTTP/1.1\r\nX: {}", "a".repeat(64));
+        assert!(matches!(parser.advance(long.as_bytes()), Err(ParseError::TooLarge)));
+        let fits = "GET / HTTP/1.1\r\nHost: a\r\n\r\n";
+        assert!(Parser { max_header_bytes: 64, ..Parser::new() }.advance(fits.as_bytes()).unwrap().is_some());
+        let body = "POST / HTTP/1.1\r\nContent-Length: 100\r\n\r\n";
+        let mut parser = Parser { max_request_bytes: 100, ..Parser::new() };
+        assert!(matches!(parser.advance(body.as_bytes()), Err(ParseError::BodyTooLarge)));
+    }
+
+    #[test]
+    fn control_characters_in_fields_are_rejected() {
+        for bad in ["X-A: a\rb", "X-A: a\x00b", "X-A: a\x7fb", "X\x01A: v", ": v"] {
+            let input = format!("GET / HTTP/1.1\r\nHost: a\r\n{}\r\n\r\n", bad);
+            assert!(matches!(Parser::new().advance(input.as_bytes()), Err(ParseError::Invalid)), "{:?}", bad);
+        }
+        let req = parse(b"GET / HTTP/1.1\r\nHost: a\r\nX-A: a\tb\r\n\r\n").unwrap();
+        assert!(req.headers.contains(&("X-A", "a\tb")));
+    }
+
+    #[test]
+    fn parse_failures_map_to_their_error_kinds() {
+        let long = format!("GET /{} HTTP/1.1\r\nHost: a\r\n\r\n", "a".repeat(MAX_URI_BYTES));
+        let err = Parser::new().advance(long.as_bytes()).unwrap_err();
+        assert!(matches!(err, ParseError::UriTooLong));
+        assert_eq!(err.to_error_kind(), ErrorKind::UriTooLong);
+        let gzip = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip\r\n\r\n";
+        let err = Parser::new().advance(gzip).unwrap_err();
+        assert!(matches!(err, ParseError::UnsupportedTransferCoding));
+        assert_eq!(err.to_error_kind(), ErrorKind::NotImplemented);
+        let mut parser = Parser { max_request_bytes: 64, ..Parser::new() };
+        let err = parser.advance(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 100\r\n\r\n").unwrap_err();
+        assert!(matches!(err, ParseError::BodyTooLarge));
+        assert_eq!(err.to_error_kind(), ErrorKind::PayloadTooLarge);
+        let mut parser = Parser { max_header_bytes: 16, ..Parser::new() };
+        assert_eq!(parser.advance(b"GET / HTTP/1.1\r\nHost: a\r\nX-Filler: aaaaaaaa").unwrap_err().to_error_kind(), ErrorKind::HeaderTooLarge);
+    }
+
+    #[test]
+    fn request_versions_are_checked() {
+        for version in ["HTTP/1.0", "HTTP/1.1"] {
+            let input = format!("GET / {}\r\nHost: a\r\n\r\n", version);
+            assert_eq!(parse(input.as_bytes()).unwrap().version, version);
+        }
+        for version in ["HTTP/2.0", "HTTP/0.9", "HTTP/9.9"] {
+            let input = format!("GET / {}\r\nHost: a\r\n\r\n", version);
+            let err = Parser::new().advance(input.as_bytes()).unwrap_err();
+            assert!(matches!(err, ParseError::UnsupportedVersion), "{}", version);
+            assert_eq!(err.to_error_kind(), ErrorKind::VersionNotSupported);
+        }
+        for version in ["HTTP/banana", "http/1.1", "HTTP/1.1.1", "HTTP/11", "HTTP/1.1 extra"] {
+            let input = format!("GET / {}\r\nHost: a\r\n\r\n", version);
+            let err = Parser::new().advance(input.as_bytes()).unwrap_err();
+            assert!(matches!(err, ParseError::Invalid), "{}", version);
+        }
+    }
+
+    #[test]
+    fn ambiguous_content_length_is_invalid() {
+        let reject = |framing: &str| {
+            let input = format!("POST / HTTP/1.1\r\nHost: a\r\n{}\r\nhello", framing);
+            matches!(Parser::new().advance(input.as_bytes()), Err(ParseError::Invalid))
+        };
+        assert!(reject("Content-Length: 5x\r\n"));
+        assert!(reject("Content-Length: -1\r\n"));
+        assert!(reject("Content-Length: +5\r\n"));
+        assert!(reject("Content-Length: 5, 5\r\n"));
+        assert!(reject("Content-Length:\r\n"));
+        assert!(reject("Content-Length: 5\r\nContent-Length: 4\r\n"));
+        assert!(reject("Content-Length: 5\r\nTransfer-Encoding: chunked\r\n"));
+        let same = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\nhello";
+        assert_eq!(&*parse(same).unwrap().body, b"hello");
+        let huge = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 99999999999999999999999\r\n\r\n";
+        assert!(matches!(Parser::new().advance(huge), Err(ParseError::BodyTooLarge)));
+    }
+}
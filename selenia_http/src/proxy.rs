@@ -0,0 +1,666 @@
+//! Reverse proxy for `proxy_pass` path prefixes, HTTP/1.1 upstreams only.
+//!
+//! The request goes upstream with its target, method, end-to-end headers and body
+//! (de-chunked, re-sent with `Content-Length`), plus this hop appended to
+//! `X-Forwarded-For` and `Forwarded`. The event loop relays the response through an
+//! [`Exchange`], which follows its framing (`Content-Length`, chunked, or none) to see
+//! where it ends; a cleanly finished upstream connection then goes back to the
+//! [`Pool`] instead of being closed. The client connection closes after the response.
+//! Which upstream serves a request is up to the route's [`Group`]. Connects are
+//! non-blocking: the exchange waits for the upstream socket to become writable, and a
+//! refused or timed-out connect ([`Exchange::reconnect`]) moves the request to the next
+//! upstream. Upstream names come from the [`DnsCache`] only; one it is still resolving is
+//! passed over. Requests reach [`forward`] only after the Host,
+//! `access_control`, WAF, `auth` and RBAC gates of `handle_request`. The final
+//! response head is held back until complete and passed through [`rewrite::head`]
+//! for the route's Location, cookie and header rules.
+//! With `verify_gzip`, a gzip body of known length is held back as well and only
+//! relayed once [`compress::verify_gzip`] accepts it; a corrupt one becomes a 502.
+//! Each connect is given the route's `connect_timeout_ms`, capped by the request's
+//! [`Deadline`]; an exchange that has relayed nothing by then is answered 504 by the
+//! event loop's sweep.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, TcpStream};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use selenia_core::config::{ProxyPoolConfig, ServerConfig};
+use selenia_core::dns::DnsCache;
+use selenia_core::{log_warn, metrics};
+use selenia_core::os::{connect, EventLoop, Interest, Token};
+
+use super::deadline::Deadline;
+use super::error::ErrorKind;
+use super::parser::Request;
+use super::tunnel::{apply, interest};
+use super::upstream::{self, Group};
+use super::{compress, forwarded, rewrite, uri};
+
+const CHUNK: usize = 16 * 1024;
+/// Response head or chunk-size/trailer line beyond this is relayed but never reused.
+const MAX_LINE: usize = 64 * 1024;
+/// gzip bodies above this are relayed unverified rather than held in memory.
+const MAX_VERIFY: u64 = 8 << 20;
+/// Sent instead of a gzip body that fails verification (nothing has been relayed yet).
+const CORRUPT_RESPONSE: &[u8] = b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// Hop-by-hop fields (RFC 9110 §7.6.1); this hop's own forwarding fields are rebuilt.
+const DROPPED: [&str; 11] = [
+    "connection", "keep-alive", "proxy-connection", "proxy-authorization", "te", "trailer",
+    "transfer-encoding", "upgrade", "content-length", "x-forwarded-proto", "x-forwarded-host",
+];
+
+/// The route for request-target `target`, longest prefix first.
+pub fn route<'a>(routes: &'a [Arc<Group>], target: &str) -> Option<&'a Arc<Group>> {
+    if routes.is_empty() { return None; }
+    let path = uri::decode_path(uri::split_target(target).0)?;
+    routes.iter().filter(|g| path.starts_with(&g.route.prefix)).max_by_key(|g| g.route.prefix.len())
+}
+
+/// Idle keep-alive upstream connections, keyed by upstream address. Idle sockets are not
+/// registered with the event loop; they are probed when checked out instead.
+#[derive(Debug)]
+pub struct Pool {
+    idle: HashMap<String, Vec<(TcpStream, Instant)>>,
+    count: usize,
+    cfg: ProxyPoolConfig,
+}
+
+/// Still connected with nothing unread: a peek on the non-blocking socket would block.
+fn healthy(s: &TcpStream) -> bool {
+    matches!(s.peek(&mut [0u8; 1]), Err(e) if e.kind() == io::ErrorKind::WouldBlock)
+}
+
+impl Pool {
+    pub fn new(cfg: &ProxyPoolConfig) -> Self {
+        Pool { idle: HashMap::new(), count: 0, cfg: cfg.clone() }
+    }
+
+    /// Idle connections held, for fd accounting.
+    pub fn idle(&self) -> usize {
+        self.count
+    }
+
+    /// Most recently used healthy connection to `upstream`; dead ones are dropped on the way.
+    fn checkout(&mut self, upstream: &str) -> Option<TcpStream> {
+        let timeout = Duration::from_millis(self.cfg.idle_timeout_ms);
+        let list = self.idle.get_mut(upstream)?;
+        let mut found = None;
+        while let Some((s, since)) = list.pop() {
+            self.count -= 1;
+            if since.elapsed() < timeout && healthy(&s) { found = Some(s); break; }
+        }
+        metrics::set_proxy_pool_idle(self.count as u64);
+        found
+    }
+
+    /// Keep `s` for the next request to `upstream` unless a bound is reached.
+    fn checkin(&mut self, upstream: &str, s: TcpStream) {
+        let list = self.idle.entry(upstream.to_string()).or_default();
+        if self.count >= self.cfg.max_idle || list.len() >= self.cfg.max_idle_per_upstream { return; }
+        list.push((s, Instant::now()));
+        self.count += 1;
+        metrics::set_proxy_pool_idle(self.count as u64);
+    }
+
+    /// Close connections idle for longer than `idle_timeout_ms`.
+    pub fn evict_idle(&mut self, now: Instant) {
+        let timeout = Duration::from_millis(self.cfg.idle_timeout_ms);
+        let before = self.count;
+        for list in self.idle.values_mut() {
+            // Oldest first: pushes are in check-in order.
+            let keep = list.iter().position(|(_, since)| now.duration_since(*since) < timeout).unwrap_or(list.len());
+            self.count -= keep;
+            list.drain(..keep);
+        }
+        self.idle.retain(|_, l| !l.is_empty());
+        if self.count != before { metrics::set_proxy_pool_idle(self.count as u64); }
+    }
+}
+
+/// `for=` node for `peer`; IPv6 is bracketed and quoted (RFC 7239 §6).
+fn node(peer: &str) -> String {
+    match peer.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("\"[{}]\"", ip),
+        _ => peer.to_string(),
+    }
+}
+
+/// The bytes to send upstream for `req`, received from `peer` over `proto`.
+fn request_bytes(req: &Request, peer: &str, proto: &str, upstream: &str) -> Vec<u8> {
+    let listed: Vec<String> = req.headers.iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case("Connection"))
+        .flat_map(|(_, v)| v.split(',').map(|t| t.trim().to_ascii_lowercase()))
+        .collect();
+    let mut head = format!("{} {} HTTP/1.1\r\n", req.method, req.path);
+    let (mut host, mut xff, mut fwd) = (None, Vec::new(), Vec::new());
+    let mut has_body = !req.body.is_empty();
+    for (k, v) in &req.headers {
+        let name = k.to_ascii_lowercase();
+        has_body |= name == "content-length" || name == "transfer-encoding";
+        match name.as_str() {
+            "x-forwarded-for" => xff.push(v.trim()),
+            "forwarded" => fwd.push(v.trim()),
+            _ if DROPPED.contains(&name.as_str()) || listed.contains(&name) => {}
+            _ => {
+                if name == "host" { host = Some(*v); }
+                head.push_str(&format!("{}: {}\r\n", k, v));
+            }
+        }
+    }
+    if host.is_none() { head.push_str(&format!("Host: {}\r\n", upstream)); }
+    xff.push(peer);
+    let mut this_hop = format!("for={};proto={}", node(peer), proto);
+    if let Some(h) = host { this_hop.push_str(&format!(";host=\"{}\"", h)); }
+    fwd.push(&this_hop);
+    head.push_str(&format!("X-Forwarded-For: {}\r\nX-Forwarded-Proto: {}\r\n", xff.join(", "), proto));
+    if let Some(h) = host { head.push_str(&format!("X-Forwarded-Host: {}\r\n", h)); }
+    head.push_str(&format!("Forwarded: {}\r\n", fwd.join(", ")));
+    if has_body { head.push_str(&format!("Content-Length: {}\r\n", req.body.len())); }
+    head.push_str("\r\n");
+    let mut out = head.into_bytes();
+    out.extend_from_slice(&req.body);
+    out
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Chunk {
+    Size,
+    Data(u64),
+    /// CRLF bytes still expected after chunk data.
+    DataEnd(u8),
+    Trailer,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Body {
+    Length(u64),
+    Chunked(Chunk),
+    /// No framing: the response ends when the upstream closes.
+    UntilClose,
+}
+
+/// Where the upstream's response ends, followed byte by byte as it is relayed.
+#[derive(Debug)]
+struct Framing {
+    head_request: bool,
+    head: Vec<u8>,
+    /// `None` while the head is incomplete.
+    body: Option<Body>,
+    line: Vec<u8>,
+    complete: bool,
+    /// The upstream keeps the connection open after this response.
+    keep_alive: bool,
+    /// The final head names gzip as the (last) content coding.
+    gzip: bool,
+}
+
+impl Framing {
+    fn new(head_request: bool) -> Self {
+        Framing { head_request, head: Vec::new(), body: None, line: Vec::new(), complete: false, keep_alive: false, gzip: false }
+    }
+
+    fn start_body(&mut self) {
+        let text = String::from_utf8_lossy(&self.head);
+        let mut lines = text.split("\r\n");
+        let status_line = lines.next().unwrap_or("");
+        let mut parts = status_line.split(' ');
+        let version = parts.next().unwrap_or("");
+        let status: u16 = parts.next().and_then(|s| s.parse().ok()).unwrap_or(0);
+        if (100..200).contains(&status) && status != 101 {
+            // Interim response; the final one follows on the same connection.
+            self.head.clear();
+            return;
+        }
+        let (mut length, mut chunked, mut close) = (None, false, version != "HTTP/1.1");
+        for l in lines {
+            let Some((k, v)) = l.split_once(':') else { continue };
+            let v = v.trim();
+            if k.eq_ignore_ascii_case("Content-Length") { length = v.parse::<u64>().ok(); }
+            if k.eq_ignore_ascii_case("Transfer-Encoding") { chunked = v.to_ascii_lowercase().ends_with("chunked"); }
+            if k.eq_ignore_ascii_case("Connection") { close |= v.split(',').any(|t| t.trim().eq_ignore_ascii_case("close")); }
+            if k.eq_ignore_ascii_case("Content-Encoding") { self.gzip = v.rsplit(',').next().map_or(false, |c| c.trim().eq_ignore_ascii_case("gzip")); }
+        }
+        self.keep_alive = !close;
+        self.body = Some(if self.head_request || status == 204 || status == 304 {
+            self.complete = true;
+            Body::Length(0)
+        } else if chunked {
+            Body::Chunked(Chunk::Size)
+        } else if let Some(n) = length.filter(|_| status != 101) {
+            self.complete = n == 0;
+            Body::Length(n)
+        } else {
+            self.keep_alive = false;
+            Body::UntilClose
+        });
+    }
+
+    /// Unframeable input: relay the rest until the upstream closes.
+    fn give_up(&mut self) {
+        self.body = Some(Body::UntilClose);
+        self.keep_alive = false;
+    }
+
+    /// Account for `data` received from the upstream; returns how many leading bytes
+    /// were taken, stopping short after a response head.
+    fn feed(&mut self, data: &[u8]) -> usize {
+        let mut i = 0;
+        while i < data.len() && !self.complete {
+            let rest = (data.len() - i) as u64;
+            match self.body {
+                None => {
+                    self.head.push(data[i]);
+                    i += 1;
+                    // Stop at the end of a head so the caller can tell it from the body.
+                    if self.head.ends_with(b"\r\n\r\n") { self.start_body(); return i; }
+                    else if self.head.len() > MAX_LINE { self.give_up(); return i; }
+                }
+                Some(Body::UntilClose) => i = data.len(),
+                Some(Body::Length(n)) => {
+                    let take = n.min(rest);
+                    i += take as usize;
+                    self.body = Some(Body::Length(n - take));
+                    self.complete = n == take;
+                }
+                Some(Body::Chunked(Chunk::Data(n))) => {
+                    let take = n.min(rest);
+                    i += take as usize;
+                    self.body = Some(Body::Chunked(if n == take { Chunk::DataEnd(2) } else { Chunk::Data(n - take) }));
+                }
+                Some(Body::Chunked(Chunk::DataEnd(n))) => {
+                    i += 1;
+                    self.body = Some(Body::Chunked(if n == 1 { Chunk::Size } else { Chunk::DataEnd(n - 1) }));
+                }
+                Some(Body::Chunked(state)) => {
+                    self.line.push(data[i]);
+                    i += 1;
+                    if data[i - 1] != b'\n' {
+                        if self.line.len() > MAX_LINE { self.give_up(); }
+                        continue;
+                    }
+                    let line = String::from_utf8_lossy(&self.line).trim().to_string();
+                    self.line.clear();
+                    match state {
+                        Chunk::Size => {
+                            let size = line.split(';').next().map(str::trim).and_then(|s| u64::from_str_radix(s, 16).ok());
+                            match size {
+                                Some(0) => self.body = Some(Body::Chunked(Chunk::Trailer)),
+                                Some(n) => self.body = Some(Body::Chunked(Chunk::Data(n))),
+                                None => self.give_up(),
+                            }
+                        }
+                        // The empty line after the trailer fields ends the message.
+                        _ => self.complete = line.is_empty(),
+                    }
+                }
+            }
+        }
+        i
+    }
+}
+
+/// One proxied request/response on an upstream connection.
+#[derive(Debug)]
+pub struct Exchange {
+    pub upstream: TcpStream,
+    /// Event-loop token of `upstream`.
+    pub token: Token,
+    group: Arc<Group>,
+    /// Index of the serving upstream in `group`; its address is the pool key.
+    backend: usize,
+    /// Upstreams tried so far, the serving one included.
+    tried: Vec<usize>,
+    /// Set while the connect is under way: when it counts as failed.
+    connect_by: Option<Instant>,
+    /// `request` carries a `Host` naming the serving upstream, for lack of the client's own.
+    own_host: bool,
+    /// Origin clients address, for rewriting upstream URLs.
+    public: Option<String>,
+    request: Vec<u8>,
+    /// Response head received so far, not yet relayed.
+    head: Vec<u8>,
+    response: Vec<u8>,
+    /// Rewritten head and body so far of a gzip response awaiting verification.
+    held: Option<(Vec<u8>, Vec<u8>)>,
+    framing: Framing,
+    /// Bytes beyond the response or a broken exchange: the connection can't be reused.
+    spoiled: bool,
+    /// The upstream failed (reset, or closed mid-response); counts against its health.
+    upstream_failed: bool,
+    /// Some response bytes reached the client; a timeout can no longer become a 504.
+    relayed: bool,
+    pub deadline: Deadline,
+    client_reg: Option<Interest>,
+    upstream_reg: Option<Interest>,
+}
+
+/// How a pump left the exchange.
+pub enum Progress {
+    Open,
+    /// Response relayed completely; `reusable` when the upstream may take another request.
+    Done { reusable: bool },
+    /// The connect was refused or timed out; see [`Exchange::reconnect`].
+    Refused,
+}
+
+/// Where [`forward`] sends a request: the socket, the serving upstream's index in the
+/// group with the ones tried before it, the bytes to write, and the public origin of the
+/// request. `connecting` while the socket's connect is still under way.
+pub struct Target {
+    pub upstream: TcpStream,
+    pub backend: usize,
+    tried: Vec<usize>,
+    connecting: bool,
+    own_host: bool,
+    pub request: Vec<u8>,
+    pub public: Option<String>,
+}
+
+/// The next upstream of `group` not in `tried` (which it joins), counted as begun: a
+/// pooled connection to it (`false`), else a connect started (`true`). Upstreams that
+/// refuse at once are marked failed; ones whose name `dns` is still resolving are passed
+/// over. Nothing is tried once `deadline` has passed.
+fn open(group: &Group, tried: &mut Vec<usize>, dns: &DnsCache, pool: &mut Pool, deadline: Deadline) -> Result<(usize, TcpStream, bool), ErrorKind> {
+    while let Some(i) = group.pick(tried) {
+        if deadline.expired() { return Err(ErrorKind::UpstreamTimeout); }
+        tried.push(i);
+        let addr = &group.backend(i).addr;
+        let (upstream, connecting) = match pool.checkout(addr) {
+            Some(s) => { metrics::observe_proxy_connection(true); (s, false) }
+            None => match upstream::connect(addr, dns) {
+                Ok(Some(s)) => { metrics::observe_proxy_connection(false); (s, true) }
+                Ok(None) => continue,
+                Err(_) => { group.record_failure(i); continue; }
+            },
+        };
+        group.begin(i);
+        return Ok((i, upstream, connecting));
+    }
+    Err(ErrorKind::BadGateway)
+}
+
+/// Pick an upstream from `group` for `req`, which has passed the request gates, take a
+/// pooled connection to it or start a connect, and build the request. `peer` is the
+/// socket address the request came from.
+pub fn forward(req: &Request, group: &Group, peer: &str, cfg: &ServerConfig, dns: &DnsCache, pool: &mut Pool, deadline: Deadline) -> Result<Target, ErrorKind> {
+    let fwd = forwarded::resolve(&req.headers, peer, &cfg.trusted_proxies);
+    let proto = fwd.as_ref().and_then(|f| f.proto).unwrap_or("http");
+    let host = fwd.as_ref().and_then(|f| f.host)
+        .or_else(|| req.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case("Host")).map(|(_, v)| v.trim()));
+    let public = group.route.public_url.clone().or_else(|| host.map(|h| format!("{}://{}", proto, h)));
+    let mut tried = Vec::new();
+    let (backend, upstream, connecting) = open(group, &mut tried, dns, pool, deadline)?;
+    let own_host = !req.headers.iter().any(|(k, _)| k.eq_ignore_ascii_case("Host"));
+    let request = request_bytes(req, peer, proto, &group.backend(backend).addr);
+    Ok(Target { upstream, backend, tried, connecting, own_host, request, public })
+}
+
+/// Point the `Host` line [`request_bytes`] added for upstream `from` at upstream `to`.
+fn retarget(request: &mut Vec<u8>, from: &str, to: &str) {
+    let line = format!("\r\nHost: {}\r\n", from);
+    if let Some(at) = request.windows(line.len()).position(|w| w == line.as_bytes()) {
+        request.splice(at..at + line.len(), format!("\r\nHost: {}\r\n", to).into_bytes());
+    }
+}
+
+/// When a connect to an upstream of `group` started now counts as failed.
+fn connect_by(group: &Group, deadline: Deadline) -> Instant {
+    Instant::now() + deadline.cap(Duration::from_millis(group.route.connect_timeout_ms))
+}
+
+impl Exchange {
+    /// `target.upstream` must be registered `Readable` under `token` and the client
+    /// still `Readable`; call [`Exchange::sync`] next.
+    pub fn new(target: Target, token: Token, group: &Arc<Group>, head_request: bool, deadline: Deadline) -> Self {
+        Exchange {
+            upstream: target.upstream,
+            token,
+            group: Arc::clone(group),
+            backend: target.backend,
+            tried: target.tried,
+            connect_by: target.connecting.then(|| connect_by(group, deadline)),
+            own_host: target.own_host,
+            public: target.public,
+            request: target.request,
+            head: Vec::new(),
+            response: Vec::new(),
+            held: None,
+            framing: Framing::new(head_request),
+            spoiled: false,
+            upstream_failed: false,
+            relayed: false,
+            deadline,
+            client_reg: Some(Interest::Readable),
+            upstream_reg: Some(Interest::Readable),
+        }
+    }
+
+    /// When a connect under way counts as failed, if one is.
+    pub fn connect_by(&self) -> Option<Instant> { self.connect_by }
+
+    /// The current upstream refused the connection or did not answer it in time: count
+    /// that against it and move the request to the next untried upstream, leaving the
+    /// exchange to be re-armed with [`Exchange::sync`]. `Err` with the status to answer
+    /// once none is left or the request's deadline has passed.
+    pub fn reconnect(&mut self, ev: &mut EventLoop, dns: &DnsCache, pool: &mut Pool) -> Result<(), ErrorKind> {
+        // A connect cut short by the deadline says nothing about the upstream.
+        if self.deadline.expired() { return Err(ErrorKind::UpstreamTimeout); }
+        self.group.record_failure(self.backend);
+        let (backend, upstream, connecting) = open(&self.group, &mut self.tried, dns, pool, self.deadline)?;
+        if self.upstream_reg.take().is_some() { let _ = ev.deregister(self.token); }
+        self.group.end(self.backend);
+        if self.own_host { retarget(&mut self.request, &self.group.backend(self.backend).addr, &self.group.backend(backend).addr); }
+        self.backend = backend;
+        self.upstream = upstream;
+        self.connect_by = connecting.then(|| connect_by(&self.group, self.deadline));
+        Ok(())
+    }
+
+    /// Send the request and relay the response until neither side can move.
+    /// `upstream_ready` when the event loop reported the upstream socket.
+    pub fn pump(&mut self, client: &TcpStream, upstream_ready: bool) -> io::Result<Progress> {
+        if let Some(by) = self.connect_by {
+            if !upstream_ready {
+                return Ok(if Instant::now() >= by { Progress::Refused } else { Progress::Open });
+            }
+            if connect::settled(&self.upstream).is_err() { return Ok(Progress::Refused); }
+            self.connect_by = None;
+        }
+        loop {
+            let mut moved = false;
+            if !self.request.is_empty() {
+                match (&self.upstream).write(&self.request) {
+                    Ok(n) => { self.request.drain(..n); moved |= n > 0; }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => { self.upstream_failed = true; return Err(e); }
+                }
+            }
+            if self.response.is_empty() && !self.framing.complete {
+                let mut tmp = [0u8; CHUNK];
+                match (&self.upstream).read(&mut tmp) {
+                    Ok(0) => {
+                        // Close-delimited bodies end here; anything else was cut short.
+                        self.spoiled = true;
+                        if !matches!(self.framing.body, Some(Body::UntilClose)) {
+                            self.upstream_failed = true;
+                            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "upstream closed mid-response"));
+                        }
+                        self.framing.complete = true;
+                    }
+                    Ok(n) => { self.take(&tmp[..n]); moved = true; }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => { self.upstream_failed = true; return Err(e); }
+                }
+            }
+            if !self.response.is_empty() {
+                match (&*client).write(&self.response) {
+                    Ok(n) => { self.response.drain(..n); moved |= n > 0; self.relayed |= n > 0; }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            if self.framing.complete && self.response.is_empty() {
+                let reusable = self.request.is_empty() && self.framing.keep_alive && !self.spoiled;
+                return Ok(Progress::Done { reusable });
+            }
+            if !moved { return Ok(Progress::Open); }
+        }
+    }
+
+    /// Queue upstream bytes for the client: heads whole (the final one rewritten), body
+    /// bytes as they come. Anything past the end of the response spoils the connection.
+    fn take(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.framing.complete { self.spoiled = true; break; }
+            let in_head = self.framing.body.is_none();
+            let used = self.framing.feed(data);
+            if in_head {
+                self.head.extend_from_slice(&data[..used]);
+                let ended = self.head.ends_with(b"\r\n\r\n");
+                if ended && self.framing.body.is_some() {
+                    let head = std::mem::take(&mut self.head);
+                    let head = rewrite::head(&head, &self.group.route, self.public.as_deref());
+                    let verify = self.group.route.verify_gzip && self.framing.gzip && !self.framing.complete
+                        && matches!(self.framing.body, Some(Body::Length(n)) if n <= MAX_VERIFY);
+                    if verify { self.held = Some((head, Vec::new())); } else { self.response.extend_from_slice(&head); }
+                } else if ended || self.framing.body.is_some() {
+                    // An interim 1xx head, or one too long to frame: relayed as is.
+                    self.response.append(&mut self.head);
+                }
+            } else if let Some((_, body)) = self.held.as_mut() {
+                body.extend_from_slice(&data[..used]);
+            } else {
+                self.response.extend_from_slice(&data[..used]);
+            }
+            data = &data[used..];
+        }
+        if self.framing.complete {
+            if let Some((head, body)) = self.held.take() {
+                if compress::verify_gzip(&body) {
+                    self.response.extend_from_slice(&head);
+                    self.response.extend_from_slice(&body);
+                } else {
+                    log_warn!("[PROXY] {}: gzip body failed CRC32/ISIZE check", self.group.backend(self.backend).addr);
+                    self.response.extend_from_slice(CORRUPT_RESPONSE);
+                    self.spoiled = true;
+                    self.upstream_failed = true;
+                }
+            }
+        }
+    }
+
+    /// Nothing has reached the client yet, so it can still be given an error response.
+    pub fn unanswered(&self) -> bool { !self.relayed }
+
+    /// Re-arm both sockets from the buffer state; `client_token` is the connection key.
+    pub fn sync(&mut self, ev: &mut EventLoop, client: &TcpStream, client_token: Token) -> io::Result<()> {
+        let client_want = interest(false, !self.response.is_empty());
+        let upstream_want = match self.connect_by {
+            Some(_) => Some(Interest::Writable),
+            None => interest(self.response.is_empty() && !self.framing.complete, !self.request.is_empty()),
+        };
+        apply(ev, client, client_token, &mut self.client_reg, client_want)?;
+        apply(ev, &self.upstream, self.token, &mut self.upstream_reg, upstream_want)
+    }
+
+    /// Deregister the upstream, report the outcome to its group and, when `reusable`,
+    /// hand the connection to `pool`.
+    pub fn finish(mut self, ev: &mut EventLoop, pool: &mut Pool, reusable: bool) {
+        if self.upstream_reg.take().is_some() { let _ = ev.deregister(self.token); }
+        self.group.end(self.backend);
+        if self.upstream_failed {
+            self.group.record_failure(self.backend);
+        } else if self.framing.complete {
+            self.group.record_success(self.backend);
+        }
+        if reusable { pool.checkin(&self.group.backend(self.backend).addr, self.upstream); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    /// What `request_bytes` sends upstream for raw request `raw` from `peer`.
+    fn upstream_request(raw: &str, peer: &str) -> String {
+        let (req, _) = Parser::new().advance(raw.as_bytes()).unwrap().unwrap();
+        String::from_utf8(request_bytes(&req, peer, "http", "10.0.0.9:8080")).unwrap()
+    }
+
+    #[test]
+    fn forwarding_headers_append_this_hop() {
+        let sent = upstream_request("POST /api/x?q=1 HTTP/1.1\r\nHost: example.com\r\nX-Forwarded-For: 192.0.2.1\r\nForwarded: for=192.0.2.1\r\nConnection: keep-alive, X-Secret\r\nX-Secret: s\r\nContent-Length: 4\r\n\r\nbody", "2001:db8::7");
+        let (head, body) = sent.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("POST /api/x?q=1 HTTP/1.1\r\n"), "{}", head);
+        assert!(head.contains("\r\nHost: example.com\r\n"));
+        assert!(head.contains("\r\nX-Forwarded-For: 192.0.2.1, 2001:db8::7\r\n"), "{}", head);
+        assert!(head.contains("\r\nX-Forwarded-Proto: http\r\nX-Forwarded-Host: example.com\r\n"), "{}", head);
+        assert!(head.contains("\r\nForwarded: for=192.0.2.1, for=\"[2001:db8::7]\";proto=http;host=\"example.com\"\r\n"), "{}", head);
+        // Hop-by-hop headers, including those listed in Connection, stay behind.
+        assert!(!head.contains("Connection") && !head.contains("X-Secret"), "{}", head);
+        assert!(head.ends_with("Content-Length: 4"));
+        assert_eq!(body, "body");
+    }
+
+    #[test]
+    fn missing_host_names_the_upstream() {
+        let sent = upstream_request("GET / HTTP/1.0\r\n\r\n", "127.0.0.1");
+        assert!(sent.contains("\r\nHost: 10.0.0.9:8080\r\n"), "{}", sent);
+        assert!(sent.contains("\r\nForwarded: for=127.0.0.1;proto=http\r\n"), "{}", sent);
+        assert!(!sent.contains("Content-Length"), "{}", sent);
+    }
+
+    /// A connected pair: the pooled (non-blocking) end and the upstream's end.
+    fn upstream_pair() -> (TcpStream, TcpStream) {
+        let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let s = TcpStream::connect(l.local_addr().unwrap()).unwrap();
+        s.set_nonblocking(true).unwrap();
+        (s, l.accept().unwrap().0)
+    }
+
+    fn bounded(max_idle_per_upstream: usize, max_idle: usize, idle_timeout_ms: u64) -> Pool {
+        Pool::new(&ProxyPoolConfig { max_idle_per_upstream, max_idle, idle_timeout_ms })
+    }
+
+    #[test]
+    fn pooled_connections_are_reused_within_bounds() {
+        let mut pool = bounded(2, 3, 60_000);
+        let mut peers = Vec::new();
+        for upstream in ["a:80", "a:80", "a:80", "b:80", "c:80"] {
+            let (s, peer) = upstream_pair();
+            pool.checkin(upstream, s);
+            peers.push(peer);
+        }
+        // Two per upstream, three in all.
+        assert_eq!(pool.idle(), 3);
+        assert!(pool.checkout("a:80").is_some());
+        assert!(pool.checkout("a:80").is_some());
+        assert!(pool.checkout("a:80").is_none());
+        assert!(pool.checkout("b:80").is_some());
+        assert!(pool.checkout("c:80").is_none());
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[test]
+    fn closed_and_expired_connections_are_discarded() {
+        let mut pool = bounded(8, 64, 60_000);
+        let (s, peer) = upstream_pair();
+        pool.checkin("a:80", s);
+        drop(peer);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(pool.checkout("a:80").is_none());
+        assert_eq!(pool.idle(), 0);
+
+        let mut pool = bounded(8, 64, 50);
+        let (s, _peer) = upstream_pair();
+        pool.checkin("a:80", s);
+        pool.evict_idle(Instant::now());
+        assert_eq!(pool.idle(), 1);
+        pool.evict_idle(Instant::now() + Duration::from_millis(60));
+        assert_eq!(pool.idle(), 0);
+    }
+}
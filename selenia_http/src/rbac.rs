@@ -5,17 +5,7 @@
 
 use core::str;
 use std::collections::HashMap;
-use std::sync::LazyLock;
-
-const BASE64_LOOKUP: LazyLock<[u8;256]> = LazyLock::new(|| {
-    const INVALID: u8 = 0xFF;
-    let mut t = [INVALID; 256];
-    let mut i = 0u8;
-    for c in b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/".iter() {
-        t[*c as usize] = i; i += 1;
-    }
-    t
-});
+use selenia_core::encoding::base64;
 
 static mut POLICIES: Option<Vec<Policy>> = None;
 
@@ -63,7 +53,7 @@ pub fn validate(path:&str, auth_header:Option<&str>) -> bool {
 fn extract_roles(token:&str)->Vec<String>{
     let parts:Vec<&str>=token.split('.').collect(); if parts.len()!=3 { return Vec::new(); }
     let payload_b64=parts[1];
-    let json_bytes = base64_url_decode(payload_b64);
+    let json_bytes = base64::decode_url(payload_b64).unwrap_or_default();
     if let Ok(s)=str::from_utf8(&json_bytes) {
         if let Some(idx)=s.find("\"roles\"") {
             if let Some(start)=s[idx..].find('[') { if let Some(end)=s[idx+start..].find(']') {
@@ -75,37 +65,18 @@ fn extract_roles(token:&str)->Vec<String>{
     Vec::new()
 }
 
-fn base64_url_decode(s:&str)->Vec<u8>{
-    // Minimal Base64(URL-safe) decoder without external crates.
-    let mut b = s.replace('-', "+").replace('_', "/");
-    while b.len() % 4 != 0 { b.push('='); }
-    decode_base64_simple(&b)
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-/// Very small base64 decoder supporting standard & URL-safe alphabet. No padding validation.
-fn decode_base64_simple(inp: &str) -> Vec<u8> {
-    const INVALID: u8 = 0xFF;
-    let bytes = inp.as_bytes();
-    let mut out = Vec::with_capacity(bytes.len() * 3 / 4);
-    let mut chunk = [0u8;4];
-    let mut idx = 0;
-    for &b in bytes {
-        if b == b'=' { break; }
-        let val = BASE64_LOOKUP[b as usize];
-        if val == INVALID { continue; }
-        chunk[idx] = val; idx +=1;
-        if idx==4 {
-            out.push((chunk[0]<<2) | (chunk[1]>>4));
-            out.push((chunk[1]<<4) | (chunk[2]>>2));
-            out.push((chunk[2]<<6) | chunk[3]);
-            idx=0;
-        }
-    }
-    if idx==3 {
-        out.push((chunk[0]<<2) | (chunk[1]>>4));
-        out.push((chunk[1]<<4) | (chunk[2]>>2));
-    } else if idx==2 {
-        out.push((chunk[0]<<2) | (chunk[1]>>4));
+    #[test]
+    fn roles_survive_an_encode_decode_round_trip() {
+        let payload = base64::encode_url(br#"{"sub":"alice","roles":["admin","finance"]}"#);
+        let token = format!("{}.{}.sig", base64::encode_url(br#"{"alg":"none"}"#), payload);
+        assert_eq!(extract_roles(&token), vec!["admin".to_string(), "finance".to_string()]);
+        // The standard alphabet with padding decodes too when it carries no '+' or '/'.
+        let padded = format!("h.{}.s", base64::encode(br#"{"roles":["ops"]}"#));
+        assert_eq!(extract_roles(&padded), vec!["ops".to_string()]);
+        assert!(extract_roles("h.%%%.s").is_empty());
     }
-    out
-} 
\ No newline at end of file
+}
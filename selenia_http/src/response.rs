@@ -0,0 +1,113 @@
+//! Response head construction shared by every status.
+//!
+//! [`ResponseHeaders::new`] writes the status line and the headers each response
+//! carries regardless of outcome – `Date`, `Server`, `X-Content-Type-Options` and, with
+//! TLS configured, `Strict-Transport-Security` – so a 404 or 403 is dressed exactly
+//! like a 200. Callers then append their own fields and pre-formatted lines
+//! (traceparent, CORS, auth challenges) and [`ResponseHeaders::finish`] the head.
+
+use std::fmt::Display;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use selenia_core::config::ServerConfig;
+
+use super::keepalive;
+
+const SERVER: &str = concat!("SWS/", env!("CARGO_PKG_VERSION"));
+
+/// Reason phrase for the statuses this server sends.
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        206 => "Partial Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        421 => "Misdirected Request",
+        425 => "Too Early",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "Error",
+    }
+}
+
+/// IMF-fixdate (RFC 9110 §5.6.7), e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(t: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let secs = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Civil date from days since 1970-01-01 (proleptic Gregorian, era-based).
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize], day, MONTHS[(month - 1) as usize], year, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+pub struct ResponseHeaders {
+    text: String,
+}
+
+impl ResponseHeaders {
+    /// Status line plus the headers common to every response. The status line says
+    /// `HTTP/1.0` to 1.0 clients and `HTTP/1.1` otherwise, whatever `version` holds.
+    pub fn new(version: &str, status: u16, cfg: &ServerConfig) -> Self {
+        let version = if version == "HTTP/1.0" { version } else { "HTTP/1.1" };
+        let mut text = format!("{} {} {}\r\nDate: {}\r\nServer: {}\r\nX-Content-Type-Options: nosniff\r\n",
+            version, status, reason(status), http_date(SystemTime::now()), SERVER);
+        if cfg.tls_cert.is_some() {
+            text.push_str("Strict-Transport-Security: max-age=31536000; includeSubDomains\r\n");
+        }
+        ResponseHeaders { text }
+    }
+
+    pub fn header(&mut self, name: &str, value: impl Display) -> &mut Self {
+        self.text.push_str(&format!("{}: {}\r\n", name, value));
+        self
+    }
+
+    /// Pre-formatted `Name: value\r\n` lines (traceparent, CORS, challenges).
+    pub fn lines(&mut self, lines: &str) -> &mut Self {
+        self.text.push_str(lines);
+        self
+    }
+
+    /// `Connection` (and, when keeping the connection, the `Keep-Alive` hint).
+    pub fn connection(&mut self, keep_alive: bool) -> &mut Self {
+        if keep_alive {
+            let (ka_timeout, ka_max) = keepalive::current();
+            self.text.push_str(&format!("Connection: keep-alive\r\nKeep-Alive: timeout={}, max={}\r\n", ka_timeout, ka_max));
+        } else {
+            self.text.push_str("Connection: close\r\n");
+        }
+        self
+    }
+
+    /// The head, terminated by the empty line.
+    pub fn finish(mut self) -> String {
+        self.text.push_str("\r\n");
+        self.text
+    }
+}
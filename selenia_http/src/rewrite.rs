@@ -0,0 +1,123 @@
+//! Response head rewriting for `proxy_pass` routes.
+//!
+//! Runs on the upstream's final response head before it reaches the client; interim
+//! 1xx heads pass unchanged and static responses never come here. Absolute `Location`
+//! / `Content-Location` URLs whose authority is one of the route's upstreams move to
+//! the public origin, `Set-Cookie` `Domain=` / `Path=` are replaced as configured, then
+//! `remove_headers` are dropped and `add_headers` appended.
+
+use selenia_core::config::ProxyPass;
+
+/// `authority` names upstream `addr` (`host:port`); the scheme's default port may be left out.
+fn names_upstream(authority: &str, scheme: &str, addr: &str) -> bool {
+    if authority.eq_ignore_ascii_case(addr) { return true; }
+    let default = if scheme.eq_ignore_ascii_case("https") { "443" } else { "80" };
+    addr.rsplit_once(':').map_or(false, |(host, port)| port == default && authority.eq_ignore_ascii_case(host))
+}
+
+/// `value` re-rooted at `public` when it is an absolute URL on an upstream.
+fn location(value: &str, route: &ProxyPass, public: &str) -> Option<String> {
+    let (scheme, rest) = value.split_once("://")?;
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    if !route.upstreams.iter().any(|a| names_upstream(&rest[..end], scheme, a)) { return None; }
+    Some(format!("{}{}", public, &rest[end..]))
+}
+
+/// `Set-Cookie` value with its `Domain` / `Path` attributes mapped.
+fn cookie(value: &str, route: &ProxyPass) -> String {
+    value.split(';').enumerate().map(|(i, attr)| {
+        // The first pair is the cookie itself, whatever its name.
+        let Some((k, v)) = attr.split_once('=').filter(|_| i > 0) else { return attr.to_string() };
+        let (k, v) = (k.trim(), v.trim());
+        if let Some((from, to)) = route.cookie_domain.as_ref().filter(|_| k.eq_ignore_ascii_case("Domain")) {
+            if v.trim_start_matches('.').eq_ignore_ascii_case(from.trim_start_matches('.')) { return format!(" {}={}", k, to); }
+        }
+        if let Some((from, to)) = route.cookie_path.as_ref().filter(|_| k.eq_ignore_ascii_case("Path")) {
+            if let Some(rest) = v.strip_prefix(from.as_str()) { return format!(" {}={}{}", k, to, rest); }
+        }
+        attr.to_string()
+    }).collect::<Vec<_>>().join(";")
+}
+
+/// The rewritten form of response head `head` (status line through the empty line).
+/// `public` is the origin clients use; without one, locations are left alone.
+pub fn head(head: &[u8], route: &ProxyPass, public: Option<&str>) -> Vec<u8> {
+    let text = String::from_utf8_lossy(head);
+    let mut lines = text.split("\r\n").filter(|l| !l.is_empty());
+    let mut out = format!("{}\r\n", lines.next().unwrap_or(""));
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            out.push_str(line);
+            out.push_str("\r\n");
+            continue;
+        };
+        let name = name.trim();
+        if route.remove_headers.iter().any(|r| r.eq_ignore_ascii_case(name)) { continue; }
+        let is = |n: &str| name.eq_ignore_ascii_case(n);
+        let replaced = match public {
+            Some(public) if route.rewrite_location && (is("Location") || is("Content-Location")) => location(value.trim(), route, public),
+            _ if is("Set-Cookie") && (route.cookie_domain.is_some() || route.cookie_path.is_some()) => Some(cookie(value.trim(), route)),
+            _ => None,
+        };
+        match replaced {
+            Some(v) => out.push_str(&format!("{}: {}\r\n", name, v)),
+            None => { out.push_str(line); out.push_str("\r\n"); }
+        }
+    }
+    for h in &route.add_headers {
+        out.push_str(h.trim());
+        out.push_str("\r\n");
+    }
+    out.push_str("\r\n");
+    out.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route() -> ProxyPass {
+        ProxyPass {
+            prefix: "/app/".into(),
+            upstreams: vec!["backend:80".into(), "10.0.0.2:8080".into()],
+            rewrite_location: true,
+            ..ProxyPass::default()
+        }
+    }
+
+    fn rewrite(upstream_head: &str, route: &ProxyPass) -> String {
+        String::from_utf8(head(upstream_head.as_bytes(), route, Some("https://public"))).unwrap()
+    }
+
+    #[test]
+    fn upstream_locations_move_to_the_public_origin() {
+        let out = rewrite("HTTP/1.1 302 Found\r\nLocation: http://backend/x?y=1\r\nContent-Location: http://10.0.0.2:8080/z\r\n\r\n", &route());
+        assert_eq!(out, "HTTP/1.1 302 Found\r\nLocation: https://public/x?y=1\r\nContent-Location: https://public/z\r\n\r\n");
+        // Other hosts, relative URLs and a non-default port stay as they are.
+        for kept in ["http://elsewhere/x", "/relative", "http://backend:8080/x"] {
+            let out = rewrite(&format!("HTTP/1.1 302 Found\r\nLocation: {}\r\n\r\n", kept), &route());
+            assert!(out.contains(&format!("\r\nLocation: {}\r\n", kept)), "{}", out);
+        }
+        let off = ProxyPass { rewrite_location: false, ..route() };
+        assert!(rewrite("HTTP/1.1 302 Found\r\nLocation: http://backend/x\r\n\r\n", &off).contains("Location: http://backend/x\r\n"));
+    }
+
+    #[test]
+    fn cookie_domain_and_path_are_mapped() {
+        let r = ProxyPass {
+            cookie_domain: Some(("backend.internal".into(), "example.com".into())),
+            cookie_path: Some(("/".into(), "/app/".into())),
+            ..route()
+        };
+        let out = rewrite("HTTP/1.1 200 OK\r\nSet-Cookie: sid=abc; Domain=.backend.internal; Path=/login; HttpOnly\r\nSet-Cookie: t=1; Domain=other.test\r\n\r\n", &r);
+        assert!(out.contains("\r\nSet-Cookie: sid=abc; Domain=example.com; Path=/app/login; HttpOnly\r\n"), "{}", out);
+        assert!(out.contains("\r\nSet-Cookie: t=1; Domain=other.test\r\n"), "{}", out);
+    }
+
+    #[test]
+    fn configured_headers_are_removed_and_added() {
+        let r = ProxyPass { remove_headers: vec!["x-powered-by".into()], add_headers: vec!["X-Frame-Options: DENY".into()], ..route() };
+        let out = rewrite("HTTP/1.1 200 OK\r\nX-Powered-By: PHP\r\nContent-Length: 0\r\n\r\n", &r);
+        assert_eq!(out, "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nX-Frame-Options: DENY\r\n\r\n");
+    }
+}
@@ -35,7 +35,9 @@ impl Router {
         node.handler=Some(id);
     }
 
+    /// Match a request-target; the query string does not take part in routing.
     pub fn find(&self, path: &str) -> Option<&str> {
+        let path = super::uri::split_target(path).0;
         let mut node=&self.root;
         for seg in path.trim_start_matches('/').split('/') {
             if let Some(next)=node.children.get(seg) { node=next; continue; }
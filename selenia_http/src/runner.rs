@@ -0,0 +1,2407 @@
+//! One event-loop worker, separated from `run_server`'s setup so it can be driven directly.
+//!
+//! `run_server` binds listeners, spawns accept threads and hands every accepted stream to
+//! [`EventLoopRunner::inject`], then calls [`EventLoopRunner::step`] in a loop. Anything
+//! that owns one end of a connected `TcpStream` pair can do the same without listeners
+//! or accept threads: inject the server end, write requests to the other end, and
+//! `step` until the response is there. With no connection activity a step returns after
+//! `timeout_ms` at the latest, so idle expiry and keep-alive behaviour are observable
+//! step by step. The idle sweep and timeout auto-tuning run on a clock tick
+//! (`SWEEP_INTERVAL`), not per poll return: a step never sleeps past the next tick, and
+//! a busy loop does not sweep more often than that.
+//!
+//! Each request holds a slot of the listener that accepted its connection until its
+//! response is written, also while that waits on the I/O pool, a full socket or an
+//! upstream. A listener with `max_concurrent_requests` queues requests beyond it, as
+//! many again as the limit, and sheds the rest with 503; slots and queues are per
+//! listener, so a flood on one does not hold up another.
+//!
+//! TLS handshakes in progress are capped per worker by `tls.max_handshakes`; a
+//! connection that would start one beyond it gets an `internal_error` alert and is closed.
+//!
+//! The configuration lives behind an [`ArcSwap`] so `reload_config` can replace it
+//! without waiting for anything in flight. Every request takes a snapshot when it is
+//! parsed and finishes under it, also after an I/O-pool round trip, so no response
+//! mixes settings of two generations. Listeners, thread pools, proxy routes and the
+//! other state built in `new` keep their startup values.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use selenia_core::config::{IdleTuneConfig, ListenMode, ServerConfig, DEFAULT_TLS_HANDSHAKES_PER_WORKER};
+use selenia_core::crypto::tls13;
+use selenia_core::dns::DnsCache;
+use selenia_core::os::{EventLoop, Interest, Token};
+use selenia_core::sync::ArcSwap;
+use selenia_core::{log_error, log_info, metrics};
+
+use super::conn_store::ConnStore;
+use super::deadline::Deadline;
+use super::error::ErrorKind;
+use super::parser::Parser;
+use super::{apply_gates, blockio, finish_file, handle_request, Routed, http2, keepalive, proxy, respond_error, should_close, tunnel, upgrade, upstream, worker_count, zerocopy};
+
+/// Cadence of the idle sweep and idle-timeout auto-tuning.
+const SWEEP_INTERVAL: Duration = Duration::from_millis(500);
+/// Consecutive failed polls tolerated (logged, then retried) before `step` gives up.
+const MAX_POLL_FAILURES: u32 = 16;
+/// Pause after a failed poll so a persistent error does not spin the loop.
+const POLL_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+#[derive(Debug)]
+struct Conn {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    parser: Parser,
+    peer: String,
+    /// TLS/plaintext decision of the listener that accepted the connection.
+    mode: ListenMode,
+    /// Unsent file body waiting for the socket to become writable (write interest armed).
+    pending: Option<zerocopy::PendingSend>,
+    /// Close once `pending` drains (request asked for `Connection: close`).
+    close_after_send: bool,
+    /// First TLS record byte seen and the handshake is still incomplete.
+    tls_started: bool,
+    /// Counted against `tls.max_handshakes` while `tls_started`.
+    handshake: Option<Handshake>,
+    /// A file response is loading on the I/O pool; later requests wait in `buf`.
+    awaiting_io: bool,
+    /// CONNECT tunnel established: the connection only relays bytes from here on.
+    tunnel: Option<tunnel::Tunnel>,
+    /// `proxy_pass` request handed upstream; the connection closes after the response.
+    proxied: Option<proxy::Exchange>,
+    /// Index of the accepting listener in `listen`.
+    listener: usize,
+    /// Listener slot of a request whose response is still in flight.
+    slot: Option<Slot>,
+    /// Waiting for a listener slot; the parsed request stays in `buf`.
+    queued: bool,
+    /// The client shut down its write side (`half_close`): answer what is in `buf`, then close.
+    read_closed: bool,
+    /// Taken off the event loop after a half-close so the EOF does not keep firing
+    /// while a response waits on the I/O pool or a listener slot.
+    parked: bool,
+}
+
+/// Read up to `chunk` bytes straight onto the end of `buf`. Parsed requests are drained
+/// from the front, so its capacity carries over between keep-alive requests.
+fn read_into(stream: &mut TcpStream, buf: &mut Vec<u8>, chunk: usize) -> io::Result<usize> {
+    let len = buf.len();
+    buf.resize(len + chunk, 0);
+    let read = stream.read(&mut buf[len..]);
+    buf.truncate(len + *read.as_ref().unwrap_or(&0));
+    read
+}
+
+/// Switch `conn` to `interest`, registering it again if it was parked.
+fn rearm(ev: &mut EventLoop, conn: &mut Conn, token: usize, interest: Interest) -> io::Result<()> {
+    if std::mem::take(&mut conn.parked) { ev.register_token(&conn.stream, token, interest) } else { ev.reregister(token, interest) }
+}
+
+/// The first setting, by its configuration name, that `new` changes among those a worker
+/// fixes at startup: the listeners, the upstream routes and pool, runner capacities, and
+/// what the process set up before its sandbox.
+fn fixed_change(old: &ServerConfig, new: &ServerConfig) -> Option<&'static str> {
+    let fixed = [
+        ("listen", old.listen_modes != new.listen_modes || old.listen_max_requests != new.listen_max_requests),
+        ("listen_backlog", old.listen_backlog != new.listen_backlog),
+        ("tcp_fastopen", old.tcp_fastopen != new.tcp_fastopen),
+        ("sticky_routing", old.sticky_routing != new.sticky_routing),
+        ("proxy_pass", old.proxy_pass != new.proxy_pass),
+        ("proxy_pool", old.proxy_pool != new.proxy_pool),
+        ("io_threads", old.io_threads != new.io_threads),
+        ("max_connections", old.max_connections != new.max_connections),
+        ("max_open_fds", old.max_open_fds != new.max_open_fds),
+        ("tls.handshake_timeout_ms", old.tls_handshake_timeout_ms != new.tls_handshake_timeout_ms),
+        ("tls.max_handshakes", old.tls_max_handshakes != new.tls_max_handshakes),
+        ("run_as_user", old.run_as_user != new.run_as_user || old.run_as_group != new.run_as_group),
+        ("umask", old.umask != new.umask),
+        ("locale_catalog", old.locale_catalog != new.locale_catalog),
+    ];
+    fixed.iter().find(|(_, changed)| *changed).map(|&(name, _)| name)
+}
+
+/// Next idle timeout for `active` of `capacity` connections (see `IdleTuneConfig`).
+fn tune_idle_timeout(current: Duration, active: usize, capacity: usize, tune: &IdleTuneConfig) -> Duration {
+    let load = active as f32 / capacity.max(1) as f32;
+    let step = Duration::from_millis(tune.step_ms);
+    let next = if load > tune.high_load {
+        current.saturating_sub(step)
+    } else if load < tune.low_load {
+        current + step
+    } else {
+        current
+    };
+    next.clamp(Duration::from_millis(tune.min_ms), Duration::from_millis(tune.max_ms))
+}
+
+impl Conn {
+    /// Half-closed with every buffered request answered: nothing more can arrive.
+    fn drained(&self) -> bool {
+        self.read_closed && self.pending.is_none() && !self.awaiting_io && !self.queued && self.proxied.is_none() && self.tunnel.is_none()
+    }
+}
+
+/// A listener's in-flight request count, released when dropped.
+#[derive(Debug)]
+struct Slot {
+    active: Arc<AtomicU64>,
+    metric: usize,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let n = self.active.fetch_sub(1, Ordering::Relaxed) - 1;
+        metrics::set_listener_active(self.metric, n);
+    }
+}
+
+/// An in-progress TLS handshake of this worker, released when dropped.
+#[derive(Debug)]
+struct Handshake(Arc<AtomicU64>);
+
+impl Drop for Handshake {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+        metrics::dec_tls_handshakes_active();
+    }
+}
+
+/// `max_concurrent_requests` state of one listener.
+#[derive(Debug)]
+struct ListenerSlots {
+    /// 0 is unlimited.
+    max: u64,
+    active: Arc<AtomicU64>,
+    metric: usize,
+    /// Connection keys queued for a slot, oldest first.
+    waiting: VecDeque<usize>,
+}
+
+impl ListenerSlots {
+    fn new(addr: &str, max: u32) -> Self {
+        ListenerSlots { max: max as u64, active: Arc::new(AtomicU64::new(0)), metric: metrics::register_listener(addr), waiting: VecDeque::new() }
+    }
+
+    fn free(&self) -> u64 {
+        if self.max == 0 { u64::MAX } else { self.max.saturating_sub(self.active.load(Ordering::Relaxed)) }
+    }
+
+    /// A slot, or `None` when the listener is at its limit.
+    fn acquire(&self) -> Option<Slot> {
+        if self.free() == 0 { return None; }
+        let n = self.active.fetch_add(1, Ordering::Relaxed) + 1;
+        metrics::set_listener_active(self.metric, n);
+        Some(Slot { active: Arc::clone(&self.active), metric: self.metric })
+    }
+}
+
+/// Connections of one event loop plus the state its sweeps keep between steps.
+pub struct EventLoopRunner {
+    /// Live configuration; `reload_config` replaces it.
+    config: ArcSwap<ServerConfig>,
+    /// Bumped by every `reload_config`; 0 is the startup configuration.
+    generation: AtomicU64,
+    /// Snapshot of `config` taken at the start of each step.
+    cfg: Arc<ServerConfig>,
+    ev: EventLoop,
+    io_pool: Option<blockio::IoPool>,
+    // Tokens are slab keys; the store's activity list drives the idle sweep.
+    conns: ConnStore<Conn>,
+    fd_base: u64,
+    fd_ceiling: u64,
+    idle_timeout: Duration,
+    req_count: u64,
+    last_adjust: Instant,
+    /// When the idle sweep runs next; advanced by `SWEEP_INTERVAL` per tick.
+    next_sweep: Instant,
+    /// Polls failed in a row; reset by a successful one.
+    poll_failures: u32,
+    tls_timeout: Duration,
+    // Handshake deadlines in arrival order; the timeout is uniform, so the front expires first.
+    tls_deadlines: VecDeque<(Instant, usize)>,
+    /// Handshakes in progress and this worker's share of `tls.max_handshakes`.
+    handshakes: Arc<AtomicU64>,
+    max_handshakes: u64,
+    // `request_timeout_ms` expiry of proxied requests, in the same arrival order.
+    proxy_deadlines: VecDeque<(Instant, usize)>,
+    /// Proxied requests and CONNECT tunnels whose upstream connect is under way, with its
+    /// `connect_timeout_ms` expiry; timeouts differ, so this is not kept in order.
+    connects: Vec<(Instant, usize)>,
+    /// Upstream socket token → connection key, for CONNECT tunnels and `proxy_pass`.
+    upstreams: HashMap<Token, usize>,
+    /// Resolver for `proxy_pass` upstreams and CONNECT targets; only started when either is configured.
+    dns: Option<Arc<DnsCache>>,
+    /// Idle keep-alive connections to `proxy_pass` upstreams.
+    pool: proxy::Pool,
+    /// One upstream group per `proxy_pass` route, in configuration order.
+    routes: Vec<Arc<upstream::Group>>,
+    /// Request slots per `listen` entry, in configuration order.
+    listeners: Vec<ListenerSlots>,
+}
+
+impl EventLoopRunner {
+    /// Runner with its own event loop (and blocking I/O pool when `io_threads > 0`)
+    /// holding at most `max_conns` connections, fewer if this worker's share of
+    /// `max_connections` is smaller. Create it before the seccomp sandbox.
+    pub fn new(cfg: ServerConfig, max_conns: usize) -> io::Result<Self> {
+        let mut ev = EventLoop::new()?;
+        // Workers are spawned before the sandbox; their wake-up socket gets a plain
+        // event-loop token, which never collides with connection (slab) tokens.
+        let io_pool = if cfg.io_threads > 0 {
+            let pool = blockio::IoPool::new(cfg.io_threads)?;
+            ev.register(pool.wake_fd(), Interest::Readable)?;
+            log_info!("blocking I/O pool: {} threads", cfg.io_threads);
+            Some(pool)
+        } else {
+            None
+        };
+        let tls_timeout = Duration::from_millis(cfg.tls_handshake_timeout_ms);
+        let idle_timeout = Duration::from_secs(30).clamp(Duration::from_millis(cfg.idle_tune.min_ms), Duration::from_millis(cfg.idle_tune.max_ms));
+        // The master's worker count; shards stay whole when the runner is driven alone.
+        let workers = worker_count() as u64;
+        let max_handshakes = cfg.tls_max_handshakes.map_or(DEFAULT_TLS_HANDSHAKES_PER_WORKER as u64, |n| (n as u64 / workers).max(1));
+        let max_conns = cfg.max_connections.map_or(max_conns, |n| (n / workers as usize).max(1).min(max_conns));
+        let dns = if cfg.proxy_pass.is_empty() && cfg.connect_proxy.is_none() { None } else { Some(DnsCache::new()) };
+        let pool = proxy::Pool::new(&cfg.proxy_pool);
+        let routes: Vec<_> = cfg.proxy_pass.iter().map(|p| Arc::new(upstream::Group::new(p))).collect();
+        if let Some(dns) = &dns {
+            upstream::prefetch(&routes, dns);
+            upstream::spawn_health_checks(&routes, dns)?;
+        }
+        let listeners = cfg.listen.iter().zip(&cfg.listen_max_requests).map(|(a, &m)| ListenerSlots::new(a, m)).collect();
+        let cfg = Arc::new(cfg);
+        Ok(EventLoopRunner {
+            config: ArcSwap::new(Arc::clone(&cfg)),
+            generation: AtomicU64::new(0),
+            cfg,
+            ev,
+            io_pool,
+            conns: ConnStore::new(max_conns),
+            fd_base: 0,
+            fd_ceiling: u64::MAX,
+            idle_timeout,
+            req_count: 0,
+            last_adjust: Instant::now(),
+            next_sweep: Instant::now() + SWEEP_INTERVAL,
+            poll_failures: 0,
+            tls_timeout,
+            tls_deadlines: VecDeque::new(),
+            handshakes: Arc::new(AtomicU64::new(0)),
+            max_handshakes,
+            proxy_deadlines: VecDeque::new(),
+            connects: Vec::new(),
+            upstreams: HashMap::new(),
+            dns,
+            pool,
+            routes,
+            listeners,
+        })
+    }
+
+    /// fd accounting: `base` descriptors are open besides connections, and `inject`
+    /// answers 503 instead of registering once another one would reach `ceiling`.
+    pub fn set_fd_budget(&mut self, base: u64, ceiling: u64) {
+        self.fd_base = base;
+        self.fd_ceiling = ceiling;
+    }
+
+    /// Override the idle timeout (30 s initially, within `idle_tune` bounds; auto-tuned while running).
+    pub fn set_idle_timeout(&mut self, idle: Duration) {
+        self.idle_timeout = idle;
+    }
+
+    /// Serve requests parsed from now on with `cfg`, its request gates and process-wide
+    /// limits loaded with it; responses already under way finish with the configuration
+    /// they started with. A `cfg` that changes what the worker fixed at startup is
+    /// refused. Returns the new generation.
+    pub fn reload_config(&self, cfg: ServerConfig) -> Result<u64, String> {
+        if cfg.listen != self.cfg.listen {
+            return Err("listen addresses changed; a master reload is needed to rebind".into());
+        }
+        if let Some(name) = fixed_change(&self.cfg, &cfg) {
+            return Err(format!("{} changed; a master reload is needed to apply it", name));
+        }
+        if cfg.connect_proxy.is_some() && self.dns.is_none() {
+            return Err("connect_proxy enabled; a master reload is needed to start its resolver".into());
+        }
+        apply_gates(&cfg);
+        self.config.store(Arc::new(cfg));
+        Ok(self.generation.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    /// Connections this worker can hold: its connection slots, capped by the fd budget.
+    fn conn_capacity(&self) -> usize {
+        let fds = self.fd_ceiling.saturating_sub(self.fd_base).min(usize::MAX as u64) as usize;
+        self.conns.capacity().min(fds).max(1)
+    }
+
+    /// Connections currently registered.
+    pub fn connections(&self) -> usize {
+        self.conns.len()
+    }
+
+    /// Point-in-time state for a SIGUSR1 dump: connections, descriptors, idle timeout
+    /// and per-listener request slots, one `key: value` line each.
+    pub fn diagnostics(&self) -> String {
+        let mut out = format!(
+            "connections: {} of {}\nopen_fds: {} of {}\nupstreams: {} active, {} pooled\nidle_timeout_ms: {}\nconfig_generation: {}\n",
+            self.conns.len(), self.conn_capacity(), self.open_fds(), self.fd_ceiling,
+            self.upstreams.len(), self.pool.idle(), self.idle_timeout.as_millis(), self.generation.load(Ordering::Relaxed),
+        );
+        for (addr, l) in self.cfg.listen.iter().zip(&self.listeners) {
+            let max = if l.max == 0 { "unlimited".to_string() } else { l.max.to_string() };
+            out.push_str(&format!("listener {}: {} active, {} queued, max {}\n", addr, l.active.load(Ordering::Relaxed), l.waiting.len(), max));
+        }
+        out
+    }
+
+    /// Descriptors held besides the listeners: connections, their upstreams, idle pooled upstreams.
+    fn open_fds(&self) -> u64 {
+        self.fd_base + (self.conns.len() + self.upstreams.len() + self.pool.idle()) as u64
+    }
+
+    /// Take over an accepted, non-blocking connection from `peer`, speaking `mode`, that
+    /// arrived on the `listener`-th `listen` entry (an index past the configured ones is unlimited).
+    pub fn inject(&mut self, mut stream: TcpStream, peer_addr: SocketAddr, mode: ListenMode, listener: usize) -> io::Result<()> {
+        let key = match self.conns.vacant_key() {
+            Some(k) if self.open_fds() + 1 < self.fd_ceiling => k,
+            _ => {
+                // At the connection cap or near RLIMIT_NOFILE: shed load explicitly instead
+                // of failing later with EMFILE.
+                let _ = stream.write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nRetry-After: 1\r\nConnection: close\r\n\r\n");
+                metrics::inc_errors();
+                metrics::inc_connections_rejected();
+                return Ok(());
+            }
+        };
+        self.ev.register_token(&stream, key, Interest::Readable)?;
+        let conn = Conn {
+            stream,
+            buf: Vec::new(),
+            parser: Parser::from_config(&self.cfg),
+            peer: peer_addr.ip().to_string(),
+            mode,
+            pending: None,
+            close_after_send: false,
+            tls_started: false,
+            handshake: None,
+            awaiting_io: false,
+            tunnel: None,
+            proxied: None,
+            listener,
+            slot: None,
+            queued: false,
+            read_closed: false,
+            parked: false,
+        };
+        keepalive::record_new_conn();
+        if self.conns.insert(conn, Instant::now()).is_err() { self.ev.deregister(key)?; }
+        Ok(())
+    }
+
+    /// Relay whatever a ready tunnel side allows; tear the tunnel down when done or broken.
+    /// A connect that fails is answered 502, one that times out 504. `upstream_ready`
+    /// when the event was for the upstream socket.
+    fn pump_tunnel(&mut self, key: usize, upstream_ready: bool) -> io::Result<()> {
+        let conn = match self.conns.get_mut(key) { Some(c) => c, None => return Ok(()) };
+        let t = match conn.tunnel.as_mut() { Some(t) => t, None => return Ok(()) };
+        let open = match t.pump(&conn.stream, upstream_ready) {
+            Ok(open) => open,
+            Err(e) => {
+                log_error!("[TUNNEL] {}: {}", conn.peer, e);
+                if t.connect_by().is_some() {
+                    let kind = if e.kind() == io::ErrorKind::TimedOut { ErrorKind::UpstreamTimeout } else { ErrorKind::BadGateway };
+                    metrics::inc_errors();
+                    let _ = respond_error(&mut conn.stream, "HTTP/1.1", kind, &self.cfg);
+                }
+                false
+            }
+        };
+        if open {
+            t.sync(&mut self.ev, &conn.stream, key)?;
+            self.conns.touch(key, Instant::now());
+        } else if let Some(c) = self.conns.remove(key) {
+            let _ = self.ev.deregister(key);
+            if let Some(t) = c.tunnel {
+                let _ = self.ev.deregister(t.token);
+                self.upstreams.remove(&t.token);
+            }
+        }
+        Ok(())
+    }
+
+    /// Relay a proxied response; when it is complete (or broken) close the client and
+    /// return a cleanly finished upstream connection to the pool. A refused connect moves
+    /// on to the next upstream; with none left the client is answered 502 (504 past the
+    /// request's deadline). `upstream_ready` when the event was for the upstream socket.
+    fn pump_exchange(&mut self, key: usize, upstream_ready: bool) -> io::Result<()> {
+        let conn = match self.conns.get_mut(key) { Some(c) => c, None => return Ok(()) };
+        let x = match conn.proxied.as_mut() { Some(x) => x, None => return Ok(()) };
+        let reusable = match x.pump(&conn.stream, upstream_ready) {
+            Ok(proxy::Progress::Open) => {
+                x.sync(&mut self.ev, &conn.stream, key)?;
+                self.conns.touch(key, Instant::now());
+                return Ok(());
+            }
+            Ok(proxy::Progress::Done { reusable }) => reusable,
+            Ok(proxy::Progress::Refused) => {
+                let dns = self.dns.as_ref().expect("proxied requests imply a resolver");
+                match x.reconnect(&mut self.ev, dns, &mut self.pool) {
+                    Ok(()) => {
+                        x.sync(&mut self.ev, &conn.stream, key)?;
+                        if let Some(at) = x.connect_by() { self.connects.push((at, key)); }
+                        return Ok(());
+                    }
+                    Err(kind) => {
+                        metrics::inc_errors();
+                        if kind == ErrorKind::UpstreamTimeout { metrics::inc_request_timeouts(); }
+                        log_error!("[PROXY] {}: no upstream accepted the connection", conn.peer);
+                        let _ = respond_error(&mut conn.stream, "HTTP/1.1", kind, &self.cfg);
+                        false
+                    }
+                }
+            }
+            Err(e) => {
+                log_error!("[PROXY] {}: {}", conn.peer, e);
+                false
+            }
+        };
+        if let Some(c) = self.conns.remove(key) {
+            let _ = self.ev.deregister(key);
+            if let Some(x) = c.proxied {
+                self.upstreams.remove(&x.token);
+                x.finish(&mut self.ev, &mut self.pool, reusable);
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait up to `timeout_ms` (never past the next sweep tick) for readiness, serve
+    /// whatever is ready, then run the TLS-handshake and proxy deadlines and, when the
+    /// tick is due, the idle sweep. A failed poll is logged and retried on the next
+    /// step; errors are returned only after `MAX_POLL_FAILURES` in a row.
+    pub fn step(&mut self, timeout_ms: isize) -> io::Result<()> {
+        self.cfg = self.config.load();
+        metrics::set_open_fds(self.open_fds());
+        metrics::set_connections(self.conns.len() as u64, self.conn_capacity() as u64);
+
+        // Queued requests whose listener has slots again; don't sleep on them.
+        let mut resumed = Vec::new();
+        for l in &mut self.listeners {
+            let free = l.free().min(l.waiting.len() as u64);
+            resumed.extend(l.waiting.drain(..free as usize));
+        }
+        resumed.retain(|&k| self.conns.get_mut(k).map_or(false, |c| std::mem::replace(&mut c.queued, false)));
+        let until_sweep = self.next_sweep.saturating_duration_since(Instant::now());
+        // Round up so the wait does not end just short of the tick.
+        let until_sweep = until_sweep.as_millis() as isize + (until_sweep.subsec_nanos() % 1_000_000 != 0) as isize;
+        let wait = if !resumed.is_empty() { 0 } else if timeout_ms < 0 { until_sweep } else { timeout_ms.min(until_sweep) };
+        let mut events = match self.ev.poll(wait) {
+            Ok(events) => { self.poll_failures = 0; events }
+            Err(e) => {
+                self.poll_failures += 1;
+                if self.poll_failures >= MAX_POLL_FAILURES { return Err(e); }
+                log_error!("[POLL] {} ({} in a row); retrying", e, self.poll_failures);
+                std::thread::sleep(POLL_RETRY_DELAY);
+                Vec::new()
+            }
+        };
+        events.extend(resumed.into_iter().map(|k| (k, true, false)));
+        // Finish responses whose file reads completed on the I/O pool.
+        if let Some(pool) = self.io_pool.as_mut() {
+            for (tok, job, outcome) in pool.completions() {
+                let conn = match self.conns.get_mut(tok) { Some(c) => c, None => continue }; // closed meanwhile
+                conn.awaiting_io = false;
+                if let Err(e) = finish_file(&mut conn.stream, job, outcome, &mut conn.pending) {
+                    log_error!("[WRITE ERROR] {}: {}", conn.peer, e);
+                    let _ = self.ev.deregister(tok);
+                    self.conns.remove(tok);
+                    continue;
+                }
+                if conn.pending.is_some() {
+                    rearm(&mut self.ev, conn, tok, Interest::Writable)?;
+                } else if conn.close_after_send || (conn.read_closed && conn.buf.is_empty()) {
+                    self.ev.deregister(tok)?;
+                    self.conns.remove(tok);
+                    continue;
+                } else {
+                    conn.slot = None;
+                    // Pipelined requests queued meanwhile.
+                    if !conn.buf.is_empty() { events.push((tok, true, false)); }
+                }
+                self.conns.touch(tok, Instant::now());
+            }
+        }
+        for (token, readable, writable) in events {
+            let tunnel_key = match self.upstreams.get(&token) {
+                Some(&key) => Some(key),
+                None => self.conns.get_mut(token).filter(|c| c.tunnel.is_some() || c.proxied.is_some()).map(|_| token),
+            };
+            if let Some(key) = tunnel_key {
+                if self.conns.get_mut(key).map_or(false, |c| c.proxied.is_some()) {
+                    self.pump_exchange(key, self.upstreams.contains_key(&token))?;
+                } else {
+                    self.pump_tunnel(key, self.upstreams.contains_key(&token))?;
+                }
+                continue;
+            }
+            let mut readable = readable;
+            if writable {
+                if let Some(conn) = self.conns.get_mut(token) {
+                    if let Some(p) = conn.pending.as_mut() {
+                        match p.resume(&conn.stream) {
+                            Ok(false) => continue, // still full; wait for the next writable event
+                            Ok(true) => {
+                                conn.pending = None;
+                                conn.slot = None;
+                                if conn.close_after_send || (conn.read_closed && conn.buf.is_empty()) {
+                                    self.ev.deregister(token)?;
+                                    self.conns.remove(token);
+                                    continue;
+                                }
+                                self.ev.reregister(token, Interest::Readable)?;
+                                // Pipelined requests that arrived meanwhile are already buffered.
+                                readable |= !conn.buf.is_empty();
+                                self.conns.touch(token, Instant::now());
+                            }
+                            Err(e) => {
+                                log_error!("[SEND ERROR] {}", e);
+                                self.ev.deregister(token)?;
+                                self.conns.remove(token);
+                                continue;
+                            }
+                        }
+                    }
+                }
+            }
+            if readable {
+                if let Some(conn) = self.conns.get_mut(token) {
+                    // After a half-close only the buffered requests are left to serve.
+                    let read = if conn.read_closed { Err(io::ErrorKind::WouldBlock.into()) } else { read_into(&mut conn.stream, &mut conn.buf, self.cfg.read_buffer_size) };
+                    match read {
+                        Ok(0) => {
+                            // EOF: the client closed, or with `half_close` only shut down its
+                            // write side and may still be reading responses to what it sent.
+                            let in_flight = conn.pending.is_some() || conn.awaiting_io || conn.queued;
+                            if !self.cfg.half_close || (conn.buf.is_empty() && !in_flight) {
+                                self.ev.deregister(token)?;
+                                self.conns.remove(token);
+                                continue;
+                            }
+                            conn.read_closed = true;
+                        }
+                        Ok(_) => {}
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                        Err(e) => {
+                            log_error!("[READ ERROR] {}", e);
+                            self.ev.deregister(token)?;
+                            self.conns.remove(token);
+                            continue;
+                        }
+                    }
+
+                    if !selenia_core::ratelimit::allow(&conn.peer) {
+                        // 429 Too Many Requests
+                        let _ = conn.stream.write_all(b"HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+                        self.ev.deregister(token)?; self.conns.remove(token); continue;
+                    }
+
+                    // One file response in flight at a time, or a request queued for a listener
+                    // slot; keep buffering until it is done.
+                    if conn.parked && !conn.awaiting_io && !conn.queued {
+                        // Resumed after a half-close: proxy and tunnel hand-offs expect it registered.
+                        rearm(&mut self.ev, conn, token, Interest::Readable)?;
+                    }
+                    if conn.awaiting_io || conn.queued {
+                        if conn.read_closed && !conn.parked {
+                            self.ev.deregister(token)?;
+                            conn.parked = true;
+                            continue;
+                        }
+                        if conn.buf.len() > self.cfg.max_conn_buffer {
+                            // Mid-response, so there is no room for a 400; just drop the flood.
+                            self.ev.deregister(token)?;
+                            self.conns.remove(token);
+                            continue;
+                        }
+                        self.conns.touch(token, Instant::now());
+                        continue;
+                    }
+
+                    let tls = match conn.mode {
+                        ListenMode::Tls => true,
+                        ListenMode::Plain => false,
+                        // First byte 0x16 is a handshake record.
+                        ListenMode::Sniff => conn.buf.first() == Some(&0x16),
+                    };
+                    if tls && !conn.tls_started && !conn.buf.is_empty() {
+                        if self.handshakes.load(Ordering::Relaxed) >= self.max_handshakes {
+                            // Refuse outright: queued handshakes would only pile up more crypto work.
+                            metrics::inc_tls_handshakes_rejected();
+                            let _ = conn.stream.write_all(&tls13::Alert::fatal(tls13::AlertDescription::InternalError).encode());
+                            self.ev.deregister(token)?;
+                            self.conns.remove(token);
+                            continue;
+                        }
+                        self.handshakes.fetch_add(1, Ordering::Relaxed);
+                        metrics::inc_tls_handshakes_active();
+                        conn.handshake = Some(Handshake(Arc::clone(&self.handshakes)));
+                        // The handshake deadline runs from the first byte, not from a complete record.
+                        conn.tls_started = true;
+                        self.tls_deadlines.push_back((Instant::now() + self.tls_timeout, token));
+                    }
+                    if tls && conn.buf.first() != Some(&0x16) {
+                        // TLS-only listener: anything but a handshake record is refused, never parsed as HTTP.
+                        let _ = conn.stream.write_all(&tls13::TlsError::UnexpectedMessage.alert_record());
+                        self.ev.deregister(token)?;
+                        self.conns.remove(token);
+                        continue;
+                    }
+
+                    if tls && conn.buf.len()>=5 {
+                        let rec_len = u16::from_be_bytes([conn.buf[3],conn.buf[4]]) as usize;
+                        if conn.buf.len() >= 5+rec_len {
+                            let handshake = &conn.buf[5..5+rec_len];
+                            match tls13::process_client_hello(handshake) {
+                                Ok((resp, mut state)) => {
+                                    // Keys exist from here on, so end with a protected close_notify
+                                    // rather than a bare FIN the client would read as truncation.
+                                    let _ = conn.stream.write_all(&resp);
+                                    let _ = conn.stream.write_all(&tls13::Alert::close_notify().encode_encrypted(&mut state));
+                                    let _ = conn.stream.shutdown(std::net::Shutdown::Write);
+                                }
+                                // Tell the peer why (e.g. handshake_failure for a disallowed suite) instead of dropping silently.
+                                Err(e) => { let _ = conn.stream.write_all(&e.alert_record()); }
+                            }
+                            self.ev.deregister(token)?;
+                            self.conns.remove(token);
+                            continue;
+                        }
+                    }
+                    if tls {
+                        if conn.read_closed {
+                            // The rest of the record can no longer arrive; free the handshake now.
+                            self.ev.deregister(token)?;
+                            self.conns.remove(token);
+                            continue;
+                        }
+                        // Record still incomplete; the handshake deadline bounds the wait.
+                        self.conns.touch(token, Instant::now());
+                        continue;
+                    }
+
+                    // HTTP/2 prior knowledge (PRI * HTTP/2.0...) detection
+                    if http2::is_preface(&conn.buf) {
+                        let _ = http2::send_preface_response(&mut conn.stream, &conn.buf, false, &self.cfg.http2_flood);
+                        self.ev.deregister(token)?;
+                        self.conns.remove(token);
+                        continue;
+                    }
+
+                    // Set when the socket left the event loop (e.g. WebSocket hand-off).
+                    let mut handed_off = false;
+                    // Set when this connection must be closed; errors never escape the loop.
+                    let mut close = false;
+                    loop {
+                        match conn.parser.advance(&conn.buf) {
+                            Ok(Some((req, consumed))) => {
+                                // Dropped once the request is answered, unless the response stays in flight.
+                                let slot = match self.listeners.get(conn.listener) {
+                                    None => None,
+                                    Some(l) => match l.acquire() {
+                                        Some(slot) => Some(slot),
+                                        None if (l.waiting.len() as u64) < l.max => {
+                                            // Parsed again when a slot frees up.
+                                            conn.queued = true;
+                                            self.listeners[conn.listener].waiting.push_back(token);
+                                            break;
+                                        }
+                                        None => {
+                                            let _ = conn.stream.write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nRetry-After: 1\r\nConnection: close\r\n\r\n");
+                                            metrics::inc_errors();
+                                            close = true;
+                                            break;
+                                        }
+                                    },
+                                };
+                                if req.method == "CONNECT" {
+                                    if let Some(cp) = &self.cfg.connect_proxy {
+                                        metrics::inc_requests();
+                                        // No resolver when `connect_proxy` was off at startup.
+                                        let opened = self.dns.as_ref().ok_or(ErrorKind::BadGateway).and_then(|dns| tunnel::open(req.path, &conn.peer, cp, dns));
+                                        match opened {
+                                            Ok(Some((upstream, addr))) => {
+                                                // The 200 (or the error) follows once the connect settles.
+                                                log_info!("{} - \"CONNECT {}\" -> {}", conn.peer, req.path, addr);
+                                                conn.buf.drain(0..consumed);
+                                                let up_token = self.ev.register(&upstream, Interest::Readable)?;
+                                                let timeout = Duration::from_millis(cp.connect_timeout_ms);
+                                                let mut t = tunnel::Tunnel::new(upstream, up_token, std::mem::take(&mut conn.buf), timeout);
+                                                t.sync(&mut self.ev, &conn.stream, token)?;
+                                                self.upstreams.insert(up_token, token);
+                                                if let Some(at) = t.connect_by() { self.connects.push((at, token)); }
+                                                conn.tunnel = Some(t);
+                                            }
+                                            Ok(None) => {
+                                                // The target's name is still being resolved; the client may retry.
+                                                log_info!("{} - \"CONNECT {}\" 503 0", conn.peer, req.path);
+                                                let _ = conn.stream.write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nRetry-After: 1\r\nConnection: close\r\n\r\n");
+                                                metrics::inc_errors();
+                                                close = true;
+                                            }
+                                            Err(kind) => {
+                                                log_info!("{} - \"CONNECT {}\" {} 0", conn.peer, req.path, kind.status_code());
+                                                metrics::inc_errors();
+                                                let _ = respond_error(&mut conn.stream, req.version, kind, &self.cfg);
+                                                close = true;
+                                            }
+                                        }
+                                        break;
+                                    }
+                                }
+                                let route = proxy::route(&self.routes, req.path).zip(self.dns.as_ref());
+                                let upgrade = if route.is_none() { upgrade::detect(&req) } else { None };
+                                let close_after = should_close(&req);
+
+                                let keep_alive = !close_after;
+                                // The request's own snapshot: a reload cannot change it mid-response.
+                                let cfg = Arc::clone(&self.cfg);
+                                let routed = match handle_request(
+                                    &mut conn.stream,
+                                    req.version,
+                                    req.method,
+                                    req.path,
+                                    &req.headers,
+                                    &req.body,
+                                    &cfg,
+                                    &cfg.locale,
+                                    keep_alive,
+                                    &conn.peer,
+                                    route.is_some() || upgrade.is_some(),
+                                ) {
+                                    Ok(routed) => routed,
+                                    Err(e) => {
+                                        // Typically the peer went away mid-response; only this connection is affected.
+                                        log_error!("[WRITE ERROR] {}: {}", conn.peer, e);
+                                        close = true;
+                                        break;
+                                    }
+                                };
+                                let file_job = match routed {
+                                    Routed::Done => None,
+                                    Routed::File(job) => Some(job),
+                                    Routed::Pass => {
+                                        if let Some((group, dns)) = route {
+                                            metrics::inc_requests();
+                                            let deadline = Deadline::after_ms(self.cfg.request_timeout_ms);
+                                            match proxy::forward(&req, group, &conn.peer, &self.cfg, dns, &mut self.pool, deadline) {
+                                                Ok(target) => {
+                                                    log_info!("{} - \"{} {}\" -> {}", conn.peer, req.method, req.path, group.backend(target.backend).addr);
+                                                    let head_request = req.method == "HEAD";
+                                                    // Anything pipelined behind this request is dropped with the connection.
+                                                    conn.buf.clear();
+                                                    let up_token = self.ev.register(&target.upstream, Interest::Readable)?;
+                                                    let mut x = proxy::Exchange::new(target, up_token, group, head_request, deadline);
+                                                    x.sync(&mut self.ev, &conn.stream, token)?;
+                                                    self.upstreams.insert(up_token, token);
+                                                    if let Some(at) = deadline.at() { self.proxy_deadlines.push_back((at, token)); }
+                                                    if let Some(at) = x.connect_by() { self.connects.push((at, token)); }
+                                                    conn.proxied = Some(x);
+                                                    conn.slot = slot;
+                                                }
+                                                Err(kind) => {
+                                                    metrics::inc_errors();
+                                                    if kind == ErrorKind::UpstreamTimeout { metrics::inc_request_timeouts(); }
+                                                    log_info!("{} - \"{} {}\" {} 0", conn.peer, req.method, req.path, kind.status_code());
+                                                    let _ = respond_error(&mut conn.stream, req.version, kind, &self.cfg);
+                                                    close = true;
+                                                }
+                                            }
+                                        } else if let Some(upgrade::Upgrade::WebSocket { accept, path }) = upgrade {
+                                            self.ev.deregister(token)?;
+                                            match conn.stream.try_clone() {
+                                                Ok(stream) => if let Err(e) = upgrade::accept_websocket(stream, &accept, path) {
+                                                    log_error!("[WS] upgrade failed: {}", e);
+                                                },
+                                                Err(e) => log_error!("[WS] upgrade failed: {}", e),
+                                            }
+                                            handed_off = true;
+                                        }
+                                        break;
+                                    }
+                                };
+                                self.req_count += 1;
+                                if self.req_count > 1 { keepalive::record_reuse_req(); }
+                                // remove consumed bytes (Parser consumed data)
+                                conn.buf.drain(0..consumed);
+
+                                if let Some(job) = file_job {
+                                    match self.io_pool.as_mut() {
+                                        Some(pool) if !pool.prefers_inline(&job.fs_path) => {
+                                            // The response resumes in the completion handler above.
+                                            pool.submit(token, job);
+                                            conn.awaiting_io = true;
+                                            conn.slot = slot;
+                                            conn.close_after_send = close_after;
+                                            break;
+                                        }
+                                        pool => {
+                                            let outcome = blockio::load_file(&job.fs_path, &job.if_none_match, job.range.as_deref(), job.stream_threshold, job.mmap_threshold, job.deadline);
+                                            if let Some(pool) = pool { pool.note_size(&job.fs_path, &outcome); }
+                                            if let Err(e) = finish_file(&mut conn.stream, job, outcome, &mut conn.pending) {
+                                                log_error!("[WRITE ERROR] {}: {}", conn.peer, e);
+                                                close = true;
+                                                break;
+                                            }
+                                        }
+                                    }
+                                }
+
+                                if conn.pending.is_some() {
+                                    // Socket buffer full: resume on EPOLLOUT, keep later requests queued.
+                                    conn.close_after_send = close_after;
+                                    conn.slot = slot;
+                                    rearm(&mut self.ev, conn, token, Interest::Writable)?;
+                                    break;
+                                }
+                                if close_after {
+                                    close = true;
+                                    break;
+                                } else if conn.buf.is_empty() {
+                                    // Keep connection open for next requests
+                                    break;
+                                }
+                            }
+                            Ok(None) => {
+                                // Need more data – unless the client has already sent more
+                                // than any request we accept may take.
+                                if conn.buf.len() > self.cfg.max_conn_buffer {
+                                    let _ = respond_error(&mut conn.stream, "HTTP/1.1", ErrorKind::PayloadTooLarge, &self.cfg);
+                                    close = true;
+                                }
+                                break;
+                            }
+                            Err(e) => {
+                                let kind = e.to_error_kind();
+                                let _ = respond_error(&mut conn.stream, "HTTP/1.1", kind, &self.cfg);
+                                close = true;
+                                break;
+                            }
+                        }
+                    }
+                    if conn.read_closed && !handed_off && !close {
+                        if conn.drained() {
+                            // Anything left in `buf` is a request that can no longer complete.
+                            close = true;
+                        } else if (conn.awaiting_io || conn.queued) && !conn.parked {
+                            self.ev.deregister(token)?;
+                            conn.parked = true;
+                        }
+                    }
+                    if handed_off {
+                        self.conns.remove(token);
+                    } else if close {
+                        // Dropping the stream closes the socket, so the client sees EOF right away.
+                        let _ = self.ev.deregister(token);
+                        self.conns.remove(token);
+                    } else {
+                        self.conns.touch(token, Instant::now());
+                    }
+                }
+            }
+        }
+        let now = Instant::now();
+        if now >= self.next_sweep {
+            self.sweep(now);
+            // Fixed cadence; after a long stall the next tick is one interval out, not a burst.
+            self.next_sweep += SWEEP_INTERVAL;
+            if self.next_sweep <= now { self.next_sweep = now + SWEEP_INTERVAL; }
+        }
+        // Stalled TLS handshakes; finished or closed connections miss the lookup.
+        while let Some(&(deadline, tok)) = self.tls_deadlines.front() {
+            if deadline > now { break; }
+            self.tls_deadlines.pop_front();
+            if self.conns.get_mut(tok).map_or(false, |c| c.tls_started) {
+                if let Some(c) = self.conns.remove(tok) {
+                    let _ = self.ev.deregister(tok);
+                    let _ = c.stream.shutdown(std::net::Shutdown::Both);
+                    metrics::inc_tls_handshake_timeouts();
+                }
+            }
+        }
+        // Upstream connects past their `connect_timeout_ms`. Exchanges and tunnels check
+        // their own expiry, so entries left by a finished or reused key do nothing.
+        let due: Vec<usize> = self.connects.iter().filter(|&&(at, _)| at <= now).map(|&(_, key)| key).collect();
+        self.connects.retain(|&(at, _)| at > now);
+        for key in due {
+            if self.conns.get_mut(key).map_or(false, |c| c.proxied.is_some()) {
+                self.pump_exchange(key, false)?;
+            } else {
+                self.pump_tunnel(key, false)?;
+            }
+        }
+        // Proxied requests past `request_timeout_ms`: 504 unless the response already began.
+        // A key reused by a later exchange carries a later deadline and is left alone.
+        while let Some(&(deadline, tok)) = self.proxy_deadlines.front() {
+            if deadline > now { break; }
+            self.proxy_deadlines.pop_front();
+            if !self.conns.get_mut(tok).and_then(|c| c.proxied.as_ref()).map_or(false, |x| x.deadline.at() == Some(deadline)) {
+                continue;
+            }
+            if let Some(mut c) = self.conns.remove(tok) {
+                let _ = self.ev.deregister(tok);
+                if let Some(x) = c.proxied.take() {
+                    if x.unanswered() {
+                        metrics::inc_errors();
+                        metrics::inc_request_timeouts();
+                        let _ = respond_error(&mut c.stream, "HTTP/1.1", ErrorKind::UpstreamTimeout, &self.cfg);
+                    }
+                    log_error!("[PROXY] {}: request_timeout_ms exceeded", c.peer);
+                    self.upstreams.remove(&x.token);
+                    x.finish(&mut self.ev, &mut self.pool, false);
+                }
+                let _ = c.stream.shutdown(std::net::Shutdown::Both);
+            }
+        }
+        Ok(())
+    }
+
+    /// Idle expiry, pooled-upstream eviction and idle-timeout auto-tuning; runs once
+    /// per `SWEEP_INTERVAL` tick.
+    fn sweep(&mut self, now: Instant) {
+        while let Some((tok, c)) = self.conns.pop_expired(now, self.idle_timeout) {
+            let _ = self.ev.deregister(tok);
+            let _ = c.stream.shutdown(std::net::Shutdown::Both);
+            if let Some(t) = c.tunnel {
+                let _ = self.ev.deregister(t.token);
+                self.upstreams.remove(&t.token);
+            }
+            if let Some(x) = c.proxied {
+                self.upstreams.remove(&x.token);
+                x.finish(&mut self.ev, &mut self.pool, false);
+            }
+        }
+        self.pool.evict_idle(now);
+
+        // Auto-tune idle timeout every 1000 requests or 30 s, whichever comes first
+        if self.req_count >= 1000 || self.last_adjust.elapsed() > Duration::from_secs(30) {
+            self.idle_timeout = tune_idle_timeout(self.idle_timeout, self.conns.len(), self.conn_capacity(), &self.cfg.idle_tune);
+            self.req_count = 0;
+            self.last_adjust = now;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+
+    /// Held by tests that load the process-wide request gates.
+    static GATES: Mutex<()> = Mutex::new(());
+
+    /// Held by tests that point the process-wide logger at their own file.
+    static LOG_FILE: Mutex<()> = Mutex::new(());
+
+    /// A configuration from extra `server:` lines (two-space indented), via a scratch file.
+    fn config(extra: &str) -> ServerConfig {
+        static SEQ: AtomicU64 = AtomicU64::new(0);
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("sws-runner-{}-{}.yaml", std::process::id(), SEQ.fetch_add(1, Ordering::Relaxed)));
+        let yaml = format!("server:\n  listen:\n    - \"127.0.0.1:8080\"\n  root_dir: \"{}\"\n  locale: \"en\"\n{}", dir.display(), extra);
+        std::fs::write(&path, yaml).unwrap();
+        let cfg = ServerConfig::load_from_yaml(&path);
+        let _ = std::fs::remove_file(&path);
+        // Every test client is 127.0.0.1, so one shared bucket would throttle the whole suite.
+        selenia_core::ratelimit::configure(0, 0);
+        cfg.unwrap()
+    }
+
+    /// A connected pair: the client end, the non-blocking server end and its peer address.
+    fn pair() -> (TcpStream, TcpStream, SocketAddr) {
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(l.local_addr().unwrap()).unwrap();
+        let (server, peer) = l.accept().unwrap();
+        server.set_nonblocking(true).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        (client, server, peer)
+    }
+
+    /// Everything the server sends until it closes the connection.
+    fn read_to_close(client: &mut TcpStream) -> Vec<u8> {
+        let mut out = Vec::new();
+        let _ = client.read_to_end(&mut out);
+        out
+    }
+
+    /// Current value of an unlabelled counter in the Prometheus exposition.
+    fn counter(name: &str) -> u64 {
+        let text = selenia_core::metrics::render();
+        let line = text.lines().find(|l| l.split(' ').next() == Some(name)).unwrap();
+        line.rsplit(' ').next().unwrap().parse().unwrap()
+    }
+
+    /// Inject a fresh connection, send `request` and step until a response head is back.
+    fn exchange(runner: &mut EventLoopRunner, request: &str) -> String {
+        let (mut client, server, peer) = pair();
+        runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+        roundtrip(runner, &mut client, request)
+    }
+
+    /// Send `request` on an injected connection and step until a response head is back.
+    fn roundtrip(runner: &mut EventLoopRunner, client: &mut TcpStream, request: &str) -> String {
+        client.write_all(request.as_bytes()).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        let mut out = Vec::new();
+        for _ in 0..100 {
+            runner.step(10).unwrap();
+            let mut tmp = [0u8; 4096];
+            match client.read(&mut tmp) {
+                Ok(0) => break,
+                Ok(n) => out.extend_from_slice(&tmp[..n]),
+                Err(_) => {}
+            }
+            if out.windows(4).any(|w| w == b"\r\n\r\n") { break; }
+        }
+        String::from_utf8_lossy(&out).into_owned()
+    }
+
+    #[test]
+    fn connections_beyond_max_connections_get_503() {
+        let mut runner = EventLoopRunner::new(config("  max_connections: 2\n"), 100).unwrap();
+        let mut held = Vec::new();
+        for _ in 0..2 {
+            let (client, server, peer) = pair();
+            runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+            held.push(client);
+        }
+        let (mut client, server, peer) = pair();
+        runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+        assert_eq!(runner.connections(), 2);
+        assert!(read_to_close(&mut client).starts_with(b"HTTP/1.1 503 "));
+    }
+
+    #[test]
+    fn without_max_connections_the_fd_budget_caps() {
+        let mut runner = EventLoopRunner::new(config(""), 3).unwrap();
+        let mut held = Vec::new();
+        for _ in 0..4 {
+            let (client, server, peer) = pair();
+            runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+            held.push(client);
+        }
+        assert_eq!(runner.connections(), 3);
+        assert!(read_to_close(&mut held[3]).starts_with(b"HTTP/1.1 503 "));
+    }
+
+    #[test]
+    fn percent_encoded_paths_are_decoded_before_mapping() {
+        let name = format!("sws-runner-{} page.txt", std::process::id());
+        let file = std::env::temp_dir().join(&name);
+        std::fs::write(&file, "spaced").unwrap();
+        let mut runner = EventLoopRunner::new(config(""), 16).unwrap();
+        let found = exchange(&mut runner, &format!("GET /{} HTTP/1.1\r\nHost: a\r\n\r\n", name.replace(' ', "%20")));
+        let _ = std::fs::remove_file(&file);
+        assert!(found.starts_with("HTTP/1.1 200 "), "{}", found);
+        assert!(exchange(&mut runner, "GET /%2e%2e/%2E%2E/etc/passwd HTTP/1.1\r\nHost: a\r\n\r\n").starts_with("HTTP/1.1 403 "));
+        assert!(exchange(&mut runner, "GET /%zz HTTP/1.1\r\nHost: a\r\n\r\n").starts_with("HTTP/1.1 400 "));
+    }
+
+    #[test]
+    fn host_header_is_required_and_matched_to_vhosts() {
+        let root = std::env::temp_dir().join(format!("sws-runner-vhost-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("only-here.txt"), "vhost").unwrap();
+        let vhosts = format!("  virtual_hosts:\n    - domain: Site.Test\n      root: \"{}\"\n", root.display());
+        let mut strict = EventLoopRunner::new(config(&format!("{}  strict_host: true\n", vhosts)), 16).unwrap();
+        let mut lenient = EventLoopRunner::new(config(&vhosts), 16).unwrap();
+        let get = |host: &str| format!("GET /only-here.txt HTTP/1.1\r\n{}\r\n", host);
+        assert!(exchange(&mut strict, &get("")).starts_with("HTTP/1.1 400 "));
+        assert!(exchange(&mut strict, &get("Host: bad host\r\n")).starts_with("HTTP/1.1 400 "));
+        assert!(exchange(&mut strict, &get("Host: other.test\r\n")).starts_with("HTTP/1.1 421 "));
+        assert!(exchange(&mut strict, &get("Host: site.test:8080\r\n")).starts_with("HTTP/1.1 200 "));
+        // Without strict_host an unknown name falls back to the default root.
+        assert!(exchange(&mut lenient, &get("Host: other.test\r\n")).starts_with("HTTP/1.1 404 "));
+        assert!(exchange(&mut lenient, &get("Host: SITE.test\r\n")).starts_with("HTTP/1.1 200 "));
+        // HTTP/1.0 may omit Host.
+        assert!(exchange(&mut lenient, "GET /only-here.txt HTTP/1.0\r\n\r\n").starts_with("HTTP/1.0 404 "));
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn access_rules_refuse_denied_peers() {
+        use selenia_core::config::AccessRule;
+        let _gates = GATES.lock().unwrap_or_else(|e| e.into_inner());
+        let rule = |prefix: &str, allow: &[&str], deny: &[&str]| AccessRule {
+            prefix: prefix.into(),
+            allow: allow.iter().map(|s| s.to_string()).collect(),
+            deny: deny.iter().map(|s| s.to_string()).collect(),
+        };
+        crate::acl::init(&[
+            rule("/", &[], &[]),
+            rule("/private/", &["10.0.0.0/8", "fd00::/8"], &[]),
+            rule("/private/open/", &["0.0.0.0/0"], &[]),
+            rule("/blocked/", &[], &["127.0.0.0/8"]),
+        ]);
+        let mut runner = EventLoopRunner::new(config(""), 16).unwrap();
+        let get = |path: &str| format!("GET {} HTTP/1.1\r\nHost: a\r\n\r\n", path);
+        assert!(exchange(&mut runner, &get("/private/x")).starts_with("HTTP/1.1 403 "));
+        assert!(exchange(&mut runner, &get("/blocked/x")).starts_with("HTTP/1.1 403 "));
+        // The longest matching prefix decides.
+        assert!(exchange(&mut runner, &get("/private/open/x")).starts_with("HTTP/1.1 404 "));
+        assert!(exchange(&mut runner, &get("/public/x")).starts_with("HTTP/1.1 404 "));
+        crate::acl::init(&[]);
+    }
+
+    #[test]
+    fn h2c_upgrade_is_served_as_http1() {
+        let mut runner = EventLoopRunner::new(config(""), 16).unwrap();
+        let head = exchange(&mut runner, "GET /no-such-file HTTP/1.1\r\nHost: a\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAAP__\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 404 "), "{}", head);
+    }
+
+    #[test]
+    fn websocket_upgrade_runs_the_gates_first() {
+        use selenia_core::config::AccessRule;
+        let _gates = GATES.lock().unwrap_or_else(|e| e.into_inner());
+        crate::acl::init(&[AccessRule { prefix: "/ws-denied/".into(), allow: Vec::new(), deny: vec!["127.0.0.0/8".into()] }]);
+        upgrade::set_websocket_handler(|_, _| {});
+        let mut runner = EventLoopRunner::new(config(""), 16).unwrap();
+        let upgrade = "HTTP/1.1\r\nHost: a\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        assert!(exchange(&mut runner, &format!("GET /ws-denied/chat {}", upgrade)).starts_with("HTTP/1.1 403 "));
+        assert!(exchange(&mut runner, &format!("GET /chat {}", upgrade.replace("Host: a\r\n", ""))).starts_with("HTTP/1.1 400 "));
+        let head = exchange(&mut runner, &format!("GET /chat {}", upgrade));
+        assert!(head.starts_with("HTTP/1.1 101 "), "{}", head);
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    }
+
+    #[test]
+    fn reload_loads_the_gates_and_refuses_fixed_settings() {
+        let _gates = GATES.lock().unwrap_or_else(|e| e.into_inner());
+        let mut runner = EventLoopRunner::new(config(""), 16).unwrap();
+        assert!(exchange(&mut runner, "GET /ops-status HTTP/1.1\r\nHost: a\r\n\r\n").starts_with("HTTP/1.1 404 "));
+        // A new operational path is guarded at once, not left public with an empty gate.
+        assert_eq!(runner.reload_config(config("  metrics_access:\n    paths: [/ops-status]\n    allow: [10.0.0.0/8]\n")), Ok(1));
+        assert!(exchange(&mut runner, "GET /ops-status HTTP/1.1\r\nHost: a\r\n\r\n").starts_with("HTTP/1.1 403 "));
+        let routed = config("  proxy_pass:\n    - prefix: /api/\n      upstream: \"127.0.0.1:9\"\n");
+        assert_eq!(runner.reload_config(routed).unwrap_err(), "proxy_pass changed; a master reload is needed to apply it");
+        assert!(runner.reload_config(config("  io_threads: 2\n")).is_err());
+        assert_eq!(runner.reload_config(config("")), Ok(2));
+        assert!(exchange(&mut runner, "GET /ops-status HTTP/1.1\r\nHost: a\r\n\r\n").starts_with("HTTP/1.1 404 "));
+    }
+
+    #[test]
+    fn refused_upstream_connect_moves_to_the_next() {
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let live = TcpListener::bind("127.0.0.1:0").unwrap();
+        let live_addr = live.local_addr().unwrap();
+        let backend = std::thread::spawn(move || {
+            let (mut s, _) = live.accept().unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut b = [0u8; 1];
+                if s.read(&mut b).unwrap() == 0 { break; }
+                head.push(b[0]);
+            }
+            s.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").unwrap();
+            String::from_utf8(head).unwrap()
+        });
+        let routes = format!(
+            "  proxy_pass:\n    - prefix: /api/\n      upstreams:\n        - \"{}\"\n        - \"{}\"\n    - prefix: /gone/\n      upstream: \"{}\"\n",
+            closed, live_addr, closed,
+        );
+        let mut runner = EventLoopRunner::new(config(&routes), 16).unwrap();
+        let head = exchange(&mut runner, "GET /api/x HTTP/1.0\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 200 "), "{}", head);
+        // The Host added for a request without one follows the request to the next upstream.
+        assert!(backend.join().unwrap().contains(&format!("\r\nHost: {}\r\n", live_addr)));
+        assert!(exchange(&mut runner, "GET /gone/x HTTP/1.1\r\nHost: a\r\n\r\n").starts_with("HTTP/1.1 502 "));
+    }
+
+    #[test]
+    fn connect_tunnel_answers_once_the_upstream_connect_settles() {
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let live = TcpListener::bind("127.0.0.1:0").unwrap();
+        let live_addr = live.local_addr().unwrap();
+        let backend = std::thread::spawn(move || {
+            let (mut s, _) = live.accept().unwrap();
+            let mut early = [0u8; 4];
+            s.read_exact(&mut early).unwrap();
+            early
+        });
+        let mut runner = EventLoopRunner::new(config("  connect_proxy:\n    destinations: [\"127.0.0.1:*\"]\n"), 16).unwrap();
+        let refused = exchange(&mut runner, &format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", closed));
+        assert!(refused.starts_with("HTTP/1.1 502 "), "{}", refused);
+        // Bytes sent behind the CONNECT reach the upstream once it is connected.
+        let head = exchange(&mut runner, &format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\nping", live_addr));
+        assert_eq!(head, "HTTP/1.1 200 Connection Established\r\n\r\n");
+        assert_eq!(&backend.join().unwrap(), b"ping");
+    }
+
+    #[test]
+    fn stalled_tls_handshake_is_closed_at_the_deadline() {
+        let timeouts = || counter("sws_tls_handshake_timeouts_total");
+        let before = timeouts();
+        let mut runner = EventLoopRunner::new(config("  tls:\n    handshake_timeout_ms: 200\n"), 16).unwrap();
+        let (mut client, server, peer) = pair();
+        runner.inject(server, peer, ListenMode::Tls, 0).unwrap();
+        // A record header promising a ClientHello that never arrives.
+        client.write_all(&[0x16, 0x03, 0x01, 0x00, 0x80, 0x01]).unwrap();
+        let started = Instant::now();
+        while runner.connections() > 0 && started.elapsed() < Duration::from_secs(2) {
+            runner.step(10).unwrap();
+        }
+        let waited = started.elapsed();
+        assert_eq!(runner.connections(), 0);
+        assert!(waited >= Duration::from_millis(190) && waited < Duration::from_secs(1), "{:?}", waited);
+        assert!(read_to_close(&mut client).is_empty());
+        assert!(timeouts() > before);
+    }
+
+    #[test]
+    fn slow_disk_read_does_not_stall_other_connections() {
+        let slow = format!("{}{}.txt", blockio::SLOW_PREFIX, std::process::id());
+        let fast = format!("sws-runner-{}-fast.txt", std::process::id());
+        let dir = std::env::temp_dir();
+        std::fs::write(dir.join(&slow), "slow").unwrap();
+        std::fs::write(dir.join(&fast), "fast").unwrap();
+        let mut runner = EventLoopRunner::new(config("  io_threads: 2\n"), 16).unwrap();
+        let (mut client, server, peer) = pair();
+        runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+        client.write_all(format!("GET /{} HTTP/1.1\r\nHost: a\r\n\r\n", slow).as_bytes()).unwrap();
+        for _ in 0..3 { runner.step(10).unwrap(); }
+        let started = Instant::now();
+        let other = exchange(&mut runner, &format!("GET /{} HTTP/1.1\r\nHost: a\r\n\r\n", fast));
+        assert!(other.starts_with("HTTP/1.1 200 "), "{}", other);
+        assert!(started.elapsed() < blockio::SLOW_LOAD);
+        // The slow response still completes once its read finishes.
+        client.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        let mut out = Vec::new();
+        let mut tmp = [0u8; 4096];
+        while !out.ends_with(b"slow") && started.elapsed() < Duration::from_secs(3) {
+            runner.step(10).unwrap();
+            if let Ok(n) = client.read(&mut tmp) { out.extend_from_slice(&tmp[..n]); }
+        }
+        let _ = std::fs::remove_file(dir.join(&slow));
+        let _ = std::fs::remove_file(dir.join(&fast));
+        assert!(out.starts_with(b"HTTP/1.1 200 "));
+        assert!(out.ends_with(b"slow"));
+    }
+
+    #[test]
+    fn write_error_closes_only_that_connection() {
+        let big = format!("sws-runner-{}-big.bin", std::process::id());
+        let dir = std::env::temp_dir();
+        std::fs::write(dir.join(&big), vec![b'x'; 8 << 20]).unwrap();
+        let mut runner = EventLoopRunner::new(config(""), 16).unwrap();
+        let (mut client, server, peer) = pair();
+        runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+        client.write_all(format!("GET /{} HTTP/1.1\r\nHost: a\r\n\r\n", big).as_bytes()).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        let mut head = [0u8; 16];
+        while client.read(&mut head).is_err() { runner.step(10).unwrap(); }
+        // Closing with unread data resets the connection mid-response.
+        drop(client);
+        for _ in 0..20 { runner.step(10).unwrap(); }
+        assert_eq!(runner.connections(), 0);
+        let _ = std::fs::remove_file(dir.join(&big));
+        let other = exchange(&mut runner, "GET /no-such-file HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(other.starts_with("HTTP/1.1 404 "), "{}", other);
+    }
+
+    #[test]
+    fn request_id_is_echoed_or_generated() {
+        let mut runner = EventLoopRunner::new(config(""), 16).unwrap();
+        let head = exchange(&mut runner, "GET /no-such-file HTTP/1.1\r\nHost: a\r\nX-Request-Id: client-42\r\n\r\n");
+        assert!(head.contains("\r\nX-Request-Id: client-42\r\n"), "{}", head);
+        let head = exchange(&mut runner, "GET /no-such-file HTTP/1.1\r\nHost: a\r\nX-Request-Id: bad id\r\n\r\n");
+        let id = head.lines().find_map(|l| l.strip_prefix("X-Request-Id: ")).unwrap();
+        assert_eq!(id.len(), 36, "{}", head);
+    }
+
+    #[test]
+    fn unparseable_flood_is_closed_at_the_buffer_cap() {
+        let mut runner = EventLoopRunner::new(config("  max_conn_buffer: 4096\n"), 16).unwrap();
+        let (mut client, server, peer) = pair();
+        runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(1))).unwrap();
+        let mut reply = Vec::new();
+        let mut tmp = [0u8; 4096];
+        // No line ends, so the request head never completes. Stop once the answer is in:
+        // writing on into the closed socket would reset the connection.
+        for _ in 0..16 {
+            client.write_all(&[b'A'; 1024]).unwrap();
+            runner.step(10).unwrap();
+            if let Ok(n) = client.read(&mut tmp) {
+                reply.extend_from_slice(&tmp[..n]);
+                break;
+            }
+        }
+        assert_eq!(runner.connections(), 0);
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        reply.extend(read_to_close(&mut client));
+        assert!(reply.starts_with(b"HTTP/1.1 413 "), "{}", String::from_utf8_lossy(&reply));
+    }
+
+    #[test]
+    fn text_responses_carry_a_charset() {
+        let dir = std::env::temp_dir();
+        let name = |ext: &str| format!("sws-runner-{}-charset.{}", std::process::id(), ext);
+        std::fs::write(dir.join(name("html")), "<p>é</p>").unwrap();
+        std::fs::write(dir.join(name("txt")), "plain").unwrap();
+        std::fs::write(dir.join(name("css")), [&[0xFE, 0xFF][..], &[0, b'a']].concat()).unwrap();
+        std::fs::write(dir.join(name("png")), [0x89, b'P', b'N', b'G']).unwrap();
+        let content_type = |runner: &mut EventLoopRunner, ext: &str| {
+            let head = exchange(runner, &format!("GET /{} HTTP/1.1\r\nHost: a\r\n\r\n", name(ext)));
+            head.lines().find_map(|l| l.strip_prefix("Content-Type: ")).map(str::to_string).unwrap_or_default()
+        };
+        let mut plain = EventLoopRunner::new(config(""), 16).unwrap();
+        assert_eq!(content_type(&mut plain, "html"), "text/html; charset=utf-8");
+        assert_eq!(content_type(&mut plain, "css"), "text/css; charset=utf-8");
+        assert_eq!(content_type(&mut plain, "png"), "image/png");
+        let mut tuned = EventLoopRunner::new(config("  charset:\n    default: iso-8859-1\n    detect_bom: true\n    overrides:\n      txt: shift_jis\n"), 16).unwrap();
+        assert_eq!(content_type(&mut tuned, "html"), "text/html; charset=iso-8859-1");
+        assert_eq!(content_type(&mut tuned, "txt"), "text/plain; charset=shift_jis");
+        assert_eq!(content_type(&mut tuned, "css"), "text/css; charset=utf-16be");
+        for ext in ["html", "txt", "css", "png"] { let _ = std::fs::remove_file(dir.join(name(ext))); }
+    }
+
+    #[test]
+    fn http10_keep_alive_follows_the_request() {
+        let mut runner = EventLoopRunner::new(config(""), 16).unwrap();
+        let (mut client, server, peer) = pair();
+        runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+        for _ in 0..2 {
+            let head = roundtrip(&mut runner, &mut client, "GET /no-such-file HTTP/1.0\r\nProxy-Connection: keep-alive\r\n\r\n");
+            assert!(head.starts_with("HTTP/1.0 404 "), "{}", head);
+            assert!(head.contains("\r\nConnection: keep-alive\r\nKeep-Alive: timeout="), "{}", head);
+        }
+        assert_eq!(runner.connections(), 1);
+        let head = roundtrip(&mut runner, &mut client, "GET /no-such-file HTTP/1.0\r\n\r\n");
+        assert!(head.contains("\r\nConnection: close\r\n"), "{}", head);
+        assert!(!head.contains("Keep-Alive:"), "{}", head);
+        runner.step(10).unwrap();
+        assert_eq!(runner.connections(), 0);
+    }
+
+    #[test]
+    fn protected_metrics_need_credentials() {
+        use selenia_core::crypto::sha256::sha256_digest;
+        use selenia_core::encoding::base64;
+        let _gates = GATES.lock().unwrap_or_else(|e| e.into_inner());
+        let tokens = std::env::temp_dir().join(format!("sws-runner-{}-metrics.tokens", std::process::id()));
+        std::fs::write(&tokens, format!("{{SHA256}}{}\n", base64::encode(&sha256_digest(b"scrape-me")))).unwrap();
+        let access = |allow: &str| config(&format!("  metrics_access:\n    allow: [{}]\n    bearer_tokens: \"{}\"\n", allow, tokens.display()));
+        let scrape = |runner: &mut EventLoopRunner, auth: &str| exchange(runner, &format!("GET /metrics HTTP/1.1\r\nHost: a\r\n{}\r\n", auth));
+
+        let cfg = access("127.0.0.0/8");
+        crate::init_metrics_access(cfg.metrics_access.as_ref());
+        let mut runner = EventLoopRunner::new(cfg, 16).unwrap();
+        let head = scrape(&mut runner, "");
+        assert!(head.starts_with("HTTP/1.1 401 ") && head.contains("WWW-Authenticate: Bearer realm=\"Metrics\""), "{}", head);
+        assert!(scrape(&mut runner, "Authorization: Bearer wrong\r\n").starts_with("HTTP/1.1 401 "));
+        assert!(scrape(&mut runner, "Authorization: Bearer scrape-me\r\n").starts_with("HTTP/1.1 200 "));
+
+        // Outside the allowlist even valid credentials are refused.
+        let cfg = access("10.0.0.0/8");
+        crate::init_metrics_access(cfg.metrics_access.as_ref());
+        let mut runner = EventLoopRunner::new(cfg, 16).unwrap();
+        assert!(scrape(&mut runner, "Authorization: Bearer scrape-me\r\n").starts_with("HTTP/1.1 403 "));
+
+        crate::init_metrics_access(None);
+        let _ = std::fs::remove_file(&tokens);
+        let mut runner = EventLoopRunner::new(config(""), 16).unwrap();
+        assert!(scrape(&mut runner, "").starts_with("HTTP/1.1 200 "));
+    }
+
+    #[test]
+    fn content_coding_is_negotiated() {
+        let name = format!("sws-runner-{}-coding.txt", std::process::id());
+        let file = std::env::temp_dir().join(&name);
+        std::fs::write(&file, "compress me ".repeat(64)).unwrap();
+        let mut runner = EventLoopRunner::new(config(""), 16).unwrap();
+        let get = |accept: &str| format!("GET /{} HTTP/1.1\r\nHost: a\r\nAccept-Encoding: {}\r\n\r\n", name, accept);
+        let gzip = exchange(&mut runner, &get("identity;q=0, gzip"));
+        let plain = exchange(&mut runner, &get("gzip;q=0"));
+        let refused = exchange(&mut runner, &get("*;q=0"));
+        let _ = std::fs::remove_file(&file);
+        assert!(gzip.starts_with("HTTP/1.1 200 ") && gzip.contains("\r\nContent-Encoding: gzip\r\n"), "{}", gzip);
+        assert!(plain.starts_with("HTTP/1.1 200 ") && !plain.contains("Content-Encoding"), "{}", plain);
+        assert!(refused.starts_with("HTTP/1.1 406 "), "{}", refused);
+    }
+
+    #[test]
+    fn large_file_headers_go_out_before_the_body() {
+        let name = format!("sws-runner-{}-stream.bin", std::process::id());
+        let file = std::env::temp_dir().join(&name);
+        let data: Vec<u8> = (0..16 << 20).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&file, &data).unwrap();
+        let mut runner = EventLoopRunner::new(config("  stream_threshold: 65536\n"), 16).unwrap();
+        let (mut client, server, peer) = pair();
+        runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+        client.write_all(format!("GET /{} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n", name).as_bytes()).unwrap();
+        runner.step(10).unwrap();
+        // One loop pass: the head is out while most of the body still waits on the socket.
+        let mut got = vec![0u8; 64 * 1024];
+        let n = client.read(&mut got).unwrap();
+        got.truncate(n);
+        let head_end = got.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8_lossy(&got[..head_end]).into_owned();
+        assert!(head.starts_with("HTTP/1.1 200 ") && head.contains(&format!("Content-Length: {}\r\n", data.len())), "{}", head);
+        assert_eq!(runner.connections(), 1);
+        std::thread::sleep(Duration::from_millis(50));
+        client.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        let mut tmp = vec![0u8; 1 << 20];
+        loop {
+            runner.step(10).unwrap();
+            match client.read(&mut tmp) {
+                Ok(0) => break,
+                Ok(n) => got.extend_from_slice(&tmp[..n]),
+                Err(_) => {}
+            }
+        }
+        let _ = std::fs::remove_file(&file);
+        assert!(got[head_end..] == data[..]);
+    }
+
+    #[test]
+    fn full_request_is_answered_and_the_connection_kept() {
+        let name = format!("sws-runner-{}-full.txt", std::process::id());
+        let path = std::env::temp_dir().join(&name);
+        std::fs::write(&path, b"hello runner").unwrap();
+        let mut runner = EventLoopRunner::new(config(""), 100).unwrap();
+        let (mut client, server, peer) = pair();
+        runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+        let request = format!("GET /{} HTTP/1.1\r\nHost: localhost\r\n\r\n", name);
+        let mut reply = roundtrip(&mut runner, &mut client, &request);
+        for _ in 0..20 {
+            if reply.ends_with("hello runner") { break; }
+            runner.step(10).unwrap();
+            let mut tmp = [0u8; 4096];
+            if let Ok(n) = client.read(&mut tmp) { reply.push_str(&String::from_utf8_lossy(&tmp[..n])); }
+        }
+        let _ = std::fs::remove_file(&path);
+        assert!(reply.starts_with("HTTP/1.1 200"), "{}", reply);
+        assert!(reply.contains("Content-Length: 12\r\n"), "{}", reply);
+        assert!(reply.ends_with("\r\n\r\nhello runner"), "{}", reply);
+        assert_eq!(runner.connections(), 1);
+    }
+
+    #[test]
+    fn idle_connection_expires_at_the_timeout() {
+        let mut runner = EventLoopRunner::new(config(""), 100).unwrap();
+        runner.set_idle_timeout(Duration::from_millis(100));
+        let (mut client, server, peer) = pair();
+        runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+        runner.step(0).unwrap();
+        assert_eq!(runner.connections(), 1);
+        let started = Instant::now();
+        while runner.connections() > 0 && started.elapsed() < Duration::from_secs(3) {
+            runner.step(50).unwrap();
+        }
+        assert_eq!(runner.connections(), 0);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert!(read_to_close(&mut client).is_empty());
+    }
+
+    #[test]
+    fn connect_tunnel_relays_to_allowed_hosts_only() {
+        let echo = TcpListener::bind("127.0.0.1:0").unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut s, _) = echo.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            while let Ok(n) = s.read(&mut buf) {
+                if n == 0 || s.write_all(&buf[..n]).is_err() { break; }
+            }
+        });
+        let other = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut runner = EventLoopRunner::new(config(&format!("  connect_proxy:\n    destinations: [\"{}\"]\n", echo_addr)), 16).unwrap();
+        let denied = exchange(&mut runner, &format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", other));
+        assert!(denied.starts_with("HTTP/1.1 403 "), "{}", denied);
+
+        let (mut client, server, peer) = pair();
+        runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+        let head = roundtrip(&mut runner, &mut client, &format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", echo_addr));
+        assert_eq!(head, "HTTP/1.1 200 Connection Established\r\n\r\n");
+        client.write_all(b"through the tunnel").unwrap();
+        let mut echoed = Vec::new();
+        for _ in 0..100 {
+            runner.step(10).unwrap();
+            let mut tmp = [0u8; 64];
+            if let Ok(n) = client.read(&mut tmp) { echoed.extend_from_slice(&tmp[..n]); }
+            if echoed.len() >= 18 { break; }
+        }
+        assert_eq!(echoed, b"through the tunnel");
+    }
+
+    #[test]
+    fn slow_responses_are_logged_at_warn() {
+        let dir = std::env::temp_dir();
+        let log = dir.join(format!("sws-runner-{}-slow.log", std::process::id()));
+        let _log = LOG_FILE.lock().unwrap();
+        selenia_core::logger::init_file(log.to_str().unwrap());
+        let slow = format!("{}{}-log.txt", blockio::SLOW_PREFIX, std::process::id());
+        let fast = format!("sws-runner-{}-fast.txt", std::process::id());
+        std::fs::write(dir.join(&slow), b"slow").unwrap();
+        std::fs::write(dir.join(&fast), b"fast").unwrap();
+        let mut runner = EventLoopRunner::new(config("  slow_request_ms: 200\n"), 16).unwrap();
+        for name in [&slow, &fast] {
+            let head = exchange(&mut runner, &format!("GET /{} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", name));
+            assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        }
+        let _ = std::fs::remove_file(dir.join(&slow));
+        let _ = std::fs::remove_file(dir.join(&fast));
+        let lines = std::fs::read_to_string(&log).unwrap();
+        let _ = std::fs::remove_file(&log);
+        let warned = |name: &str| lines.lines().any(|l| l.contains("\"lvl\":\"WARN\"") && l.contains("slow request") && l.contains(name));
+        assert!(warned(&format!("GET /{}", slow)), "{}", lines);
+        assert!(lines.lines().any(|l| l.contains(&slow) && l.contains("trace_id=")), "{}", lines);
+        assert!(!warned(&fast), "{}", lines);
+    }
+
+    #[test]
+    fn listener_mode_decides_tls_without_sniffing() {
+        let mut runner = EventLoopRunner::new(config(""), 16).unwrap();
+        // A TLS-only listener answers plaintext with an alert, never an HTTP response.
+        let (mut client, server, peer) = pair();
+        runner.inject(server, peer, ListenMode::Tls, 0).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        for _ in 0..10 { runner.step(10).unwrap(); }
+        assert_eq!(read_to_close(&mut client), [21, 0x03, 0x03, 0x00, 0x02, 2, 10]);
+        // A plaintext listener parses a leading 0x16 as HTTP, which it is not.
+        let head = exchange(&mut runner, "\x16\x03\x01\x00\x05hello\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 400 "), "{:?}", head);
+        // Sniffing still takes the same bytes for a TLS record.
+        let (mut client, server, peer) = pair();
+        runner.inject(server, peer, ListenMode::Sniff, 0).unwrap();
+        client.write_all(b"\x16\x03\x01\x00\x05hello").unwrap();
+        for _ in 0..10 { runner.step(10).unwrap(); }
+        let reply = read_to_close(&mut client);
+        assert_eq!(reply.first(), Some(&21), "{:?}", reply);
+    }
+
+    #[test]
+    fn error_responses_carry_the_common_headers() {
+        let name = format!("sws-runner-{}-common.txt", std::process::id());
+        std::fs::write(std::env::temp_dir().join(&name), b"ok").unwrap();
+        let mut cfg = config("");
+        cfg.tls_cert = Some("cert.pem".into());
+        let mut runner = EventLoopRunner::new(cfg, 16).unwrap();
+        let ok = exchange(&mut runner, &format!("GET /{} HTTP/1.1\r\nHost: localhost\r\n\r\n", name));
+        let missing = exchange(&mut runner, "GET /sws-runner-no-such-file HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let _ = std::fs::remove_file(std::env::temp_dir().join(&name));
+        assert!(ok.starts_with("HTTP/1.1 200 "), "{}", ok);
+        assert!(missing.starts_with("HTTP/1.1 404 "), "{}", missing);
+        for head in [&ok, &missing] {
+            for name in ["Date: ", "Server: ", "X-Content-Type-Options: nosniff", "Strict-Transport-Security: max-age="] {
+                assert!(head.contains(&format!("\r\n{}", name)), "{} missing from {}", name, head);
+            }
+        }
+    }
+
+    #[test]
+    fn proxied_response_is_relayed_with_forwarding_headers() {
+        let live = TcpListener::bind("127.0.0.1:0").unwrap();
+        let live_addr = live.local_addr().unwrap();
+        let backend = std::thread::spawn(move || {
+            let (mut s, _) = live.accept().unwrap();
+            let mut got = Vec::new();
+            while !got.ends_with(b"\r\n\r\nping") {
+                let mut b = [0u8; 1];
+                if s.read(&mut b).unwrap() == 0 { break; }
+                got.push(b[0]);
+            }
+            s.write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 4\r\nX-Backend: yes\r\n\r\npong").unwrap();
+            String::from_utf8(got).unwrap()
+        });
+        let mut runner = EventLoopRunner::new(config(&format!("  proxy_pass:\n    - prefix: /api/\n      upstream: \"{}\"\n", live_addr)), 16).unwrap();
+        let (mut client, server, peer) = pair();
+        runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+        let mut reply = roundtrip(&mut runner, &mut client, "POST /api/items HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-For: 192.0.2.1\r\nContent-Length: 4\r\n\r\nping");
+        for _ in 0..50 {
+            if reply.ends_with("pong") { break; }
+            runner.step(10).unwrap();
+            let mut tmp = [0u8; 256];
+            if let Ok(n) = client.read(&mut tmp) { reply.push_str(&String::from_utf8_lossy(&tmp[..n])); }
+        }
+        assert!(reply.starts_with("HTTP/1.1 201 "), "{}", reply);
+        assert!(reply.contains("\r\nX-Backend: yes\r\n") && reply.ends_with("\r\n\r\npong"), "{}", reply);
+        let upstream = backend.join().unwrap();
+        assert!(upstream.starts_with("POST /api/items HTTP/1.1\r\n"), "{}", upstream);
+        assert!(upstream.contains("\r\nX-Forwarded-For: 192.0.2.1, 127.0.0.1\r\n"), "{}", upstream);
+        assert!(upstream.contains("\r\nForwarded: for=127.0.0.1;proto=http;host=\"localhost\"\r\n"), "{}", upstream);
+    }
+
+    #[test]
+    fn proxied_requests_reuse_the_upstream_connection() {
+        let live = TcpListener::bind("127.0.0.1:0").unwrap();
+        let live_addr = live.local_addr().unwrap();
+        let backend = std::thread::spawn(move || {
+            let (mut s, _) = live.accept().unwrap();
+            for _ in 0..3 {
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    let mut b = [0u8; 1];
+                    if s.read(&mut b).unwrap() == 0 { return false; }
+                    head.push(b[0]);
+                }
+                s.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").unwrap();
+            }
+            // Every request came over the first connection; no second one is waiting.
+            live.set_nonblocking(true).unwrap();
+            live.accept().is_err()
+        });
+        let mut runner = EventLoopRunner::new(config(&format!("  proxy_pass:\n    - prefix: /api/\n      upstream: \"{}\"\n", live_addr)), 16).unwrap();
+        for _ in 0..3 {
+            let head = exchange(&mut runner, "GET /api/x HTTP/1.1\r\nHost: a\r\n\r\n");
+            assert!(head.starts_with("HTTP/1.1 200 "), "{}", head);
+            // Let the exchange finish and hand its connection back to the pool.
+            for _ in 0..5 { runner.step(10).unwrap(); }
+        }
+        assert!(backend.join().unwrap());
+    }
+
+    #[test]
+    fn ipv6_host_header_selects_its_vhost() {
+        let mut runner = EventLoopRunner::new(config("  strict_host: true\n  virtual_hosts:\n    - domain: \"[::1]\"\n      root: \"/nonexistent\"\n"), 16).unwrap();
+        for host in ["[::1]:8080", "[0:0::1]", "[::1]"] {
+            let head = exchange(&mut runner, &format!("GET /x HTTP/1.1\r\nHost: {}\r\n\r\n", host));
+            assert!(head.starts_with("HTTP/1.1 404 "), "{}: {}", host, head);
+        }
+        let head = exchange(&mut runner, "GET /x HTTP/1.1\r\nHost: [::2]\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 421 "), "{}", head);
+        let head = exchange(&mut runner, "GET /x HTTP/1.1\r\nHost: ::1\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 400 "), "{}", head);
+    }
+
+    #[test]
+    fn duplicate_host_and_embedded_cr_get_400() {
+        let mut runner = EventLoopRunner::new(config(""), 16).unwrap();
+        let head = exchange(&mut runner, "GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 400 "), "{}", head);
+        let head = exchange(&mut runner, "GET / HTTP/1.1\r\nHost: a\r\nX-Note: one\rSet-Cookie: x=1\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 400 "), "{}", head);
+        assert!(!head.contains("Set-Cookie"), "{}", head);
+    }
+
+    #[test]
+    fn slow_stage_is_answered_504_at_the_deadline() {
+        let name = format!("{}{}-deadline.txt", blockio::SLOW_PREFIX, std::process::id());
+        let path = std::env::temp_dir().join(&name);
+        std::fs::write(&path, b"late").unwrap();
+        let before = counter("sws_request_timeouts_total");
+        let mut runner = EventLoopRunner::new(config("  request_timeout_ms: 100\n"), 16).unwrap();
+        let started = Instant::now();
+        let head = exchange(&mut runner, &format!("GET /{} HTTP/1.1\r\nHost: a\r\n\r\n", name));
+        let _ = std::fs::remove_file(&path);
+        assert!(head.starts_with("HTTP/1.1 504 "), "{}", head);
+        assert!(started.elapsed() < blockio::SLOW_LOAD * 2);
+        assert!(counter("sws_request_timeouts_total") > before);
+    }
+
+    #[test]
+    fn silent_upstream_is_answered_504_at_the_deadline() {
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let routes = format!("  request_timeout_ms: 150\n  proxy_pass:\n    - prefix: /api/\n      upstream: \"{}\"\n", silent.local_addr().unwrap());
+        let mut runner = EventLoopRunner::new(config(&routes), 16).unwrap();
+        let (mut client, server, peer) = pair();
+        runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+        client.write_all(b"GET /api/x HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        let started = Instant::now();
+        let mut out = Vec::new();
+        while !out.windows(4).any(|w| w == b"\r\n\r\n") && started.elapsed() < Duration::from_secs(3) {
+            runner.step(20).unwrap();
+            let mut tmp = [0u8; 512];
+            if let Ok(n) = client.read(&mut tmp) { out.extend_from_slice(&tmp[..n]); }
+        }
+        let head = String::from_utf8_lossy(&out);
+        assert!(head.starts_with("HTTP/1.1 504 "), "{}", head);
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        drop(silent);
+    }
+
+    #[test]
+    fn corrupt_proxied_gzip_becomes_502() {
+        let good = crate::compress::encode(b"proxied gzip body", crate::compress::Encoding::Gzip);
+        let mut bad = good.clone();
+        let n = bad.len();
+        bad[n - 8] ^= 1;
+        let live = TcpListener::bind("127.0.0.1:0").unwrap();
+        let live_addr = live.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for (s, body) in live.incoming().zip([good, bad]) {
+                let mut s = s.unwrap();
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    let mut b = [0u8; 1];
+                    if s.read(&mut b).unwrap() == 0 { break; }
+                    head.push(b[0]);
+                }
+                let _ = s.write_all(format!("HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).as_bytes());
+                let _ = s.write_all(&body);
+            }
+        });
+        let routes = format!("  proxy_pass:\n    - prefix: /api/\n      upstream: \"{}\"\n      verify_gzip: true\n", live_addr);
+        let mut runner = EventLoopRunner::new(config(&routes), 16).unwrap();
+        let head = exchange(&mut runner, "GET /api/good HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 200 "), "{}", head);
+        let head = exchange(&mut runner, "GET /api/bad HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 502 "), "{}", head);
+    }
+
+    #[test]
+    fn saturated_listener_does_not_hold_up_another() {
+        let slow = format!("{}{}-listener.txt", blockio::SLOW_PREFIX, std::process::id());
+        let dir = std::env::temp_dir();
+        std::fs::write(dir.join(&slow), "slow").unwrap();
+        let mut cfg = config("  io_threads: 2\n");
+        cfg.listen = vec!["127.0.0.1:8080".into(), "127.0.0.1:8081".into()];
+        cfg.listen_modes = vec![ListenMode::Plain; 2];
+        cfg.listen_max_requests = vec![1, 0];
+        let mut runner = EventLoopRunner::new(cfg, 16).unwrap();
+        // On the public listener: one request holds its only slot, one queues, one is shed.
+        let mut public = Vec::new();
+        for name in [slow.as_str(), "no-such-file", "no-such-file"] {
+            let (mut client, server, peer) = pair();
+            runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+            client.write_all(format!("GET /{} HTTP/1.1\r\nHost: a\r\n\r\n", name).as_bytes()).unwrap();
+            client.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+            for _ in 0..3 { runner.step(10).unwrap(); }
+            public.push((client, Vec::new()));
+        }
+        let started = Instant::now();
+        let (mut admin, server, peer) = pair();
+        runner.inject(server, peer, ListenMode::Plain, 1).unwrap();
+        let head = roundtrip(&mut runner, &mut admin, "GET /no-such-file HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 404 "), "{}", head);
+        assert!(started.elapsed() < blockio::SLOW_LOAD);
+        while public.iter().any(|(_, out)| !out.windows(4).any(|w| w == b"\r\n\r\n")) && started.elapsed() < Duration::from_secs(3) {
+            runner.step(10).unwrap();
+            for (client, out) in &mut public {
+                let mut tmp = [0u8; 512];
+                if let Ok(n) = client.read(&mut tmp) { out.extend_from_slice(&tmp[..n]); }
+            }
+        }
+        let _ = std::fs::remove_file(dir.join(&slow));
+        let status: Vec<&str> = public.iter().map(|(_, out)| std::str::from_utf8(&out[..12]).unwrap()).collect();
+        assert_eq!(status, ["HTTP/1.1 200", "HTTP/1.1 404", "HTTP/1.1 503"]);
+    }
+
+    #[test]
+    fn response_is_finished_after_a_client_half_close() {
+        let name = format!("{}{}-half-close.bin", blockio::SLOW_PREFIX, std::process::id());
+        let file = std::env::temp_dir().join(&name);
+        let data: Vec<u8> = (0..4 << 10).map(|i| (i % 253) as u8).collect();
+        std::fs::write(&file, &data).unwrap();
+        let mut runner = EventLoopRunner::new(config("  io_threads: 2\n"), 16).unwrap();
+        let (mut client, server, peer) = pair();
+        runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+        client.write_all(format!("GET /{} HTTP/1.1\r\nHost: a\r\n\r\n", name).as_bytes()).unwrap();
+        // The request is on the I/O pool when the client shuts down its write side.
+        runner.step(10).unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        let mut got = Vec::new();
+        let mut tmp = [0u8; 8192];
+        for _ in 0..500 {
+            runner.step(10).unwrap();
+            match client.read(&mut tmp) {
+                Ok(0) => break,
+                Ok(n) => got.extend_from_slice(&tmp[..n]),
+                Err(_) => {}
+            }
+        }
+        let _ = std::fs::remove_file(&file);
+        let head_end = got.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        assert!(got.starts_with(b"HTTP/1.1 200 "));
+        assert!(got[head_end..] == data[..]);
+        assert_eq!(runner.connections(), 0);
+    }
+
+    #[test]
+    fn handshakes_beyond_the_cap_are_shed() {
+        let mut cfg = config("");
+        cfg.tls_max_handshakes = Some(1);
+        let mut runner = EventLoopRunner::new(cfg, 16).unwrap();
+        let rejected = counter("sws_tls_handshakes_rejected_total");
+        // Half a record header: the handshake has begun but cannot progress.
+        let start = |runner: &mut EventLoopRunner| {
+            let (mut client, server, peer) = pair();
+            runner.inject(server, peer, ListenMode::Tls, 0).unwrap();
+            client.write_all(&[0x16, 0x03]).unwrap();
+            for _ in 0..3 { runner.step(10).unwrap(); }
+            client
+        };
+        let first = start(&mut runner);
+        let started = Instant::now();
+        let mut second = start(&mut runner);
+        assert_eq!(read_to_close(&mut second), [21, 0x03, 0x03, 0x00, 0x02, 2, 80]);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(counter("sws_tls_handshakes_rejected_total") > rejected);
+        assert_eq!(runner.connections(), 1);
+        // Closing the stalled handshake frees its place for the next one.
+        drop(first);
+        for _ in 0..3 { runner.step(10).unwrap(); }
+        let mut third = start(&mut runner);
+        assert_eq!(runner.connections(), 1);
+        third.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        assert!(third.read(&mut [0u8; 16]).is_err());
+    }
+
+    #[test]
+    fn large_body_is_read_in_small_chunks_and_the_buffer_kept() {
+        let name = format!("sws-runner-{}-upload.txt", std::process::id());
+        std::fs::write(std::env::temp_dir().join(&name), b"after the upload").unwrap();
+        let mut runner = EventLoopRunner::new(config("  read_buffer_size: 1024\n"), 16).unwrap();
+        let key = runner.conns.vacant_key().unwrap();
+        let (mut client, server, peer) = pair();
+        runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+        let body = vec![b'x'; 64 << 10];
+        let mut writer = client.try_clone().unwrap();
+        let upload = std::thread::spawn(move || {
+            writer.write_all(format!("POST /{} HTTP/1.1\r\nHost: a\r\nContent-Length: {}\r\n\r\n", name, body.len()).as_bytes()).unwrap();
+            writer.write_all(&body).unwrap();
+            name
+        });
+        client.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        let mut got = Vec::new();
+        while !got.windows(4).any(|w| w == b"\r\n\r\n") {
+            runner.step(10).unwrap();
+            let mut tmp = [0u8; 4096];
+            if let Ok(n) = client.read(&mut tmp) { got.extend_from_slice(&tmp[..n]); }
+        }
+        let name = upload.join().unwrap();
+        assert!(got.starts_with(b"HTTP/1.1 "));
+        // The whole body went through the parser, so the next request starts where it ended.
+        let buf = runner.conns.get_mut(key).unwrap().buf.as_ptr();
+        let head = roundtrip(&mut runner, &mut client, &format!("GET /{} HTTP/1.1\r\nHost: a\r\n\r\n", name));
+        let _ = std::fs::remove_file(std::env::temp_dir().join(&name));
+        assert!(head.starts_with("HTTP/1.1 200 "), "{}", head);
+        let conn = runner.conns.get_mut(key).unwrap();
+        assert!(conn.buf.is_empty() && conn.buf.as_ptr() == buf, "buffer reallocated between requests");
+    }
+
+    #[test]
+    fn sampling_decision_travels_in_the_traceparent() {
+        let mut cfg = config("");
+        cfg.otel.trace_sample_rate = 0.0;
+        let mut runner = EventLoopRunner::new(cfg, 16).unwrap();
+        let fresh = exchange(&mut runner, "GET /sws-runner-no-such-file HTTP/1.1\r\nHost: a\r\n\r\n");
+        let traceparent = |head: &str| head.lines().find_map(|l| l.strip_prefix("traceparent: ")).unwrap().to_string();
+        assert!(traceparent(&fresh).ends_with("-00"), "{}", fresh);
+        // A caller's sampled context is exported and passed on whatever the local rate.
+        let incoming = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        let head = exchange(&mut runner, &format!("GET /sws-runner-no-such-file HTTP/1.1\r\nHost: a\r\ntraceparent: {}\r\n\r\n", incoming));
+        assert_eq!(traceparent(&head), incoming);
+    }
+
+    #[test]
+    fn get_with_a_body_keeps_the_pipeline_in_step() {
+        let name = format!("sws-runner-{}-getbody.txt", std::process::id());
+        let file = std::env::temp_dir().join(&name);
+        std::fs::write(&file, "next").unwrap();
+        let pipelined = format!(
+            "GET /{0} HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nGET /GET /{0} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n",
+            name
+        );
+        let run = |extra: &str| {
+            let mut runner = EventLoopRunner::new(config(extra), 16).unwrap();
+            let (mut client, server, peer) = pair();
+            runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+            client.write_all(pipelined.as_bytes()).unwrap();
+            client.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+            let mut out = Vec::new();
+            for _ in 0..100 {
+                runner.step(10).unwrap();
+                let mut tmp = [0u8; 4096];
+                match client.read(&mut tmp) {
+                    Ok(0) => break,
+                    Ok(n) => out.extend_from_slice(&tmp[..n]),
+                    Err(_) => {}
+                }
+            }
+            let text = String::from_utf8_lossy(&out).into_owned();
+            text.match_indices("HTTP/1.1 ").map(|(i, _)| text[i + 9..i + 12].to_string()).collect::<Vec<_>>()
+        };
+        let discarded = run("");
+        let rejected = run("  reject_get_body: true\n");
+        let _ = std::fs::remove_file(&file);
+        // The body looks like the start of a request line; left unread it would garble the next one.
+        assert_eq!(discarded, ["200", "200"]);
+        assert_eq!(rejected, ["400", "200"]);
+    }
+
+    #[test]
+    fn alt_svc_advertises_the_configured_quic_port() {
+        let request = "GET /sws-runner-no-such-file HTTP/1.1\r\nHost: a\r\n\r\n";
+        let alt_svc = |extra: &str| {
+            let head = exchange(&mut EventLoopRunner::new(config(extra), 16).unwrap(), request);
+            head.lines().find_map(|l| l.strip_prefix("Alt-Svc: ")).map(str::to_string)
+        };
+        assert_eq!(alt_svc("  http3:\n    port: 8443\n").as_deref(), Some("h3=\":8443\"; ma=86400"));
+        assert_eq!(alt_svc("  http3:\n    port: 8443\n    alt_svc: false\n"), None);
+        assert_eq!(alt_svc(""), None);
+    }
+
+    #[test]
+    fn idle_sweep_keeps_its_tick_whatever_the_traffic() {
+        let name = format!("sws-runner-{}-empty.txt", std::process::id());
+        let file = std::env::temp_dir().join(&name);
+        std::fs::write(&file, "").unwrap();
+        let request = format!("GET /{} HTTP/1.1\r\nHost: a\r\n\r\n", name);
+        let mut runner = EventLoopRunner::new(config(""), 100).unwrap();
+        runner.set_idle_timeout(Duration::from_millis(100));
+        let (_idle, server, peer) = pair();
+        runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+        let (mut busy, server, peer) = pair();
+        runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+        // Every step returns on traffic; the sweep still runs once per tick, not per step.
+        let first_tick = runner.next_sweep;
+        let started = Instant::now();
+        let mut ticks = 0;
+        let mut last = first_tick;
+        while runner.connections() > 1 && started.elapsed() < Duration::from_secs(3) {
+            assert!(roundtrip(&mut runner, &mut busy, &request).starts_with("HTTP/1.1 200 "));
+            std::thread::sleep(Duration::from_millis(20));
+            if runner.next_sweep != last { ticks += 1; last = runner.next_sweep; }
+        }
+        let _ = std::fs::remove_file(&file);
+        assert_eq!(runner.connections(), 1);
+        assert!(started.elapsed() < SWEEP_INTERVAL * 2 + Duration::from_millis(200), "{:?}", started.elapsed());
+        assert!(ticks <= 2, "{}", ticks);
+        assert_eq!((runner.next_sweep - first_tick).as_millis() % SWEEP_INTERVAL.as_millis(), 0);
+    }
+
+    #[test]
+    fn quiet_step_wakes_for_the_sweep() {
+        let mut runner = EventLoopRunner::new(config(""), 100).unwrap();
+        runner.set_idle_timeout(Duration::from_millis(50));
+        let (_client, server, peer) = pair();
+        runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+        std::thread::sleep(Duration::from_millis(60));
+        // No events arrive; the long wait ends at the tick and the sweep expires the connection.
+        let started = Instant::now();
+        runner.step(10_000).unwrap();
+        assert!(started.elapsed() <= SWEEP_INTERVAL + Duration::from_millis(100), "{:?}", started.elapsed());
+        assert_eq!(runner.connections(), 0);
+    }
+
+    #[test]
+    fn idle_timeout_tunes_with_load_within_bounds() {
+        let tune = config("  idle_tune:\n    min_ms: 10000\n    max_ms: 40000\n    step_ms: 10000\n    high_load: 0.8\n    low_load: 0.2\n").idle_tune;
+        let secs = Duration::from_secs;
+        // 90 of 100 slots busy: shorter, down to min_ms and no further.
+        assert_eq!(tune_idle_timeout(secs(30), 90, 100, &tune), secs(20));
+        assert_eq!(tune_idle_timeout(secs(15), 90, 100, &tune), secs(10));
+        assert_eq!(tune_idle_timeout(secs(10), 100, 100, &tune), secs(10));
+        // 10 of 100: longer, up to max_ms.
+        assert_eq!(tune_idle_timeout(secs(20), 10, 100, &tune), secs(30));
+        assert_eq!(tune_idle_timeout(secs(35), 0, 100, &tune), secs(40));
+        // Between the thresholds the timeout holds.
+        assert_eq!(tune_idle_timeout(secs(25), 50, 100, &tune), secs(25));
+        // The starting 30 s is clamped into the bounds.
+        let runner = EventLoopRunner::new(config("  idle_tune:\n    max_ms: 12000\n"), 16).unwrap();
+        assert_eq!(runner.idle_timeout, Duration::from_millis(12000));
+    }
+
+    #[test]
+    fn directory_and_case_redirects_keep_the_query() {
+        let dir = format!("sws-runner-{}-Norm", std::process::id());
+        let root = std::env::temp_dir().join(&dir);
+        std::fs::create_dir_all(root.join("Sub")).unwrap();
+        std::fs::write(root.join("Sub").join("Page.txt"), "page").unwrap();
+        let location = |runner: &mut EventLoopRunner, target: &str| {
+            let head = exchange(runner, &format!("GET {} HTTP/1.1\r\nHost: a\r\n\r\n", target));
+            assert!(head.starts_with("HTTP/1.1 301 "), "{}", head);
+            head.lines().find_map(|l| l.strip_prefix("Location: ")).unwrap().to_string()
+        };
+        let mut runner = EventLoopRunner::new(config("  trailing_slash_redirect: true\n  case_insensitive_paths: true\n"), 16).unwrap();
+        assert_eq!(location(&mut runner, &format!("/{}/Sub?x=1&y", dir)), format!("/{}/Sub/?x=1&y", dir));
+        assert_eq!(location(&mut runner, &format!("/{}/sub/PAGE.TXT?v=2", dir.to_uppercase())), format!("/{}/Sub/Page.txt?v=2", dir));
+        assert_eq!(location(&mut runner, &format!("/{}/SUB", dir)), format!("/{}/Sub/", dir));
+        assert!(exchange(&mut runner, &format!("GET /{}/Sub/Page.txt HTTP/1.1\r\nHost: a\r\n\r\n", dir)).starts_with("HTTP/1.1 200 "));
+        assert!(exchange(&mut runner, &format!("GET /{}/../../etc/passwd HTTP/1.1\r\nHost: a\r\n\r\n", dir)).starts_with("HTTP/1.1 403 "));
+        // Both off by default.
+        let mut plain = EventLoopRunner::new(config(""), 16).unwrap();
+        assert!(exchange(&mut plain, &format!("GET /{}/sub/page.txt HTTP/1.1\r\nHost: a\r\n\r\n", dir)).starts_with("HTTP/1.1 404 "));
+        assert!(!exchange(&mut plain, &format!("GET /{}/Sub HTTP/1.1\r\nHost: a\r\n\r\n", dir)).starts_with("HTTP/1.1 301 "));
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn failed_reads_fall_back_to_the_last_good_copy() {
+        let name = format!("sws-runner-{}-stale.txt", std::process::id());
+        let path = std::env::temp_dir().join(&name);
+        std::fs::write(&path, "good copy").unwrap();
+        let request = format!("GET /{} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n", name);
+        let fetch = |runner: &mut EventLoopRunner| {
+            let (mut client, server, peer) = pair();
+            runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+            client.write_all(request.as_bytes()).unwrap();
+            client.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+            let mut out = Vec::new();
+            for _ in 0..100 {
+                runner.step(10).unwrap();
+                let mut tmp = [0u8; 4096];
+                match client.read(&mut tmp) {
+                    Ok(0) => break,
+                    Ok(n) => out.extend_from_slice(&tmp[..n]),
+                    Err(_) => {}
+                }
+            }
+            String::from_utf8_lossy(&out).into_owned()
+        };
+        let cache = "  cache:\n    max_age: 60\n    stale_while_revalidate: 30\n    stale_if_error: 300\n";
+        let mut stale = EventLoopRunner::new(config(&format!("{}    serve_stale_on_error: true\n", cache)), 16).unwrap();
+        let mut strict = EventLoopRunner::new(config(cache), 16).unwrap();
+        let mut plain = EventLoopRunner::new(config("  cache:\n    max_age: 60\n    stale_while_revalidate: 30\n"), 16).unwrap();
+        let fresh = fetch(&mut stale);
+        assert!(fresh.starts_with("HTTP/1.1 200 "), "{}", fresh);
+        assert!(fresh.contains("Cache-Control: max-age=60, stale-while-revalidate=30, stale-if-error=300\r\n"), "{}", fresh);
+        assert!(!fresh.contains("Warning:"));
+        assert!(fetch(&mut plain).contains("Cache-Control: max-age=60, stale-while-revalidate=30\r\n"));
+        assert!(fetch(&mut strict).starts_with("HTTP/1.1 200 "));
+
+        blockio::UNREADABLE.lock().unwrap().push(name.clone());
+        let served = counter("sws_stale_served_total");
+        let fallback = fetch(&mut stale);
+        let failed = fetch(&mut strict);
+        blockio::UNREADABLE.lock().unwrap().retain(|n| *n != name);
+        let _ = std::fs::remove_file(&path);
+        assert!(fallback.starts_with("HTTP/1.1 200 "), "{}", fallback);
+        assert!(fallback.contains("Warning: 111 - \"Revalidation Failed\"\r\n"), "{}", fallback);
+        assert!(fallback.contains("\r\nAge: "), "{}", fallback);
+        assert!(fallback.ends_with("\r\n\r\ngood copy"), "{}", fallback);
+        assert!(counter("sws_stale_served_total") > served);
+        // Without `serve_stale_on_error` the failure shows.
+        assert!(failed.starts_with("HTTP/1.1 500 "), "{}", failed);
+    }
+
+    #[test]
+    fn every_response_is_served_under_one_config_generation() {
+        let _gates = GATES.lock().unwrap_or_else(|e| e.into_inner());
+        // Generation g advertises port 1000 + g and labels text with charset gen-g; the
+        // port is set at parse time, the charset when the I/O pool hands the file back.
+        let generation = |g: u16| config(&format!(
+            "  io_threads: 2\n  rate_limit:\n    capacity: 0\n  http3:\n    port: {}\n  charset:\n    default: gen-{}\n",
+            1000 + g, g
+        ));
+        let dir = std::env::temp_dir();
+        let names: Vec<String> = (0..4).map(|i| format!("sws-runner-{}-gen{}.txt", std::process::id(), i))
+            .chain([format!("{}runner-{}-gen.txt", blockio::SLOW_PREFIX, std::process::id())])
+            .collect();
+        for n in &names { std::fs::write(dir.join(n), "generation").unwrap(); }
+        let mut runner = EventLoopRunner::new(generation(0), 64).unwrap();
+        let mut clients = Vec::new();
+        let mut sent = Vec::new();
+        for round in 0..40u16 {
+            // All files first, the slow one among them; then one request per round.
+            let batch = if round == 0 { &names[..] } else { &names[round as usize % 4..][..1] };
+            for n in batch {
+                let (mut client, server, peer) = pair();
+                runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+                client.write_all(format!("GET /{} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n", n).as_bytes()).unwrap();
+                client.set_nonblocking(true).unwrap();
+                clients.push((client, Vec::new(), false));
+                sent.push(round);
+            }
+            runner.step(5).unwrap();
+            assert_eq!(runner.reload_config(generation(round + 1)), Ok(round as u64 + 1));
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while clients.iter().any(|c| !c.2) && Instant::now() < deadline {
+            runner.step(10).unwrap();
+            for (client, out, done) in clients.iter_mut().filter(|c| !c.2) {
+                let mut tmp = [0u8; 4096];
+                match client.read(&mut tmp) {
+                    Ok(0) => *done = true,
+                    Ok(n) => out.extend_from_slice(&tmp[..n]),
+                    Err(_) => {}
+                }
+            }
+        }
+        for n in &names { let _ = std::fs::remove_file(dir.join(n)); }
+        let mut seen = std::collections::HashSet::new();
+        for ((_, out, done), round) in clients.iter().zip(&sent) {
+            let text = String::from_utf8_lossy(out);
+            assert!(*done && text.starts_with("HTTP/1.1 200 "), "{}", text);
+            let port: u16 = text.split("Alt-Svc: h3=\":").nth(1).unwrap()[..4].parse().unwrap();
+            let g = port - 1000;
+            assert!(text.contains(&format!("Content-Type: text/plain; charset=gen-{}\r\n", g)), "{}", text);
+            // The generation in force when the request was parsed, not a later one.
+            assert_eq!(g, *round, "{}", text);
+            seen.insert(g);
+        }
+        assert!(seen.len() > 10);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn large_files_are_sent_byte_exact_from_a_mapping() {
+        let name = format!("sws-runner-{}-mapped.bin", std::process::id());
+        let path = std::env::temp_dir().join(&name);
+        let data: Vec<u8> = (0..4u32 << 20).map(|i| (i % 253) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let mut runner = EventLoopRunner::new(config("  stream_threshold: 65536\n  mmap_threshold: 1048576\n"), 16).unwrap();
+        let mut fetch = |extra: &str| {
+            let (mut client, server, peer) = pair();
+            runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+            client.write_all(format!("GET /{} HTTP/1.1\r\nHost: a\r\n{}Connection: close\r\n\r\n", name, extra).as_bytes()).unwrap();
+            client.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+            let (mut got, mut tmp) = (Vec::new(), vec![0u8; 1 << 20]);
+            for _ in 0..2000 {
+                runner.step(10).unwrap();
+                match client.read(&mut tmp) {
+                    Ok(0) => break,
+                    Ok(n) => got.extend_from_slice(&tmp[..n]),
+                    Err(_) => {}
+                }
+            }
+            let head_end = got.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+            (String::from_utf8_lossy(&got[..head_end]).into_owned(), got[head_end..].to_vec())
+        };
+        let (head, body) = fetch("");
+        assert!(head.starts_with("HTTP/1.1 200 "), "{}", head);
+        assert!(body == data);
+        #[cfg(target_os = "linux")]
+        assert!(std::fs::read_to_string("/proc/self/maps").unwrap().contains(&name));
+        let (head, body) = fetch("Range: bytes=1000000-1999999\r\n");
+        assert!(head.starts_with("HTTP/1.1 206 ") && head.contains(&format!("Content-Range: bytes 1000000-1999999/{}\r\n", data.len())), "{}", head);
+        assert!(body == data[1_000_000..2_000_000]);
+        // Same size, new content and mtime: the old mapping is not reused.
+        let changed: Vec<u8> = data.iter().map(|b| b ^ 0xff).collect();
+        std::fs::write(&path, &changed).unwrap();
+        let later = std::time::SystemTime::now() + Duration::from_secs(5);
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+        let (_, body) = fetch("Range: bytes=0-99\r\n");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(body, changed[..100]);
+    }
+
+    #[test]
+    fn rejected_requests_get_their_own_status_lines() {
+        let name = format!("sws-runner-{}-ranged.txt", std::process::id());
+        let path = std::env::temp_dir().join(&name);
+        std::fs::write(&path, "0123456789").unwrap();
+        let mut runner = EventLoopRunner::new(config(""), 16).unwrap();
+        let cases = [
+            (format!("GET /{} HTTP/1.1\r\nHost: a\r\n\r\n", "u".repeat(9000)), "HTTP/1.1 414 URI Too Long\r\n"),
+            ("POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip\r\n\r\n".to_string(), "HTTP/1.1 501 Not Implemented\r\n"),
+            ("POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 2000000\r\n\r\n".to_string(), "HTTP/1.1 413 Content Too Large\r\n"),
+            (format!("GET /{} HTTP/1.1\r\nHost: a\r\nRange: bytes=50-60\r\n\r\n", name), "HTTP/1.1 416 Range Not Satisfiable\r\n"),
+        ];
+        for (request, status) in &cases {
+            let head = exchange(&mut runner, request);
+            assert!(head.starts_with(status), "{}", head);
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn status_line_version_never_echoes_the_request() {
+        let name = format!("sws-runner-{}-version.txt", std::process::id());
+        let path = std::env::temp_dir().join(&name);
+        std::fs::write(&path, "v").unwrap();
+        let mut runner = EventLoopRunner::new(config(""), 16).unwrap();
+        let cases = [
+            ("HTTP/1.1", "HTTP/1.1 200 "),
+            ("HTTP/1.0", "HTTP/1.0 200 "),
+            ("HTTP/2.0", "HTTP/1.1 505 HTTP Version Not Supported\r\n"),
+            ("HTTP/9.9", "HTTP/1.1 505 HTTP Version Not Supported\r\n"),
+            ("HTTP/banana", "HTTP/1.1 400 "),
+        ];
+        for (version, status) in cases {
+            let head = exchange(&mut runner, &format!("GET /{} {}\r\nHost: a\r\nConnection: close\r\n\r\n", name, version));
+            assert!(head.starts_with(status), "{}: {}", version, head);
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn idle_tune_measures_load_against_max_connections() {
+        let tune = "  idle_tune:\n    min_ms: 10000\n    max_ms: 40000\n    step_ms: 10000\n    high_load: 0.8\n    low_load: 0.2\n";
+        let mut capped = EventLoopRunner::new(config(&format!("  max_connections: 4\n{}", tune)), 100).unwrap();
+        let mut uncapped = EventLoopRunner::new(config(tune), 100).unwrap();
+        assert_eq!((capped.conn_capacity(), uncapped.conn_capacity()), (4, 100));
+        let mut held = Vec::new();
+        for runner in [&mut capped, &mut uncapped] {
+            for _ in 0..4 {
+                let (client, server, peer) = pair();
+                runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+                held.push(client);
+            }
+            runner.req_count = 1000;
+            runner.sweep(Instant::now());
+        }
+        // 4 of 4 is high load, 4 of 100 low: one step down from 30 s, one step up.
+        assert_eq!(capped.idle_timeout, Duration::from_secs(20));
+        assert_eq!(uncapped.idle_timeout, Duration::from_secs(40));
+        let rejected = counter("sws_conn_rejected_total");
+        let (mut client, server, peer) = pair();
+        capped.inject(server, peer, ListenMode::Plain, 0).unwrap();
+        assert!(read_to_close(&mut client).starts_with(b"HTTP/1.1 503 "));
+        assert_eq!(capped.connections(), 4);
+        assert!(counter("sws_conn_rejected_total") > rejected);
+    }
+
+    #[test]
+    fn ambiguous_body_framing_ends_the_pipeline() {
+        let name = format!("sws-runner-{}-framing.txt", std::process::id());
+        let file = std::env::temp_dir().join(&name);
+        std::fs::write(&file, "next").unwrap();
+        let mut runner = EventLoopRunner::new(config(""), 16).unwrap();
+        let mut statuses = |framing: &str, body: &str| {
+            let (mut client, server, peer) = pair();
+            runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+            let pipelined = format!(
+                "GET /{0} HTTP/1.1\r\nHost: a\r\n{1}\r\n{2}GET /{0} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n",
+                name, framing, body
+            );
+            client.write_all(pipelined.as_bytes()).unwrap();
+            client.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+            let mut out = Vec::new();
+            for _ in 0..100 {
+                runner.step(10).unwrap();
+                let mut tmp = [0u8; 4096];
+                match client.read(&mut tmp) {
+                    Ok(0) => break,
+                    Ok(n) => out.extend_from_slice(&tmp[..n]),
+                    Err(_) => {}
+                }
+            }
+            let text = String::from_utf8_lossy(&out).into_owned();
+            text.match_indices("HTTP/1.1 ").map(|(i, _)| text[i + 9..i + 12].to_string()).collect::<Vec<_>>()
+        };
+        // Each body below would be read as a request line if its framing were guessed at.
+        assert_eq!(statuses("Content-Length: 5x\r\n", "GET /"), ["400"]);
+        assert_eq!(statuses("Content-Length: -1\r\n", "GET /"), ["400"]);
+        assert_eq!(statuses("Content-Length: 5\r\nContent-Length: 0\r\n", "GET /"), ["400"]);
+        assert_eq!(statuses("Content-Length: 0\r\nTransfer-Encoding: chunked\r\n", "5\r\nGET /\r\n0\r\n\r\n"), ["400"]);
+        assert_eq!(statuses("Transfer-Encoding: chunked\r\nContent-Length: 5\r\n", "0\r\n\r\n"), ["400"]);
+        // Repeating the same length is not a conflict.
+        assert_eq!(statuses("Content-Length: 5\r\nContent-Length: 5\r\n", "GET /"), ["200", "200"]);
+        let _ = std::fs::remove_file(&file);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn uploads_are_created_with_the_configured_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("sws-runner-{}-uploads", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let put = |runner: &mut EventLoopRunner, target: &str| {
+            exchange(runner, &format!("PUT {} HTTP/1.1\r\nHost: a\r\nContent-Length: 7\r\n\r\nuploade", target))
+        };
+        let extra = format!("  upload_dir: \"{}\"\n  upload_file_mode: 0640\n", dir.display().to_string().replace('\\', "/"));
+        let mut runner = EventLoopRunner::new(config(&extra), 16).unwrap();
+        let created = put(&mut runner, "/a.txt");
+        let repeated = put(&mut runner, "/a.txt");
+        let escaped = put(&mut runner, "/../a.txt");
+        let refused = put(&mut EventLoopRunner::new(config(""), 16).unwrap(), "/b.txt");
+        let stored = std::fs::read(dir.join("a.txt"));
+        let mode = std::fs::metadata(dir.join("a.txt")).map(|m| m.permissions().mode() & 0o7777);
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(created.starts_with("HTTP/1.1 201 "), "{}", created);
+        assert_eq!(stored.unwrap(), b"uploade");
+        assert_eq!(mode.unwrap(), 0o640);
+        // An existing file is never replaced.
+        assert!(repeated.starts_with("HTTP/1.1 409 "), "{}", repeated);
+        assert!(escaped.starts_with("HTTP/1.1 403 "), "{}", escaped);
+        assert!(refused.starts_with("HTTP/1.1 405 "), "{}", refused);
+    }
+
+    #[test]
+    fn failed_body_reads_are_answered_with_500() {
+        let name = format!("sws-runner-{}-readfail.txt", std::process::id());
+        let path = std::env::temp_dir().join(&name);
+        std::fs::write(&path, "body").unwrap();
+        let request = format!("GET /{} HTTP/1.1\r\nHost: a\r\n\r\n", name);
+        let mut runner = EventLoopRunner::new(config(""), 16).unwrap();
+        let (mut client, server, peer) = pair();
+        runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+        let errors = counter("sws_errors_total");
+        blockio::READ_FAILS.lock().unwrap().push(name.clone());
+        let failed = roundtrip(&mut runner, &mut client, &request);
+        blockio::READ_FAILS.lock().unwrap().retain(|n| *n != name);
+        // The connection survives the failure and serves the next request.
+        let next = roundtrip(&mut runner, &mut client, &request);
+        let _ = std::fs::remove_file(&path);
+        assert!(failed.starts_with("HTTP/1.1 500 Internal Server Error\r\n"), "{}", failed);
+        assert!(counter("sws_errors_total") > errors);
+        assert!(next.starts_with("HTTP/1.1 200 "), "{}", next);
+    }
+
+    #[test]
+    fn failed_file_responses_are_logged_and_end_their_span() {
+        let dir = std::env::temp_dir();
+        let log = dir.join(format!("sws-runner-{}-failed.log", std::process::id()));
+        let _log = LOG_FILE.lock().unwrap();
+        selenia_core::logger::init_file(log.to_str().unwrap());
+        let open = format!("{}{}-open.txt", blockio::SLOW_PREFIX, std::process::id());
+        let read = format!("{}{}-read.txt", blockio::SLOW_PREFIX, std::process::id());
+        std::fs::write(dir.join(&open), b"open").unwrap();
+        std::fs::write(dir.join(&read), b"read").unwrap();
+        blockio::UNREADABLE.lock().unwrap().push(open.clone());
+        blockio::READ_FAILS.lock().unwrap().push(read.clone());
+        // Slow loads make the span's end visible as a slow-request line.
+        let mut runner = EventLoopRunner::new(config("  slow_request_ms: 200\n"), 16).unwrap();
+        let heads: Vec<String> = [&open, &read].iter()
+            .map(|name| exchange(&mut runner, &format!("GET /{} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n", name)))
+            .collect();
+        blockio::UNREADABLE.lock().unwrap().retain(|n| *n != open);
+        blockio::READ_FAILS.lock().unwrap().retain(|n| *n != read);
+        let _ = std::fs::remove_file(dir.join(&open));
+        let _ = std::fs::remove_file(dir.join(&read));
+        let lines = std::fs::read_to_string(&log).unwrap();
+        let _ = std::fs::remove_file(&log);
+        for (name, head) in [&open, &read].iter().zip(&heads) {
+            assert!(head.starts_with("HTTP/1.1 500 "), "{}", head);
+            let access = format!("\\\"GET /{}\\\" 500 0 ", name);
+            assert!(lines.lines().any(|l| l.contains("\"lvl\":\"INFO\"") && l.contains(&access)), "{}", lines);
+            let slow = format!("slow request: \\\"GET /{}\\\" 500 ", name);
+            assert!(lines.lines().any(|l| l.contains("\"lvl\":\"WARN\"") && l.contains(&slow)), "{}", lines);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn compressed_uploads_are_stored_inflated() {
+        let dir = std::env::temp_dir().join(format!("sws-runner-{}-gzip-uploads", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let extra = format!("  upload_dir: \"{}\"\n  max_conn_buffer: 65536\n", dir.display().to_string().replace('\\', "/"));
+        let mut runner = EventLoopRunner::new(config(&extra), 16).unwrap();
+        let mut put = |target: &str, encoding: &str, body: &[u8]| {
+            let (mut client, server, peer) = pair();
+            runner.inject(server, peer, ListenMode::Plain, 0).unwrap();
+            client.write_all(format!("PUT {} HTTP/1.1\r\nHost: a\r\nContent-Encoding: {}\r\nContent-Length: {}\r\n\r\n", target, encoding, body.len()).as_bytes()).unwrap();
+            client.write_all(body).unwrap();
+            roundtrip(&mut runner, &mut client, "")
+        };
+        let text = b"an upload that travelled gzip-compressed ".repeat(20);
+        let gzip = crate::compress::encode(&text, crate::compress::Encoding::Gzip);
+        let mut corrupt = gzip.clone();
+        corrupt[12] ^= 0xff;
+        let bomb = crate::compress::encode(&vec![0u8; 100_000], crate::compress::Encoding::Gzip);
+        let created = put("/text.txt", "gzip", &gzip);
+        let broken = put("/broken.txt", "gzip", &corrupt);
+        let unknown = put("/brotli.txt", "br", b"xxxx");
+        let oversized = put("/bomb.txt", "gzip", &bomb);
+        let stored = std::fs::read(dir.join("text.txt"));
+        let refused_left_nothing = ["broken.txt", "brotli.txt", "bomb.txt"].iter().all(|n| !dir.join(n).exists());
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(created.starts_with("HTTP/1.1 201 "), "{}", created);
+        assert_eq!(stored.unwrap(), text);
+        assert!(broken.starts_with("HTTP/1.1 400 "), "{}", broken);
+        assert!(unknown.starts_with("HTTP/1.1 415 "), "{}", unknown);
+        assert!(oversized.starts_with("HTTP/1.1 413 "), "{}", oversized);
+        assert!(refused_left_nothing);
+    }
+}
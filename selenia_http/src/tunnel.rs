@@ -0,0 +1,218 @@
+//! Forward-proxy `CONNECT` tunnels (RFC 9110 §9.3.6), opt-in via `connect_proxy`.
+//!
+//! `open` checks the target against the destination allowlist and starts a
+//! non-blocking connect, the name looked up in the [`DnsCache`] alone; the event loop
+//! then registers the upstream socket next to the client and calls [`Tunnel::pump`]
+//! whenever either side is ready. The `200` goes out only once the connect has
+//! completed; a refused one is answered 502, one that takes longer than
+//! `connect_timeout_ms` 504. Each direction buffers at most one
+//! read; while that buffer waits for the far side, the near side is not read, so a
+//! slow reader throttles the sender instead of growing memory. Interest is re-armed
+//! from the buffer state after every pump ([`Tunnel::sync`]).
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+use selenia_core::cidr::Cidr;
+use selenia_core::config::{split_destination, ConnectProxy};
+use selenia_core::dns::DnsCache;
+use selenia_core::os::{connect, EventLoop, Interest, Token};
+
+use super::error::ErrorKind;
+
+const CHUNK: usize = 16 * 1024;
+/// Sent to the client once the upstream connect has completed.
+const ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+
+fn host_matches(pattern: &str, host: &str, ip: IpAddr) -> bool {
+    if let Some(net) = Cidr::parse(pattern) { return net.contains(ip); }
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => host.len() > suffix.len() + 1
+            && host[host.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+            && host.as_bytes()[host.len() - suffix.len() - 1] == b'.',
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+/// `host:port` as requested may be reached at resolved address `ip`.
+fn destination_allowed(cp: &ConnectProxy, host: &str, port: u16, ip: IpAddr) -> bool {
+    cp.destinations.iter().filter_map(|d| split_destination(d)).any(|(h, p)| {
+        (p == "*" || p.parse::<u16>() == Ok(port)) && host_matches(h, host, ip)
+    })
+}
+
+/// Start connecting to a CONNECT request-target (`host:port`) for `peer`, provided its
+/// address (returned with the socket) passes the allowlist. `Ok(None)` while `dns` is
+/// still resolving the name.
+pub fn open(target: &str, peer: &str, cp: &ConnectProxy, dns: &DnsCache) -> Result<Option<(TcpStream, SocketAddr)>, ErrorKind> {
+    if !cp.allow.is_empty() {
+        let ip: IpAddr = peer.parse().map_err(|_| ErrorKind::ProxyDenied)?;
+        if !cp.allow.iter().filter_map(|c| Cidr::parse(c)).any(|c| c.contains(ip)) { return Err(ErrorKind::ProxyDenied); }
+    }
+    let (host, port) = split_destination(target).ok_or(ErrorKind::MalformedHeader)?;
+    let port: u16 = port.parse().map_err(|_| ErrorKind::MalformedHeader)?;
+    if host.is_empty() { return Err(ErrorKind::MalformedHeader); }
+    let Some(ip) = host.parse::<IpAddr>().ok().or_else(|| dns.resolve(host)) else {
+        return Ok(None);
+    };
+    if !destination_allowed(cp, host, port, ip) { return Err(ErrorKind::ProxyDenied); }
+    let addr = SocketAddr::new(ip, port);
+    connect::start(addr).map(|s| Some((s, addr))).map_err(|_| ErrorKind::BadGateway)
+}
+
+/// One direction of the relay.
+#[derive(Debug, Default)]
+struct Half {
+    buf: Vec<u8>,
+    /// The reading side sent FIN.
+    eof: bool,
+    /// FIN forwarded to the writing side.
+    shut: bool,
+}
+
+#[derive(Debug)]
+pub struct Tunnel {
+    pub upstream: TcpStream,
+    /// Event-loop token of `upstream`.
+    pub token: Token,
+    /// Set while the connect is under way: when it counts as failed.
+    connect_by: Option<Instant>,
+    to_upstream: Half,
+    to_client: Half,
+    /// Currently registered interest per side; `None` while neither direction can move.
+    client_reg: Option<Interest>,
+    upstream_reg: Option<Interest>,
+}
+
+/// Read one chunk from `src` into an empty `half`, then flush it to `dst`.
+/// Returns whether anything moved.
+fn relay(src: &TcpStream, dst: &TcpStream, half: &mut Half) -> io::Result<bool> {
+    let mut moved = false;
+    if half.buf.is_empty() && !half.eof {
+        let mut tmp = [0u8; CHUNK];
+        match (&*src).read(&mut tmp) {
+            Ok(0) => { half.eof = true; moved = true; }
+            Ok(n) => { half.buf.extend_from_slice(&tmp[..n]); moved = true; }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+    }
+    if !half.buf.is_empty() {
+        match (&*dst).write(&half.buf) {
+            Ok(n) => { half.buf.drain(..n); moved |= n > 0; }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+    }
+    if half.eof && half.buf.is_empty() && !half.shut {
+        let _ = dst.shutdown(Shutdown::Write);
+        half.shut = true;
+    }
+    Ok(moved)
+}
+
+pub(super) fn interest(read: bool, write: bool) -> Option<Interest> {
+    match (read, write) {
+        (true, true) => Some(Interest::ReadWrite),
+        (true, false) => Some(Interest::Readable),
+        (false, true) => Some(Interest::Writable),
+        (false, false) => None,
+    }
+}
+
+pub(super) fn apply(ev: &mut EventLoop, io: &TcpStream, token: Token, cur: &mut Option<Interest>, want: Option<Interest>) -> io::Result<()> {
+    match (*cur, want) {
+        (Some(_), None) => ev.deregister(token)?,
+        (None, Some(w)) => ev.register_token(io, token, w)?,
+        (Some(c), Some(w)) if c != w => ev.reregister(token, w)?,
+        _ => {}
+    }
+    *cur = want;
+    Ok(())
+}
+
+impl Tunnel {
+    /// `upstream` as returned by [`open`], its connect given `connect_timeout`. `early`
+    /// holds client bytes that arrived behind the CONNECT request. Both sockets are
+    /// expected to be registered `Readable` already; call [`Tunnel::sync`] next.
+    pub fn new(upstream: TcpStream, token: Token, early: Vec<u8>, connect_timeout: Duration) -> Self {
+        Tunnel {
+            upstream,
+            token,
+            connect_by: Some(Instant::now() + connect_timeout),
+            to_upstream: Half { buf: early, ..Half::default() },
+            to_client: Half::default(),
+            client_reg: Some(Interest::Readable),
+            upstream_reg: Some(Interest::Readable),
+        }
+    }
+
+    /// When a connect under way counts as failed, if one is.
+    pub fn connect_by(&self) -> Option<Instant> { self.connect_by }
+
+    /// Move bytes both ways until neither side makes progress. `Ok(false)` once both
+    /// directions have finished and the tunnel can be closed. `upstream_ready` when the
+    /// event loop reported the upstream socket; until then a connect under way only
+    /// checks its timeout (`TimedOut`). A failed connect is its error.
+    pub fn pump(&mut self, client: &TcpStream, upstream_ready: bool) -> io::Result<bool> {
+        if let Some(by) = self.connect_by {
+            if !upstream_ready {
+                if Instant::now() < by { return Ok(true); }
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out"));
+            }
+            connect::settled(&self.upstream)?;
+            self.connect_by = None;
+            self.to_client.buf.extend_from_slice(ESTABLISHED);
+        }
+        while relay(client, &self.upstream, &mut self.to_upstream)? | relay(&self.upstream, client, &mut self.to_client)? {}
+        Ok(!(self.to_upstream.shut && self.to_client.shut))
+    }
+
+    /// Re-arm both sockets from the buffer state; `client_token` is the connection key.
+    pub fn sync(&mut self, ev: &mut EventLoop, client: &TcpStream, client_token: Token) -> io::Result<()> {
+        let (up, down) = (&self.to_upstream, &self.to_client);
+        let (client_want, upstream_want) = match self.connect_by {
+            Some(_) => (None, Some(Interest::Writable)),
+            None => (
+                interest(up.buf.is_empty() && !up.eof, !down.buf.is_empty()),
+                interest(down.buf.is_empty() && !down.eof, !up.buf.is_empty()),
+            ),
+        };
+        apply(ev, client, client_token, &mut self.client_reg, client_want)?;
+        apply(ev, &self.upstream, self.token, &mut self.upstream_reg, upstream_want)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxy(destinations: &[&str]) -> ConnectProxy {
+        ConnectProxy { destinations: destinations.iter().map(|d| d.to_string()).collect(), allow: Vec::new(), connect_timeout_ms: 1000 }
+    }
+
+    #[test]
+    fn destinations_match_host_and_port() {
+        let cp = proxy(&["db.internal:5432", "*.corp.example:*", "10.0.0.0/8:443"]);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(destination_allowed(&cp, "db.internal", 5432, ip));
+        assert!(!destination_allowed(&cp, "db.internal", 5433, ip));
+        assert!(destination_allowed(&cp, "git.corp.example", 22, ip));
+        assert!(!destination_allowed(&cp, "corp.example", 22, ip));
+        assert!(!destination_allowed(&cp, "evilcorp.example", 22, ip));
+        assert!(destination_allowed(&cp, "anything", 443, "10.1.2.3".parse().unwrap()));
+        assert!(!destination_allowed(&cp, "anything", 443, ip));
+    }
+
+    #[test]
+    fn disallowed_targets_and_peers_are_denied() {
+        let dns = DnsCache::new();
+        let mut cp = proxy(&["127.0.0.1:9"]);
+        assert!(matches!(open("127.0.0.2:9", "127.0.0.1", &cp, &dns), Err(ErrorKind::ProxyDenied)));
+        assert!(matches!(open("127.0.0.1:10", "127.0.0.1", &cp, &dns), Err(ErrorKind::ProxyDenied)));
+        cp.allow = vec!["10.0.0.0/8".into()];
+        assert!(matches!(open("127.0.0.1:9", "127.0.0.1", &cp, &dns), Err(ErrorKind::ProxyDenied)));
+    }
+}
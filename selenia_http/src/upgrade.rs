@@ -0,0 +1,132 @@
+//! HTTP/1.1 `Upgrade` handling (RFC 7230 §6.7).
+//!
+//! * `websocket` – RFC 6455 opening handshake. The socket is handed to the
+//!   handler registered via [`set_websocket_handler`]; without a handler the
+//!   `Upgrade` header is ignored and the request is served normally.
+//! * `h2c` is ignored (RFC 7540 §3.2 lets a server decline by not switching) until
+//!   cleartext HTTP/2 can serve the upgraded request; the request stays HTTP/1.1.
+//!
+//! The event loop upgrades only after the request has passed the same Host, access,
+//! WAF, auth and RBAC gates as any other.
+
+use std::io::{self, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, RwLock};
+
+use selenia_core::crypto::sha1::sha1_digest;
+use selenia_core::encoding::base64;
+use super::parser::Request;
+
+/// RFC 6455 §1.3 magic GUID appended to `Sec-WebSocket-Key`.
+const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+type WsHandler = Arc<dyn Fn(TcpStream, String) + Send + Sync>;
+
+static WS_HANDLER: RwLock<Option<WsHandler>> = RwLock::new(None);
+
+/// Register the handler receiving upgraded WebSocket sockets (blocking mode) and request path.
+/// Each connection runs on its own thread.
+pub fn set_websocket_handler<F: Fn(TcpStream, String) + Send + Sync + 'static>(f: F) {
+    *WS_HANDLER.write().unwrap() = Some(Arc::new(f));
+}
+
+/// Upgrade requested by the client and accepted by the server.
+pub enum Upgrade {
+    WebSocket { accept: String, path: String },
+}
+
+fn header<'a>(req: &Request<'a>, name: &str) -> Option<&'a str> {
+    req.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| *v)
+}
+
+fn has_token(v: &str, token: &str) -> bool {
+    v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+/// Inspect `Connection: Upgrade` + `Upgrade:` headers. Returns `None` when the
+/// request should be served as plain HTTP/1.1.
+pub fn detect(req: &Request) -> Option<Upgrade> {
+    if req.version != "HTTP/1.1" { return None; }
+    if !header(req, "Connection").map_or(false, |v| has_token(v, "upgrade")) { return None; }
+    let proto = header(req, "Upgrade")?;
+    if has_token(proto, "websocket") && req.method == "GET" {
+        if WS_HANDLER.read().unwrap().is_none() { return None; }
+        if header(req, "Sec-WebSocket-Version").map(str::trim) != Some("13") { return None; }
+        let key = header(req, "Sec-WebSocket-Key")?.trim();
+        return Some(Upgrade::WebSocket { accept: websocket_accept(key), path: req.path.to_string() });
+    }
+    None
+}
+
+/// `Sec-WebSocket-Accept` = base64(SHA-1(key + GUID)).
+pub fn websocket_accept(key: &str) -> String {
+    let mut input = String::with_capacity(key.len() + WS_GUID.len());
+    input.push_str(key);
+    input.push_str(WS_GUID);
+    base64::encode(&sha1_digest(input.as_bytes()))
+}
+
+/// Send `101 Switching Protocols` for WebSocket and hand the socket to the registered handler.
+pub fn accept_websocket(mut stream: TcpStream, accept: &str, path: String) -> io::Result<()> {
+    let resp = format!("HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Accept: {}\r\n\r\n", accept);
+    stream.write_all(resp.as_bytes())?;
+    let handler = match WS_HANDLER.read().unwrap().clone() { Some(h) => h, None => return Ok(()) };
+    stream.set_nonblocking(false)?;
+    std::thread::Builder::new()
+        .name("ws-conn".into())
+        .spawn(move || handler(stream, path))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+    use std::io::Read;
+
+    const KEY: (&str, &str) = ("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==");
+
+    fn request<'a>(method: &'a str, version: &'a str, headers: &[(&'a str, &'a str)]) -> Request<'a> {
+        Request { method, path: "/chat", version, headers: headers.to_vec(), body: Cow::Borrowed(b"") }
+    }
+
+    #[test]
+    fn websocket_accept_matches_rfc6455_example() {
+        assert_eq!(websocket_accept("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn websocket_upgrade_is_detected() {
+        set_websocket_handler(|_, _| {});
+        let headers = [("Connection", "keep-alive, Upgrade"), ("Upgrade", "websocket"), ("Sec-WebSocket-Version", "13"), KEY];
+        match detect(&request("GET", "HTTP/1.1", &headers)) {
+            Some(Upgrade::WebSocket { accept, path }) => {
+                assert_eq!(accept, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+                assert_eq!(path, "/chat");
+            }
+            None => panic!("upgrade not detected"),
+        }
+        assert!(detect(&request("POST", "HTTP/1.1", &headers)).is_none());
+        assert!(detect(&request("GET", "HTTP/1.0", &headers)).is_none());
+        assert!(detect(&request("GET", "HTTP/1.1", &headers[1..])).is_none());
+        assert!(detect(&request("GET", "HTTP/1.1", &[headers[0], headers[1], ("Sec-WebSocket-Version", "8"), KEY])).is_none());
+    }
+
+    #[test]
+    fn h2c_upgrade_is_declined() {
+        let headers = [("Connection", "Upgrade, HTTP2-Settings"), ("Upgrade", "h2c"), ("HTTP2-Settings", "AAMAAABkAAQAAP__")];
+        assert!(detect(&request("GET", "HTTP/1.1", &headers)).is_none());
+    }
+
+    #[test]
+    fn switching_protocols_response() {
+        set_websocket_handler(|_, _| {});
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        accept_websocket(server, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=", "/chat".into()).unwrap();
+        let mut head = String::new();
+        client.read_to_string(&mut head).unwrap();
+        assert_eq!(head, "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n");
+    }
+}
@@ -0,0 +1,292 @@
+//! Upstream groups behind `proxy_pass`: selection, passive and active health.
+//!
+//! Every route owns a [`Group`] over its upstreams. [`Group::pick`] skips ejected
+//! upstreams (all of them count when none is left) and balances round-robin or by
+//! requests in flight. Passively, `max_fails` consecutive connect or mid-response
+//! failures eject an upstream for `fail_timeout_ms`; a completed response resets the
+//! count. Routes with `health_path` also get a probe thread driven by a periodic
+//! [`Timer`]: a failing `GET` ejects, a passing one readmits at once. Upstream names are
+//! looked up in the [`DnsCache`] alone, so neither [`connect`] nor a probe waits on a
+//! resolver; an upstream whose name is not cached yet is passed over.
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use selenia_core::config::{split_destination, Balance, ProxyPass};
+use selenia_core::dns::DnsCache;
+use selenia_core::os::{connect, Timer};
+use selenia_core::{log_info, log_warn, metrics};
+
+
+#[derive(Debug)]
+pub struct Backend {
+    pub addr: String,
+    /// Requests in flight, for `least_conn`.
+    active: AtomicUsize,
+    /// Consecutive failures since the last success.
+    fails: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+    metric: usize,
+}
+
+impl Backend {
+    fn new(addr: &str) -> Self {
+        Backend {
+            addr: addr.to_string(),
+            active: AtomicUsize::new(0),
+            fails: AtomicU32::new(0),
+            ejected_until: Mutex::new(None),
+            metric: metrics::register_upstream(addr),
+        }
+    }
+
+    /// In rotation at `now`; an expired ejection is lifted here.
+    fn available(&self, now: Instant) -> bool {
+        let mut until = self.ejected_until.lock().unwrap();
+        match *until {
+            Some(t) if t > now => false,
+            Some(_) => {
+                *until = None;
+                metrics::set_upstream_healthy(self.metric, true);
+                log_info!("upstream {} back in rotation", self.addr);
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn eject(&self, for_: Duration) {
+        let mut until = self.ejected_until.lock().unwrap();
+        if until.is_none() { log_warn!("upstream {} ejected", self.addr); }
+        *until = Some(Instant::now() + for_);
+        metrics::set_upstream_healthy(self.metric, false);
+    }
+
+    fn readmit(&self) {
+        if self.ejected_until.lock().unwrap().take().is_some() {
+            metrics::set_upstream_healthy(self.metric, true);
+            log_info!("upstream {} passed its health check", self.addr);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Group {
+    pub route: ProxyPass,
+    backends: Vec<Backend>,
+    next: AtomicUsize,
+}
+
+impl Group {
+    pub fn new(route: &ProxyPass) -> Self {
+        let backends = route.upstreams.iter().map(|a| Backend::new(a)).collect();
+        Group { route: route.clone(), backends, next: AtomicUsize::new(0) }
+    }
+
+    pub fn backend(&self, i: usize) -> &Backend {
+        &self.backends[i]
+    }
+
+    /// Next upstream to try, skipping indices in `tried`; `None` once all were tried.
+    pub fn pick(&self, tried: &[usize]) -> Option<usize> {
+        let now = Instant::now();
+        let fresh: Vec<usize> = (0..self.backends.len()).filter(|i| !tried.contains(i)).collect();
+        let up: Vec<usize> = fresh.iter().copied().filter(|&i| self.backends[i].available(now)).collect();
+        // With every remaining upstream ejected, trying one beats refusing outright.
+        let candidates = if up.is_empty() { fresh } else { up };
+        if candidates.is_empty() { return None; }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut rotated = (0..candidates.len()).map(|k| candidates[(start + k) % candidates.len()]);
+        match self.route.balance {
+            Balance::RoundRobin => rotated.next(),
+            Balance::LeastConn => rotated.min_by_key(|&i| self.backends[i].active.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// A request went to upstream `i`; pair with [`Group::end`].
+    pub fn begin(&self, i: usize) {
+        self.backends[i].active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn end(&self, i: usize) {
+        self.backends[i].active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn record_success(&self, i: usize) {
+        self.backends[i].fails.store(0, Ordering::Relaxed);
+    }
+
+    pub fn record_failure(&self, i: usize) {
+        let b = &self.backends[i];
+        metrics::inc_upstream_failures(b.metric);
+        if b.fails.fetch_add(1, Ordering::Relaxed) + 1 >= self.route.max_fails {
+            b.fails.store(0, Ordering::Relaxed);
+            b.eject(Duration::from_millis(self.route.fail_timeout_ms));
+        }
+    }
+
+    /// One round of active checks over every upstream, ejected ones included.
+    fn probe_all(&self, path: &str, dns: &DnsCache) {
+        let timeout = Duration::from_millis(self.route.connect_timeout_ms);
+        for b in &self.backends {
+            match probe(&b.addr, path, timeout, dns) {
+                Some(true) => {
+                    b.fails.store(0, Ordering::Relaxed);
+                    b.readmit();
+                }
+                Some(false) => {
+                    metrics::inc_upstream_failures(b.metric);
+                    b.eject(Duration::from_millis(self.route.fail_timeout_ms));
+                }
+                // Not resolved yet; the next round probes it.
+                None => {}
+            }
+        }
+    }
+}
+
+/// `addr` (`host:port`) as a socket address, its name looked up in `dns` alone so the
+/// caller never waits on a resolver. `Ok(None)` while the cache is still resolving it.
+fn resolve(addr: &str, dns: &DnsCache) -> io::Result<Option<SocketAddr>> {
+    let bad = || io::Error::new(io::ErrorKind::InvalidInput, "invalid upstream address");
+    let (host, port) = split_destination(addr).ok_or_else(bad)?;
+    let port: u16 = port.parse().map_err(|_| bad())?;
+    Ok(host.parse::<IpAddr>().ok().or_else(|| dns.resolve(host)).map(|ip| SocketAddr::new(ip, port)))
+}
+
+/// Start a non-blocking connect to `addr`; the socket settles once writable
+/// ([`connect::settled`]). `Ok(None)` while `dns` is still resolving the name.
+pub fn connect(addr: &str, dns: &DnsCache) -> io::Result<Option<TcpStream>> {
+    resolve(addr, dns)?.map(connect::start).transpose()
+}
+
+/// Ask `dns` for every upstream name now, so the first requests find them cached.
+pub fn prefetch(groups: &[Arc<Group>], dns: &DnsCache) {
+    for b in groups.iter().flat_map(|g| &g.backends) {
+        let _ = resolve(&b.addr, dns);
+    }
+}
+
+/// `GET path` on its own connection; a 2xx or 3xx status passes. `None` while the
+/// name is not resolved yet. Runs on the probe thread, so it may block.
+fn probe(addr: &str, path: &str, timeout: Duration, dns: &DnsCache) -> Option<bool> {
+    let target = match resolve(addr, dns) {
+        Ok(Some(target)) => target,
+        Ok(None) => return None,
+        Err(_) => return Some(false),
+    };
+    let attempt = || -> io::Result<bool> {
+        let mut s = TcpStream::connect_timeout(&target, timeout)?;
+        s.set_read_timeout(Some(timeout))?;
+        s.write_all(format!("GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: SWS-health\r\nConnection: close\r\n\r\n", path, addr).as_bytes())?;
+        let mut head = [0u8; 12];
+        s.read_exact(&mut head)?;
+        let status = std::str::from_utf8(&head[9..12]).ok().and_then(|c| c.parse::<u16>().ok());
+        Ok(head.starts_with(b"HTTP/1.") && status.map_or(false, |c| (200..400).contains(&c)))
+    };
+    Some(attempt().unwrap_or(false))
+}
+
+/// Start one probe thread per group with a `health_path`. Call before the sandbox.
+pub fn spawn_health_checks(groups: &[Arc<Group>], dns: &Arc<DnsCache>) -> io::Result<()> {
+    for g in groups {
+        let Some(path) = g.route.health_path.clone() else { continue };
+        let mut timer = Timer::new(g.route.health_interval_ms, true)?;
+        let (g, dns) = (Arc::clone(g), Arc::clone(dns));
+        thread::spawn(move || loop {
+            if let Err(e) = timer.wait() {
+                log_warn!("health check timer for {} failed: {}", g.route.prefix, e);
+                return;
+            }
+            g.probe_all(&path, &dns);
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn group(upstreams: &[&str], balance: Balance) -> Group {
+        Group::new(&ProxyPass {
+            prefix: "/api/".into(),
+            upstreams: upstreams.iter().map(|u| u.to_string()).collect(),
+            balance,
+            max_fails: 2,
+            fail_timeout_ms: 60_000,
+            ..ProxyPass::default()
+        })
+    }
+
+    #[test]
+    fn round_robin_spreads_requests_evenly() {
+        let g = group(&["192.0.2.1:80", "192.0.2.2:80", "192.0.2.3:80"], Balance::RoundRobin);
+        let picks: Vec<usize> = (0..6).map(|_| g.pick(&[]).unwrap()).collect();
+        assert_eq!(picks, [0, 1, 2, 0, 1, 2]);
+        // A retry never returns to an upstream already tried.
+        assert_eq!(g.pick(&[0, 2]), Some(1));
+        assert_eq!(g.pick(&[0, 1, 2]), None);
+    }
+
+    #[test]
+    fn least_conn_prefers_the_idlest() {
+        let g = group(&["192.0.2.1:80", "192.0.2.2:80", "192.0.2.3:80"], Balance::LeastConn);
+        g.begin(0);
+        g.begin(0);
+        g.begin(1);
+        assert_eq!(g.pick(&[]), Some(2));
+        g.begin(2);
+        g.begin(2);
+        assert_eq!(g.pick(&[]), Some(1));
+        g.end(0);
+        g.end(0);
+        assert_eq!(g.pick(&[]), Some(0));
+    }
+
+    #[test]
+    fn failing_upstream_is_ejected_until_its_timeout() {
+        let mut g = group(&["192.0.2.1:80", "192.0.2.2:80"], Balance::RoundRobin);
+        g.route.fail_timeout_ms = 50;
+        g.record_failure(1);
+        assert!((0..4).map(|_| g.pick(&[]).unwrap()).any(|i| i == 1));
+        // A success in between resets the count.
+        g.record_success(1);
+        g.record_failure(1);
+        assert!((0..4).map(|_| g.pick(&[]).unwrap()).any(|i| i == 1));
+        g.record_failure(1);
+        assert!((0..4).all(|_| g.pick(&[]) == Some(0)));
+        // With every other upstream tried, the ejected one is still better than nothing.
+        assert_eq!(g.pick(&[0]), Some(1));
+        std::thread::sleep(Duration::from_millis(80));
+        assert!((0..4).map(|_| g.pick(&[]).unwrap()).any(|i| i == 1));
+    }
+
+    #[test]
+    fn health_checks_eject_and_readmit() {
+        let healthy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let healthy_addr = healthy.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            for s in healthy.incoming() {
+                let mut s = s.unwrap();
+                let _ = s.read(&mut [0u8; 512]);
+                let _ = s.write_all(b"HTTP/1.1 204 No Content\r\n\r\n");
+            }
+        });
+        let down = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let dns = DnsCache::new();
+        let mut g = group(&[&healthy_addr, &down], Balance::RoundRobin);
+        g.route.connect_timeout_ms = 500;
+        // Passive failures took the healthy upstream out; a passing probe brings it back.
+        g.record_failure(0);
+        g.record_failure(0);
+        assert!((0..4).all(|_| g.pick(&[]) == Some(1)));
+        g.probe_all("/healthz", &dns);
+        assert!((0..4).all(|_| g.pick(&[]) == Some(0)));
+    }
+}
@@ -0,0 +1,197 @@
+//! Request-target helpers (RFC 3986): path/query split, query-string parsing and
+//! strict path percent-decoding.
+
+/// Split a request-target into path and optional query; any `#fragment` is dropped.
+pub fn split_target(target: &str) -> (&str, Option<&str>) {
+    let target = target.split('#').next().unwrap_or("");
+    match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    }
+}
+
+fn hex_val(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Lenient `application/x-www-form-urlencoded` decoding: `+` is a space and
+/// malformed `%` escapes are kept literally. Invalid UTF-8 is replaced.
+fn decode_component(s: &str) -> String {
+    let b = s.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        match b[i] {
+            b'+' => out.push(b' '),
+            b'%' => match (b.get(i + 1).copied().and_then(hex_val), b.get(i + 2).copied().and_then(hex_val)) {
+                (Some(h), Some(l)) => { out.push(h << 4 | l); i += 2; }
+                _ => out.push(b'%'),
+            },
+            c => out.push(c),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Parse a query string (`a=1&b=x+y&flag`) into decoded pairs, preserving order
+/// and duplicates. Keys without `=` get an empty value; empty segments are skipped.
+pub fn parse_query(query: &str) -> Vec<(String, String)> {
+    query.split('&')
+        .filter(|seg| !seg.is_empty())
+        .map(|seg| {
+            let (k, v) = seg.split_once('=').unwrap_or((seg, ""));
+            (decode_component(k), decode_component(v))
+        })
+        .collect()
+}
+
+/// Strict percent-decoding for the path component: every `%` must be followed by
+/// two hex digits, the result must be UTF-8, and decoded NUL/control bytes are
+/// refused. `+` stays literal (it only means space in queries). `None` → 400.
+pub fn decode_path(path: &str) -> Option<String> {
+    let b = path.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        let c = if b[i] == b'%' {
+            let h = hex_val(*b.get(i + 1)?)?;
+            let l = hex_val(*b.get(i + 2)?)?;
+            i += 2;
+            h << 4 | l
+        } else {
+            b[i]
+        };
+        if c < 0x20 || c == 0x7f { return None; }
+        out.push(c);
+        i += 1;
+    }
+    String::from_utf8(out).ok()
+}
+
+/// Percent-encode a decoded path for a `Location` header: RFC 3986 `pchar`s and `/`
+/// stay literal, everything else (including `%`) becomes `%XX`.
+pub fn encode_path(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for &c in path.as_bytes() {
+        if c.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@/".contains(&c) {
+            out.push(c as char);
+        } else {
+            out.push_str(&format!("%{:02X}", c));
+        }
+    }
+    out
+}
+
+/// Validate a `Host` header value (RFC 7230 §5.4 / RFC 3986 §3.2.2) and return the
+/// host part without port: a reg-name / IPv4 or a bracketed IPv6 literal, optionally
+/// with a zone identifier (`[fe80::1%25eth0]`, RFC 6874; a bare `%` is tolerated).
+pub fn parse_host(value: &str) -> Option<&str> {
+    let value = value.trim();
+    let (host, port) = if let Some(rest) = value.strip_prefix('[') {
+        let (v6, after) = rest.split_once(']')?;
+        let (ip, zone) = match v6.split_once('%') { Some((ip, z)) => (ip, Some(z)), None => (v6, None) };
+        if ip.is_empty() || !ip.bytes().all(|c| c.is_ascii_hexdigit() || c == b':' || c == b'.') { return None; }
+        if zone.map_or(false, |z| z.is_empty() || !z.bytes().all(|c| c.is_ascii_alphanumeric() || b"-._~%".contains(&c))) { return None; }
+        let port = match after { "" => None, p => Some(p.strip_prefix(':')?) };
+        (&value[..v6.len() + 2], port)
+    } else {
+        let (h, p) = match value.split_once(':') { Some((h, p)) => (h, Some(p)), None => (value, None) };
+        if h.is_empty() || h.len() > 253 { return None; }
+        if !h.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'.' || c == b'_') { return None; }
+        (h, p)
+    };
+    if let Some(p) = port {
+        if p.is_empty() || p.len() > 5 || !p.bytes().all(|c| c.is_ascii_digit()) || p.parse::<u32>().ok()? > 65535 { return None; }
+    }
+    Some(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(v: &[(&str, &str)]) -> Vec<(String, String)> {
+        v.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn target_splits_at_the_first_question_mark() {
+        assert_eq!(split_target("/a/b?x=1&y=2"), ("/a/b", Some("x=1&y=2")));
+        assert_eq!(split_target("/a?b?c"), ("/a", Some("b?c")));
+        assert_eq!(split_target("/a#frag?x=1"), ("/a", None));
+        assert_eq!(split_target("/a"), ("/a", None));
+        assert_eq!(split_target("/a?"), ("/a", Some("")));
+    }
+
+    #[test]
+    fn query_keeps_every_parameter_in_order() {
+        assert_eq!(parse_query("a=1&b=2&c=3"), pairs(&[("a", "1"), ("b", "2"), ("c", "3")]));
+        assert_eq!(parse_query("tag=x&tag=y&tag=x"), pairs(&[("tag", "x"), ("tag", "y"), ("tag", "x")]));
+    }
+
+    #[test]
+    fn query_values_are_decoded() {
+        assert_eq!(parse_query("q=a+b%20c&k%3D=%E2%9C%93"), pairs(&[("q", "a b c"), ("k=", "\u{2713}")]));
+        // Malformed escapes stay literal; invalid UTF-8 is replaced.
+        assert_eq!(parse_query("p=100%&r=%zz&s=%ff"), pairs(&[("p", "100%"), ("r", "%zz"), ("s", "\u{fffd}")]));
+    }
+
+    #[test]
+    fn path_escapes_are_decoded_strictly() {
+        assert_eq!(decode_path("/my%20file.txt").unwrap(), "/my file.txt");
+        assert_eq!(decode_path("/a+b").unwrap(), "/a+b");
+        assert_eq!(decode_path("/caf%C3%A9").unwrap(), "/caf\u{e9}");
+        // Traversal decodes to `..`, which the filesystem mapping then refuses.
+        assert_eq!(decode_path("/%2e%2e/%2E%2E/etc/passwd").unwrap(), "/../../etc/passwd");
+        assert_eq!(decode_path("/%zz"), None);
+        assert_eq!(decode_path("/%2"), None);
+        assert_eq!(decode_path("/%"), None);
+        assert_eq!(decode_path("/a%00b"), None);
+        assert_eq!(decode_path("/a%0d%0aSet-Cookie:x"), None);
+        assert_eq!(decode_path("/%ff"), None);
+    }
+
+    #[test]
+    fn encoded_paths_decode_back() {
+        for path in ["/my file.txt", "/caf\u{e9}/100%", "/a?b#c", "/plain-path_1.~"] {
+            assert_eq!(decode_path(&encode_path(path)).unwrap(), path);
+        }
+        assert_eq!(encode_path("/a b/%"), "/a%20b/%25");
+    }
+
+    #[test]
+    fn host_values_are_validated() {
+        assert_eq!(parse_host("example.com"), Some("example.com"));
+        assert_eq!(parse_host(" example.com:8080 "), Some("example.com"));
+        assert_eq!(parse_host("10.0.0.1:443"), Some("10.0.0.1"));
+        assert_eq!(parse_host("my_host-1.local"), Some("my_host-1.local"));
+        for bad in ["", ":80", "example.com:", "example.com:99999", "example.com:8o", "a b", "a/b", "a@b", "evil.com\r\nX: y", &"a".repeat(254)] {
+            assert_eq!(parse_host(bad), None, "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn empty_values_and_segments() {
+        assert_eq!(parse_query("flag&empty=&=v"), pairs(&[("flag", ""), ("empty", ""), ("", "v")]));
+        assert_eq!(parse_query("&&a=1&"), pairs(&[("a", "1")]));
+        assert!(parse_query("").is_empty());
+    }
+
+    #[test]
+    fn bracketed_ipv6_hosts_keep_their_zone() {
+        assert_eq!(parse_host("[::1]:8080"), Some("[::1]"));
+        assert_eq!(parse_host("[2001:db8::1]"), Some("[2001:db8::1]"));
+        assert_eq!(parse_host("[fe80::1%25eth0]:443"), Some("[fe80::1%25eth0]"));
+        assert_eq!(parse_host("[fe80::1%eth0]"), Some("[fe80::1%eth0]"));
+        assert_eq!(parse_host("[::ffff:192.0.2.1]"), Some("[::ffff:192.0.2.1]"));
+        for bad in ["[::1", "[]", "[::1]8080", "[::1]:", "[fe80::1%]", "[fe80::1%eth/0]", "[::g]", "::1", "::1:8080"] {
+            assert_eq!(parse_host(bad), None, "{:?}", bad);
+        }
+    }
+}
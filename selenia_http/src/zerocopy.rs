@@ -4,9 +4,14 @@
 use std::fs::File;
 use std::io::{self};
 use std::net::TcpStream;
+use std::sync::Arc;
+
+use super::mmap::MappedFile;
 
 #[cfg(target_os = "linux")]
 use std::os::unix::io::AsRawFd;
+#[cfg(not(target_os = "linux"))]
+use std::io::{Read, Seek, SeekFrom, Write};
 
 #[cfg(target_os = "windows")]
 use std::os::windows::io::{AsRawHandle, AsRawSocket};
@@ -28,46 +33,44 @@ extern "system" {
 /// Transfer entire `file_len` bytes from `file` to `stream`.
 /// Chooses the most efficient zero-copy path when available.
 pub fn transfer(stream: &TcpStream, file: &File, file_len: u64) -> io::Result<()> {
+    transfer_range(stream, file, 0, file_len)
+}
+
+/// Transfer `len` bytes of `file` starting at `offset` (206 range responses).
+/// Linux seeds the `sendfile` offset directly, so the file position is left untouched.
+/// Blocking-socket variant; the event loop uses [`send_file`] which survives EAGAIN.
+#[allow(dead_code)]
+pub fn transfer_range(stream: &TcpStream, file: &File, offset: u64, len: u64) -> io::Result<()> {
     #[cfg(target_os = "linux")]
     {
-        use libc::{off_t, sendfile};
-
-        let out_fd = stream.as_raw_fd();
-        let in_fd = file.as_raw_fd();
-        let mut offset: off_t = 0;
-
-        while (offset as u64) < file_len {
-            let remaining = file_len - offset as u64;
-            let count = remaining.min(1 << 30) as usize; // up to 1 GiB per call to avoid EINVAL on some kernels
-            let ret = unsafe { sendfile(out_fd, in_fd, &mut offset, count) };
-            if ret < 0 {
-                return Err(std::io::Error::last_os_error());
-            }
-            if ret == 0 { break; }
-        }
-        return Ok(());
+        let mut off = offset;
+        return sendfile_until(stream, file, &mut off, offset + len);
     }
     #[cfg(target_os="windows")]
     {
         // Try TransmitFile for zero-copy on Windows (falls back to buffered copy on failure).
+        // TransmitFile reads from the current file position, so seek to `offset` first.
         const TF_USE_DEFAULT_WORKER: u32 = 0x00000000;
+        let mut f = file;
+        f.seek(SeekFrom::Start(offset))?;
         let sock = stream.as_raw_socket() as usize;
         let handle = file.as_raw_handle() as usize;
-        // TransmitFile parameters: write entire file in one go. Windows limits to 2^32-1 bytes; ensure safe cast.
-        let to_write = if file_len > u32::MAX as u64 { u32::MAX } else { file_len as u32 };
-        let ok = unsafe {
-            TransmitFile(
-                sock,
-                handle,
-                to_write,
-                0,                // nNumberOfBytesPerSend=0 -> use default chunk size
-                core::ptr::null_mut(),
-                core::ptr::null_mut(),
-                TF_USE_DEFAULT_WORKER,
-            )
-        };
-        if ok != 0 {
-            return Ok(());
+        // Windows limits a single call to 2^32-1 bytes; larger ranges use the fallback below.
+        if len <= u32::MAX as u64 {
+            let ok = unsafe {
+                TransmitFile(
+                    sock,
+                    handle,
+                    len as u32,
+                    0,                // nNumberOfBytesPerSend=0 -> use default chunk size
+                    core::ptr::null_mut(),
+                    core::ptr::null_mut(),
+                    TF_USE_DEFAULT_WORKER,
+                )
+            };
+            if ok != 0 {
+                return Ok(());
+            }
         }
         // If TransmitFile failed, fall back to user-space copy.
         // No early return here; execution will continue to portable fallback below.
@@ -76,15 +79,198 @@ pub fn transfer(stream: &TcpStream, file: &File, file_len: u64) -> io::Result<()
     {
         // Portable fallback – copy via userspace buffer (64 KiB).
         let mut reader = file;
+        reader.seek(SeekFrom::Start(offset))?;
         let mut writer = stream; // Obtain mutable borrow for Write trait
         let mut buf = [0u8; 65536];
         let mut written: u64 = 0;
-        while written < file_len {
-            let n = reader.read(&mut buf)?;
+        while written < len {
+            let want = (len - written).min(buf.len() as u64) as usize;
+            let n = reader.read(&mut buf[..want])?;
             if n == 0 { break; }
             writer.write_all(&buf[..n])?;
             written += n as u64;
         }
         return Ok(());
     }
-} 
\ No newline at end of file
+}
+
+/// Linux `sendfile` loop from `*off` up to `end`. `*off` tracks progress, so after a
+/// `WouldBlock` error the caller can resume exactly where the socket buffer filled up.
+#[cfg(target_os = "linux")]
+fn sendfile_until(stream: &TcpStream, file: &File, off: &mut u64, end: u64) -> io::Result<()> {
+    use libc::{off_t, sendfile};
+
+    let out_fd = stream.as_raw_fd();
+    let in_fd = file.as_raw_fd();
+    while *off < end {
+        let count = (end - *off).min(1 << 30) as usize; // up to 1 GiB per call to avoid EINVAL on some kernels
+        let mut pos: off_t = *off as off_t;
+        let ret = unsafe { sendfile(out_fd, in_fd, &mut pos, count) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted { continue; }
+            return Err(err);
+        }
+        if ret == 0 {
+            // File shrank underneath us; the promised Content-Length can no longer be met.
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file truncated during sendfile"));
+        }
+        *off = pos as u64;
+    }
+    Ok(())
+}
+
+/// File body that could not be written in one go because the non-blocking socket
+/// filled up. The event loop parks it on the connection and calls [`PendingSend::resume`]
+/// when the socket reports writable again.
+#[derive(Debug)]
+pub struct PendingSend {
+    source: Source,
+    offset: u64,
+    end: u64,
+}
+
+#[derive(Debug)]
+enum Source {
+    File(File),
+    /// Cached mapping (`mmap_threshold`).
+    Map(Arc<MappedFile>),
+}
+
+impl PendingSend {
+    /// Continue the transfer from the current offset. `Ok(true)` once the whole range is out,
+    /// `Ok(false)` when the socket would block again.
+    pub fn resume(&mut self, stream: &TcpStream) -> io::Result<bool> {
+        let sent = match &self.source {
+            Source::File(file) => send_step(stream, file, &mut self.offset, self.end),
+            Source::Map(map) => map.write_range(stream, &mut self.offset, self.end),
+        };
+        match sent {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Send `len` bytes of `file` from `offset` on a non-blocking socket. Returns the
+/// unsent remainder when the socket buffer fills up (EAGAIN) instead of failing.
+pub fn send_file(stream: &TcpStream, file: File, offset: u64, len: u64) -> io::Result<Option<PendingSend>> {
+    let mut pending = PendingSend { source: Source::File(file), offset, end: offset + len };
+    if pending.resume(stream)? { Ok(None) } else { Ok(Some(pending)) }
+}
+
+/// [`send_file`] from a mapping of the file rather than its descriptor.
+pub fn send_mapped(stream: &TcpStream, map: Arc<MappedFile>, offset: u64, len: u64) -> io::Result<Option<PendingSend>> {
+    map.will_need(offset, len);
+    let mut pending = PendingSend { source: Source::Map(map), offset, end: offset + len };
+    if pending.resume(stream)? { Ok(None) } else { Ok(Some(pending)) }
+}
+
+#[cfg(target_os = "linux")]
+fn send_step(stream: &TcpStream, file: &File, off: &mut u64, end: u64) -> io::Result<()> {
+    sendfile_until(stream, file, off, end)
+}
+
+/// Portable resumable path: positioned reads plus plain writes, advancing `*off` only
+/// by what the socket actually accepted.
+#[cfg(not(target_os = "linux"))]
+fn send_step(stream: &TcpStream, file: &File, off: &mut u64, end: u64) -> io::Result<()> {
+    let mut reader = file;
+    let mut writer = stream;
+    let mut buf = [0u8; 65536];
+    while *off < end {
+        reader.seek(SeekFrom::Start(*off))?;
+        let want = (end - *off).min(buf.len() as u64) as usize;
+        let n = reader.read(&mut buf[..want])?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file truncated during send"));
+        }
+        let w = writer.write(&buf[..n])?;
+        *off += w as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A scratch file of `len` bytes where byte `i` is `i % 251`, so any misplaced range shows.
+    fn pattern_file(len: usize) -> (std::path::PathBuf, File, Vec<u8>) {
+        static SEQ: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!("sws-zerocopy-{}-{}", std::process::id(), SEQ.fetch_add(1, Ordering::Relaxed)));
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        (path.clone(), File::open(&path).unwrap(), data)
+    }
+
+    fn pair() -> (TcpStream, TcpStream) {
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(l.local_addr().unwrap()).unwrap();
+        (client, l.accept().unwrap().0)
+    }
+
+    #[test]
+    fn range_transfer_sends_exact_bytes() {
+        let (path, mut file, data) = pattern_file(4 << 20);
+        let (mut client, server) = pair();
+        let (offset, len) = (1_000_003u64, 2_500_001u64);
+        let reader = std::thread::spawn(move || {
+            let mut got = Vec::new();
+            client.read_to_end(&mut got).unwrap();
+            got
+        });
+        transfer_range(&server, &file, offset, len).unwrap();
+        drop(server);
+        assert!(reader.join().unwrap() == data[offset as usize..(offset + len) as usize]);
+        // The file position is untouched, so the same descriptor can serve another range.
+        assert_eq!(file.stream_position().unwrap(), 0);
+        let mut head = [0u8; 4];
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_exact(&mut head).unwrap();
+        assert_eq!(head, [0, 1, 2, 3]);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn send_file_resumes_after_eagain() {
+        let (path, file, data) = pattern_file(8 << 20);
+        let (mut client, server) = pair();
+        server.set_nonblocking(true).unwrap();
+        let expected = data.clone();
+        let reader = std::thread::spawn(move || {
+            // A slow reader: small reads with pauses keep the socket buffer full.
+            let mut got = Vec::new();
+            let mut buf = [0u8; 64 * 1024];
+            while got.len() < expected.len() {
+                let n = client.read(&mut buf).unwrap();
+                if n == 0 { break; }
+                got.extend_from_slice(&buf[..n]);
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            got
+        });
+        let mut pending = send_file(&server, file, 0, data.len() as u64).unwrap().expect("socket buffer filled up");
+        let mut stalls = 1;
+        while !pending.resume(&server).unwrap() {
+            stalls += 1;
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        assert!(stalls > 1);
+        assert!(reader.join().unwrap() == data);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn truncated_file_is_an_error() {
+        let (path, file, _) = pattern_file(1000);
+        let (_client, server) = pair();
+        let err = transfer_range(&server, &file, 900, 200).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let _ = std::fs::remove_file(path);
+    }
+}
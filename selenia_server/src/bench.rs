@@ -0,0 +1,206 @@
+//! Built-in loopback benchmark (`sws benchmark`).
+//!
+//! Starts a worker of this binary on an ephemeral loopback port, drives it with
+//! keep-alive GETs from `connections` client threads for `duration` seconds and
+//! prints throughput, latency quantiles (from the metrics latency histogram) and the
+//! error count. Serves `--root` or, without one, a temporary directory holding a
+//! small `index.html`. Needs nothing but the binary itself.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use selenia_core::metrics;
+
+pub struct Options {
+    pub connections: usize,
+    pub duration: Duration,
+    pub path: String,
+    pub root: Option<PathBuf>,
+}
+
+impl Options {
+    /// `--connections N --duration SECS --path /p --root DIR`, all optional.
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut o = Options { connections: 32, duration: Duration::from_secs(5), path: "/".into(), root: None };
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or_else(|| format!("{} needs a value", flag))?;
+            match flag.as_str() {
+                "--connections" => o.connections = value.parse().ok().filter(|&n| n > 0).ok_or_else(|| format!("invalid --connections: {}", value))?,
+                "--duration" => o.duration = Duration::from_secs(value.parse().ok().filter(|&n| n > 0).ok_or_else(|| format!("invalid --duration: {}", value))?),
+                "--path" if !value.starts_with('/') => return Err(format!("--path must start with '/': {}", value)),
+                "--path" => o.path = value,
+                "--root" => o.root = Some(PathBuf::from(value)),
+                _ => return Err(format!("unknown option {} {}", flag, value)),
+            }
+        }
+        Ok(o)
+    }
+}
+
+/// What the client threads saw.
+pub struct Report {
+    pub requests: u64,
+    pub errors: u64,
+    pub elapsed: Duration,
+}
+
+impl Report {
+    pub fn requests_per_sec(&self) -> f64 { self.requests as f64 / self.elapsed.as_secs_f64() }
+}
+
+/// Worker process plus the scratch directory holding its config; both go on drop.
+struct Server {
+    child: Child,
+    dir: PathBuf,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Start the worker and wait until it accepts connections.
+fn start_server(root: Option<&PathBuf>) -> io::Result<(Server, u16)> {
+    let dir = std::env::temp_dir().join(format!("sws-bench-{}", std::process::id()));
+    let www = dir.join("www");
+    std::fs::create_dir_all(&www)?;
+    let root = match root {
+        Some(r) => std::fs::canonicalize(r)?,
+        None => {
+            std::fs::write(www.join("index.html"), "<html><body>Selenia benchmark</body></html>\n")?;
+            www
+        }
+    };
+    // Ask the kernel for a free port; the worker binds it right after.
+    let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+    let cfg = dir.join("bench.yaml");
+    std::fs::write(&cfg, format!("server:\n  listen:\n    - \"127.0.0.1:{}\"\n  root_dir: \"{}\"\n  locale: \"en\"\n  rate_limit:\n    capacity: 0\n", port, root.display()))?;
+    let child = Command::new(std::env::current_exe()?)
+        .arg(&cfg)
+        .env("SWS_ROLE", "worker")
+        .current_dir(&dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let mut server = Server { child, dir };
+    let ready_by = Instant::now() + Duration::from_secs(5);
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        if let Some(status) = server.child.try_wait()? {
+            return Err(io::Error::new(io::ErrorKind::Other, format!("server exited early ({})", status)));
+        }
+        if Instant::now() >= ready_by {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "server did not start listening"));
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    Ok((server, port))
+}
+
+/// Outcome of one request on a keep-alive connection.
+struct Reply {
+    ok: bool,
+    /// The server announced `Connection: close` (e.g. its per-connection request cap).
+    close: bool,
+}
+
+/// One keep-alive request/response; `ok` is false for a non-2xx status.
+fn exchange(reader: &mut BufReader<TcpStream>, request: &[u8]) -> io::Result<Reply> {
+    reader.get_mut().write_all(request)?;
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 { return Err(io::ErrorKind::UnexpectedEof.into()); }
+    let ok = line.split_whitespace().nth(1).map_or(false, |s| s.starts_with('2'));
+    let (mut length, mut close) = (0usize, false);
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 { return Err(io::ErrorKind::UnexpectedEof.into()); }
+        let header = line.trim_end();
+        if header.is_empty() { break; }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+            } else if name.eq_ignore_ascii_case("connection") {
+                close = value.trim().eq_ignore_ascii_case("close");
+            }
+        }
+    }
+    io::copy(&mut reader.by_ref().take(length as u64), &mut io::sink())?;
+    Ok(Reply { ok, close })
+}
+
+/// Run the benchmark against a fresh worker.
+pub fn run(opts: &Options) -> io::Result<Report> {
+    let (_server, port) = start_server(opts.root.as_ref())?;
+    let request = format!("GET {} HTTP/1.1\r\nHost: 127.0.0.1:{}\r\n\r\n", opts.path, port).into_bytes();
+    let requests = Arc::new(AtomicU64::new(0));
+    let errors = Arc::new(AtomicU64::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+    let start = Instant::now();
+    let clients: Vec<_> = (0..opts.connections).map(|_| {
+        let (request, requests, errors, stop) = (request.clone(), Arc::clone(&requests), Arc::clone(&errors), Arc::clone(&stop));
+        thread::spawn(move || {
+            let mut conn = None;
+            while !stop.load(Ordering::Relaxed) {
+                if conn.is_none() {
+                    match TcpStream::connect(("127.0.0.1", port)) {
+                        Ok(s) => conn = Some(BufReader::new(s)),
+                        Err(_) => { errors.fetch_add(1, Ordering::Relaxed); thread::sleep(Duration::from_millis(10)); continue; }
+                    }
+                }
+                let sent = Instant::now();
+                match exchange(conn.as_mut().unwrap(), &request) {
+                    Ok(reply) => {
+                        metrics::observe_latency(sent.elapsed());
+                        requests.fetch_add(1, Ordering::Relaxed);
+                        if !reply.ok { errors.fetch_add(1, Ordering::Relaxed); }
+                        if reply.close { conn = None; }
+                    }
+                    // Broken or closed connection: count it and reconnect.
+                    Err(_) => { errors.fetch_add(1, Ordering::Relaxed); conn = None; }
+                }
+            }
+        })
+    }).collect();
+    thread::sleep(opts.duration);
+    stop.store(true, Ordering::Relaxed);
+    for c in clients { let _ = c.join(); }
+    Ok(Report { requests: requests.load(Ordering::Relaxed), errors: errors.load(Ordering::Relaxed), elapsed: start.elapsed() })
+}
+
+/// Human-readable summary of `report` and the latency histogram.
+pub fn print(opts: &Options, report: &Report) {
+    println!("{} connections, {:.1?}, GET {}", opts.connections, report.elapsed, opts.path);
+    println!("requests: {}  errors: {}  throughput: {:.1} req/s", report.requests, report.errors, report.requests_per_sec());
+    println!(
+        "latency p50 <= {:?}  p90 <= {:?}  p99 <= {:?}",
+        metrics::latency_quantile(0.5), metrics::latency_quantile(0.9), metrics::latency_quantile(0.99)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Options;
+    use std::time::Duration;
+
+    fn parse(args: &[&str]) -> Result<Options, String> { Options::parse(args.iter().map(|s| s.to_string())) }
+
+    #[test]
+    fn options_default_and_override() {
+        let o = parse(&[]).unwrap();
+        assert_eq!((o.connections, o.duration, o.path.as_str(), o.root), (32, Duration::from_secs(5), "/", None));
+        let o = parse(&["--connections", "4", "--duration", "1", "--path", "/a.html", "--root", "/srv"]).unwrap();
+        assert_eq!((o.connections, o.duration, o.path.as_str()), (4, Duration::from_secs(1), "/a.html"));
+        assert_eq!(o.root.unwrap().to_str(), Some("/srv"));
+        for bad in [&["--connections", "0"][..], &["--duration", "x"], &["--path", "a.html"], &["--threads", "2"], &["--root"]] {
+            assert!(parse(bad).is_err(), "{:?}", bad);
+        }
+    }
+}
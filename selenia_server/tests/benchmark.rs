@@ -0,0 +1,22 @@
+//! `sws benchmark` end to end: the built binary starts its own worker and loads it.
+
+use std::process::Command;
+
+#[test]
+fn short_benchmark_serves_without_errors() {
+    let root = std::env::temp_dir().join(format!("sws-bench-test-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("index.html"), "<html><body>bench</body></html>\n").unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_selenia_server"))
+        .args(["benchmark", "--connections", "4", "--duration", "1", "--path", "/index.html", "--root"])
+        .arg(&root)
+        .output()
+        .unwrap();
+    let _ = std::fs::remove_dir_all(&root);
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(out.status.success(), "{}{}", stdout, String::from_utf8_lossy(&out.stderr));
+    let line = stdout.lines().find(|l| l.starts_with("requests: ")).unwrap();
+    let field = |name: &str| -> u64 { line.split_whitespace().skip_while(|w| *w != name).nth(1).unwrap().parse().unwrap() };
+    assert!(field("requests:") > 0, "{}", line);
+    assert_eq!(field("errors:"), 0, "{}", line);
+}
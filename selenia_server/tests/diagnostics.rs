@@ -0,0 +1,67 @@
+//! SIGUSR1 asks every worker for a diagnostics dump; with `diagnostics_file` set it
+//! lands there instead of the log.
+#![cfg(unix)]
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn get(port: u16) -> std::io::Result<bool> {
+    let mut s = TcpStream::connect(("127.0.0.1", port))?;
+    s.set_read_timeout(Some(Duration::from_secs(5)))?;
+    s.write_all(b"GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+    let mut out = Vec::new();
+    s.read_to_end(&mut out)?;
+    Ok(out.starts_with(b"HTTP/1.1 200 "))
+}
+
+#[test]
+fn sigusr1_writes_a_diagnostics_dump() {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let dir = std::env::temp_dir().join(format!("sws-diag-test-{}", std::process::id()));
+    let www = dir.join("www");
+    std::fs::create_dir_all(&www).unwrap();
+    std::fs::write(www.join("index.html"), "diag\n").unwrap();
+    let dump = dir.join("diag.txt");
+    let cfg = dir.join("sws.yaml");
+    let yaml = format!(
+        "server:\n  listen:\n    - \"127.0.0.1:{}\"\n  root_dir: \"{}\"\n  locale: \"en\"\n  diagnostics_file: \"{}\"\n",
+        port, www.display(), dump.display()
+    );
+    std::fs::write(&cfg, yaml).unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_selenia_server"))
+        .arg(&cfg)
+        .current_dir(&dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let ready_by = Instant::now() + Duration::from_secs(10);
+    while !matches!(get(port), Ok(true)) && Instant::now() < ready_by {
+        thread::sleep(Duration::from_millis(50));
+    }
+    unsafe { libc::kill(child.id() as i32, libc::SIGUSR1) };
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut text = String::new();
+    while Instant::now() < deadline {
+        text = std::fs::read_to_string(&dump).unwrap_or_default();
+        if text.contains(&format!("listener 127.0.0.1:{}", port)) { break; }
+        thread::sleep(Duration::from_millis(50));
+    }
+    // A dump is no shutdown: the server still answers afterwards.
+    let alive = matches!(get(port), Ok(true));
+    unsafe { libc::kill(child.id() as i32, libc::SIGTERM) };
+    let stop_by = Instant::now() + Duration::from_secs(5);
+    while child.try_wait().ok().flatten().is_none() && Instant::now() < stop_by {
+        thread::sleep(Duration::from_millis(20));
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+    let _ = std::fs::remove_dir_all(&dir);
+    assert!(text.starts_with("# diagnostics pid "), "{:?}", text);
+    assert!(text.contains("\nconnections: "), "{}", text);
+    assert!(text.contains(&format!("listener 127.0.0.1:{}: ", port)), "{}", text);
+    assert!(alive);
+}
@@ -0,0 +1,38 @@
+//! `sws plugin validate` exit codes and messages, against freshly compiled libraries.
+#![cfg(unix)]
+
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+/// Compile `src` into a `cdylib` named `lib<name>.so` in a scratch directory.
+fn build(name: &str, src: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("sws-plugin-cli-{}-{}", std::process::id(), name));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("plugin.rs"), src).unwrap();
+    let out = dir.join(format!("lib{}.so", name));
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    let status = Command::new(rustc).args(["--crate-type", "cdylib", "--edition", "2021", "-o"]).arg(&out).arg(dir.join("plugin.rs")).status().unwrap();
+    assert!(status.success());
+    out
+}
+
+fn sws(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_selenia_server")).args(args).output().unwrap()
+}
+
+#[test]
+fn validate_exit_codes_and_messages() {
+    let good = build("cli-good", "#[no_mangle] pub extern \"C\" fn sws_plugin_init() {}");
+    let bad = build("cli-bad", "#[no_mangle] pub extern \"C\" fn unrelated() {}");
+    let ok = sws(&["plugin", "validate", good.to_str().unwrap()]);
+    let missing = sws(&["plugin", "validate", bad.to_str().unwrap()]);
+    for lib in [&good, &bad] { let _ = std::fs::remove_dir_all(lib.parent().unwrap()); }
+    assert_eq!(ok.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&ok.stdout), format!("Validation OK: {} (libcli-good, ABI v0)\n", good.display()));
+    assert_eq!(missing.status.code(), Some(1));
+    let err = String::from_utf8_lossy(&missing.stderr);
+    assert!(err.starts_with(&format!("Validation failed: {}: required symbol missing", bad.display())), "{}", err);
+    let usage = sws(&["plugin", "validate"]);
+    assert_eq!(usage.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&usage.stderr).starts_with("Usage: sws plugin <install|validate> <file.so>"));
+}
@@ -0,0 +1,100 @@
+//! Hot reload with `listener_handoff`: the master keeps the listening sockets, so
+//! clients hammering the port across a SIGHUP never see a refused connection.
+#![cfg(unix)]
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Master process and its scratch directory; the master takes its workers down on SIGTERM.
+struct Master {
+    child: Child,
+    dir: PathBuf,
+}
+
+impl Drop for Master {
+    fn drop(&mut self) {
+        unsafe { libc::kill(self.child.id() as i32, libc::SIGTERM) };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while self.child.try_wait().ok().flatten().is_none() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn start(port: u16) -> Master {
+    let dir = std::env::temp_dir().join(format!("sws-reload-test-{}", std::process::id()));
+    let www = dir.join("www");
+    std::fs::create_dir_all(&www).unwrap();
+    std::fs::write(www.join("index.html"), "reload\n").unwrap();
+    let cfg = dir.join("sws.yaml");
+    let yaml = format!(
+        "server:\n  listen:\n    - \"127.0.0.1:{}\"\n  root_dir: \"{}\"\n  locale: \"en\"\n  listener_handoff: true\n  rate_limit:\n    capacity: 0\n",
+        port, www.display()
+    );
+    std::fs::write(&cfg, yaml).unwrap();
+    let child = Command::new(env!("CARGO_BIN_EXE_selenia_server"))
+        .arg(&cfg)
+        .current_dir(&dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    Master { child, dir }
+}
+
+/// One request on a fresh connection; `Err` only when the connection itself fails.
+fn get(port: u16) -> std::io::Result<bool> {
+    let mut s = TcpStream::connect(("127.0.0.1", port))?;
+    s.set_read_timeout(Some(Duration::from_secs(5)))?;
+    s.write_all(b"GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+    let mut out = Vec::new();
+    s.read_to_end(&mut out)?;
+    Ok(out.starts_with(b"HTTP/1.1 200 "))
+}
+
+#[test]
+fn no_connection_is_refused_across_a_reload() {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let master = start(port);
+    let ready_by = Instant::now() + Duration::from_secs(10);
+    while !matches!(get(port), Ok(true)) {
+        assert!(Instant::now() < ready_by, "server did not come up");
+        thread::sleep(Duration::from_millis(50));
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    let clients: Vec<_> = (0..4).map(|_| {
+        let stop = Arc::clone(&stop);
+        thread::spawn(move || {
+            let (mut served, mut failed) = (0u32, Vec::new());
+            while !stop.load(Ordering::Relaxed) {
+                match get(port) {
+                    Ok(_) => served += 1,
+                    Err(e) => failed.push(e.kind()),
+                }
+            }
+            (served, failed)
+        })
+    }).collect();
+    for _ in 0..2 {
+        thread::sleep(Duration::from_millis(700));
+        unsafe { libc::kill(master.child.id() as i32, libc::SIGHUP) };
+    }
+    thread::sleep(Duration::from_millis(1500));
+    stop.store(true, Ordering::Relaxed);
+    let mut served = 0;
+    for c in clients {
+        let (n, failed) = c.join().unwrap();
+        assert!(failed.is_empty(), "{} connections failed, first {:?}", failed.len(), failed.first());
+        served += n;
+    }
+    assert!(served > 0);
+}